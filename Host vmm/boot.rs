@@ -0,0 +1,308 @@
+// src/boot.rs - Linux Boot Protocol
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use linux_loader::configurator::linux::LinuxBootConfigurator;
+use linux_loader::configurator::{BootConfigurator, BootParams};
+use linux_loader::loader::bootparam::{boot_e820_entry, boot_params, setup_header};
+use linux_loader::loader::{self, BzImage, Cmdline, Elf, KernelLoader, KernelLoaderResult};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+use crate::config::VmConfig;
+use crate::{layout, memory};
+
+// --- Kernel, Initramfs and Zero Page ---
+//
+// Implements the 64-bit Linux boot protocol (Documentation/x86/boot.rst):
+// the kernel image is loaded at or above 1 MiB, the initramfs at the top of
+// low memory, and `boot_params` (the "zero page") tells the kernel where
+// they are, where its command line is and what the E820 memory map looks
+// like. ELF vmlinux images and bzImages are both accepted.
+
+/// `boot_params.hdr.boot_flag`.
+const KERNEL_BOOT_FLAG_MAGIC: u16 = 0xaa55;
+/// `boot_params.hdr.header`: "HdrS".
+const KERNEL_HDR_MAGIC: u32 = 0x5372_6448;
+/// `type_of_loader` for a boot loader without an assigned ID.
+const KERNEL_LOADER_OTHER: u8 = 0xff;
+/// Alignment of a relocatable kernel.
+const KERNEL_MIN_ALIGNMENT_BYTES: u32 = 0x0100_0000;
+/// The 64-bit entry point of a bzImage, from the start of its protected-mode code.
+const BZIMAGE_64BIT_ENTRY_OFFSET: u64 = 0x200;
+
+/// E820 entry types.
+pub const E820_RAM: u32 = 1;
+pub const E820_RESERVED: u32 = 2;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Loads the configured kernel, initramfs and command line, writes the
+/// zero page and returns the 64-bit entry point.
+pub fn load_linux(guest_mem: &GuestMemoryMmap, config: &VmConfig) -> Result<GuestAddress> {
+    let kernel_path = config.kernel.as_ref().ok_or_else(|| anyhow!("No kernel configured"))?;
+    let kernel = load_kernel(guest_mem, kernel_path)?;
+    let entry = match kernel.setup_header {
+        Some(_) => kernel.kernel_load.unchecked_add(BZIMAGE_64BIT_ENTRY_OFFSET),
+        None => kernel.kernel_load,
+    };
+
+    let cmdline = build_cmdline(&config.cmdline)?;
+    loader::load_cmdline(guest_mem, GuestAddress(layout::CMDLINE_START), &cmdline)
+        .map_err(|e| anyhow!("Failed to write the kernel command line: {}", e))?;
+
+    let initrd_addr_max = match kernel.setup_header {
+        Some(hdr) if hdr.initrd_addr_max != 0 => u64::from(hdr.initrd_addr_max),
+        _ => layout::INITRD_ADDR_MAX,
+    };
+    let initramfs = match &config.initramfs {
+        Some(path) => Some(load_initramfs(guest_mem, path, kernel.kernel_end, initrd_addr_max)?),
+        None => None,
+    };
+
+    let cmdline_size = cmdline.as_cstring().map(|c| c.as_bytes_with_nul().len()).unwrap_or(0);
+    write_zero_page(guest_mem, kernel.setup_header, cmdline_size, initramfs)?;
+    log::info!(
+        "Loaded {} (entry {:#x}, end {:#x}){}",
+        kernel_path.display(),
+        entry.raw_value(),
+        kernel.kernel_end,
+        initramfs.map(|(addr, size)| format!(", initramfs {:#x}+{:#x}", addr.raw_value(), size)).unwrap_or_default()
+    );
+    Ok(entry)
+}
+
+/// Loads an ELF vmlinux or a bzImage, told apart by the ELF magic.
+fn load_kernel(guest_mem: &GuestMemoryMmap, path: &Path) -> Result<KernelLoaderResult> {
+    let mut image = File::open(path).with_context(|| format!("Failed to open kernel {}", path.display()))?;
+    let mut magic = [0u8; 4];
+    image.read_exact(&mut magic).with_context(|| format!("Failed to read kernel {}", path.display()))?;
+
+    let highmem = Some(GuestAddress(layout::HIMEM_START));
+    let result = if &magic == ELF_MAGIC {
+        Elf::load(guest_mem, None, &mut image, highmem)
+    } else {
+        BzImage::load(guest_mem, None, &mut image, highmem)
+    };
+    result.map_err(|e| anyhow!("Failed to load kernel {}: {}", path.display(), e))
+}
+
+/// Validates the command line against the size the zero page allows.
+fn build_cmdline(args: &str) -> Result<Cmdline> {
+    let mut cmdline = Cmdline::new(layout::CMDLINE_MAX_SIZE).map_err(|e| anyhow!("{}", e))?;
+    cmdline.insert_str(args).map_err(|e| anyhow!("Invalid kernel command line: {}", e))?;
+    Ok(cmdline)
+}
+
+/// Places the initramfs at the highest page-aligned address below both the
+/// end of low memory (the MMIO gap, with enough RAM) and `initrd_addr_max`,
+/// above the kernel.
+fn load_initramfs(
+    guest_mem: &GuestMemoryMmap,
+    path: &Path,
+    kernel_end: u64,
+    initrd_addr_max: u64,
+) -> Result<(GuestAddress, u64)> {
+    let image = std::fs::read(path).with_context(|| format!("Failed to read initramfs {}", path.display()))?;
+    let size = image.len() as u64;
+    let limit = memory::low_memory_end(guest_mem).min(initrd_addr_max + 1);
+    let start = limit
+        .checked_sub(size)
+        .map(|start| start & !(PAGE_SIZE - 1))
+        .filter(|start| *start >= kernel_end)
+        .ok_or_else(|| anyhow!("Initramfs {} ({} bytes) does not fit in guest memory", path.display(), size))?;
+    guest_mem.write_slice(&image, GuestAddress(start))?;
+    Ok((GuestAddress(start), size))
+}
+
+/// The E820 map handed to the kernel: in the region at 0, low RAM below
+/// the EBDA, the EBDA and legacy ROM area reserved and the rest RAM; every
+/// other guest memory region as RAM. The MMIO gap is left out.
+pub fn e820_map(guest_mem: &GuestMemoryMmap) -> Vec<boot_e820_entry> {
+    let mut map = Vec::new();
+    for region in guest_mem.iter() {
+        let (start, end) = (region.start_addr().raw_value(), region.last_addr().raw_value() + 1);
+        if start == 0 {
+            map.push(boot_e820_entry { addr: 0, size: layout::EBDA_START.min(end), type_: E820_RAM });
+            map.push(boot_e820_entry {
+                addr: layout::EBDA_START,
+                size: layout::HIMEM_START - layout::EBDA_START,
+                type_: E820_RESERVED,
+            });
+            if end > layout::HIMEM_START {
+                map.push(boot_e820_entry {
+                    addr: layout::HIMEM_START,
+                    size: end - layout::HIMEM_START,
+                    type_: E820_RAM,
+                });
+            }
+        } else {
+            map.push(boot_e820_entry { addr: start, size: end - start, type_: E820_RAM });
+        }
+    }
+    map
+}
+
+fn write_zero_page(
+    guest_mem: &GuestMemoryMmap,
+    kernel_header: Option<setup_header>,
+    cmdline_size: usize,
+    initramfs: Option<(GuestAddress, u64)>,
+) -> Result<()> {
+    let mut params = boot_params::default();
+    // A bzImage brings its own header; keep what it says about itself.
+    if let Some(hdr) = kernel_header {
+        params.hdr = hdr;
+    }
+    params.hdr.type_of_loader = KERNEL_LOADER_OTHER;
+    params.hdr.boot_flag = KERNEL_BOOT_FLAG_MAGIC;
+    params.hdr.header = KERNEL_HDR_MAGIC;
+    params.hdr.cmd_line_ptr = layout::CMDLINE_START as u32;
+    params.hdr.cmdline_size = cmdline_size as u32;
+    if kernel_header.is_none() {
+        params.hdr.kernel_alignment = KERNEL_MIN_ALIGNMENT_BYTES;
+    }
+    if let Some((addr, size)) = initramfs {
+        params.hdr.ramdisk_image = addr.raw_value() as u32;
+        params.hdr.ramdisk_size = size as u32;
+    }
+
+    let map = e820_map(guest_mem);
+    if map.len() > params.e820_table.len() {
+        bail!("E820 map has {} entries, the zero page holds {}", map.len(), params.e820_table.len());
+    }
+    params.e820_table[..map.len()].copy_from_slice(&map);
+    params.e820_entries = map.len() as u8;
+
+    LinuxBootConfigurator::write_bootparams::<GuestMemoryMmap>(
+        &BootParams::new(&params, GuestAddress(layout::ZERO_PAGE_START)),
+        guest_mem,
+    )
+    .map_err(|e| anyhow!("Failed to write the zero page: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::offset_of;
+    use std::path::PathBuf;
+    use vm_memory::ByteValued;
+
+    const MEM_SIZE: u64 = 64 << 20;
+
+    /// A scratch file holding `data`, removed on drop.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, data: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!("did-vm-host-{}-{}", std::process::id(), name));
+            std::fs::write(&path, data).unwrap();
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn guest_memory(size: u64) -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0), size as usize)]).unwrap()
+    }
+
+    /// A field of the zero page, `offset` bytes into `boot_params`.
+    fn zero_page_field<T: ByteValued>(guest_mem: &GuestMemoryMmap, offset: usize) -> T {
+        guest_mem.read_obj(GuestAddress(layout::ZERO_PAGE_START + offset as u64)).unwrap()
+    }
+
+    fn hdr_field<T: ByteValued>(guest_mem: &GuestMemoryMmap, offset: usize) -> T {
+        zero_page_field(guest_mem, offset_of!(boot_params, hdr) + offset)
+    }
+
+    #[test]
+    fn cmdline_must_fit_the_zero_page_limit() {
+        assert!(build_cmdline("console=ttyS0 panic=-1").is_ok());
+        assert!(build_cmdline(&"x".repeat(layout::CMDLINE_MAX_SIZE - 1)).is_ok());
+        assert!(build_cmdline(&"x".repeat(layout::CMDLINE_MAX_SIZE)).is_err());
+        assert!(build_cmdline(&"x".repeat(layout::CMDLINE_MAX_SIZE + 1)).is_err());
+    }
+
+    #[test]
+    fn initramfs_goes_page_aligned_to_the_top_of_low_memory() {
+        let guest_mem = guest_memory(MEM_SIZE);
+        let image: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let file = TempFile::new("initramfs-top", &image);
+        let kernel_end = 0x100_0000;
+
+        let (addr, size) = load_initramfs(&guest_mem, &file.0, kernel_end, layout::INITRD_ADDR_MAX).unwrap();
+        let start = addr.raw_value();
+        assert_eq!(size, image.len() as u64);
+        assert_eq!(start % PAGE_SIZE, 0);
+        assert!(start >= kernel_end);
+        assert!(start + size <= MEM_SIZE && MEM_SIZE - (start + size) < PAGE_SIZE);
+        let mut loaded = vec![0u8; image.len()];
+        guest_mem.read_slice(&mut loaded, addr).unwrap();
+        assert_eq!(loaded, image);
+
+        // A kernel that cannot reach the top of memory lowers the limit.
+        let initrd_addr_max = 0x200_0000 - 1;
+        let (addr, size) = load_initramfs(&guest_mem, &file.0, kernel_end, initrd_addr_max).unwrap();
+        assert_eq!(addr.raw_value() % PAGE_SIZE, 0);
+        assert!(addr.raw_value() + size <= initrd_addr_max + 1);
+        assert!(addr.raw_value() >= kernel_end);
+    }
+
+    #[test]
+    fn initramfs_that_does_not_fit_is_refused() {
+        let guest_mem = guest_memory(MEM_SIZE);
+        let file = TempFile::new("initramfs-big", &vec![0u8; 0x10_0000]);
+
+        // Above the kernel there is less room than the image needs.
+        let err = load_initramfs(&guest_mem, &file.0, MEM_SIZE - 0x8_0000, layout::INITRD_ADDR_MAX).unwrap_err();
+        assert!(err.to_string().contains("does not fit"), "{}", err);
+        // Larger than all of memory.
+        let err = load_initramfs(&guest_memory(0x8_0000), &file.0, 0, layout::INITRD_ADDR_MAX).unwrap_err();
+        assert!(err.to_string().contains("does not fit"), "{}", err);
+    }
+
+    #[test]
+    fn zero_page_describes_the_boot() {
+        let guest_mem = guest_memory(MEM_SIZE);
+        let initramfs = (GuestAddress(0x300_0000), 0x1234);
+        write_zero_page(&guest_mem, None, 42, Some(initramfs)).unwrap();
+
+        assert_eq!(hdr_field::<u16>(&guest_mem, offset_of!(setup_header, boot_flag)), KERNEL_BOOT_FLAG_MAGIC);
+        assert_eq!(hdr_field::<u32>(&guest_mem, offset_of!(setup_header, header)), KERNEL_HDR_MAGIC);
+        assert_eq!(hdr_field::<u8>(&guest_mem, offset_of!(setup_header, type_of_loader)), KERNEL_LOADER_OTHER);
+        assert_eq!(hdr_field::<u32>(&guest_mem, offset_of!(setup_header, cmd_line_ptr)), layout::CMDLINE_START as u32);
+        assert_eq!(hdr_field::<u32>(&guest_mem, offset_of!(setup_header, cmdline_size)), 42);
+        assert_eq!(hdr_field::<u32>(&guest_mem, offset_of!(setup_header, ramdisk_image)), 0x300_0000);
+        assert_eq!(hdr_field::<u32>(&guest_mem, offset_of!(setup_header, ramdisk_size)), 0x1234);
+        assert_eq!(
+            hdr_field::<u32>(&guest_mem, offset_of!(setup_header, kernel_alignment)),
+            KERNEL_MIN_ALIGNMENT_BYTES
+        );
+
+        let entries = e820_map(&guest_mem);
+        assert_eq!(zero_page_field::<u8>(&guest_mem, offset_of!(boot_params, e820_entries)), entries.len() as u8);
+        let table = offset_of!(boot_params, e820_table);
+        for (i, entry) in entries.iter().enumerate() {
+            let at = table + i * std::mem::size_of::<boot_e820_entry>();
+            assert_eq!(zero_page_field::<u64>(&guest_mem, at), { entry.addr });
+            assert_eq!(zero_page_field::<u64>(&guest_mem, at + 8), { entry.size });
+            assert_eq!(zero_page_field::<u32>(&guest_mem, at + 16), { entry.type_ });
+        }
+    }
+
+    #[test]
+    fn zero_page_without_initramfs_leaves_the_ramdisk_fields_clear() {
+        let guest_mem = guest_memory(MEM_SIZE);
+        write_zero_page(&guest_mem, None, 1, None).unwrap();
+        assert_eq!(hdr_field::<u32>(&guest_mem, offset_of!(setup_header, ramdisk_image)), 0);
+        assert_eq!(hdr_field::<u32>(&guest_mem, offset_of!(setup_header, ramdisk_size)), 0);
+    }
+}
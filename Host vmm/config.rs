@@ -0,0 +1,157 @@
+// src/config.rs - VMM Configuration
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+
+// --- VM Configuration ---
+//
+// What to boot is read from the environment, so one host binary can run
+// different DID agent images: a kernel (ELF vmlinux or bzImage), an optional
+// initramfs, the kernel command line, the number of vCPUs and how much
+// memory the guest gets and how it is backed, and whether it gets a vsock
+// device to reach the host. Without a kernel the VMM runs its built-in
+// self-test guest, on a single vCPU and without devices.
+
+/// Environment variables read by `VmConfig::from_env`.
+pub const VMM_KERNEL_ENV: &str = "VMM_KERNEL";
+pub const VMM_INITRAMFS_ENV: &str = "VMM_INITRAMFS";
+pub const VMM_CMDLINE_ENV: &str = "VMM_CMDLINE";
+pub const VMM_VCPUS_ENV: &str = "VMM_VCPUS";
+pub const VMM_MEMORY_MB_ENV: &str = "VMM_MEMORY_MB";
+pub const VMM_MEMORY_BACKING_ENV: &str = "VMM_MEMORY_BACKING";
+pub const VMM_HUGEPAGES_ENV: &str = "VMM_HUGEPAGES";
+pub const VMM_VSOCK_UDS_ENV: &str = "VMM_VSOCK_UDS";
+pub const VMM_VSOCK_CID_ENV: &str = "VMM_VSOCK_CID";
+
+/// Console on COM1; `reboot=k` and `panic=1` make the guest leave through
+/// the i8042 reset, which stops the VMM, instead of hanging.
+pub const DEFAULT_CMDLINE: &str = "console=ttyS0 reboot=k panic=1 pci=off nomodule";
+
+/// The most vCPUs the MP table below the end of low memory can describe.
+pub const MAX_VCPUS: u8 = 32;
+
+pub const DEFAULT_MEMORY_MB: u64 = 512;
+
+/// The first CID available to guests; 0-2 are reserved for the hypervisor
+/// and the host.
+pub const DEFAULT_VSOCK_CID: u64 = 3;
+
+/// What guest RAM is mapped from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryBacking {
+    /// Private anonymous memory (`anonymous`, the default).
+    Anonymous,
+    /// A shared memfd (`memfd`), which device backends in other processes
+    /// can map too.
+    Memfd,
+}
+
+/// Whether guest RAM uses huge pages, and which kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugePages {
+    /// Base pages only (`none`, the default).
+    None,
+    /// Transparent huge pages (`thp`), requested with `madvise`.
+    Transparent,
+    /// Pages from the hugetlbfs pool (`hugetlbfs`), through a memfd, so it
+    /// implies `MemoryBacking::Memfd`; the pool must be large enough for all
+    /// of guest RAM.
+    Hugetlbfs,
+}
+
+/// The guest's vsock device.
+#[derive(Debug, Clone)]
+pub struct VsockConfig {
+    pub guest_cid: u64,
+    /// Host-side Unix socket; see `vsock` for how connections map onto it.
+    pub uds_path: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct VmConfig {
+    /// ELF vmlinux or bzImage; `None` runs the self-test payload.
+    pub kernel: Option<PathBuf>,
+    pub initramfs: Option<PathBuf>,
+    pub cmdline: String,
+    /// vCPU 0 boots; the others wait for its INIT/SIPI.
+    pub vcpus: u8,
+    pub memory_mb: u64,
+    pub memory_backing: MemoryBacking,
+    pub huge_pages: HugePages,
+    /// Set by `VMM_VSOCK_UDS`; the CID defaults to `DEFAULT_VSOCK_CID`.
+    pub vsock: Option<VsockConfig>,
+}
+
+impl VmConfig {
+    /// Reads the `VMM_*` variables above. The command line defaults to
+    /// `DEFAULT_CMDLINE`, the vCPU count to 1 and the memory to
+    /// `DEFAULT_MEMORY_MB` of anonymous memory without huge pages, and
+    /// there is no vsock device unless a socket path is given.
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let vcpus = match var(VMM_VCPUS_ENV) {
+            Some(count) => count.parse().with_context(|| format!("Invalid {}: {:?}", VMM_VCPUS_ENV, count))?,
+            None => 1,
+        };
+        let memory_mb = match var(VMM_MEMORY_MB_ENV) {
+            Some(size) => size.parse().with_context(|| format!("Invalid {}: {:?}", VMM_MEMORY_MB_ENV, size))?,
+            None => DEFAULT_MEMORY_MB,
+        };
+        let mut memory_backing = match var(VMM_MEMORY_BACKING_ENV).as_deref() {
+            None | Some("anonymous") => MemoryBacking::Anonymous,
+            Some("memfd") => MemoryBacking::Memfd,
+            Some(other) => bail!("Invalid {}: {:?} (anonymous or memfd)", VMM_MEMORY_BACKING_ENV, other),
+        };
+        let huge_pages = match var(VMM_HUGEPAGES_ENV).as_deref() {
+            None | Some("none") => HugePages::None,
+            Some("thp") => HugePages::Transparent,
+            Some("hugetlbfs") => HugePages::Hugetlbfs,
+            Some(other) => bail!("Invalid {}: {:?} (none, thp or hugetlbfs)", VMM_HUGEPAGES_ENV, other),
+        };
+        if huge_pages == HugePages::Hugetlbfs {
+            memory_backing = MemoryBacking::Memfd;
+        }
+        let guest_cid = match var(VMM_VSOCK_CID_ENV) {
+            Some(cid) => cid.parse().with_context(|| format!("Invalid {}: {:?}", VMM_VSOCK_CID_ENV, cid))?,
+            None => DEFAULT_VSOCK_CID,
+        };
+        let vsock = var(VMM_VSOCK_UDS_ENV).map(|path| VsockConfig { guest_cid, uds_path: PathBuf::from(path) });
+        let config = VmConfig {
+            kernel: var(VMM_KERNEL_ENV).map(PathBuf::from),
+            initramfs: var(VMM_INITRAMFS_ENV).map(PathBuf::from),
+            cmdline: var(VMM_CMDLINE_ENV).unwrap_or_else(|| DEFAULT_CMDLINE.to_string()),
+            vcpus,
+            memory_mb,
+            memory_backing,
+            huge_pages,
+            vsock,
+        };
+        if !(1..=MAX_VCPUS).contains(&config.vcpus) {
+            bail!("{} must be between 1 and {}, got {}", VMM_VCPUS_ENV, MAX_VCPUS, config.vcpus);
+        }
+        if config.kernel.is_none() && config.vcpus > 1 {
+            bail!("The self-test guest runs on one vCPU; set {} to boot {} vCPUs", VMM_KERNEL_ENV, config.vcpus);
+        }
+        if config.memory_mb == 0 {
+            bail!("{} must be at least 1", VMM_MEMORY_MB_ENV);
+        }
+        if config.huge_pages == HugePages::Hugetlbfs && !config.memory_mb.is_multiple_of(2) {
+            bail!("{} must be a multiple of 2 with 2 MiB hugetlbfs pages", VMM_MEMORY_MB_ENV);
+        }
+        if let Some(vsock) = &config.vsock {
+            // CIDs are 32 bits on the wire; u32::MAX is VMADDR_CID_ANY.
+            if !(DEFAULT_VSOCK_CID..u64::from(u32::MAX)).contains(&vsock.guest_cid) {
+                bail!("{} must be between {} and {}", VMM_VSOCK_CID_ENV, DEFAULT_VSOCK_CID, u32::MAX - 1);
+            }
+            if config.kernel.is_none() {
+                bail!("The self-test guest has no vsock driver; set {} to use {}", VMM_KERNEL_ENV, VMM_VSOCK_UDS_ENV);
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn memory_size(&self) -> u64 {
+        self.memory_mb << 20
+    }
+}
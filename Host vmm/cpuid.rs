@@ -0,0 +1,137 @@
+// src/cpuid.rs - CPUID Filtering
+
+use kvm_bindings::{CpuId, KVM_CPUID_FLAG_SIGNIFCANT_INDEX};
+
+// --- Per-vCPU CPUID ---
+//
+// KVM reports what it can virtualize; each vCPU gets a copy of that,
+// trimmed to what this VMM backs and rewritten to describe the topology it
+// builds: one socket whose cores are the vCPUs, one thread each, APIC ID
+// equal to the vCPU index (KVM's default).
+
+const LEAF_FEATURES: u32 = 0x1;
+const LEAF_CACHE_PARAMS: u32 = 0x4;
+const LEAF_PERF_MONITORING: u32 = 0xa;
+const LEAF_EXT_TOPOLOGY: u32 = 0xb;
+
+/// Leaf 1 ECX: VMX (no nested virtualization) and the hypervisor bit.
+const ECX_VMX: u32 = 1 << 5;
+const ECX_HYPERVISOR: u32 = 1 << 31;
+/// Leaf 1 EDX: more than one logical processor per package.
+const EDX_HTT: u32 = 1 << 28;
+
+/// Leaf 0xb level types.
+const LEVEL_TYPE_SMT: u32 = 1;
+const LEVEL_TYPE_CORE: u32 = 2;
+
+/// The CPUID vCPU `id` of `count` sees.
+pub fn filter(supported: &CpuId, id: u8, count: u8) -> CpuId {
+    let mut cpuid = supported.clone();
+    let (id, count) = (u32::from(id), u32::from(count));
+    // Bits needed to hold a core index: the APIC ID shift to the package level.
+    let core_bits = u32::BITS - (count - 1).leading_zeros();
+
+    for entry in cpuid.as_mut_slice() {
+        match entry.function {
+            LEAF_FEATURES => {
+                // EBX[31:24] initial APIC ID, EBX[23:16] logical processors per package.
+                entry.ebx = (entry.ebx & 0x0000_ffff) | (id << 24) | (count << 16);
+                entry.ecx = (entry.ecx & !ECX_VMX) | ECX_HYPERVISOR;
+                if count > 1 {
+                    entry.edx |= EDX_HTT;
+                } else {
+                    entry.edx &= !EDX_HTT;
+                }
+            }
+            LEAF_CACHE_PARAMS => {
+                // EAX[31:26] cores per package - 1; EAX[25:14] threads sharing
+                // the cache - 1: every core for the last level, one otherwise.
+                let level = (entry.eax >> 5) & 0x7;
+                let sharing = if level >= 3 { count - 1 } else { 0 };
+                entry.eax = (entry.eax & 0x3fff) | ((count - 1) << 26) | (sharing << 14);
+            }
+            LEAF_PERF_MONITORING => {
+                // No virtual PMU.
+                (entry.eax, entry.ebx, entry.ecx, entry.edx) = (0, 0, 0, 0);
+            }
+            LEAF_EXT_TOPOLOGY => {
+                let level = entry.index;
+                (entry.eax, entry.ebx, entry.ecx) = match level {
+                    0 => (0, 1, LEVEL_TYPE_SMT << 8),
+                    1 => (core_bits, count, (LEVEL_TYPE_CORE << 8) | level),
+                    _ => (0, 0, level),
+                };
+                entry.edx = id;
+                entry.flags |= KVM_CPUID_FLAG_SIGNIFCANT_INDEX;
+            }
+            _ => {}
+        }
+    }
+    cpuid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvm_bindings::kvm_cpuid_entry2;
+
+    /// A minimal KVM CPUID: the feature leaf, an L1 and an L3 cache
+    /// descriptor and both levels of the topology leaf.
+    fn supported() -> CpuId {
+        let entry = |function, index, eax| kvm_cpuid_entry2 { function, index, eax, ..Default::default() };
+        CpuId::from_entries(&[
+            kvm_cpuid_entry2 { ebx: 0x0000_0800, ecx: ECX_VMX, ..entry(LEAF_FEATURES, 0, 0) },
+            entry(LEAF_CACHE_PARAMS, 0, 1 << 5),
+            entry(LEAF_CACHE_PARAMS, 3, 3 << 5),
+            entry(LEAF_EXT_TOPOLOGY, 0, 0),
+            entry(LEAF_EXT_TOPOLOGY, 1, 0),
+        ])
+        .unwrap()
+    }
+
+    fn leaf(cpuid: &CpuId, function: u32, index: u32) -> kvm_cpuid_entry2 {
+        *cpuid
+            .as_slice()
+            .iter()
+            .find(|entry| entry.function == function && entry.index == index)
+            .unwrap()
+    }
+
+    #[test]
+    fn apic_ids_match_the_vcpu_index() {
+        for id in [0, 1, 7, 31] {
+            let cpuid = filter(&supported(), id, 32);
+            let features = leaf(&cpuid, LEAF_FEATURES, 0);
+            assert_eq!(features.ebx >> 24, u32::from(id));
+            assert_eq!((features.ebx >> 16) & 0xff, 32);
+            // The low bits (CLFLUSH size, brand) are kept.
+            assert_eq!(features.ebx & 0xffff, 0x0800);
+            assert_eq!(features.ecx & ECX_VMX, 0);
+            assert_ne!(features.ecx & ECX_HYPERVISOR, 0);
+            for level in 0..2 {
+                let topology = leaf(&cpuid, LEAF_EXT_TOPOLOGY, level);
+                assert_eq!(topology.edx, u32::from(id));
+                assert_ne!(topology.flags & KVM_CPUID_FLAG_SIGNIFCANT_INDEX, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn topology_has_one_core_per_vcpu() {
+        for (count, core_bits) in [(1, 0), (2, 1), (3, 2), (32, 5)] {
+            let cpuid = filter(&supported(), 0, count);
+            let smt = leaf(&cpuid, LEAF_EXT_TOPOLOGY, 0);
+            assert_eq!((smt.eax, smt.ebx, smt.ecx >> 8), (0, 1, LEVEL_TYPE_SMT));
+            let core = leaf(&cpuid, LEAF_EXT_TOPOLOGY, 1);
+            assert_eq!(core.eax, core_bits, "{} vCPUs", count);
+            assert_eq!((core.ebx, core.ecx >> 8, core.ecx & 0xff), (u32::from(count), LEVEL_TYPE_CORE, 1));
+
+            let htt = leaf(&cpuid, LEAF_FEATURES, 0).edx & EDX_HTT != 0;
+            assert_eq!(htt, count > 1);
+            // Only the last-level cache is shared, by every core.
+            let (l1, l3) = (leaf(&cpuid, LEAF_CACHE_PARAMS, 0).eax, leaf(&cpuid, LEAF_CACHE_PARAMS, 3).eax);
+            assert_eq!((l1 >> 26, (l1 >> 14) & 0xfff), (u32::from(count) - 1, 0));
+            assert_eq!((l3 >> 26, (l3 >> 14) & 0xfff), (u32::from(count) - 1, u32::from(count) - 1));
+        }
+    }
+}
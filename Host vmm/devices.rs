@@ -0,0 +1,158 @@
+// src/devices.rs - Device Bus and Legacy Devices
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+use vm_superio::{I8042Device, Serial, Trigger};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::sync_error;
+
+// --- Device Bus ---
+//
+// Port I/O and MMIO accesses that KVM cannot complete in the kernel exit to
+// the VMM, which routes them by address to the device that claimed the
+// range. Accesses nobody claimed read as all ones and writes are dropped,
+// like on an empty ISA bus.
+
+/// COM1, the guest console.
+pub const SERIAL_PORT_BASE: u64 = 0x3f8;
+pub const SERIAL_PORT_LEN: u64 = 0x8;
+
+/// The i8042 keyboard controller; the guest resets through its command port.
+pub const I8042_PORT_BASE: u64 = 0x60;
+pub const I8042_PORT_LEN: u64 = 0x5;
+
+/// A device on the port I/O or MMIO bus. `offset` is relative to the start
+/// of the range the device was inserted at.
+pub trait BusDevice: Send {
+    fn read(&mut self, offset: u64, data: &mut [u8]);
+    fn write(&mut self, offset: u64, data: &[u8]);
+}
+
+/// Devices keyed by the start of their address range.
+#[derive(Default)]
+pub struct Bus {
+    devices: BTreeMap<u64, (u64, Arc<Mutex<dyn BusDevice>>)>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Bus::default()
+    }
+
+    /// Claims `[base, base + len)` for `device`.
+    pub fn insert(&mut self, device: Arc<Mutex<dyn BusDevice>>, base: u64, len: u64) -> Result<()> {
+        let end = base
+            .checked_add(len)
+            .filter(|_| len > 0)
+            .ok_or_else(|| anyhow!("Invalid bus range {:#x}+{:#x}", base, len))?;
+        if let Some((&other, &(other_len, _))) = self.devices.range(..end).next_back() {
+            if other + other_len > base {
+                bail!("Bus range {:#x}..{:#x} overlaps the device at {:#x}", base, end, other);
+            }
+        }
+        self.devices.insert(base, (len, device));
+        Ok(())
+    }
+
+    fn resolve(&self, addr: u64) -> Option<(u64, &Arc<Mutex<dyn BusDevice>>)> {
+        let (base, (len, device)) = self.devices.range(..=addr).next_back()?;
+        (addr - base < *len).then_some((addr - base, device))
+    }
+
+    /// Reads from the device claiming `addr`. Returns false, with `data`
+    /// set to all ones, if there is none.
+    pub fn read(&self, addr: u64, data: &mut [u8]) -> Result<bool> {
+        let Some((offset, device)) = self.resolve(addr) else {
+            data.fill(0xff);
+            return Ok(false);
+        };
+        sync_error::lock(device, "bus device")
+            .with_context(|| format!("Device at {:#x}", addr))?
+            .read(offset, data);
+        Ok(true)
+    }
+
+    /// Writes to the device claiming `addr`. Returns false if there is none.
+    pub fn write(&self, addr: u64, data: &[u8]) -> Result<bool> {
+        let Some((offset, device)) = self.resolve(addr) else {
+            return Ok(false);
+        };
+        sync_error::lock(device, "bus device")
+            .with_context(|| format!("Device at {:#x}", addr))?
+            .write(offset, data);
+        Ok(true)
+    }
+}
+
+// --- Legacy Devices ---
+
+/// Signals an `EventFd`: serial interrupts and the i8042 reset request.
+pub struct EventFdTrigger(pub EventFd);
+
+impl Trigger for EventFdTrigger {
+    type E = io::Error;
+
+    fn trigger(&self) -> io::Result<()> {
+        self.0.write(1)
+    }
+}
+
+/// A 16550A UART whose transmitted bytes go to `W`.
+pub struct SerialConsole<W: Write + Send> {
+    serial: Serial<EventFdTrigger, vm_superio::serial::NoEvents, W>,
+}
+
+impl<W: Write + Send> SerialConsole<W> {
+    /// `interrupt` is signalled whenever the UART raises its IRQ line.
+    pub fn new(interrupt: EventFd, out: W) -> Self {
+        SerialConsole { serial: Serial::new(EventFdTrigger(interrupt), out) }
+    }
+}
+
+impl<W: Write + Send> BusDevice for SerialConsole<W> {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        if let [byte] = data {
+            *byte = self.serial.read(offset as u8);
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if let [byte] = data {
+            if let Err(e) = self.serial.write(offset as u8, *byte) {
+                log::warn!("Serial console write failed: {:?}", e);
+            }
+        }
+    }
+}
+
+/// The i8042 controller, only as far as the guest uses it to reset.
+pub struct ResetController {
+    i8042: I8042Device<EventFdTrigger>,
+}
+
+impl ResetController {
+    /// `reset_evt` is signalled when the guest asks for a CPU reset.
+    pub fn new(reset_evt: EventFd) -> Self {
+        ResetController { i8042: I8042Device::new(EventFdTrigger(reset_evt)) }
+    }
+}
+
+impl BusDevice for ResetController {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        if let [byte] = data {
+            *byte = self.i8042.read(offset as u8);
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if let [byte] = data {
+            if let Err(e) = self.i8042.write(offset as u8, *byte) {
+                log::warn!("i8042 reset request failed: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+// src/layout.rs - Guest Physical Memory Layout
+
+// --- Boot-Time Layout ---
+//
+// Where the VMM puts what the kernel needs to start in 64-bit mode. All of
+// it lives in the first megabyte, below the EBDA, clear of the kernel (at
+// or above `HIMEM_START`) and of the initramfs (at the top of low memory).
+
+/// GDT and IDT of the boot CPU.
+pub const BOOT_GDT_START: u64 = 0x500;
+pub const BOOT_IDT_START: u64 = 0x520;
+
+/// `boot_params`, passed to the kernel in RSI.
+pub const ZERO_PAGE_START: u64 = 0x7000;
+
+/// Initial stack pointer; the stack grows down towards the zero page.
+pub const BOOT_STACK_POINTER: u64 = 0x8ff0;
+
+/// Identity-mapping page tables: one PML4, one PDPT and one page directory
+/// of 2 MiB pages covering the first GiB.
+pub const PML4_START: u64 = 0x9000;
+pub const PDPTE_START: u64 = 0xa000;
+pub const PDE_START: u64 = 0xb000;
+
+/// The kernel command line, NUL-terminated.
+pub const CMDLINE_START: u64 = 0x20000;
+pub const CMDLINE_MAX_SIZE: usize = 0x1000;
+
+/// Start of the Extended BIOS Data Area; low RAM ends here. The MP table
+/// lives in it, where the kernel scans the last KiB of base memory.
+pub const EBDA_START: u64 = 0x9fc00;
+pub const MPTABLE_START: u64 = EBDA_START;
+
+/// The ACPI RSDP and the tables it points to, in the BIOS area the kernel
+/// searches for the RSDP.
+pub const ACPI_START: u64 = 0xe_0000;
+
+/// First address past the legacy ISA hole; the kernel is loaded here or above.
+pub const HIMEM_START: u64 = 0x10_0000;
+
+/// Where KVM puts the three pages of the real-mode TSS on Intel; must not
+/// overlap guest RAM.
+pub const KVM_TSS_ADDRESS: usize = 0xfffb_d000;
+
+/// Default addresses of the IOAPIC and of each CPU's local APIC.
+pub const IOAPIC_START: u64 = 0xfec0_0000;
+pub const APIC_START: u64 = 0xfee0_0000;
+
+/// Highest address the initramfs may end at, for kernels that do not say.
+pub const INITRD_ADDR_MAX: u64 = 0x37ff_ffff;
+
+// --- Guest RAM ---
+//
+// RAM starts at 0 and runs up to the 32-bit MMIO gap; whatever does not
+// fit below the gap continues at 4 GiB. The gap leaves room for the
+// APICs, the KVM TSS and device MMIO below 4 GiB, where 32-bit devices
+// and the self-test's MMIO window can reach it.
+
+/// The 32-bit MMIO gap, `[MMIO_GAP_START, MMIO_GAP_END)`.
+pub const MMIO_GAP_START: u64 = 0xc000_0000;
+pub const MMIO_GAP_END: u64 = 0x1_0000_0000;
+
+/// Virtio-mmio devices, one `VIRTIO_MMIO_SIZE` window each, from the start
+/// of the gap.
+pub const VIRTIO_MMIO_START: u64 = MMIO_GAP_START;
@@ -1,40 +1,168 @@
 // src/main.rs - Conceptual VMM Host Logic
 
-use kvm::Kvm;
-use vm_memory::{GuestMemory, GuestMemoryMmap};
-use anyhow::Result;
+mod boot;
+mod config;
+mod cpuid;
+mod devices;
+mod layout;
+mod memory;
+mod payload;
+mod regs;
+mod signals;
+mod smp;
+mod sync_error;
+mod vcpu;
+mod virtio_mmio;
+mod vsock;
 
-const KERNEL_PATH: &str = "/path/to/vmlinux";
-const MEMORY_SIZE_MB: u64 = 512;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use kvm_bindings::{kvm_pit_config, kvm_userspace_memory_region, KVM_MAX_CPUID_ENTRIES, KVM_PIT_SPEAKER_DUMMY};
+use kvm_ioctls::Kvm;
+use vm_memory::{Address, GuestMemory, GuestMemoryRegion};
+use vmm_sys_util::eventfd::EventFd;
+use anyhow::{bail, Result};
+
+use config::VmConfig;
+use devices::{Bus, ResetController, SerialConsole};
+use signals::HostRequest;
+use vcpu::{Vcpu, VcpuThreads};
+use virtio_mmio::{MmioTransport, VirtioInterrupt};
+use vsock::Vsock;
+
+/// The legacy IRQ of COM1.
+const SERIAL_IRQ: u32 = 4;
+/// The IRQ of the virtio-vsock device, free on a PC without COM2.
+const VSOCK_IRQ: u32 = 5;
+
+/// How often the VMM thread checks for host requests while the guest runs.
+const HOST_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 fn main() -> Result<()> {
     // 1. Initialize Logging
     env_logger::init();
     log::info!("Starting DID Infrastructure Host VMM...");
+    let mut config = VmConfig::from_env()?;
 
     // 2. Initialize KVM and create the VM
     let kvm = Kvm::new()?;
     let vm = kvm.create_vm()?;
 
-    // 3. Setup Guest Memory (512MB example)
-    let memory_size = MEMORY_SIZE_MB * 1024 * 1024;
-    let guest_mem = GuestMemoryMmap::new(vec![(0x0, memory_size)]).map_err(|e| anyhow::anyhow!("Failed to create GuestMemory: {:?}", e))?;
-    vm.set_user_memory_region(0, memory_size, guest_mem.as_slice().as_ptr() as u64)?;
-
-    // 4. Load the Guest Kernel (Minimal Linux for DID Agent)
-    // This is a complex step, typically involving linux-loader and setting up VCPUs and MSRs.
-    // For simplicity, this is omitted, but conceptually, the loader initializes the guest state.
-    log::info!("Loading kernel and setting up initial guest state...");
-    // ... use linux_loader::cmdline::Cmdline, linux_loader::loader::load_kernel, etc. ...
-
-    // 5. Setup VCPUs and run the Guest
-    // For a single-core DID agent:
-    let vcpu = vm.create_vcpu(0)?;
-    
-    // In a real VMM, you'd enter a main loop here to handle VCPU exits (e.g., I/O, MMIO)
-    log::info!("Entering main VCPU run loop for DID Agent...");
-    
-    // ... VCPU run loop logic ...
+    // 3. Setup Guest Memory (512MB by default), split around the MMIO gap
+    let guest_mem = memory::create_guest_memory(&config)?;
+    for (slot, region) in guest_mem.iter().enumerate() {
+        let host_addr = guest_mem.get_host_address(region.start_addr())?;
+        let mem_region = kvm_userspace_memory_region {
+            slot: slot as u32,
+            guest_phys_addr: region.start_addr().raw_value(),
+            memory_size: region.len(),
+            userspace_addr: host_addr as u64,
+            flags: 0,
+        };
+        // SAFETY: `guest_mem` owns the mapping and stays alive while the guest runs.
+        unsafe { vm.set_user_memory_region(mem_region)? };
+    }
+
+    // 4. Linux needs the in-kernel PIC, IOAPIC and PIT. The self-test runs
+    // without them, so that its HLT comes back to the VMM.
+    let boots_linux = config.kernel.is_some();
+    if boots_linux {
+        vm.set_tss_address(layout::KVM_TSS_ADDRESS)?;
+        vm.create_irq_chip()?;
+        vm.create_pit2(kvm_pit_config { flags: KVM_PIT_SPEAKER_DUMMY, ..Default::default() })?;
+    }
+
+    // 5. Attach the legacy devices: the serial console on COM1 and the
+    // i8042, through which the guest requests a reset.
+    let reset_evt = EventFd::new(libc::EFD_NONBLOCK)?;
+    let serial_evt = EventFd::new(libc::EFD_NONBLOCK)?;
+    if boots_linux {
+        vm.register_irqfd(&serial_evt, SERIAL_IRQ)?;
+    }
+    let mut pio_bus = Bus::new();
+    let serial = SerialConsole::new(serial_evt.try_clone()?, std::io::stdout());
+    pio_bus.insert(Arc::new(Mutex::new(serial)), devices::SERIAL_PORT_BASE, devices::SERIAL_PORT_LEN)?;
+    let i8042 = ResetController::new(reset_evt.try_clone()?);
+    pio_bus.insert(Arc::new(Mutex::new(i8042)), devices::I8042_PORT_BASE, devices::I8042_PORT_LEN)?;
+
+    // The vsock device, for the agent's attestation traffic to the host,
+    // goes at the start of the MMIO gap; the kernel learns of it from the
+    // command line.
+    let mut mmio_bus = Bus::new();
+    if let Some(vsock_config) = &config.vsock {
+        let irq_evt = EventFd::new(libc::EFD_NONBLOCK)?;
+        vm.register_irqfd(&irq_evt, VSOCK_IRQ)?;
+        let vsock = Vsock::new(vsock_config.guest_cid, &vsock_config.uds_path)?;
+        let transport = MmioTransport::new(vsock, guest_mem.clone(), Arc::new(VirtioInterrupt::new(irq_evt)))?;
+        let size = virtio_mmio::VIRTIO_MMIO_SIZE;
+        mmio_bus.insert(Arc::new(Mutex::new(transport)), layout::VIRTIO_MMIO_START, size)?;
+        config.cmdline.push_str(&format!(
+            " virtio_mmio.device={}K@{:#x}:{}",
+            size >> 10,
+            layout::VIRTIO_MMIO_START,
+            VSOCK_IRQ
+        ));
+    }
+    let (pio_bus, mmio_bus) = (Arc::new(pio_bus), Arc::new(mmio_bus));
+
+    // 6. Load the Guest Kernel (Minimal Linux for DID Agent), its initramfs
+    // and command line, and describe them and the vCPUs to it.
+    let entry = match &config.kernel {
+        Some(kernel) => {
+            log::info!("Loading kernel {} and setting up initial guest state...", kernel.display());
+            let entry = boot::load_linux(&guest_mem, &config)?;
+            smp::setup_tables(&guest_mem, config.vcpus)?;
+            Some(entry)
+        }
+        None => {
+            log::info!("No kernel configured ({} unset); running the self-test guest", config::VMM_KERNEL_ENV);
+            None
+        }
+    };
+
+    // 7. Setup VCPUs: vCPU 0 boots, the others wait for the kernel to
+    // start them.
+    let supported_cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)?;
+    let mut vcpus = Vec::with_capacity(config.vcpus as usize);
+    for id in 0..config.vcpus {
+        let vcpu = Vcpu::new(&vm, id, pio_bus.clone(), mmio_bus.clone(), &reset_evt)?;
+        match entry {
+            Some(entry) => {
+                vcpu.fd().set_cpuid2(&cpuid::filter(&supported_cpuid, id, config.vcpus))?;
+                if id == 0 {
+                    regs::setup_long_mode(vcpu.fd(), &guest_mem, entry)?;
+                } else {
+                    regs::setup_application_processor(vcpu.fd())?;
+                }
+            }
+            None => payload::load_selftest(&guest_mem, vcpu.fd())?,
+        }
+        vcpus.push(vcpu);
+    }
+
+    // 8. Run the Guest, one thread per vCPU, until it stops or the host
+    // asks the VMM to stop it.
+    log::info!("Starting {} vCPU thread(s) for DID Agent...", config.vcpus);
+    signals::install()?;
+    let vcpus = VcpuThreads::start(vcpus)?;
+    while !vcpus.wait_for_stop(HOST_POLL_INTERVAL)? {
+        match signals::take_request() {
+            Some(HostRequest::Pause) => vcpus.pause()?,
+            Some(HostRequest::Resume) => vcpus.resume()?,
+            Some(HostRequest::Shutdown) => {
+                log::info!("Shutdown requested by the host");
+                break;
+            }
+            None => {}
+        }
+    }
+
+    match vcpus.shutdown()? {
+        Some((id, reason)) if reason.is_error() => bail!("Guest stopped by vCPU {}: {}", id, reason),
+        Some((id, reason)) => log::info!("Guest stopped by vCPU {}: {}", id, reason),
+        None => log::info!("Guest stopped by the host"),
+    }
 
     Ok(())
 }
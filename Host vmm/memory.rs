@@ -0,0 +1,192 @@
+// src/memory.rs - Guest Memory Regions and Backing
+
+use std::fs::File;
+use std::os::fd::FromRawFd;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use vm_memory::mmap::MmapRegionBuilder;
+use vm_memory::{
+    Address, FileOffset, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap,
+};
+
+use crate::config::{HugePages, MemoryBacking, VmConfig};
+use crate::layout;
+
+// --- Guest Memory ---
+//
+// Guest RAM is laid out around the 32-bit MMIO gap (see `layout`) and
+// mapped one host region per guest region. With a memfd backing, all
+// regions are windows into one shared memfd at consecutive offsets, so a
+// device backend handed that fd can map the guest's memory; each region's
+// `file_offset()` says where it is.
+
+/// Where `size` bytes of guest RAM go: below the MMIO gap, then above 4 GiB.
+pub fn guest_ranges(size: u64) -> Vec<(GuestAddress, u64)> {
+    let low = size.min(layout::MMIO_GAP_START);
+    let mut ranges = vec![(GuestAddress(0), low)];
+    if size > low {
+        ranges.push((GuestAddress(layout::MMIO_GAP_END), size - low));
+    }
+    ranges
+}
+
+/// The end of the RAM starting at 0: where low memory stops, either at the
+/// end of guest memory or at the MMIO gap.
+pub fn low_memory_end(guest_mem: &GuestMemoryMmap) -> u64 {
+    guest_mem
+        .find_region(GuestAddress(0))
+        .map(|region| region.last_addr().raw_value() + 1)
+        .unwrap_or(0)
+}
+
+/// Maps guest RAM as configured.
+pub fn create_guest_memory(config: &VmConfig) -> Result<GuestMemoryMmap> {
+    let size = config.memory_size();
+    let memfd = match config.memory_backing {
+        MemoryBacking::Memfd => Some(create_memfd(size, config.huge_pages == HugePages::Hugetlbfs)?),
+        MemoryBacking::Anonymous => None,
+    };
+
+    let mut regions = Vec::new();
+    let mut file_offset = 0;
+    for (guest_addr, len) in guest_ranges(size) {
+        let len = usize::try_from(len).context("Guest memory region does not fit the host address space")?;
+        let builder = MmapRegionBuilder::new(len).with_mmap_prot(libc::PROT_READ | libc::PROT_WRITE);
+        let builder = match &memfd {
+            // Hugetlbfs pages are reserved at mmap time, so a pool that is
+            // too small fails here instead of with SIGBUS in the guest.
+            Some(file) => builder
+                .with_file_offset(FileOffset::from_arc(file.clone(), file_offset))
+                .with_mmap_flags(match config.huge_pages {
+                    HugePages::Hugetlbfs => libc::MAP_SHARED,
+                    _ => libc::MAP_SHARED | libc::MAP_NORESERVE,
+                })
+                .with_hugetlbfs(config.huge_pages == HugePages::Hugetlbfs),
+            None => builder.with_mmap_flags(libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE),
+        };
+        let mapping = builder
+            .build()
+            .with_context(|| format!("Failed to map guest memory at {:#x}+{:#x}", guest_addr.raw_value(), len))?;
+        if config.huge_pages == HugePages::Transparent {
+            // SAFETY: the range is exactly the mapping just created.
+            if unsafe { libc::madvise(mapping.as_ptr().cast(), len, libc::MADV_HUGEPAGE) } != 0 {
+                log::warn!("Transparent huge pages unavailable: {}", std::io::Error::last_os_error());
+            }
+        }
+        regions.push(
+            GuestRegionMmap::new(mapping, guest_addr)
+                .ok_or_else(|| anyhow!("Guest memory region at {:#x} overflows", guest_addr.raw_value()))?,
+        );
+        file_offset += len as u64;
+    }
+
+    let guest_mem = GuestMemoryMmap::from_regions(regions)
+        .map_err(|e| anyhow!("Failed to create GuestMemory: {:?}", e))?;
+    for region in guest_mem.iter() {
+        log::info!(
+            "Guest RAM {:#x}..{:#x} ({:?}, huge pages: {:?})",
+            region.start_addr().raw_value(),
+            region.last_addr().raw_value() + 1,
+            config.memory_backing,
+            config.huge_pages
+        );
+    }
+    Ok(guest_mem)
+}
+
+/// An anonymous memory file of `size` bytes, from the 2 MiB hugetlbfs pool
+/// if `hugetlb` is set (`VmConfig` checks the size is a multiple).
+fn create_memfd(size: u64, hugetlb: bool) -> Result<Arc<File>> {
+    let mut flags = libc::MFD_CLOEXEC;
+    if hugetlb {
+        flags |= libc::MFD_HUGETLB | libc::MFD_HUGE_2MB;
+    }
+    // SAFETY: the name is a valid C string and the result is checked.
+    let fd = unsafe { libc::memfd_create(c"did-vm-guest-ram".as_ptr(), flags) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to create the guest memory memfd");
+    }
+    // SAFETY: `fd` was just created and nothing else owns it.
+    let file = unsafe { File::from_raw_fd(fd) };
+    file.set_len(size).context("Failed to size the guest memory memfd")?;
+    Ok(Arc::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot::{self, E820_RAM, E820_RESERVED};
+    use crate::config::{HugePages, MemoryBacking};
+
+    const MIB: u64 = 1 << 20;
+
+    /// Below, at and above the start of the MMIO gap.
+    const SIZES: [u64; 3] = [64 * MIB, layout::MMIO_GAP_START, layout::MMIO_GAP_START + 64 * MIB];
+
+    fn config(size: u64, memory_backing: MemoryBacking) -> VmConfig {
+        VmConfig {
+            kernel: None,
+            initramfs: None,
+            cmdline: String::new(),
+            vcpus: 1,
+            memory_mb: size / MIB,
+            memory_backing,
+            huge_pages: HugePages::None,
+            vsock: None,
+        }
+    }
+
+    #[test]
+    fn ranges_skip_the_mmio_gap() {
+        assert_eq!(guest_ranges(64 * MIB), [(GuestAddress(0), 64 * MIB)]);
+        assert_eq!(guest_ranges(layout::MMIO_GAP_START), [(GuestAddress(0), layout::MMIO_GAP_START)]);
+        assert_eq!(
+            guest_ranges(layout::MMIO_GAP_START + 64 * MIB),
+            [(GuestAddress(0), layout::MMIO_GAP_START), (GuestAddress(layout::MMIO_GAP_END), 64 * MIB)]
+        );
+
+        for size in SIZES {
+            let ranges = guest_ranges(size);
+            assert_eq!(ranges.iter().map(|(_, len)| len).sum::<u64>(), size);
+            for (start, len) in ranges {
+                let (start, end) = (start.raw_value(), start.raw_value() + len);
+                assert!(end <= layout::MMIO_GAP_START || start >= layout::MMIO_GAP_END, "{:#x}..{:#x}", start, end);
+            }
+        }
+    }
+
+    #[test]
+    fn e820_covers_exactly_guest_ram() {
+        for size in SIZES {
+            let guest_mem = create_guest_memory(&config(size, MemoryBacking::Anonymous)).unwrap();
+            assert_eq!(low_memory_end(&guest_mem), size.min(layout::MMIO_GAP_START));
+
+            let map = boot::e820_map(&guest_mem);
+            let total = |type_| map.iter().filter(|e| e.type_ == type_).map(|e| e.size).sum::<u64>();
+            let reserved: Vec<_> = map.iter().filter(|e| e.type_ == E820_RESERVED).map(|e| (e.addr, e.size)).collect();
+            assert_eq!(reserved, [(layout::EBDA_START, layout::HIMEM_START - layout::EBDA_START)]);
+            assert_eq!(total(E820_RAM) + total(E820_RESERVED), size);
+
+            for entry in map.iter().filter(|e| e.type_ == E820_RAM) {
+                let (start, end) = (entry.addr, entry.addr + entry.size);
+                assert!(end <= layout::MMIO_GAP_START || start >= layout::MMIO_GAP_END, "{:#x}..{:#x}", start, end);
+                assert!(end <= layout::EBDA_START || start >= layout::HIMEM_START, "{:#x}..{:#x}", start, end);
+            }
+        }
+    }
+
+    #[test]
+    fn memfd_regions_are_consecutive_windows() {
+        let size = layout::MMIO_GAP_START + 64 * MIB;
+        let guest_mem = create_guest_memory(&config(size, MemoryBacking::Memfd)).unwrap();
+        let mut expected = 0;
+        for region in guest_mem.iter() {
+            let offset = region.file_offset().expect("memfd-backed region");
+            assert_eq!(offset.start(), expected);
+            expected += region.len();
+        }
+        assert_eq!(expected, size);
+        assert_eq!(guest_mem.num_regions(), 2);
+    }
+}
@@ -0,0 +1,146 @@
+// src/payload.rs - Built-in Self-Test Guest
+
+use anyhow::Result;
+use kvm_ioctls::VcpuFd;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+// --- Self-Test Payload ---
+//
+// A few bytes of 16-bit real-mode code that exercise every kind of exit the
+// run loop handles, so the host can be checked without a kernel: it prints
+// a line on COM1 (port I/O), writes and reads back a byte through ES
+// (MMIO, ES's base points outside guest RAM) and halts.
+
+/// Where the payload is loaded and starts executing.
+pub const SELFTEST_LOAD_ADDR: u64 = 0x1000;
+
+/// Guest-physical address of the MMIO accesses; not backed by RAM.
+pub const SELFTEST_MMIO_ADDR: u64 = 0xd000_0000;
+
+/// The message follows the code, at `SELFTEST_LOAD_ADDR + SELFTEST_CODE.len()`.
+const SELFTEST_CODE: &[u8] = &[
+    0xba, 0xf8, 0x03, //                 mov  $0x3f8, %dx
+    0xbe, 0x19, 0x10, //                 mov  $0x1019, %si
+    0xac, //                          1: lodsb
+    0x84, 0xc0, //                       test %al, %al
+    0x74, 0x03, //                       jz   2f
+    0xee, //                             out  %al, (%dx)
+    0xeb, 0xf8, //                       jmp  1b
+    0x26, 0xc6, 0x06, 0x00, 0x00, 0x5a, // 2: movb $0x5a, %es:0
+    0x26, 0xa0, 0x00, 0x00, //           mov  %es:0, %al
+    0xf4, //                             hlt
+];
+const SELFTEST_MESSAGE: &[u8] = b"Hello from the guest\n\0";
+
+/// Copies the payload into guest memory and points the vCPU at it in real
+/// mode.
+pub fn load_selftest(guest_mem: &GuestMemoryMmap, vcpu: &VcpuFd) -> Result<()> {
+    let code = GuestAddress(SELFTEST_LOAD_ADDR);
+    guest_mem.write_slice(SELFTEST_CODE, code)?;
+    guest_mem.write_slice(SELFTEST_MESSAGE, GuestAddress(SELFTEST_LOAD_ADDR + SELFTEST_CODE.len() as u64))?;
+
+    let mut sregs = vcpu.get_sregs()?;
+    sregs.cs.base = 0;
+    sregs.cs.selector = 0;
+    sregs.ds.base = 0;
+    sregs.ds.selector = 0;
+    sregs.es.base = SELFTEST_MMIO_ADDR;
+    vcpu.set_sregs(&sregs)?;
+
+    let mut regs = vcpu.get_regs()?;
+    regs.rip = SELFTEST_LOAD_ADDR;
+    // Bit 1 is reserved and always set.
+    regs.rflags = 0x2;
+    vcpu.set_regs(&regs)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{self, Bus, BusDevice, SerialConsole};
+    use crate::vcpu::{Vcpu, VcpuExitReason, VcpuThreads};
+    use kvm_bindings::kvm_userspace_memory_region;
+    use kvm_ioctls::Kvm;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use vm_memory::GuestMemory;
+    use vmm_sys_util::eventfd::EventFd;
+
+    /// Collects what the guest transmits on COM1.
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// One byte of MMIO-backed storage, recording every access.
+    #[derive(Default)]
+    struct MmioByte {
+        value: u8,
+        writes: Vec<(u64, Vec<u8>)>,
+        reads: Vec<u64>,
+    }
+
+    impl BusDevice for MmioByte {
+        fn read(&mut self, offset: u64, data: &mut [u8]) {
+            self.reads.push(offset);
+            data.fill(self.value);
+        }
+
+        fn write(&mut self, offset: u64, data: &[u8]) {
+            self.writes.push((offset, data.to_vec()));
+            self.value = data[0];
+        }
+    }
+
+    #[test]
+    fn selftest_runs_under_kvm() {
+        // Needs /dev/kvm, which CI containers often lack.
+        if !std::path::Path::new("/dev/kvm").exists() {
+            eprintln!("skipping: /dev/kvm is not available");
+            return;
+        }
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let guest_mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let region = kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: 0,
+            memory_size: 0x10000,
+            userspace_addr: guest_mem.get_host_address(GuestAddress(0)).unwrap() as u64,
+            flags: 0,
+        };
+        // SAFETY: `guest_mem` outlives the VM, which is dropped at the end of the test.
+        unsafe { vm.set_user_memory_region(region).unwrap() };
+
+        let output = Output::default();
+        let reset_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let mut pio_bus = Bus::new();
+        let serial = SerialConsole::new(EventFd::new(libc::EFD_NONBLOCK).unwrap(), output.clone());
+        pio_bus.insert(Arc::new(Mutex::new(serial)), devices::SERIAL_PORT_BASE, devices::SERIAL_PORT_LEN).unwrap();
+        let mmio = Arc::new(Mutex::new(MmioByte::default()));
+        let mut mmio_bus = Bus::new();
+        mmio_bus.insert(mmio.clone(), SELFTEST_MMIO_ADDR, 0x1000).unwrap();
+
+        let vcpu = Vcpu::new(&vm, 0, Arc::new(pio_bus), Arc::new(mmio_bus), &reset_evt).unwrap();
+        load_selftest(&guest_mem, vcpu.fd()).unwrap();
+        let vcpus = VcpuThreads::start(vec![vcpu]).unwrap();
+        assert!(vcpus.wait_for_stop(Duration::from_secs(10)).unwrap(), "the self-test did not halt");
+
+        assert_eq!(vcpus.shutdown().unwrap(), Some((0, VcpuExitReason::Halted)));
+        assert_eq!(&*output.0.lock().unwrap(), b"Hello from the guest\n");
+        let mmio = mmio.lock().unwrap();
+        assert_eq!(mmio.writes, vec![(0, vec![0x5a])]);
+        assert_eq!(mmio.reads, vec![0]);
+    }
+}
@@ -0,0 +1,189 @@
+// src/regs.rs - Initial vCPU State for 64-bit Boot
+
+use anyhow::{bail, Result};
+use kvm_bindings::{kvm_fpu, kvm_msr_entry, kvm_regs, kvm_segment, kvm_sregs, Msrs};
+use kvm_ioctls::VcpuFd;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use crate::layout;
+
+// --- Registers, Segments and Page Tables ---
+//
+// The 64-bit boot protocol enters the kernel already in long mode: flat
+// code and data segments from a minimal GDT, paging on with the low memory
+// identity-mapped, RSI pointing at the zero page and interrupts off. Only
+// the boot CPU starts there; the others wait for the kernel's INIT/SIPI,
+// which resets their registers, so they just get the FPU, MSRs and LINTs.
+
+const X86_CR0_PE: u64 = 0x1;
+const X86_CR0_PG: u64 = 0x8000_0000;
+const X86_CR4_PAE: u64 = 0x20;
+const EFER_LME: u64 = 0x100;
+const EFER_LMA: u64 = 0x400;
+
+/// Present, writable; with `PDE_PS`, a 2 MiB page.
+const PTE_PRESENT_RW: u64 = 0x3;
+const PDE_PS: u64 = 0x80;
+
+const MSR_IA32_TSC: u32 = 0x10;
+const MSR_IA32_SYSENTER_CS: u32 = 0x174;
+const MSR_IA32_SYSENTER_ESP: u32 = 0x175;
+const MSR_IA32_SYSENTER_EIP: u32 = 0x176;
+const MSR_IA32_MISC_ENABLE: u32 = 0x1a0;
+const MSR_IA32_MISC_ENABLE_FAST_STRING: u64 = 0x1;
+const MSR_STAR: u32 = 0xc000_0081;
+const MSR_LSTAR: u32 = 0xc000_0082;
+const MSR_CSTAR: u32 = 0xc000_0083;
+const MSR_SYSCALL_MASK: u32 = 0xc000_0084;
+const MSR_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+/// Local APIC LVT registers and delivery modes, for `set_lint`.
+const APIC_LVT0: usize = 0x350;
+const APIC_LVT1: usize = 0x360;
+const APIC_MODE_NMI: u32 = 0x4;
+const APIC_MODE_EXTINT: u32 = 0x7;
+
+/// Null, 64-bit code, data and TSS descriptors.
+const BOOT_GDT: [u64; 4] = [
+    gdt_entry(0, 0, 0),
+    gdt_entry(0xa09b, 0, 0xfffff),
+    gdt_entry(0xc093, 0, 0xfffff),
+    gdt_entry(0x808b, 0, 0xfffff),
+];
+
+const fn gdt_entry(flags: u16, base: u32, limit: u32) -> u64 {
+    (((base as u64) & 0xff00_0000) << (56 - 24))
+        | (((flags as u64) & 0x0000_f0ff) << 40)
+        | (((limit as u64) & 0x000f_0000) << (48 - 16))
+        | (((base as u64) & 0x00ff_ffff) << 16)
+        | ((limit as u64) & 0x0000_ffff)
+}
+
+/// The segment register contents a GDT descriptor loads.
+fn segment_from_gdt(entry: u64, index: u8) -> kvm_segment {
+    let limit = ((entry >> 32) & 0x000f_0000) | (entry & 0x0000_ffff);
+    let granularity = ((entry >> 55) & 0x1) as u8;
+    let present = ((entry >> 47) & 0x1) as u8;
+    kvm_segment {
+        base: ((entry >> 16) & 0x00ff_ffff) | ((entry >> 32) & 0xff00_0000),
+        limit: if granularity == 1 { ((limit << 12) | 0xfff) as u32 } else { limit as u32 },
+        selector: u16::from(index) * 8,
+        type_: ((entry >> 40) & 0xf) as u8,
+        present,
+        dpl: ((entry >> 45) & 0x3) as u8,
+        db: ((entry >> 54) & 0x1) as u8,
+        s: ((entry >> 44) & 0x1) as u8,
+        l: ((entry >> 53) & 0x1) as u8,
+        g: granularity,
+        avl: ((entry >> 52) & 0x1) as u8,
+        unusable: u8::from(present == 0),
+        padding: 0,
+    }
+}
+
+/// Puts a vCPU in long mode at `entry` with the zero page in RSI.
+pub fn setup_long_mode(vcpu: &VcpuFd, guest_mem: &GuestMemoryMmap, entry: GuestAddress) -> Result<()> {
+    setup_fpu(vcpu)?;
+    setup_msrs(vcpu)?;
+    setup_sregs(vcpu, guest_mem)?;
+    setup_regs(vcpu, entry)?;
+    set_lint(vcpu)
+}
+
+/// Prepares a vCPU that the kernel starts later.
+pub fn setup_application_processor(vcpu: &VcpuFd) -> Result<()> {
+    setup_fpu(vcpu)?;
+    setup_msrs(vcpu)?;
+    set_lint(vcpu)
+}
+
+fn setup_fpu(vcpu: &VcpuFd) -> Result<()> {
+    let fpu = kvm_fpu { fcw: 0x37f, mxcsr: 0x1f80, ..Default::default() };
+    vcpu.set_fpu(&fpu)?;
+    Ok(())
+}
+
+fn setup_msrs(vcpu: &VcpuFd) -> Result<()> {
+    let entries: Vec<kvm_msr_entry> = [
+        (MSR_IA32_SYSENTER_CS, 0),
+        (MSR_IA32_SYSENTER_ESP, 0),
+        (MSR_IA32_SYSENTER_EIP, 0),
+        (MSR_STAR, 0),
+        (MSR_CSTAR, 0),
+        (MSR_KERNEL_GS_BASE, 0),
+        (MSR_SYSCALL_MASK, 0),
+        (MSR_LSTAR, 0),
+        (MSR_IA32_TSC, 0),
+        (MSR_IA32_MISC_ENABLE, MSR_IA32_MISC_ENABLE_FAST_STRING),
+    ]
+    .into_iter()
+    .map(|(index, data)| kvm_msr_entry { index, data, ..Default::default() })
+    .collect();
+    let msrs = Msrs::from_entries(&entries)?;
+    let written = vcpu.set_msrs(&msrs)?;
+    if written != entries.len() {
+        bail!("Only {} of {} boot MSRs were accepted", written, entries.len());
+    }
+    Ok(())
+}
+
+fn setup_regs(vcpu: &VcpuFd, entry: GuestAddress) -> Result<()> {
+    let regs = kvm_regs {
+        // Bit 1 is reserved and always set.
+        rflags: 0x2,
+        rip: entry.0,
+        rsp: layout::BOOT_STACK_POINTER,
+        rbp: layout::BOOT_STACK_POINTER,
+        rsi: layout::ZERO_PAGE_START,
+        ..Default::default()
+    };
+    vcpu.set_regs(&regs)?;
+    Ok(())
+}
+
+fn setup_sregs(vcpu: &VcpuFd, guest_mem: &GuestMemoryMmap) -> Result<()> {
+    let mut sregs: kvm_sregs = vcpu.get_sregs()?;
+
+    for (index, entry) in BOOT_GDT.iter().enumerate() {
+        guest_mem.write_obj(*entry, GuestAddress(layout::BOOT_GDT_START + index as u64 * 8))?;
+    }
+    sregs.gdt.base = layout::BOOT_GDT_START;
+    sregs.gdt.limit = std::mem::size_of_val(&BOOT_GDT) as u16 - 1;
+    guest_mem.write_obj(0u64, GuestAddress(layout::BOOT_IDT_START))?;
+    sregs.idt.base = layout::BOOT_IDT_START;
+    sregs.idt.limit = std::mem::size_of::<u64>() as u16 - 1;
+
+    let code = segment_from_gdt(BOOT_GDT[1], 1);
+    let data = segment_from_gdt(BOOT_GDT[2], 2);
+    sregs.cs = code;
+    (sregs.ds, sregs.es, sregs.fs, sregs.gs, sregs.ss) = (data, data, data, data, data);
+    sregs.tr = segment_from_gdt(BOOT_GDT[3], 3);
+
+    // Identity-map the first GiB with 2 MiB pages.
+    guest_mem.write_obj(layout::PDPTE_START | PTE_PRESENT_RW, GuestAddress(layout::PML4_START))?;
+    guest_mem.write_obj(layout::PDE_START | PTE_PRESENT_RW, GuestAddress(layout::PDPTE_START))?;
+    for i in 0..512u64 {
+        guest_mem.write_obj((i << 21) | PDE_PS | PTE_PRESENT_RW, GuestAddress(layout::PDE_START + i * 8))?;
+    }
+    sregs.cr3 = layout::PML4_START;
+    sregs.cr4 |= X86_CR4_PAE;
+    sregs.cr0 |= X86_CR0_PE | X86_CR0_PG;
+    sregs.efer |= EFER_LME | EFER_LMA;
+
+    vcpu.set_sregs(&sregs)?;
+    Ok(())
+}
+
+/// Routes the legacy PIC through LINT0 (ExtINT) and NMIs through LINT1.
+fn set_lint(vcpu: &VcpuFd) -> Result<()> {
+    let mut lapic = vcpu.get_lapic()?;
+    for (reg, mode) in [(APIC_LVT0, APIC_MODE_EXTINT), (APIC_LVT1, APIC_MODE_NMI)] {
+        let bytes: [u8; 4] = std::array::from_fn(|i| lapic.regs[reg + i] as u8);
+        let value = (u32::from_le_bytes(bytes) & !0x700) | (mode << 8);
+        for (i, byte) in value.to_le_bytes().into_iter().enumerate() {
+            lapic.regs[reg + i] = byte as _;
+        }
+    }
+    vcpu.set_lapic(&lapic)?;
+    Ok(())
+}
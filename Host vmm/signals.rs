@@ -0,0 +1,59 @@
+// src/signals.rs - Host Control Signals
+
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use anyhow::{Context, Result};
+use libc::{c_int, c_void, siginfo_t};
+use vmm_sys_util::signal::register_signal_handler;
+
+// --- Host Requests ---
+//
+// The VMM process is controlled with signals: SIGUSR1 pauses the guest,
+// SIGUSR2 resumes it, and SIGINT or SIGTERM shut it down. The handlers only
+// record the request; the VMM thread picks it up with `take_request`
+// between waits, and carries it out across the vCPU threads.
+
+/// A request to the VMM, delivered as a signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostRequest {
+    Pause,
+    Resume,
+    Shutdown,
+}
+
+const NO_REQUEST: u8 = 0;
+const PAUSE_REQUEST: u8 = 1;
+const RESUME_REQUEST: u8 = 2;
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// The latest of pause and resume; only the last one counts.
+static PAUSE_OR_RESUME: AtomicU8 = AtomicU8::new(NO_REQUEST);
+
+extern "C" fn handle_request(num: c_int, _: *mut siginfo_t, _: *mut c_void) {
+    match num {
+        libc::SIGUSR1 => PAUSE_OR_RESUME.store(PAUSE_REQUEST, Ordering::SeqCst),
+        libc::SIGUSR2 => PAUSE_OR_RESUME.store(RESUME_REQUEST, Ordering::SeqCst),
+        _ => SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst),
+    }
+}
+
+/// Installs the handlers for the control signals.
+pub fn install() -> Result<()> {
+    for num in [libc::SIGUSR1, libc::SIGUSR2, libc::SIGINT, libc::SIGTERM] {
+        register_signal_handler(num, handle_request)
+            .with_context(|| format!("Failed to install the handler for signal {}", num))?;
+    }
+    Ok(())
+}
+
+/// The pending request, if any; shutdown takes precedence.
+pub fn take_request() -> Option<HostRequest> {
+    if SHUTDOWN_REQUESTED.swap(false, Ordering::SeqCst) {
+        return Some(HostRequest::Shutdown);
+    }
+    match PAUSE_OR_RESUME.swap(NO_REQUEST, Ordering::SeqCst) {
+        PAUSE_REQUEST => Some(HostRequest::Pause),
+        RESUME_REQUEST => Some(HostRequest::Resume),
+        _ => None,
+    }
+}
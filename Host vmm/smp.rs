@@ -0,0 +1,353 @@
+// src/smp.rs - Multiprocessor Tables
+
+use anyhow::{bail, Result};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use crate::layout;
+
+// --- CPU Enumeration ---
+//
+// The kernel learns how many CPUs there are, and where the IOAPIC is, from
+// firmware tables. Both kinds are written: the ACPI MADT, which kernels
+// prefer, and the Intel MP table, which they fall back to (e.g. with
+// `acpi=off`). Each describes the vCPUs as local APICs 0..n, the in-kernel
+// IOAPIC, and the legacy PIC wired through LINT0 with NMIs on LINT1.
+
+/// Legacy ISA IRQs, identity-mapped onto the IOAPIC pins like KVM's
+/// default GSI routing does.
+const ISA_IRQS: u8 = 16;
+
+const APIC_VERSION: u8 = 0x14;
+const IOAPIC_VERSION: u8 = 0x11;
+
+const OEM_ID: &[u8; 6] = b"DIDVMM";
+
+/// Makes the bytes of a checksummed table sum to zero.
+fn checksum(bytes: &[u8]) -> u8 {
+    0u8.wrapping_sub(bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)))
+}
+
+/// Writes both tables for `vcpus` CPUs.
+pub fn setup_tables(guest_mem: &GuestMemoryMmap, vcpus: u8) -> Result<()> {
+    setup_mptable(guest_mem, vcpus)?;
+    setup_acpi(guest_mem, vcpus)
+}
+
+// --- MP Table ---
+
+const MPF_SIGNATURE: &[u8; 4] = b"_MP_";
+const MPC_SIGNATURE: &[u8; 4] = b"PCMP";
+const MP_SPEC_REV: u8 = 4;
+const MPF_SIZE: usize = 16;
+const MPC_HEADER_SIZE: usize = 44;
+
+const MP_PROCESSOR: u8 = 0;
+const MP_BUS: u8 = 1;
+const MP_IOAPIC: u8 = 2;
+const MP_INTSRC: u8 = 3;
+const MP_LINTSRC: u8 = 4;
+
+const MP_INT: u8 = 0;
+const MP_NMI: u8 = 1;
+const MP_EXTINT: u8 = 3;
+
+const CPU_ENABLED: u8 = 0x1;
+const CPU_BOOTPROCESSOR: u8 = 0x2;
+/// Family 6, and the FPU and APIC feature bits.
+const CPU_SIGNATURE: u32 = 0x600;
+const CPU_FEATURES: u32 = 0x201;
+
+/// The floating pointer, followed by the configuration table it points to.
+fn setup_mptable(guest_mem: &GuestMemoryMmap, vcpus: u8) -> Result<()> {
+    let ioapic_id = vcpus;
+    let mut entries = Vec::new();
+    for id in 0..vcpus {
+        let flags = if id == 0 { CPU_ENABLED | CPU_BOOTPROCESSOR } else { CPU_ENABLED };
+        entries.extend_from_slice(&[MP_PROCESSOR, id, APIC_VERSION, flags]);
+        entries.extend_from_slice(&CPU_SIGNATURE.to_le_bytes());
+        entries.extend_from_slice(&CPU_FEATURES.to_le_bytes());
+        entries.extend_from_slice(&[0; 8]);
+    }
+    entries.extend_from_slice(&[MP_BUS, 0]);
+    entries.extend_from_slice(b"ISA   ");
+    entries.extend_from_slice(&[MP_IOAPIC, ioapic_id, IOAPIC_VERSION, CPU_ENABLED]);
+    entries.extend_from_slice(&(layout::IOAPIC_START as u32).to_le_bytes());
+    for irq in 0..ISA_IRQS {
+        // Conforming polarity and trigger mode, bus 0.
+        entries.extend_from_slice(&[MP_INTSRC, MP_INT, 0, 0, 0, irq, ioapic_id, irq]);
+    }
+    // To every local APIC (0xff): the PIC on LINT0, NMIs on LINT1.
+    entries.extend_from_slice(&[MP_LINTSRC, MP_EXTINT, 0, 0, 0, 0, 0xff, 0]);
+    entries.extend_from_slice(&[MP_LINTSRC, MP_NMI, 0, 0, 0, 0, 0xff, 1]);
+    // Processors, the bus, the IOAPIC, its inputs and the two LINT entries.
+    let entry_count = u16::from(vcpus) + 2 + u16::from(ISA_IRQS) + 2;
+
+    let config_addr = layout::MPTABLE_START + MPF_SIZE as u64;
+    let mut config = Vec::with_capacity(MPC_HEADER_SIZE + entries.len());
+    config.extend_from_slice(MPC_SIGNATURE);
+    config.extend_from_slice(&((MPC_HEADER_SIZE + entries.len()) as u16).to_le_bytes());
+    config.extend_from_slice(&[MP_SPEC_REV, 0]);
+    config.extend_from_slice(OEM_ID);
+    config.extend_from_slice(b"  ");
+    config.extend_from_slice(b"DID-VM-HOST ");
+    // No OEM table.
+    config.extend_from_slice(&[0; 6]);
+    config.extend_from_slice(&entry_count.to_le_bytes());
+    config.extend_from_slice(&(layout::APIC_START as u32).to_le_bytes());
+    // No extended table.
+    config.extend_from_slice(&[0; 4]);
+    config.extend_from_slice(&entries);
+    config[7] = checksum(&config);
+
+    let mut mpf = Vec::with_capacity(MPF_SIZE);
+    mpf.extend_from_slice(MPF_SIGNATURE);
+    mpf.extend_from_slice(&(config_addr as u32).to_le_bytes());
+    // Length in 16-byte units, then no default configuration or IMCR.
+    mpf.extend_from_slice(&[1, MP_SPEC_REV, 0, 0, 0, 0, 0, 0]);
+    mpf[10] = checksum(&mpf);
+
+    if config_addr + config.len() as u64 > layout::EBDA_START + 0x400 {
+        bail!("MP table for {} vCPUs does not fit in the EBDA", vcpus);
+    }
+    guest_mem.write_slice(&mpf, GuestAddress(layout::MPTABLE_START))?;
+    guest_mem.write_slice(&config, GuestAddress(config_addr))?;
+    Ok(())
+}
+
+// --- ACPI ---
+//
+// RSDP -> XSDT -> FADT and MADT. The FADT declares a hardware-reduced
+// platform, so there are no fixed ACPI registers to emulate, and points at
+// an empty DSDT: no devices are described in AML.
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_SIZE: usize = 36;
+const SDT_HEADER_SIZE: usize = 36;
+const FADT_SIZE: usize = 276;
+const FADT_REVISION: u8 = 6;
+const MADT_REVISION: u8 = 5;
+
+/// FADT fields the VMM sets.
+const FADT_FLAGS_OFFSET: usize = 112;
+const FADT_X_DSDT_OFFSET: usize = 140;
+const FADT_HYPERVISOR_ID_OFFSET: usize = 268;
+const FADT_HW_REDUCED_ACPI: u32 = 1 << 20;
+
+const MADT_PCAT_COMPAT: u32 = 0x1;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IOAPIC: u8 = 1;
+const MADT_LOCAL_APIC_NMI: u8 = 4;
+const MADT_ENABLED: u32 = 0x1;
+
+/// Tables are placed on 16-byte boundaries after the RSDP.
+const ACPI_TABLE_ALIGN: u64 = 16;
+
+/// A standard ACPI table header followed by `body`, checksummed.
+fn sdt(signature: &[u8; 4], revision: u8, body: &[u8]) -> Vec<u8> {
+    let mut table = Vec::with_capacity(SDT_HEADER_SIZE + body.len());
+    table.extend_from_slice(signature);
+    table.extend_from_slice(&((SDT_HEADER_SIZE + body.len()) as u32).to_le_bytes());
+    table.extend_from_slice(&[revision, 0]);
+    table.extend_from_slice(OEM_ID);
+    table.extend_from_slice(b"DIDVMHST");
+    table.extend_from_slice(&1u32.to_le_bytes());
+    table.extend_from_slice(b"DIDV");
+    table.extend_from_slice(&1u32.to_le_bytes());
+    table.extend_from_slice(body);
+    table[9] = checksum(&table);
+    table
+}
+
+fn madt(vcpus: u8) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&(layout::APIC_START as u32).to_le_bytes());
+    body.extend_from_slice(&MADT_PCAT_COMPAT.to_le_bytes());
+    for id in 0..vcpus {
+        // ACPI processor UID and APIC ID are both the vCPU index.
+        body.extend_from_slice(&[MADT_LOCAL_APIC, 8, id, id]);
+        body.extend_from_slice(&MADT_ENABLED.to_le_bytes());
+    }
+    body.extend_from_slice(&[MADT_IOAPIC, 12, vcpus, 0]);
+    body.extend_from_slice(&(layout::IOAPIC_START as u32).to_le_bytes());
+    // GSI base.
+    body.extend_from_slice(&0u32.to_le_bytes());
+    // All processors (0xff), default polarity and trigger, LINT1.
+    body.extend_from_slice(&[MADT_LOCAL_APIC_NMI, 6, 0xff, 0, 0, 1]);
+    sdt(b"APIC", MADT_REVISION, &body)
+}
+
+fn fadt(dsdt_addr: u64) -> Vec<u8> {
+    let mut body = vec![0u8; FADT_SIZE - SDT_HEADER_SIZE];
+    let field = |offset: usize| offset - SDT_HEADER_SIZE;
+    body[field(FADT_FLAGS_OFFSET)..][..4].copy_from_slice(&FADT_HW_REDUCED_ACPI.to_le_bytes());
+    body[field(FADT_X_DSDT_OFFSET)..][..8].copy_from_slice(&dsdt_addr.to_le_bytes());
+    body[field(FADT_HYPERVISOR_ID_OFFSET)..][..8].copy_from_slice(b"DIDVMHST");
+    sdt(b"FACP", FADT_REVISION, &body)
+}
+
+fn setup_acpi(guest_mem: &GuestMemoryMmap, vcpus: u8) -> Result<()> {
+    let align = |addr: u64| (addr + ACPI_TABLE_ALIGN - 1) & !(ACPI_TABLE_ALIGN - 1);
+    let dsdt_addr = align(layout::ACPI_START + RSDP_SIZE as u64);
+    let dsdt = sdt(b"DSDT", 2, &[]);
+    let fadt_addr = align(dsdt_addr + dsdt.len() as u64);
+    let fadt = fadt(dsdt_addr);
+    let madt_addr = align(fadt_addr + fadt.len() as u64);
+    let madt = madt(vcpus);
+    let xsdt_addr = align(madt_addr + madt.len() as u64);
+    let xsdt_body: Vec<u8> = [fadt_addr, madt_addr].iter().flat_map(|addr| addr.to_le_bytes()).collect();
+    let xsdt = sdt(b"XSDT", 1, &xsdt_body);
+    if xsdt_addr + xsdt.len() as u64 > layout::HIMEM_START {
+        bail!("ACPI tables for {} vCPUs do not fit below 1 MiB", vcpus);
+    }
+
+    let mut rsdp = Vec::with_capacity(RSDP_SIZE);
+    rsdp.extend_from_slice(RSDP_SIGNATURE);
+    rsdp.push(0);
+    rsdp.extend_from_slice(OEM_ID);
+    // Revision 2; no RSDT, only the XSDT.
+    rsdp.push(2);
+    rsdp.extend_from_slice(&0u32.to_le_bytes());
+    rsdp.extend_from_slice(&(RSDP_SIZE as u32).to_le_bytes());
+    rsdp.extend_from_slice(&xsdt_addr.to_le_bytes());
+    rsdp.extend_from_slice(&[0; 4]);
+    // The ACPI 1.0 checksum covers the first 20 bytes, the extended one all of it.
+    rsdp[8] = checksum(&rsdp[..20]);
+    rsdp[32] = checksum(&rsdp);
+
+    guest_mem.write_slice(&rsdp, GuestAddress(layout::ACPI_START))?;
+    for (addr, table) in [(dsdt_addr, &dsdt), (fadt_addr, &fadt), (madt_addr, &madt), (xsdt_addr, &xsdt)] {
+        guest_mem.write_slice(table, GuestAddress(addr))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MAX_VCPUS;
+
+    fn guest_memory() -> GuestMemoryMmap {
+        GuestMemoryMmap::from_ranges(&[(GuestAddress(0), layout::HIMEM_START as usize)]).unwrap()
+    }
+
+    fn read(guest_mem: &GuestMemoryMmap, addr: u64, len: usize) -> Vec<u8> {
+        let mut bytes = vec![0u8; len];
+        guest_mem.read_slice(&mut bytes, GuestAddress(addr)).unwrap();
+        bytes
+    }
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn sums_to_zero(bytes: &[u8]) -> bool {
+        bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+    }
+
+    /// The floating pointer and the configuration table it points to.
+    fn mptable(guest_mem: &GuestMemoryMmap) -> (Vec<u8>, u64, Vec<u8>) {
+        let mpf = read(guest_mem, layout::MPTABLE_START, MPF_SIZE);
+        let config_addr = u64::from(u32_at(&mpf, 4));
+        let length = u16_at(&read(guest_mem, config_addr, MPC_HEADER_SIZE), 4);
+        (mpf, config_addr, read(guest_mem, config_addr, length.into()))
+    }
+
+    /// An ACPI table at `addr`, by the length in its header.
+    fn sdt_at(guest_mem: &GuestMemoryMmap, addr: u64) -> Vec<u8> {
+        let length = u32_at(&read(guest_mem, addr, SDT_HEADER_SIZE), 4);
+        read(guest_mem, addr, length as usize)
+    }
+
+    /// The RSDP and every table reachable from it.
+    fn acpi_tables(guest_mem: &GuestMemoryMmap) -> (Vec<u8>, Vec<Vec<u8>>) {
+        let rsdp = read(guest_mem, layout::ACPI_START, RSDP_SIZE);
+        let xsdt = sdt_at(guest_mem, u64_at(&rsdp, 24));
+        let mut tables: Vec<_> = (SDT_HEADER_SIZE..xsdt.len())
+            .step_by(8)
+            .map(|offset| sdt_at(guest_mem, u64_at(&xsdt, offset)))
+            .collect();
+        let fadt = tables.iter().find(|t| &t[..4] == b"FACP").unwrap();
+        let dsdt = sdt_at(guest_mem, u64_at(fadt, FADT_X_DSDT_OFFSET));
+        tables.push(dsdt);
+        tables.push(xsdt);
+        (rsdp, tables)
+    }
+
+    #[test]
+    fn mp_table_checksums_and_fits_the_ebda() {
+        for vcpus in [1, 2, MAX_VCPUS] {
+            let guest_mem = guest_memory();
+            setup_tables(&guest_mem, vcpus).unwrap();
+            let (mpf, config_addr, config) = mptable(&guest_mem);
+
+            assert_eq!(&mpf[..4], MPF_SIGNATURE);
+            assert!(sums_to_zero(&mpf));
+            assert_eq!(&config[..4], MPC_SIGNATURE);
+            assert!(sums_to_zero(&config));
+            assert!(config_addr + config.len() as u64 <= layout::EBDA_START + 0x400);
+
+            // One enabled processor entry per vCPU, the first the BSP.
+            let processors: Vec<_> = config[MPC_HEADER_SIZE..]
+                .chunks(20)
+                .take_while(|entry| entry[0] == MP_PROCESSOR)
+                .map(|entry| (entry[1], entry[3]))
+                .collect();
+            assert_eq!(processors.len(), usize::from(vcpus));
+            for (id, &(apic_id, flags)) in processors.iter().enumerate() {
+                assert_eq!(usize::from(apic_id), id);
+                assert_ne!(flags & CPU_ENABLED, 0);
+                assert_eq!(flags & CPU_BOOTPROCESSOR != 0, id == 0);
+            }
+        }
+    }
+
+    #[test]
+    fn acpi_tables_checksum() {
+        let guest_mem = guest_memory();
+        setup_tables(&guest_mem, 4).unwrap();
+        let (rsdp, tables) = acpi_tables(&guest_mem);
+
+        assert_eq!(&rsdp[..8], RSDP_SIGNATURE);
+        assert!(sums_to_zero(&rsdp[..20]), "ACPI 1.0 checksum");
+        assert!(sums_to_zero(&rsdp), "extended checksum");
+        let signatures: Vec<_> = tables.iter().map(|t| String::from_utf8_lossy(&t[..4]).into_owned()).collect();
+        assert_eq!(signatures, ["FACP", "APIC", "DSDT", "XSDT"]);
+        for table in &tables {
+            assert!(sums_to_zero(table), "{} checksum", String::from_utf8_lossy(&table[..4]));
+        }
+    }
+
+    #[test]
+    fn madt_lists_each_vcpu_and_the_ioapic() {
+        for vcpus in [1, 3, MAX_VCPUS] {
+            let madt = madt(vcpus);
+            let mut lapics = Vec::new();
+            let mut ioapics = Vec::new();
+            let mut offset = SDT_HEADER_SIZE + 8;
+            while offset < madt.len() {
+                let entry = &madt[offset..offset + usize::from(madt[offset + 1])];
+                match entry[0] {
+                    MADT_LOCAL_APIC => lapics.push((entry[3], u32_at(entry, 4))),
+                    MADT_IOAPIC => ioapics.push((entry[2], u32_at(entry, 4))),
+                    _ => {}
+                }
+                offset += entry.len();
+            }
+
+            assert_eq!(lapics.len(), usize::from(vcpus));
+            for (id, &(apic_id, flags)) in lapics.iter().enumerate() {
+                assert_eq!(usize::from(apic_id), id);
+                assert_eq!(flags & MADT_ENABLED, MADT_ENABLED);
+            }
+            assert_eq!(ioapics, [(vcpus, layout::IOAPIC_START as u32)]);
+        }
+    }
+}
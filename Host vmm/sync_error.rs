@@ -0,0 +1,39 @@
+// src/sync_error.rs - Cross-Thread Synchronization Errors
+
+use std::error::Error;
+use std::fmt;
+use std::sync::{Mutex, MutexGuard};
+
+// --- Synchronization Errors ---
+//
+// The vCPU threads, the VMM thread and the devices share state behind
+// mutexes. A thread that panics while holding one poisons it; instead of
+// propagating the panic, whoever touches the lock next gets a `SyncError`
+// naming it and stops cleanly. This is the VMM's own type; the attestation
+// library's `SyncError` is unrelated and does not convert to or from it.
+
+#[derive(Debug)]
+pub enum SyncError {
+    /// The named lock was poisoned because a thread panicked while holding it.
+    LockPoisoned(&'static str),
+    /// A vCPU thread panicked instead of returning.
+    VcpuPanicked(u8),
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncError::LockPoisoned(lock) => {
+                write!(f, "The {} lock was poisoned due to a panic in the holding thread", lock)
+            }
+            SyncError::VcpuPanicked(id) => write!(f, "vCPU {} thread panicked", id),
+        }
+    }
+}
+
+impl Error for SyncError {}
+
+/// Locks `mutex`, naming it in the error if it was poisoned.
+pub fn lock<'a, T: ?Sized>(mutex: &'a Mutex<T>, name: &'static str) -> Result<MutexGuard<'a, T>, SyncError> {
+    mutex.lock().map_err(|_| SyncError::LockPoisoned(name))
+}
@@ -0,0 +1,349 @@
+// src/vcpu.rs - vCPU Run Loop
+
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use kvm_ioctls::{VcpuExit, VcpuFd, VmFd};
+use libc::{c_int, c_void, siginfo_t};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::signal::{register_signal_handler, Killable, SIGRTMIN};
+
+use crate::devices::Bus;
+use crate::sync_error::{self, SyncError};
+
+// --- vCPU Run Loop ---
+//
+// `KVM_RUN` executes the guest until it does something the kernel hands back
+// to userspace. Port I/O and MMIO go to the device buses and the guest is
+// resumed; anything that ends the guest (HLT without an interrupt
+// controller to wake it, a triple fault, a reset or power-off request, or
+// KVM failing to enter or emulate) stops the loop with the reason.
+
+/// Why a vCPU stopped running the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VcpuExitReason {
+    /// The guest executed HLT and nothing can wake it.
+    Halted,
+    /// Triple fault, or the guest otherwise shut the CPU down.
+    Shutdown,
+    /// The guest asked the i8042 for a CPU reset.
+    Reset,
+    /// `KVM_SYSTEM_EVENT` of the given type (shutdown, reset, crash...).
+    SystemEvent(u32),
+    /// The hardware refused to enter the guest.
+    FailEntry { reason: u64, cpu: u32 },
+    /// KVM could not emulate an instruction or handle an exit.
+    InternalError { suberror: u32 },
+}
+
+impl VcpuExitReason {
+    /// Whether the guest stopped because something went wrong rather than
+    /// on its own request.
+    pub fn is_error(&self) -> bool {
+        matches!(self, VcpuExitReason::FailEntry { .. } | VcpuExitReason::InternalError { .. })
+    }
+}
+
+impl fmt::Display for VcpuExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VcpuExitReason::Halted => write!(f, "halted"),
+            VcpuExitReason::Shutdown => write!(f, "shutdown (triple fault)"),
+            VcpuExitReason::Reset => write!(f, "reset requested"),
+            VcpuExitReason::SystemEvent(kind) => write!(f, "system event {}", kind),
+            VcpuExitReason::FailEntry { reason, cpu } => {
+                write!(f, "VM entry failed on CPU {} (hardware reason {:#x})", cpu, reason)
+            }
+            VcpuExitReason::InternalError { suberror } => write!(f, "KVM internal error (suberror {})", suberror),
+        }
+    }
+}
+
+/// One vCPU with the buses its exits are dispatched to.
+pub struct Vcpu {
+    id: u8,
+    fd: VcpuFd,
+    pio_bus: Arc<Bus>,
+    mmio_bus: Arc<Bus>,
+    /// Signalled by the i8042 when the guest requests a reset.
+    reset_evt: EventFd,
+}
+
+impl Vcpu {
+    pub fn new(vm: &VmFd, id: u8, pio_bus: Arc<Bus>, mmio_bus: Arc<Bus>, reset_evt: &EventFd) -> Result<Self> {
+        let fd = vm.create_vcpu(id as u64).with_context(|| format!("Failed to create vCPU {}", id))?;
+        Ok(Vcpu { id, fd, pio_bus, mmio_bus, reset_evt: reset_evt.try_clone()? })
+    }
+
+    /// The KVM handle, for setting up registers before `run`.
+    pub fn fd(&self) -> &VcpuFd {
+        &self.fd
+    }
+
+    /// Runs the guest until it stops, and returns why; `None` if the VMM
+    /// stopped it through `control`.
+    pub fn run(&mut self, control: &VcpuControl) -> Result<Option<VcpuExitReason>> {
+        log::info!("vCPU {} entering run loop", self.id);
+        loop {
+            if !control.checkpoint()? {
+                return Ok(None);
+            }
+            let exit = match self.fd.run() {
+                Ok(exit) => exit,
+                // A signal, such as a kick from the VMM thread, interrupted
+                // KVM_RUN; the guest state is intact.
+                Err(e) if e.errno() == libc::EINTR || e.errno() == libc::EAGAIN => continue,
+                Err(e) => return Err(e).with_context(|| format!("KVM_RUN failed on vCPU {}", self.id)),
+            };
+            match exit {
+                VcpuExit::IoIn(port, data) => {
+                    if !self.pio_bus.read(port as u64, data)? {
+                        log::debug!("vCPU {}: unhandled port read at {:#x}", self.id, port);
+                    }
+                }
+                VcpuExit::IoOut(port, data) => {
+                    if !self.pio_bus.write(port as u64, data)? {
+                        log::debug!("vCPU {}: unhandled port write at {:#x}", self.id, port);
+                    }
+                    if self.reset_requested() {
+                        return Ok(Some(VcpuExitReason::Reset));
+                    }
+                }
+                VcpuExit::MmioRead(addr, data) => {
+                    if !self.mmio_bus.read(addr, data)? {
+                        log::debug!("vCPU {}: unhandled MMIO read at {:#x}", self.id, addr);
+                    }
+                }
+                VcpuExit::MmioWrite(addr, data) => {
+                    if !self.mmio_bus.write(addr, data)? {
+                        log::debug!("vCPU {}: unhandled MMIO write at {:#x}", self.id, addr);
+                    }
+                }
+                VcpuExit::Hlt => return Ok(Some(VcpuExitReason::Halted)),
+                VcpuExit::Shutdown => return Ok(Some(VcpuExitReason::Shutdown)),
+                VcpuExit::SystemEvent(kind, _) => return Ok(Some(VcpuExitReason::SystemEvent(kind))),
+                VcpuExit::FailEntry(reason, cpu) => {
+                    return Ok(Some(VcpuExitReason::FailEntry { reason, cpu }));
+                }
+                VcpuExit::InternalError => {
+                    // SAFETY: KVM filled in `internal` for KVM_EXIT_INTERNAL_ERROR.
+                    let suberror = unsafe { self.fd.get_kvm_run().__bindgen_anon_1.internal.suberror };
+                    return Ok(Some(VcpuExitReason::InternalError { suberror }));
+                }
+                VcpuExit::Intr | VcpuExit::IrqWindowOpen => {}
+                other => bail!("vCPU {}: unexpected exit {:?}", self.id, other),
+            }
+        }
+    }
+
+    /// Consumes a pending reset request, if any.
+    fn reset_requested(&self) -> bool {
+        self.reset_evt.read().is_ok()
+    }
+
+    /// Runs the vCPU on its own thread. Whether it stops on its own or
+    /// fails, it asks `control` to stop the other vCPUs too.
+    fn spawn(mut self, control: Arc<VcpuControl>) -> Result<JoinHandle<Result<()>>> {
+        let id = self.id;
+        thread::Builder::new()
+            .name(format!("vcpu{}", id))
+            .spawn(move || match self.run(&control) {
+                Ok(Some(reason)) => {
+                    log::info!("vCPU {} stopped: {}", id, reason);
+                    control.guest_stopped(id, reason)?;
+                    Ok(())
+                }
+                Ok(None) => Ok(()),
+                Err(e) => {
+                    control.set_requested(RunState::Stopping)?;
+                    Err(e)
+                }
+            })
+            .with_context(|| format!("Failed to start the vCPU {} thread", id))
+    }
+}
+
+// --- Threads and Coordination ---
+//
+// Each vCPU runs on its own thread. The VMM thread steers them through a
+// shared `VcpuControl`: it sets the state they should be in, then kicks
+// them out of KVM_RUN with `VCPU_KICK_SIGNAL` until they have all noticed.
+// A kick can land just before a thread enters KVM_RUN and be lost, hence
+// the repetition. The first vCPU to stop on the guest's behalf records why
+// and asks for the rest to be stopped.
+
+/// How often unresponsive vCPU threads are kicked again.
+const KICK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Interrupts KVM_RUN on a vCPU thread; the handler itself does nothing.
+fn vcpu_kick_signal() -> c_int {
+    SIGRTMIN()
+}
+
+extern "C" fn handle_kick(_: c_int, _: *mut siginfo_t, _: *mut c_void) {}
+
+/// What the VMM wants the vCPUs to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunState {
+    Running,
+    Paused,
+    Stopping,
+}
+
+struct ControlState {
+    requested: RunState,
+    /// vCPU threads currently parked in `Paused`.
+    parked: usize,
+    /// The first vCPU to stop on its own, and why.
+    stopped_by: Option<(u8, VcpuExitReason)>,
+}
+
+/// The state shared between the VMM thread and the vCPU threads.
+pub struct VcpuControl {
+    state: Mutex<ControlState>,
+    changed: Condvar,
+}
+
+impl VcpuControl {
+    fn new() -> Self {
+        VcpuControl {
+            state: Mutex::new(ControlState { requested: RunState::Running, parked: 0, stopped_by: None }),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, ControlState>, SyncError> {
+        sync_error::lock(&self.state, "vCPU control")
+    }
+
+    fn set_requested(&self, requested: RunState) -> Result<(), SyncError> {
+        let mut state = self.lock()?;
+        // Nothing brings stopping vCPUs back.
+        if state.requested != RunState::Stopping {
+            state.requested = requested;
+        }
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    fn guest_stopped(&self, id: u8, reason: VcpuExitReason) -> Result<(), SyncError> {
+        let mut state = self.lock()?;
+        state.stopped_by.get_or_insert((id, reason));
+        state.requested = RunState::Stopping;
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Called by a vCPU thread between exits: parks it while the VMM has
+    /// the vCPUs paused, and returns whether it should keep running.
+    fn checkpoint(&self) -> Result<bool, SyncError> {
+        let mut state = self.lock()?;
+        if state.requested == RunState::Paused {
+            state.parked += 1;
+            self.changed.notify_all();
+            while state.requested == RunState::Paused {
+                state = self.changed.wait(state).map_err(|_| SyncError::LockPoisoned("vCPU control"))?;
+            }
+            state.parked -= 1;
+        }
+        Ok(state.requested == RunState::Running)
+    }
+}
+
+/// The running vCPU threads.
+pub struct VcpuThreads {
+    control: Arc<VcpuControl>,
+    threads: Vec<(u8, JoinHandle<Result<()>>)>,
+}
+
+impl VcpuThreads {
+    /// Starts one thread per vCPU, all running.
+    pub fn start(vcpus: Vec<Vcpu>) -> Result<Self> {
+        register_signal_handler(vcpu_kick_signal(), handle_kick).context("Failed to install the vCPU kick handler")?;
+        let control = Arc::new(VcpuControl::new());
+        let mut started = VcpuThreads { control: control.clone(), threads: Vec::with_capacity(vcpus.len()) };
+        for vcpu in vcpus {
+            let id = vcpu.id;
+            match vcpu.spawn(control.clone()) {
+                Ok(thread) => started.threads.push((id, thread)),
+                Err(e) => {
+                    // Do not leave the vCPUs started so far running.
+                    let _ = started.shutdown();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(started)
+    }
+
+    /// Waits up to `timeout` for a vCPU to stop the guest; true once one has.
+    pub fn wait_for_stop(&self, timeout: Duration) -> Result<bool, SyncError> {
+        let state = self.control.lock()?;
+        let (state, _) = self
+            .control
+            .changed
+            .wait_timeout_while(state, timeout, |state| state.requested != RunState::Stopping)
+            .map_err(|_| SyncError::LockPoisoned("vCPU control"))?;
+        Ok(state.requested == RunState::Stopping)
+    }
+
+    /// Stops every vCPU at its next exit and waits until all are parked.
+    pub fn pause(&self) -> Result<()> {
+        self.control.set_requested(RunState::Paused)?;
+        loop {
+            let state = self.control.lock()?;
+            let live = self.threads.iter().filter(|(_, thread)| !thread.is_finished()).count();
+            if state.requested != RunState::Paused || state.parked >= live {
+                break;
+            }
+            drop(state);
+            self.kick();
+            thread::sleep(KICK_INTERVAL);
+        }
+        log::info!("Paused {} vCPUs", self.threads.len());
+        Ok(())
+    }
+
+    /// Lets paused vCPUs run again.
+    pub fn resume(&self) -> Result<()> {
+        self.control.set_requested(RunState::Running)?;
+        log::info!("Resumed {} vCPUs", self.threads.len());
+        Ok(())
+    }
+
+    /// Stops and joins every vCPU thread. Returns the vCPU that stopped
+    /// the guest and why, or `None` if the VMM stopped it.
+    pub fn shutdown(mut self) -> Result<Option<(u8, VcpuExitReason)>> {
+        self.control.set_requested(RunState::Stopping)?;
+        while self.threads.iter().any(|(_, thread)| !thread.is_finished()) {
+            self.kick();
+            thread::sleep(KICK_INTERVAL);
+        }
+
+        let mut first_error = None;
+        for (id, thread) in std::mem::take(&mut self.threads) {
+            let error = match thread.join() {
+                Ok(Ok(())) => continue,
+                Ok(Err(e)) => e.context(format!("vCPU {} failed", id)),
+                Err(_) => SyncError::VcpuPanicked(id).into(),
+            };
+            log::error!("{:#}", error);
+            first_error.get_or_insert(error);
+        }
+        match first_error {
+            Some(error) => Err(error),
+            None => Ok(self.control.lock()?.stopped_by),
+        }
+    }
+
+    fn kick(&self) {
+        for (id, thread) in self.threads.iter().filter(|(_, thread)| !thread.is_finished()) {
+            if let Err(e) = thread.kill(vcpu_kick_signal()) {
+                log::debug!("Failed to kick vCPU {}: {}", id, e);
+            }
+        }
+    }
+}
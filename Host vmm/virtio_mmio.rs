@@ -0,0 +1,477 @@
+// src/virtio_mmio.rs - Virtio MMIO Transport
+
+use std::io;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use virtio_bindings::virtio_config::{
+    VIRTIO_CONFIG_S_ACKNOWLEDGE, VIRTIO_CONFIG_S_DRIVER, VIRTIO_CONFIG_S_DRIVER_OK, VIRTIO_CONFIG_S_FAILED,
+    VIRTIO_CONFIG_S_FEATURES_OK, VIRTIO_F_VERSION_1,
+};
+use virtio_bindings::virtio_mmio::*;
+use virtio_queue::{Queue, QueueT};
+use vm_memory::GuestMemoryMmap;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::devices::BusDevice;
+
+// --- Virtio over MMIO ---
+//
+// The virtio-mmio (version 2) register window a driver uses to discover a
+// device, negotiate features, hand over its virtqueues and get notified.
+// The transport is generic; what the device does with its queues once the
+// driver sets DRIVER_OK is up to the `VirtioDevice`. The guest finds the
+// window through `virtio_mmio.device=<size>@<addr>:<irq>` on the kernel
+// command line.
+
+/// Size of one device's register window and config space.
+pub const VIRTIO_MMIO_SIZE: u64 = 0x1000;
+
+/// "virt", little-endian.
+const MMIO_MAGIC: u32 = 0x7472_6976;
+const MMIO_VERSION: u32 = 2;
+const VENDOR_ID: u32 = 0;
+
+/// A virtio device behind the transport.
+pub trait VirtioDevice: Send {
+    /// The virtio device ID (`VIRTIO_ID_*`).
+    fn device_type(&self) -> u32;
+    /// One entry per virtqueue: the largest size the driver may pick.
+    fn queue_max_sizes(&self) -> &[u16];
+    /// Device-specific feature bits; `VIRTIO_F_VERSION_1` is added by the
+    /// transport.
+    fn features(&self) -> u64;
+    /// Reads the device-specific config space.
+    fn read_config(&self, offset: u64, data: &mut [u8]);
+    /// Starts the device once the driver has set DRIVER_OK.
+    fn activate(&mut self, mem: GuestMemoryMmap, queues: Vec<Queue>, interrupt: Arc<VirtioInterrupt>) -> Result<()>;
+    /// The driver made buffers available on queue `index`.
+    fn queue_notify(&mut self, index: u32);
+    /// Stops the device; it may be activated again afterwards.
+    fn reset(&mut self);
+}
+
+/// The interrupt status register and the line behind it.
+pub struct VirtioInterrupt {
+    status: AtomicU32,
+    irq_evt: EventFd,
+}
+
+impl VirtioInterrupt {
+    /// `irq_evt` is registered as an irqfd for the device's IRQ.
+    pub fn new(irq_evt: EventFd) -> Self {
+        VirtioInterrupt { status: AtomicU32::new(0), irq_evt }
+    }
+
+    /// Tells the driver there are new used buffers.
+    pub fn signal_used_queue(&self) -> io::Result<()> {
+        self.status.fetch_or(VIRTIO_MMIO_INT_VRING, Ordering::SeqCst);
+        self.irq_evt.write(1)
+    }
+}
+
+/// The register window of one virtio device.
+pub struct MmioTransport<D: VirtioDevice> {
+    device: D,
+    guest_mem: GuestMemoryMmap,
+    interrupt: Arc<VirtioInterrupt>,
+    queues: Vec<Queue>,
+    queue_sel: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    status: u32,
+    activated: bool,
+}
+
+impl<D: VirtioDevice> MmioTransport<D> {
+    pub fn new(device: D, guest_mem: GuestMemoryMmap, interrupt: Arc<VirtioInterrupt>) -> Result<Self> {
+        let queues = Self::new_queues(&device)?;
+        Ok(MmioTransport {
+            device,
+            guest_mem,
+            interrupt,
+            queues,
+            queue_sel: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            status: 0,
+            activated: false,
+        })
+    }
+
+    fn new_queues(device: &D) -> Result<Vec<Queue>> {
+        let queues = device.queue_max_sizes().iter().map(|max| Queue::new(*max)).collect::<Result<_, _>>()?;
+        Ok(queues)
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device.features() | (1 << VIRTIO_F_VERSION_1)
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    /// Queues can only be set up between FEATURES_OK and DRIVER_OK.
+    fn queue_configurable(&self) -> bool {
+        self.status & (VIRTIO_CONFIG_S_FEATURES_OK | VIRTIO_CONFIG_S_DRIVER_OK | VIRTIO_CONFIG_S_FAILED)
+            == VIRTIO_CONFIG_S_FEATURES_OK
+    }
+
+    fn read_register(&self, offset: u32) -> u32 {
+        let queue = self.queues.get(self.queue_sel as usize);
+        match offset {
+            VIRTIO_MMIO_MAGIC_VALUE => MMIO_MAGIC,
+            VIRTIO_MMIO_VERSION => MMIO_VERSION,
+            VIRTIO_MMIO_DEVICE_ID => self.device.device_type(),
+            VIRTIO_MMIO_VENDOR_ID => VENDOR_ID,
+            VIRTIO_MMIO_DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            VIRTIO_MMIO_QUEUE_NUM_MAX => queue.map(|q| q.max_size()).unwrap_or(0).into(),
+            VIRTIO_MMIO_QUEUE_READY => queue.map(|q| q.ready()).unwrap_or(false).into(),
+            VIRTIO_MMIO_INTERRUPT_STATUS => self.interrupt.status.load(Ordering::SeqCst),
+            VIRTIO_MMIO_STATUS => self.status,
+            VIRTIO_MMIO_CONFIG_GENERATION => 0,
+            _ => {
+                log::debug!("virtio-mmio: read of unknown register {:#x}", offset);
+                0
+            }
+        }
+    }
+
+    fn write_register(&mut self, offset: u32, value: u32) {
+        let configurable = self.queue_configurable();
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            VIRTIO_MMIO_DRIVER_FEATURES => {
+                let shift = match self.driver_features_sel {
+                    0 => 0,
+                    1 => 32,
+                    _ => return,
+                };
+                self.driver_features = (self.driver_features & !(0xffff_ffff << shift)) | (u64::from(value) << shift);
+            }
+            VIRTIO_MMIO_QUEUE_SEL => self.queue_sel = value,
+            VIRTIO_MMIO_INTERRUPT_ACK => {
+                self.interrupt.status.fetch_and(!value, Ordering::SeqCst);
+            }
+            VIRTIO_MMIO_STATUS => self.set_status(value),
+            VIRTIO_MMIO_QUEUE_NOTIFY => {
+                if self.activated {
+                    self.device.queue_notify(value);
+                }
+            }
+            _ if !configurable => log::warn!("virtio-mmio: write to {:#x} outside queue setup", offset),
+            VIRTIO_MMIO_QUEUE_NUM => self.with_queue(|q| q.set_size(value as u16)),
+            VIRTIO_MMIO_QUEUE_READY => self.with_queue(|q| q.set_ready(value == 1)),
+            VIRTIO_MMIO_QUEUE_DESC_LOW => self.with_queue(|q| q.set_desc_table_address(Some(value), None)),
+            VIRTIO_MMIO_QUEUE_DESC_HIGH => self.with_queue(|q| q.set_desc_table_address(None, Some(value))),
+            VIRTIO_MMIO_QUEUE_AVAIL_LOW => self.with_queue(|q| q.set_avail_ring_address(Some(value), None)),
+            VIRTIO_MMIO_QUEUE_AVAIL_HIGH => self.with_queue(|q| q.set_avail_ring_address(None, Some(value))),
+            VIRTIO_MMIO_QUEUE_USED_LOW => self.with_queue(|q| q.set_used_ring_address(Some(value), None)),
+            VIRTIO_MMIO_QUEUE_USED_HIGH => self.with_queue(|q| q.set_used_ring_address(None, Some(value))),
+            _ => log::debug!("virtio-mmio: write of unknown register {:#x}", offset),
+        }
+    }
+
+    fn with_queue(&mut self, f: impl FnOnce(&mut Queue)) {
+        if let Some(queue) = self.selected_queue() {
+            f(queue);
+        }
+    }
+
+    /// Drives the device status state machine: 0 resets the device,
+    /// FEATURES_OK is refused for features the device does not offer, and
+    /// DRIVER_OK activates it.
+    fn set_status(&mut self, value: u32) {
+        if value == 0 {
+            self.reset();
+            return;
+        }
+        let added = value & !self.status;
+        if added & VIRTIO_CONFIG_S_FEATURES_OK != 0 && self.driver_features & !self.device_features() != 0 {
+            log::warn!("virtio-mmio: driver accepted unoffered features {:#x}", self.driver_features);
+            return;
+        }
+        self.status = value;
+        if added & VIRTIO_CONFIG_S_DRIVER_OK != 0 && !self.activated {
+            let ready = value & (VIRTIO_CONFIG_S_ACKNOWLEDGE | VIRTIO_CONFIG_S_DRIVER | VIRTIO_CONFIG_S_FEATURES_OK)
+                == (VIRTIO_CONFIG_S_ACKNOWLEDGE | VIRTIO_CONFIG_S_DRIVER | VIRTIO_CONFIG_S_FEATURES_OK)
+                && self.queues.iter().all(|q| q.is_valid(&self.guest_mem));
+            // The device takes the configured queues; fresh ones wait for
+            // the driver after a reset.
+            let result = if ready {
+                Self::new_queues(&self.device)
+                    .map(|fresh| std::mem::replace(&mut self.queues, fresh))
+                    .and_then(|queues| self.device.activate(self.guest_mem.clone(), queues, self.interrupt.clone()))
+            } else {
+                Err(anyhow!("driver set DRIVER_OK with invalid queues or status {:#x}", value))
+            };
+            match result {
+                Ok(()) => self.activated = true,
+                Err(e) => {
+                    log::error!("virtio-mmio: failed to activate device {}: {:#}", self.device.device_type(), e);
+                    self.status |= VIRTIO_CONFIG_S_FAILED;
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        if self.activated {
+            self.device.reset();
+            self.activated = false;
+        }
+        self.queues.iter_mut().for_each(|q| q.reset());
+        self.queue_sel = 0;
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.status = 0;
+        self.interrupt.status.store(0, Ordering::SeqCst);
+    }
+}
+
+impl<D: VirtioDevice> BusDevice for MmioTransport<D> {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        if offset >= u64::from(VIRTIO_MMIO_CONFIG) {
+            self.device.read_config(offset - u64::from(VIRTIO_MMIO_CONFIG), data);
+        } else if data.len() == 4 {
+            data.copy_from_slice(&self.read_register(offset as u32).to_le_bytes());
+        } else {
+            log::warn!("virtio-mmio: {}-byte read of register {:#x}", data.len(), offset);
+            data.fill(0);
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        match <[u8; 4]>::try_from(data) {
+            Ok(bytes) if offset < u64::from(VIRTIO_MMIO_CONFIG) => {
+                self.write_register(offset as u32, u32::from_le_bytes(bytes));
+            }
+            // The device config space is read-only.
+            _ => log::warn!("virtio-mmio: ignored {}-byte write at {:#x}", data.len(), offset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use vm_memory::GuestAddress;
+
+    const QUEUE_SIZE: u16 = 16;
+    const DEVICE_FEATURE: u64 = 1 << 3;
+    const ACKNOWLEDGE_DRIVER: u32 = VIRTIO_CONFIG_S_ACKNOWLEDGE | VIRTIO_CONFIG_S_DRIVER;
+
+    /// What the transport did to the device.
+    #[derive(Debug, Default)]
+    struct DeviceLog {
+        activated_queues: Option<Vec<u16>>,
+        notified: Vec<u32>,
+        resets: u32,
+    }
+
+    struct TestDevice(Arc<Mutex<DeviceLog>>);
+
+    impl VirtioDevice for TestDevice {
+        fn device_type(&self) -> u32 {
+            19
+        }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            &[256, 256]
+        }
+
+        fn features(&self) -> u64 {
+            DEVICE_FEATURE
+        }
+
+        fn read_config(&self, offset: u64, data: &mut [u8]) {
+            data.fill(0xc0 + offset as u8);
+        }
+
+        fn activate(&mut self, _: GuestMemoryMmap, queues: Vec<Queue>, _: Arc<VirtioInterrupt>) -> Result<()> {
+            self.0.lock().unwrap().activated_queues = Some(queues.iter().map(|q| q.size()).collect());
+            Ok(())
+        }
+
+        fn queue_notify(&mut self, index: u32) {
+            self.0.lock().unwrap().notified.push(index);
+        }
+
+        fn reset(&mut self) {
+            self.0.lock().unwrap().resets += 1;
+        }
+    }
+
+    struct TestTransport {
+        transport: MmioTransport<TestDevice>,
+        log: Arc<Mutex<DeviceLog>>,
+        irq_evt: EventFd,
+    }
+
+    impl TestTransport {
+        fn new() -> Self {
+            let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10_0000)]).unwrap();
+            let irq_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+            let interrupt = Arc::new(VirtioInterrupt::new(irq_evt.try_clone().unwrap()));
+            let log = Arc::new(Mutex::new(DeviceLog::default()));
+            let transport = MmioTransport::new(TestDevice(log.clone()), mem, interrupt).unwrap();
+            TestTransport { transport, log, irq_evt }
+        }
+
+        fn read(&mut self, register: u32) -> u32 {
+            let mut data = [0u8; 4];
+            self.transport.read(register.into(), &mut data);
+            u32::from_le_bytes(data)
+        }
+
+        fn write(&mut self, register: u32, value: u32) {
+            self.transport.write(register.into(), &value.to_le_bytes());
+        }
+
+        /// Writes the 64-bit driver feature set.
+        fn accept_features(&mut self, features: u64) {
+            self.write(VIRTIO_MMIO_DRIVER_FEATURES_SEL, 0);
+            self.write(VIRTIO_MMIO_DRIVER_FEATURES, features as u32);
+            self.write(VIRTIO_MMIO_DRIVER_FEATURES_SEL, 1);
+            self.write(VIRTIO_MMIO_DRIVER_FEATURES, (features >> 32) as u32);
+        }
+
+        /// Sets up queue `index` with its rings at `base`.
+        fn setup_queue(&mut self, index: u32, base: u64) {
+            self.write(VIRTIO_MMIO_QUEUE_SEL, index);
+            self.write(VIRTIO_MMIO_QUEUE_NUM, QUEUE_SIZE.into());
+            self.write(VIRTIO_MMIO_QUEUE_DESC_LOW, base as u32);
+            self.write(VIRTIO_MMIO_QUEUE_DESC_HIGH, 0);
+            self.write(VIRTIO_MMIO_QUEUE_AVAIL_LOW, (base + 0x1000) as u32);
+            self.write(VIRTIO_MMIO_QUEUE_USED_LOW, (base + 0x2000) as u32);
+            self.write(VIRTIO_MMIO_QUEUE_READY, 1);
+        }
+    }
+
+    #[test]
+    fn identifies_the_device() {
+        let mut t = TestTransport::new();
+        assert_eq!(t.read(VIRTIO_MMIO_MAGIC_VALUE), MMIO_MAGIC);
+        assert_eq!(t.read(VIRTIO_MMIO_VERSION), 2);
+        assert_eq!(t.read(VIRTIO_MMIO_DEVICE_ID), 19);
+        assert_eq!(t.read(VIRTIO_MMIO_VENDOR_ID), VENDOR_ID);
+
+        let mut config = [0u8; 2];
+        t.transport.read(u64::from(VIRTIO_MMIO_CONFIG) + 4, &mut config);
+        assert_eq!(config, [0xc4; 2]);
+        // Config space is read-only and registers are 32 bits wide.
+        t.transport.write(u64::from(VIRTIO_MMIO_CONFIG), &[1]);
+        t.transport.write(VIRTIO_MMIO_STATUS.into(), &[1, 0]);
+        assert_eq!(t.read(VIRTIO_MMIO_STATUS), 0);
+    }
+
+    #[test]
+    fn negotiates_features_and_activates_on_driver_ok() {
+        let mut t = TestTransport::new();
+        t.write(VIRTIO_MMIO_STATUS, ACKNOWLEDGE_DRIVER);
+
+        t.write(VIRTIO_MMIO_DEVICE_FEATURES_SEL, 0);
+        assert_eq!(t.read(VIRTIO_MMIO_DEVICE_FEATURES), DEVICE_FEATURE as u32);
+        t.write(VIRTIO_MMIO_DEVICE_FEATURES_SEL, 1);
+        assert_eq!(t.read(VIRTIO_MMIO_DEVICE_FEATURES), 1 << (VIRTIO_F_VERSION_1 - 32));
+
+        // FEATURES_OK is refused while the driver asks for more than offered.
+        t.accept_features(1 << 5);
+        t.write(VIRTIO_MMIO_STATUS, ACKNOWLEDGE_DRIVER | VIRTIO_CONFIG_S_FEATURES_OK);
+        assert_eq!(t.read(VIRTIO_MMIO_STATUS), ACKNOWLEDGE_DRIVER);
+
+        // Queues cannot be configured before FEATURES_OK.
+        t.write(VIRTIO_MMIO_QUEUE_SEL, 0);
+        t.write(VIRTIO_MMIO_QUEUE_READY, 1);
+        assert_eq!(t.read(VIRTIO_MMIO_QUEUE_READY), 0);
+
+        t.accept_features(DEVICE_FEATURE | 1 << VIRTIO_F_VERSION_1);
+        t.write(VIRTIO_MMIO_STATUS, ACKNOWLEDGE_DRIVER | VIRTIO_CONFIG_S_FEATURES_OK);
+        assert_eq!(t.read(VIRTIO_MMIO_STATUS), ACKNOWLEDGE_DRIVER | VIRTIO_CONFIG_S_FEATURES_OK);
+        assert_eq!(t.transport.driver_features, DEVICE_FEATURE | 1 << VIRTIO_F_VERSION_1);
+
+        for index in 0..2 {
+            t.write(VIRTIO_MMIO_QUEUE_SEL, index);
+            assert_eq!(t.read(VIRTIO_MMIO_QUEUE_NUM_MAX), 256);
+            t.setup_queue(index, 0x1_0000 * (u64::from(index) + 1));
+            assert_eq!(t.read(VIRTIO_MMIO_QUEUE_READY), 1);
+        }
+        // There is no third queue.
+        t.write(VIRTIO_MMIO_QUEUE_SEL, 2);
+        assert_eq!(t.read(VIRTIO_MMIO_QUEUE_NUM_MAX), 0);
+
+        // Notifications only reach an active device.
+        t.write(VIRTIO_MMIO_QUEUE_NOTIFY, 1);
+        assert!(t.log.lock().unwrap().notified.is_empty());
+
+        let ok = ACKNOWLEDGE_DRIVER | VIRTIO_CONFIG_S_FEATURES_OK | VIRTIO_CONFIG_S_DRIVER_OK;
+        t.write(VIRTIO_MMIO_STATUS, ok);
+        assert_eq!(t.read(VIRTIO_MMIO_STATUS), ok);
+        assert_eq!(t.log.lock().unwrap().activated_queues, Some(vec![QUEUE_SIZE, QUEUE_SIZE]));
+
+        // Once running, the queues can no longer be changed.
+        t.write(VIRTIO_MMIO_QUEUE_SEL, 0);
+        t.write(VIRTIO_MMIO_QUEUE_NUM, 8);
+        assert_eq!(t.transport.queues[0].size(), 256);
+        t.write(VIRTIO_MMIO_QUEUE_NOTIFY, 1);
+        assert_eq!(t.log.lock().unwrap().notified, vec![1]);
+
+        // Used buffers raise the interrupt until the driver acknowledges it.
+        t.transport.interrupt.signal_used_queue().unwrap();
+        assert_eq!(t.irq_evt.read().unwrap(), 1);
+        assert_eq!(t.read(VIRTIO_MMIO_INTERRUPT_STATUS), VIRTIO_MMIO_INT_VRING);
+        t.write(VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_INT_VRING);
+        assert_eq!(t.read(VIRTIO_MMIO_INTERRUPT_STATUS), 0);
+    }
+
+    #[test]
+    fn fails_driver_ok_with_invalid_queues() {
+        let mut t = TestTransport::new();
+        t.accept_features(1 << VIRTIO_F_VERSION_1);
+        t.write(VIRTIO_MMIO_STATUS, ACKNOWLEDGE_DRIVER | VIRTIO_CONFIG_S_FEATURES_OK);
+        // Only one of the two queues is set up.
+        t.setup_queue(0, 0x1_0000);
+        t.write(VIRTIO_MMIO_STATUS, ACKNOWLEDGE_DRIVER | VIRTIO_CONFIG_S_FEATURES_OK | VIRTIO_CONFIG_S_DRIVER_OK);
+        assert_ne!(t.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_FAILED, 0);
+        assert!(t.log.lock().unwrap().activated_queues.is_none());
+    }
+
+    #[test]
+    fn writing_zero_status_resets_the_device() {
+        let mut t = TestTransport::new();
+        t.accept_features(1 << VIRTIO_F_VERSION_1);
+        t.write(VIRTIO_MMIO_STATUS, ACKNOWLEDGE_DRIVER | VIRTIO_CONFIG_S_FEATURES_OK);
+        t.setup_queue(0, 0x1_0000);
+        t.setup_queue(1, 0x2_0000);
+        t.write(VIRTIO_MMIO_STATUS, ACKNOWLEDGE_DRIVER | VIRTIO_CONFIG_S_FEATURES_OK | VIRTIO_CONFIG_S_DRIVER_OK);
+        t.transport.interrupt.signal_used_queue().unwrap();
+
+        t.write(VIRTIO_MMIO_STATUS, 0);
+        assert_eq!(t.log.lock().unwrap().resets, 1);
+        assert_eq!(t.read(VIRTIO_MMIO_STATUS), 0);
+        assert_eq!(t.read(VIRTIO_MMIO_INTERRUPT_STATUS), 0);
+        assert_eq!(t.transport.driver_features, 0);
+        t.write(VIRTIO_MMIO_QUEUE_SEL, 0);
+        assert_eq!(t.read(VIRTIO_MMIO_QUEUE_READY), 0);
+
+        // The driver can start over and activate the device again.
+        t.accept_features(1 << VIRTIO_F_VERSION_1);
+        t.write(VIRTIO_MMIO_STATUS, ACKNOWLEDGE_DRIVER | VIRTIO_CONFIG_S_FEATURES_OK);
+        t.setup_queue(0, 0x1_0000);
+        t.setup_queue(1, 0x2_0000);
+        t.write(VIRTIO_MMIO_STATUS, ACKNOWLEDGE_DRIVER | VIRTIO_CONFIG_S_FEATURES_OK | VIRTIO_CONFIG_S_DRIVER_OK);
+        assert_eq!(t.read(VIRTIO_MMIO_STATUS) & VIRTIO_CONFIG_S_FAILED, 0);
+        assert!(t.transport.activated);
+    }
+}
@@ -0,0 +1,1344 @@
+// src/vsock.rs - Virtio Vsock Device
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::num::Wrapping;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use anyhow::{bail, Context, Result};
+use virtio_bindings::virtio_ids::VIRTIO_ID_VSOCK;
+use virtio_queue::{Queue, QueueOwnedT, QueueT};
+use virtio_vsock::packet::{VsockPacket, PKT_HEADER_SIZE};
+use vm_memory::bitmap::BitmapSlice;
+use vm_memory::GuestMemoryMmap;
+use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::virtio_mmio::{VirtioDevice, VirtioInterrupt};
+
+// --- Virtio Vsock ---
+//
+// Gives the guest AF_VSOCK stream sockets to the host, so the attestation
+// agent inside the CVM can reach the KBS through a host-side proxy. The host
+// end is a Unix socket path, following the Firecracker convention:
+// - a guest connection to host port P is connected to the Unix socket
+//   `<uds_path>_<P>`, where the proxy (e.g. `socat UNIX-LISTEN:<uds_path>_P
+//   TCP:<kbs>`) listens;
+// - a host process connects to `<uds_path>` and sends `CONNECT <port>\n`;
+//   the VMM opens a connection to that guest port and answers
+//   `OK <local port>\n`, after which the socket carries the stream.
+// A worker thread moves packets between the virtqueues and the sockets,
+// polling the queue notifications, the listener and every connection. Guest
+// data a host socket cannot take yet waits in its connection until the
+// socket is writable again; the credit the guest is given bounds that
+// backlog, so the worker never blocks on a slow host process.
+
+/// The CID the guest reaches the host at.
+pub const VSOCK_HOST_CID: u64 = 2;
+
+const RX_QUEUE: usize = 0;
+const TX_QUEUE: usize = 1;
+const QUEUE_MAX_SIZES: [u16; 3] = [256; 3];
+
+/// Packet header `type` and `op` values (virtio spec, 5.10.6).
+const VSOCK_TYPE_STREAM: u16 = 1;
+const VSOCK_OP_REQUEST: u16 = 1;
+const VSOCK_OP_RESPONSE: u16 = 2;
+const VSOCK_OP_RST: u16 = 3;
+const VSOCK_OP_SHUTDOWN: u16 = 4;
+const VSOCK_OP_RW: u16 = 5;
+const VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+/// `SHUTDOWN` flags: the sender will receive, send no more.
+const VSOCK_FLAGS_SHUTDOWN_RCV: u32 = 1;
+const VSOCK_FLAGS_SHUTDOWN_SEND: u32 = 2;
+
+/// Receive buffer advertised to the guest per connection: the most guest
+/// data that may wait for the host socket.
+const CONN_BUF_ALLOC: u32 = 256 * 1024;
+/// Send a credit update once this much guest data was forwarded unannounced.
+const CREDIT_UPDATE_THRESHOLD: u32 = CONN_BUF_ALLOC / 4;
+/// Largest payload of one packet.
+const MAX_PKT_DATA: u32 = 64 * 1024;
+/// Local ports of host-initiated connections start here.
+const HOST_PORT_START: u32 = 1 << 30;
+/// Longest `CONNECT <port>\n` line accepted from a host process.
+const MAX_CONNECT_LINE: usize = 32;
+
+/// epoll tokens of the worker's own fds; connections use their fd.
+const TOKEN_QUEUE: u64 = u64::MAX;
+const TOKEN_KILL: u64 = u64::MAX - 1;
+const TOKEN_LISTENER: u64 = u64::MAX - 2;
+
+/// The vsock device: the guest CID and the host Unix socket.
+pub struct Vsock {
+    guest_cid: u64,
+    uds_path: PathBuf,
+    listener: UnixListener,
+    queue_evt: EventFd,
+    worker: Option<(EventFd, JoinHandle<()>)>,
+}
+
+impl Vsock {
+    /// Listens on `uds_path` for host-initiated connections, replacing a
+    /// stale socket left there by an earlier run.
+    pub fn new(guest_cid: u64, uds_path: &Path) -> Result<Self> {
+        if let Ok(meta) = std::fs::symlink_metadata(uds_path) {
+            if !meta.file_type().is_socket() {
+                bail!("{} exists and is not a socket", uds_path.display());
+            }
+            std::fs::remove_file(uds_path).with_context(|| format!("Failed to remove {}", uds_path.display()))?;
+        }
+        let listener =
+            UnixListener::bind(uds_path).with_context(|| format!("Failed to listen on {}", uds_path.display()))?;
+        listener.set_nonblocking(true)?;
+        log::info!("vsock: guest CID {}, host socket {}", guest_cid, uds_path.display());
+        Ok(Vsock {
+            guest_cid,
+            uds_path: uds_path.to_path_buf(),
+            listener,
+            queue_evt: EventFd::new(libc::EFD_NONBLOCK)?,
+            worker: None,
+        })
+    }
+
+    fn stop_worker(&mut self) {
+        if let Some((kill_evt, worker)) = self.worker.take() {
+            if let Err(e) = kill_evt.write(1) {
+                log::error!("vsock: failed to stop the worker: {}", e);
+                return;
+            }
+            if worker.join().is_err() {
+                log::error!("vsock: worker thread panicked");
+            }
+        }
+    }
+}
+
+impl VirtioDevice for Vsock {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_VSOCK
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        &QUEUE_MAX_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    /// The config space is the guest CID, a little-endian u64.
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = self.guest_cid.to_le_bytes();
+        data.fill(0);
+        if let Some(bytes) = config.get(offset as usize..) {
+            let len = bytes.len().min(data.len());
+            data[..len].copy_from_slice(&bytes[..len]);
+        }
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap, queues: Vec<Queue>, interrupt: Arc<VirtioInterrupt>) -> Result<()> {
+        let kill_evt = EventFd::new(libc::EFD_NONBLOCK)?;
+        let mut worker = VsockWorker::new(
+            self.guest_cid,
+            self.uds_path.clone(),
+            mem,
+            queues,
+            interrupt,
+            self.listener.try_clone()?,
+            self.queue_evt.try_clone()?,
+            kill_evt.try_clone()?,
+        )?;
+        let handle = thread::Builder::new().name("vsock".to_string()).spawn(move || {
+            if let Err(e) = worker.run() {
+                log::error!("vsock: worker stopped: {:#}", e);
+            }
+        })?;
+        self.worker = Some((kill_evt, handle));
+        Ok(())
+    }
+
+    fn queue_notify(&mut self, _index: u32) {
+        if let Err(e) = self.queue_evt.write(1) {
+            log::error!("vsock: failed to signal the worker: {}", e);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.stop_worker();
+    }
+}
+
+impl Drop for Vsock {
+    fn drop(&mut self) {
+        self.stop_worker();
+    }
+}
+
+// --- Connections ---
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnState {
+    /// Host-initiated; the guest has not answered the REQUEST yet.
+    Connecting,
+    Established,
+    /// The host socket reached EOF and the guest was sent SHUTDOWN.
+    HostClosed,
+}
+
+/// Local (host) port and peer (guest) port.
+type ConnKey = (u32, u32);
+
+struct Connection {
+    stream: UnixStream,
+    state: ConnState,
+    /// The host socket may have data: set on EPOLLIN, cleared on EAGAIN.
+    readable: bool,
+    /// Bytes for the host socket that it has not taken yet; written out on
+    /// EPOLLOUT.
+    pending: VecDeque<u8>,
+    /// Leading bytes of `pending` that are the `OK <port>` reply rather
+    /// than guest data.
+    pending_reply: usize,
+    /// What to do once `pending` is written out, after a SHUTDOWN from the
+    /// guest: stop writing to the host (`Write`) or close (`Both`).
+    shutdown_after_flush: Option<Shutdown>,
+    /// Bytes sent to the guest, and what it reported consuming of them
+    /// out of its `peer_buf_alloc`.
+    rx_cnt: Wrapping<u32>,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: Wrapping<u32>,
+    /// Guest bytes written to the host socket, and the count last told to
+    /// the guest.
+    fwd_cnt: Wrapping<u32>,
+    announced_fwd_cnt: Wrapping<u32>,
+}
+
+impl Connection {
+    fn new(stream: UnixStream, state: ConnState) -> Self {
+        Connection {
+            stream,
+            state,
+            readable: state == ConnState::Established,
+            pending: VecDeque::new(),
+            pending_reply: 0,
+            shutdown_after_flush: None,
+            rx_cnt: Wrapping(0),
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: Wrapping(0),
+            fwd_cnt: Wrapping(0),
+            announced_fwd_cnt: Wrapping(0),
+        }
+    }
+
+    /// How much more the guest can take before it reports progress.
+    fn peer_credit(&self) -> u32 {
+        self.peer_buf_alloc.saturating_sub((self.rx_cnt - self.peer_fwd_cnt).0)
+    }
+
+    fn update_peer_credit<B: BitmapSlice>(&mut self, pkt: &VsockPacket<B>) {
+        self.peer_buf_alloc = pkt.buf_alloc();
+        self.peer_fwd_cnt = Wrapping(pkt.fwd_cnt());
+    }
+
+    /// Writes as much of `pending` as the host socket takes without
+    /// blocking. Returns how many bytes of guest data went out.
+    fn flush_pending(&mut self) -> io::Result<usize> {
+        let mut written = 0;
+        while !self.pending.is_empty() {
+            let (front, _) = self.pending.as_slices();
+            match self.stream.write(front) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(len) => {
+                    self.pending.drain(..len);
+                    written += len;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let reply = written.min(self.pending_reply);
+        self.pending_reply -= reply;
+        Ok(written - reply)
+    }
+}
+
+/// A host process that connected to the Unix socket and has not finished
+/// its `CONNECT <port>` line.
+struct Handshake {
+    stream: UnixStream,
+    line: Vec<u8>,
+}
+
+/// A header-only packet waiting for a guest RX buffer.
+struct ControlPacket {
+    key: ConnKey,
+    op: u16,
+    flags: u32,
+}
+
+// --- Worker ---
+
+struct VsockWorker {
+    guest_cid: u64,
+    uds_path: PathBuf,
+    mem: GuestMemoryMmap,
+    queues: Vec<Queue>,
+    interrupt: Arc<VirtioInterrupt>,
+    listener: UnixListener,
+    queue_evt: EventFd,
+    kill_evt: EventFd,
+    epoll: Epoll,
+    conns: HashMap<ConnKey, Connection>,
+    /// Which connection or handshake a polled fd belongs to.
+    conn_fds: HashMap<RawFd, ConnKey>,
+    handshakes: HashMap<RawFd, Handshake>,
+    control: VecDeque<ControlPacket>,
+    next_host_port: u32,
+}
+
+impl VsockWorker {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        guest_cid: u64,
+        uds_path: PathBuf,
+        mem: GuestMemoryMmap,
+        queues: Vec<Queue>,
+        interrupt: Arc<VirtioInterrupt>,
+        listener: UnixListener,
+        queue_evt: EventFd,
+        kill_evt: EventFd,
+    ) -> Result<Self> {
+        let epoll = Epoll::new()?;
+        for (fd, token) in [
+            (queue_evt.as_raw_fd(), TOKEN_QUEUE),
+            (kill_evt.as_raw_fd(), TOKEN_KILL),
+            (listener.as_raw_fd(), TOKEN_LISTENER),
+        ] {
+            epoll.ctl(ControlOperation::Add, fd, EpollEvent::new(EventSet::IN, token))?;
+        }
+        Ok(VsockWorker {
+            guest_cid,
+            uds_path,
+            mem,
+            queues,
+            interrupt,
+            listener,
+            queue_evt,
+            kill_evt,
+            epoll,
+            conns: HashMap::new(),
+            conn_fds: HashMap::new(),
+            handshakes: HashMap::new(),
+            control: VecDeque::new(),
+            next_host_port: HOST_PORT_START,
+        })
+    }
+
+    fn run(&mut self) -> Result<()> {
+        let mut events = vec![EpollEvent::default(); 64];
+        while self.poll(&mut events, -1)? {
+            self.process_queues()?;
+        }
+        Ok(())
+    }
+
+    /// Waits up to `timeout_ms` for events and handles them. Returns false
+    /// once the worker is asked to stop.
+    fn poll(&mut self, events: &mut [EpollEvent], timeout_ms: i32) -> Result<bool> {
+        let count = match self.epoll.wait(timeout_ms, events) {
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(true),
+            Err(e) => return Err(e).context("epoll_wait failed"),
+        };
+        for event in &events[..count] {
+            match event.data() {
+                TOKEN_KILL => {
+                    let _ = self.kill_evt.read();
+                    return Ok(false);
+                }
+                // Buffers were made available on some queue; both are
+                // processed afterwards.
+                TOKEN_QUEUE => {
+                    let _ = self.queue_evt.read();
+                }
+                TOKEN_LISTENER => self.accept_host_connections(),
+                fd => self.socket_ready(fd as RawFd, event.event_set()),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Moves everything that can move: guest TX packets to the host, then
+    /// pending control packets and host data to the guest.
+    fn process_queues(&mut self) -> Result<()> {
+        let used_tx = self.process_tx()?;
+        let used_rx = self.process_rx()?;
+        let mut notify = false;
+        for (used, index) in [(used_tx, TX_QUEUE), (used_rx, RX_QUEUE)] {
+            if used && self.queues[index].needs_notification(&self.mem)? {
+                notify = true;
+            }
+        }
+        if notify {
+            self.interrupt.signal_used_queue()?;
+        }
+        Ok(())
+    }
+
+    fn process_tx(&mut self) -> Result<bool> {
+        let mem = self.mem.clone();
+        let mut used = false;
+        while let Some(mut chain) = self.queues[TX_QUEUE].pop_descriptor_chain(&mem) {
+            let head = chain.head_index();
+            match VsockPacket::from_tx_virtq_chain(&mem, &mut chain, MAX_PKT_DATA) {
+                Ok(pkt) => self.handle_tx_packet(&pkt),
+                Err(e) => log::warn!("vsock: dropping malformed TX packet: {:?}", e),
+            }
+            self.queues[TX_QUEUE].add_used(&mem, head, 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn handle_tx_packet<B: BitmapSlice>(&mut self, pkt: &VsockPacket<B>) {
+        if pkt.src_cid() != self.guest_cid || pkt.dst_cid() != VSOCK_HOST_CID {
+            log::debug!("vsock: dropping packet from CID {} to CID {}", pkt.src_cid(), pkt.dst_cid());
+            return;
+        }
+        let key = (pkt.dst_port(), pkt.src_port());
+        if pkt.type_() != VSOCK_TYPE_STREAM {
+            self.queue_control(key, VSOCK_OP_RST, 0);
+            return;
+        }
+        if pkt.op() == VSOCK_OP_REQUEST {
+            self.connect_to_host(key, pkt);
+            return;
+        }
+        let Some(conn) = self.conns.get_mut(&key) else {
+            // Nothing to answer an RST for an unknown connection with.
+            if pkt.op() != VSOCK_OP_RST {
+                self.queue_control(key, VSOCK_OP_RST, 0);
+            }
+            return;
+        };
+        conn.update_peer_credit(pkt);
+
+        match (pkt.op(), conn.state) {
+            (VSOCK_OP_RESPONSE, ConnState::Connecting) => {
+                conn.state = ConnState::Established;
+                conn.readable = true;
+                let reply = format!("OK {}\n", key.0);
+                conn.pending_reply = reply.len();
+                conn.pending.extend(reply.as_bytes());
+                self.forward_to_host(key);
+            }
+            (VSOCK_OP_RW, ConnState::Established | ConnState::HostClosed) => {
+                let len = pkt.data_slice().map(|slice| (pkt.len() as usize).min(slice.len())).unwrap_or(0);
+                // The guest may only send as much as the credit it was given.
+                if conn.pending.len() - conn.pending_reply + len > CONN_BUF_ALLOC as usize {
+                    log::warn!("vsock: guest port {} overran its credit", key.1);
+                    self.reset_connection(key);
+                    return;
+                }
+                if let Some(slice) = pkt.data_slice() {
+                    let mut data = vec![0u8; len];
+                    slice.copy_to(&mut data[..]);
+                    conn.pending.extend(data);
+                }
+                self.forward_to_host(key);
+            }
+            (VSOCK_OP_SHUTDOWN, _) => {
+                let flags = pkt.flags() & (VSOCK_FLAGS_SHUTDOWN_RCV | VSOCK_FLAGS_SHUTDOWN_SEND);
+                if flags == VSOCK_FLAGS_SHUTDOWN_RCV | VSOCK_FLAGS_SHUTDOWN_SEND {
+                    conn.shutdown_after_flush = Some(Shutdown::Both);
+                } else if flags & VSOCK_FLAGS_SHUTDOWN_SEND != 0 {
+                    conn.shutdown_after_flush = Some(Shutdown::Write);
+                }
+                // What the guest sent before still goes to the host first.
+                self.forward_to_host(key);
+            }
+            (VSOCK_OP_RST, _) => self.remove_connection(key),
+            // The new credit is already recorded.
+            (VSOCK_OP_CREDIT_UPDATE, _) => {}
+            (VSOCK_OP_CREDIT_REQUEST, _) => self.queue_credit_update(key),
+            (op, state) => {
+                log::debug!("vsock: unexpected op {} on a {:?} connection", op, state);
+                self.reset_connection(key);
+            }
+        }
+    }
+
+    /// Writes what the host socket takes of the connection's pending data,
+    /// tells the guest about the room it freed, and carries out a SHUTDOWN
+    /// once nothing is left.
+    fn forward_to_host(&mut self, key: ConnKey) {
+        let Some(conn) = self.conns.get_mut(&key) else {
+            return;
+        };
+        match conn.flush_pending() {
+            Ok(written) => conn.fwd_cnt += written as u32,
+            Err(e) => {
+                log::warn!("vsock: write to host port {} failed: {}", key.0, e);
+                self.reset_connection(key);
+                return;
+            }
+        }
+        if conn.pending.is_empty() {
+            match conn.shutdown_after_flush.take() {
+                Some(Shutdown::Write) => {
+                    let _ = conn.stream.shutdown(Shutdown::Write);
+                }
+                Some(_) => {
+                    self.reset_connection(key);
+                    return;
+                }
+                None => {}
+            }
+        }
+        if (conn.fwd_cnt - conn.announced_fwd_cnt).0 >= CREDIT_UPDATE_THRESHOLD {
+            self.queue_credit_update(key);
+        }
+    }
+
+    /// The guest connects to host port `key.0`: connect to `<uds_path>_<port>`.
+    fn connect_to_host<B: BitmapSlice>(&mut self, key: ConnKey, pkt: &VsockPacket<B>) {
+        if self.conns.contains_key(&key) {
+            self.reset_connection(key);
+            return;
+        }
+        let path = format!("{}_{}", self.uds_path.display(), key.0);
+        let stream = match UnixStream::connect(&path).and_then(|s| s.set_nonblocking(true).map(|()| s)) {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("vsock: guest port {} could not reach {}: {}", key.1, path, e);
+                self.queue_control(key, VSOCK_OP_RST, 0);
+                return;
+            }
+        };
+        let mut conn = Connection::new(stream, ConnState::Established);
+        conn.update_peer_credit(pkt);
+        match self.add_connection(key, conn) {
+            Ok(()) => {
+                log::info!("vsock: guest port {} connected to {}", key.1, path);
+                self.queue_control(key, VSOCK_OP_RESPONSE, 0);
+            }
+            Err(e) => {
+                log::error!("vsock: failed to poll {}: {}", path, e);
+                self.queue_control(key, VSOCK_OP_RST, 0);
+            }
+        }
+    }
+
+    fn add_connection(&mut self, key: ConnKey, conn: Connection) -> io::Result<()> {
+        let fd = conn.stream.as_raw_fd();
+        // Edge-triggered: `readable` remembers pending data while the guest
+        // has no room for it, and `pending` what the host socket has no room
+        // for until the next EPOLLOUT.
+        let events = EventSet::IN | EventSet::OUT | EventSet::READ_HANG_UP | EventSet::EDGE_TRIGGERED;
+        self.epoll.ctl(ControlOperation::Add, fd, EpollEvent::new(events, fd as u64))?;
+        self.conn_fds.insert(fd, key);
+        self.conns.insert(key, conn);
+        Ok(())
+    }
+
+    fn remove_connection(&mut self, key: ConnKey) {
+        if let Some(conn) = self.conns.remove(&key) {
+            let fd = conn.stream.as_raw_fd();
+            let _ = self.epoll.ctl(ControlOperation::Delete, fd, EpollEvent::default());
+            self.conn_fds.remove(&fd);
+            self.control.retain(|packet| packet.key != key);
+        }
+    }
+
+    /// Drops the connection and tells the guest.
+    fn reset_connection(&mut self, key: ConnKey) {
+        self.remove_connection(key);
+        self.queue_control(key, VSOCK_OP_RST, 0);
+    }
+
+    fn queue_control(&mut self, key: ConnKey, op: u16, flags: u32) {
+        self.control.push_back(ControlPacket { key, op, flags });
+    }
+
+    /// Queues a CREDIT_UPDATE unless one is already waiting; it carries the
+    /// counts current when it is sent.
+    fn queue_credit_update(&mut self, key: ConnKey) {
+        if !self.control.iter().any(|packet| packet.key == key && packet.op == VSOCK_OP_CREDIT_UPDATE) {
+            self.queue_control(key, VSOCK_OP_CREDIT_UPDATE, 0);
+        }
+    }
+
+    fn accept_host_connections(&mut self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    log::warn!("vsock: accept on {} failed: {}", self.uds_path.display(), e);
+                    return;
+                }
+            };
+            let fd = stream.as_raw_fd();
+            let events = EventSet::IN | EventSet::READ_HANG_UP | EventSet::EDGE_TRIGGERED;
+            let registered = stream
+                .set_nonblocking(true)
+                .and_then(|()| self.epoll.ctl(ControlOperation::Add, fd, EpollEvent::new(events, fd as u64)));
+            match registered {
+                Ok(()) => {
+                    self.handshakes.insert(fd, Handshake { stream, line: Vec::new() });
+                    self.read_handshake(fd);
+                }
+                Err(e) => log::warn!("vsock: dropping host connection: {}", e),
+            }
+        }
+    }
+
+    fn socket_ready(&mut self, fd: RawFd, events: EventSet) {
+        if self.handshakes.contains_key(&fd) {
+            self.read_handshake(fd);
+            return;
+        }
+        let Some(&key) = self.conn_fds.get(&fd) else {
+            return;
+        };
+        if events.intersects(EventSet::IN | EventSet::READ_HANG_UP | EventSet::HANG_UP | EventSet::ERROR) {
+            if let Some(conn) = self.conns.get_mut(&key) {
+                conn.readable = true;
+            }
+        }
+        if events.contains(EventSet::OUT) {
+            self.forward_to_host(key);
+        }
+    }
+
+    /// Reads the `CONNECT <port>` line a byte at a time, so nothing sent
+    /// after it is consumed, then asks the guest to accept the connection.
+    fn read_handshake(&mut self, fd: RawFd) {
+        let Some(handshake) = self.handshakes.get_mut(&fd) else {
+            return;
+        };
+        let mut byte = [0u8; 1];
+        let port = loop {
+            match handshake.stream.read(&mut byte) {
+                Ok(1) if byte[0] == b'\n' => break parse_connect(&handshake.line),
+                Ok(1) if handshake.line.len() < MAX_CONNECT_LINE => handshake.line.push(byte[0]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // EOF, an error or an over-long line.
+                _ => break None,
+            }
+        };
+
+        let Some(handshake) = self.handshakes.remove(&fd) else {
+            return;
+        };
+        let _ = self.epoll.ctl(ControlOperation::Delete, fd, EpollEvent::default());
+        let Some(peer_port) = port else {
+            log::warn!("vsock: host connection sent an invalid CONNECT line");
+            return;
+        };
+        let key = (self.next_host_port, peer_port);
+        self.next_host_port = self.next_host_port.wrapping_add(1).max(HOST_PORT_START);
+        match self.add_connection(key, Connection::new(handshake.stream, ConnState::Connecting)) {
+            Ok(()) => {
+                log::info!("vsock: host connecting to guest port {} from port {}", peer_port, key.0);
+                self.queue_control(key, VSOCK_OP_REQUEST, 0);
+            }
+            Err(e) => log::warn!("vsock: failed to poll host connection: {}", e),
+        }
+    }
+
+    fn process_rx(&mut self) -> Result<bool> {
+        let mem = self.mem.clone();
+        let mut used = false;
+        loop {
+            let control = self.control.front().map(|packet| (packet.key, packet.op, packet.flags));
+            let data_key = match control {
+                Some(_) => None,
+                None => self
+                    .conns
+                    .iter()
+                    .find(|(_, conn)| {
+                        conn.readable && conn.state == ConnState::Established && conn.peer_credit() > 0
+                    })
+                    .map(|(key, _)| *key),
+            };
+            if control.is_none() && data_key.is_none() {
+                return Ok(used);
+            }
+
+            let Some(mut chain) = self.queues[RX_QUEUE].pop_descriptor_chain(&mem) else {
+                // Out of guest buffers; the next RX notification resumes.
+                return Ok(used);
+            };
+            let head = chain.head_index();
+            let mut pkt = match VsockPacket::from_rx_virtq_chain(&mem, &mut chain, MAX_PKT_DATA) {
+                Ok(pkt) => pkt,
+                Err(e) => {
+                    log::warn!("vsock: unusable RX buffer: {:?}", e);
+                    self.queues[RX_QUEUE].add_used(&mem, head, 0)?;
+                    used = true;
+                    continue;
+                }
+            };
+            pkt.set_header_from_raw(&[0u8; PKT_HEADER_SIZE]).map_err(|e| anyhow::anyhow!("{:?}", e))?;
+
+            let len = match (control, data_key) {
+                (Some((key, op, flags)), _) => {
+                    self.control.pop_front();
+                    self.fill_header(&mut pkt, key, op);
+                    pkt.set_flags(flags);
+                    PKT_HEADER_SIZE as u32
+                }
+                (None, Some(key)) if pkt.data_slice().is_none_or(|slice| slice.is_empty()) => {
+                    // No room for data; reading into it would look like EOF.
+                    log::warn!("vsock: unusable RX buffer for host port {}: no data space", key.0);
+                    self.queues[RX_QUEUE].add_used(&mem, head, 0)?;
+                    used = true;
+                    continue;
+                }
+                (None, Some(key)) => match self.read_host_data(&mut pkt, key) {
+                    Some(len) => len,
+                    None => {
+                        // Nothing to send after all; give the buffer back.
+                        self.queues[RX_QUEUE].go_to_previous_position();
+                        continue;
+                    }
+                },
+                (None, None) => unreachable!(),
+            };
+            self.queues[RX_QUEUE].add_used(&mem, head, len)?;
+            used = true;
+        }
+    }
+
+    fn fill_header<B: BitmapSlice>(&mut self, pkt: &mut VsockPacket<B>, key: ConnKey, op: u16) {
+        let fwd_cnt = match self.conns.get_mut(&key) {
+            Some(conn) => {
+                conn.announced_fwd_cnt = conn.fwd_cnt;
+                conn.fwd_cnt.0
+            }
+            None => 0,
+        };
+        pkt.set_src_cid(VSOCK_HOST_CID)
+            .set_dst_cid(self.guest_cid)
+            .set_src_port(key.0)
+            .set_dst_port(key.1)
+            .set_type(VSOCK_TYPE_STREAM)
+            .set_op(op)
+            .set_buf_alloc(CONN_BUF_ALLOC)
+            .set_fwd_cnt(fwd_cnt);
+    }
+
+    /// Fills `pkt` with data from the host socket, or with a SHUTDOWN on
+    /// EOF. Returns the used length, or `None` if there was nothing to read.
+    fn read_host_data<B: BitmapSlice>(&mut self, pkt: &mut VsockPacket<B>, key: ConnKey) -> Option<u32> {
+        let conn = self.conns.get_mut(&key)?;
+        let room = pkt.data_slice().map(|slice| slice.len()).unwrap_or(0);
+        let mut data = vec![0u8; room.min(conn.peer_credit() as usize)];
+        let read = match conn.stream.read(&mut data) {
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted => {
+                conn.readable = e.kind() == io::ErrorKind::Interrupted;
+                return None;
+            }
+            Err(e) => {
+                log::warn!("vsock: read from host port {} failed: {}", key.0, e);
+                0
+            }
+        };
+        if read == 0 {
+            // The host closed its end: no more data either way.
+            conn.state = ConnState::HostClosed;
+            conn.readable = false;
+            self.fill_header(pkt, key, VSOCK_OP_SHUTDOWN);
+            pkt.set_flags(VSOCK_FLAGS_SHUTDOWN_RCV | VSOCK_FLAGS_SHUTDOWN_SEND);
+            return Some(PKT_HEADER_SIZE as u32);
+        }
+        conn.rx_cnt += read as u32;
+        if let Some(slice) = pkt.data_slice() {
+            slice.copy_from(&data[..read]);
+        }
+        self.fill_header(pkt, key, VSOCK_OP_RW);
+        pkt.set_len(read as u32);
+        Some((PKT_HEADER_SIZE + read) as u32)
+    }
+}
+
+/// Parses `CONNECT <port>`.
+fn parse_connect(line: &[u8]) -> Option<u32> {
+    let line = std::str::from_utf8(line).ok()?.trim_end_matches('\r');
+    line.strip_prefix("CONNECT ")?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::{Duration, Instant};
+    use virtio_bindings::virtio_ring::{VRING_DESC_F_NEXT, VRING_DESC_F_WRITE};
+    use vm_memory::{Bytes, GuestAddress};
+
+    const GUEST_CID: u64 = 3;
+    const QUEUE_SIZE: u16 = 16;
+    const RX_BUFFERS: u16 = 8;
+    const RX_DATA_SIZE: u32 = 4096;
+
+    /// A split virtqueue laid out in guest memory, driven as a guest driver
+    /// would: chains are made available, used entries collected.
+    struct GuestQueue {
+        base: u64,
+        next_desc: u16,
+        avail_idx: u16,
+        used_seen: u16,
+    }
+
+    impl GuestQueue {
+        fn new(base: u64) -> Self {
+            GuestQueue { base, next_desc: 0, avail_idx: 0, used_seen: 0 }
+        }
+
+        fn desc_table(&self) -> u64 {
+            self.base
+        }
+
+        fn avail_ring(&self) -> u64 {
+            self.base + 0x1000
+        }
+
+        fn used_ring(&self) -> u64 {
+            self.base + 0x2000
+        }
+
+        /// The device side of the queue, as the transport would hand it over.
+        fn queue(&self) -> Queue {
+            let mut queue = Queue::new(QUEUE_SIZE).unwrap();
+            queue.set_size(QUEUE_SIZE);
+            queue.set_desc_table_address(Some(self.desc_table() as u32), Some(0));
+            queue.set_avail_ring_address(Some(self.avail_ring() as u32), Some(0));
+            queue.set_used_ring_address(Some(self.used_ring() as u32), Some(0));
+            queue.set_ready(true);
+            queue
+        }
+
+        /// Makes a chain of `(address, length, flags)` buffers available.
+        fn add(&mut self, mem: &GuestMemoryMmap, buffers: &[(u64, u32, u32)]) {
+            let head = self.next_desc;
+            for (i, &(addr, len, flags)) in buffers.iter().enumerate() {
+                let index = self.next_desc;
+                self.next_desc = (self.next_desc + 1) % QUEUE_SIZE;
+                let flags = if i + 1 < buffers.len() { flags | VRING_DESC_F_NEXT } else { flags };
+                let desc = self.desc_table() + 16 * u64::from(index);
+                mem.write_obj(addr, GuestAddress(desc)).unwrap();
+                mem.write_obj(len, GuestAddress(desc + 8)).unwrap();
+                mem.write_obj(flags as u16, GuestAddress(desc + 12)).unwrap();
+                mem.write_obj(self.next_desc, GuestAddress(desc + 14)).unwrap();
+            }
+            let slot = self.avail_ring() + 4 + 2 * u64::from(self.avail_idx % QUEUE_SIZE);
+            mem.write_obj(head, GuestAddress(slot)).unwrap();
+            self.avail_idx = self.avail_idx.wrapping_add(1);
+            mem.write_obj(self.avail_idx, GuestAddress(self.avail_ring() + 2)).unwrap();
+        }
+
+        /// Makes an RX buffer available: a header descriptor followed by a
+        /// data one.
+        fn add_rx_buffer(&mut self, mem: &GuestMemoryMmap, header: u64, data: u64) {
+            let header = (header, PKT_HEADER_SIZE as u32, VRING_DESC_F_WRITE);
+            self.add(mem, &[header, (data, RX_DATA_SIZE, VRING_DESC_F_WRITE)]);
+        }
+
+        /// The next used chain: its head descriptor and the length written.
+        fn next_used(&mut self, mem: &GuestMemoryMmap) -> Option<(u16, u32)> {
+            let used_idx: u16 = mem.read_obj(GuestAddress(self.used_ring() + 2)).unwrap();
+            if used_idx == self.used_seen {
+                return None;
+            }
+            let entry = self.used_ring() + 4 + 8 * u64::from(self.used_seen % QUEUE_SIZE);
+            self.used_seen = self.used_seen.wrapping_add(1);
+            let id: u32 = mem.read_obj(GuestAddress(entry)).unwrap();
+            Some((id as u16, mem.read_obj(GuestAddress(entry + 4)).unwrap()))
+        }
+
+        /// Shrinks the data descriptor of the next chain the device takes
+        /// to zero bytes; returns that chain's head.
+        fn empty_next_rx_buffer(&self, mem: &GuestMemoryMmap) -> u16 {
+            let slot = self.avail_ring() + 4 + 2 * u64::from(self.used_seen % QUEUE_SIZE);
+            let head: u16 = mem.read_obj(GuestAddress(slot)).unwrap();
+            let data: u16 = mem.read_obj(GuestAddress(self.desc_table() + 16 * u64::from(head) + 14)).unwrap();
+            mem.write_obj(0u32, GuestAddress(self.desc_table() + 16 * u64::from(data) + 8)).unwrap();
+            head
+        }
+
+        /// The buffer address of descriptor `index`.
+        fn buffer(&self, mem: &GuestMemoryMmap, index: u16) -> u64 {
+            mem.read_obj(GuestAddress(self.desc_table() + 16 * u64::from(index))).unwrap()
+        }
+    }
+
+    /// A vsock packet header (virtio spec, 5.10.6).
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    struct Header {
+        src_cid: u64,
+        dst_cid: u64,
+        src_port: u32,
+        dst_port: u32,
+        len: u32,
+        type_: u16,
+        op: u16,
+        flags: u32,
+        buf_alloc: u32,
+        fwd_cnt: u32,
+    }
+
+    impl Header {
+        /// A guest packet from `guest_port` to `host_port`.
+        fn from_guest(guest_port: u32, host_port: u32, op: u16) -> Self {
+            Header {
+                src_cid: GUEST_CID,
+                dst_cid: VSOCK_HOST_CID,
+                src_port: guest_port,
+                dst_port: host_port,
+                type_: VSOCK_TYPE_STREAM,
+                op,
+                buf_alloc: CONN_BUF_ALLOC,
+                ..Header::default()
+            }
+        }
+
+        fn to_bytes(self) -> [u8; PKT_HEADER_SIZE] {
+            let mut bytes = [0u8; PKT_HEADER_SIZE];
+            bytes[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+            bytes[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+            bytes[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+            bytes[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+            bytes[24..28].copy_from_slice(&self.len.to_le_bytes());
+            bytes[28..30].copy_from_slice(&self.type_.to_le_bytes());
+            bytes[30..32].copy_from_slice(&self.op.to_le_bytes());
+            bytes[32..36].copy_from_slice(&self.flags.to_le_bytes());
+            bytes[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+            bytes[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+            bytes
+        }
+
+        fn from_bytes(bytes: &[u8; PKT_HEADER_SIZE]) -> Self {
+            let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+            let u16_at = |at: usize| u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap());
+            Header {
+                src_cid: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+                dst_cid: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+                src_port: u32_at(16),
+                dst_port: u32_at(20),
+                len: u32_at(24),
+                type_: u16_at(28),
+                op: u16_at(30),
+                flags: u32_at(32),
+                buf_alloc: u32_at(36),
+                fwd_cnt: u32_at(40),
+            }
+        }
+    }
+
+    /// A worker with its queues in guest memory and its host socket in a
+    /// fresh directory, stepped by hand instead of on its own thread.
+    struct TestVsock {
+        mem: GuestMemoryMmap,
+        worker: VsockWorker,
+        rx: GuestQueue,
+        tx: GuestQueue,
+        tx_buffers: u64,
+        events: Vec<EpollEvent>,
+        dir: PathBuf,
+        uds_path: PathBuf,
+    }
+
+    impl TestVsock {
+        fn new() -> Self {
+            static NEXT: AtomicU32 = AtomicU32::new(0);
+            let dir = std::env::temp_dir()
+                .join(format!("vsock-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::SeqCst)));
+            std::fs::create_dir_all(&dir).unwrap();
+            let uds_path = dir.join("v.sock");
+            let listener = UnixListener::bind(&uds_path).unwrap();
+            listener.set_nonblocking(true).unwrap();
+
+            let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x100_0000)]).unwrap();
+            let mut rx = GuestQueue::new(0x1_0000);
+            let tx = GuestQueue::new(0x2_0000);
+            for i in 0..u64::from(RX_BUFFERS) {
+                let header = 0x10_0000 + i * 0x2000;
+                rx.add_rx_buffer(&mem, header, header + 0x1000);
+            }
+            let worker = VsockWorker::new(
+                GUEST_CID,
+                uds_path.clone(),
+                mem.clone(),
+                vec![rx.queue(), tx.queue(), GuestQueue::new(0x3_0000).queue()],
+                Arc::new(VirtioInterrupt::new(EventFd::new(libc::EFD_NONBLOCK).unwrap())),
+                listener,
+                EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+                EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            )
+            .unwrap();
+            TestVsock {
+                mem,
+                worker,
+                rx,
+                tx,
+                tx_buffers: 0x20_0000,
+                events: vec![EpollEvent::default(); 16],
+                dir,
+                uds_path,
+            }
+        }
+
+        /// Listens where a guest connection to `port` is forwarded.
+        fn listen(&self, port: u32) -> UnixListener {
+            UnixListener::bind(format!("{}_{}", self.uds_path.display(), port)).unwrap()
+        }
+
+        /// Puts a guest packet on the TX queue and lets the worker take it.
+        fn send(&mut self, header: Header, data: &[u8]) {
+            let addr = self.tx_buffers;
+            self.tx_buffers = if self.tx_buffers >= 0xc0_0000 { 0x20_0000 } else { self.tx_buffers + 0x2_0000 };
+            let header = Header { len: data.len() as u32, ..header };
+            self.mem.write_slice(&header.to_bytes(), GuestAddress(addr)).unwrap();
+            if data.is_empty() {
+                self.tx.add(&self.mem, &[(addr, PKT_HEADER_SIZE as u32, 0)]);
+            } else {
+                self.mem.write_slice(data, GuestAddress(addr + 0x1000)).unwrap();
+                self.tx.add(&self.mem, &[(addr, PKT_HEADER_SIZE as u32, 0), (addr + 0x1000, data.len() as u32, 0)]);
+            }
+            assert!(self.worker.process_tx().unwrap());
+            assert_eq!(self.tx.next_used(&self.mem).map(|(_, len)| len), Some(0));
+        }
+
+        /// Polls the sockets once and returns the next packet the worker
+        /// gives the guest, recycling its RX buffer.
+        fn try_recv(&mut self, timeout: Duration) -> Option<(Header, Vec<u8>)> {
+            let deadline = Instant::now() + timeout;
+            loop {
+                assert!(self.worker.poll(&mut self.events, 10).unwrap());
+                self.worker.process_rx().unwrap();
+                if let Some((head, len)) = self.rx.next_used(&self.mem) {
+                    let header_addr = self.rx.buffer(&self.mem, head);
+                    let data_addr = self.rx.buffer(&self.mem, (head + 1) % QUEUE_SIZE);
+                    let mut bytes = [0u8; PKT_HEADER_SIZE];
+                    self.mem.read_slice(&mut bytes, GuestAddress(header_addr)).unwrap();
+                    let header = Header::from_bytes(&bytes);
+                    assert_eq!(len, PKT_HEADER_SIZE as u32 + header.len);
+                    let mut data = vec![0u8; header.len as usize];
+                    self.mem.read_slice(&mut data, GuestAddress(data_addr)).unwrap();
+                    self.rx.add_rx_buffer(&self.mem, header_addr, data_addr);
+                    return Some((header, data));
+                }
+                if Instant::now() >= deadline {
+                    return None;
+                }
+            }
+        }
+
+        fn recv(&mut self) -> (Header, Vec<u8>) {
+            self.try_recv(Duration::from_secs(5)).expect("no packet for the guest")
+        }
+
+        /// Receives the next packet, which must be `op` to `guest_port`.
+        fn expect(&mut self, guest_port: u32, op: u16) -> (Header, Vec<u8>) {
+            let (header, data) = self.recv();
+            assert_eq!((header.op, header.dst_port), (op, guest_port), "{:?}", header);
+            assert_eq!((header.src_cid, header.dst_cid, header.type_), (VSOCK_HOST_CID, GUEST_CID, VSOCK_TYPE_STREAM));
+            (header, data)
+        }
+
+        /// Connects guest port `guest_port` to the host process listening
+        /// on `port`.
+        fn connect(&mut self, guest_port: u32, port: u32, listener: &UnixListener) -> UnixStream {
+            self.send(Header::from_guest(guest_port, port, VSOCK_OP_REQUEST), &[]);
+            let (header, _) = self.expect(guest_port, VSOCK_OP_RESPONSE);
+            assert_eq!((header.src_port, header.buf_alloc, header.fwd_cnt), (port, CONN_BUF_ALLOC, 0));
+            let (stream, _) = listener.accept().unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            stream
+        }
+
+        fn connection(&self, key: ConnKey) -> &Connection {
+            self.worker.conns.get(&key).expect("no such connection")
+        }
+    }
+
+    impl Drop for TestVsock {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn guest_connects_to_a_host_port() {
+        let mut vsock = TestVsock::new();
+        let listener = vsock.listen(1234);
+        let mut host = vsock.connect(5000, 1234, &listener);
+
+        let guest = Header::from_guest(5000, 1234, VSOCK_OP_RW);
+        vsock.send(guest, b"hello");
+        let mut buf = [0u8; 5];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        host.write_all(b"world").unwrap();
+        let (header, data) = vsock.expect(5000, VSOCK_OP_RW);
+        assert_eq!((header.src_port, header.fwd_cnt, &data[..]), (1234, 5, &b"world"[..]));
+
+        // The host hanging up shuts the stream down both ways...
+        drop(host);
+        let (header, _) = vsock.expect(5000, VSOCK_OP_SHUTDOWN);
+        assert_eq!(header.flags, VSOCK_FLAGS_SHUTDOWN_RCV | VSOCK_FLAGS_SHUTDOWN_SEND);
+        assert_eq!(vsock.connection((1234, 5000)).state, ConnState::HostClosed);
+
+        // ...and the guest's RST ends the connection without a reply.
+        vsock.send(Header::from_guest(5000, 1234, VSOCK_OP_RST), &[]);
+        assert!(vsock.worker.conns.is_empty());
+        assert!(vsock.try_recv(Duration::from_millis(50)).is_none());
+    }
+
+    #[test]
+    fn unreachable_ports_and_unknown_connections_are_reset() {
+        let mut vsock = TestVsock::new();
+
+        // Nothing listens for port 9999.
+        vsock.send(Header::from_guest(5000, 9999, VSOCK_OP_REQUEST), &[]);
+        vsock.expect(5000, VSOCK_OP_RST);
+
+        vsock.send(Header::from_guest(5001, 1234, VSOCK_OP_RW), b"x");
+        vsock.expect(5001, VSOCK_OP_RST);
+
+        // Packets for another CID are dropped without an answer.
+        vsock.send(Header { dst_cid: 7, ..Header::from_guest(5002, 1234, VSOCK_OP_REQUEST) }, &[]);
+        assert!(vsock.try_recv(Duration::from_millis(50)).is_none());
+        assert!(vsock.worker.conns.is_empty());
+    }
+
+    #[test]
+    fn host_data_is_limited_by_the_guest_credit() {
+        let mut vsock = TestVsock::new();
+        let listener = vsock.listen(1234);
+        let mut host = vsock.connect(5000, 1234, &listener);
+
+        // The guest has room for 100 bytes.
+        let guest = Header::from_guest(5000, 1234, VSOCK_OP_CREDIT_UPDATE);
+        vsock.send(Header { buf_alloc: 100, ..guest }, &[]);
+        host.write_all(&[7u8; 300]).unwrap();
+        let (_, data) = vsock.expect(5000, VSOCK_OP_RW);
+        assert_eq!(data.len(), 100);
+        assert!(vsock.try_recv(Duration::from_millis(50)).is_none(), "sent beyond the guest's credit");
+        assert_eq!(vsock.connection((1234, 5000)).peer_credit(), 0);
+
+        // Consuming 100 bytes and growing the buffer frees room for the rest.
+        vsock.send(Header { buf_alloc: 1000, fwd_cnt: 100, ..guest }, &[]);
+        let (_, data) = vsock.expect(5000, VSOCK_OP_RW);
+        assert_eq!(data.len(), 200);
+
+        // A host write larger than one RX buffer is split.
+        let big: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        vsock.send(Header { buf_alloc: CONN_BUF_ALLOC, fwd_cnt: 300, ..guest }, &[]);
+        host.write_all(&big).unwrap();
+        let mut received = Vec::new();
+        while received.len() < big.len() {
+            let (header, data) = vsock.expect(5000, VSOCK_OP_RW);
+            assert!(header.len <= RX_DATA_SIZE);
+            received.extend(data);
+        }
+        assert_eq!(received, big);
+    }
+
+    #[test]
+    fn an_rx_buffer_without_data_space_is_returned_unused() {
+        let mut vsock = TestVsock::new();
+        let listener = vsock.listen(1234);
+        let mut host = vsock.connect(5000, 1234, &listener);
+        let head = vsock.rx.empty_next_rx_buffer(&vsock.mem);
+
+        host.write_all(b"data").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let used = loop {
+            assert!(vsock.worker.poll(&mut vsock.events, 10).unwrap());
+            vsock.worker.process_rx().unwrap();
+            if let Some(used) = vsock.rx.next_used(&vsock.mem) {
+                break used;
+            }
+            assert!(Instant::now() < deadline, "the empty buffer was never returned");
+        };
+        assert_eq!(used, (head, 0));
+
+        // The connection is still open and the data arrives in the next buffer.
+        assert_eq!(vsock.connection((1234, 5000)).state, ConnState::Established);
+        let (_, data) = vsock.expect(5000, VSOCK_OP_RW);
+        assert_eq!(data, b"data");
+    }
+
+    #[test]
+    fn guest_data_is_acknowledged_through_fwd_cnt() {
+        let mut vsock = TestVsock::new();
+        let listener = vsock.listen(1234);
+        let mut host = vsock.connect(5000, 1234, &listener);
+        let guest = Header::from_guest(5000, 1234, VSOCK_OP_RW);
+
+        // Credit updates are sent unprompted once enough was forwarded.
+        let chunk = vec![1u8; MAX_PKT_DATA as usize];
+        let mut sent = 0;
+        while sent < CREDIT_UPDATE_THRESHOLD as usize {
+            vsock.send(guest, &chunk);
+            sent += chunk.len();
+        }
+        let mut forwarded = vec![0u8; sent];
+        host.read_exact(&mut forwarded).unwrap();
+        let (header, _) = vsock.expect(5000, VSOCK_OP_CREDIT_UPDATE);
+        assert_eq!((header.buf_alloc, header.fwd_cnt), (CONN_BUF_ALLOC, sent as u32));
+        assert!(vsock.try_recv(Duration::from_millis(50)).is_none(), "credit update sent twice");
+
+        // And on request.
+        vsock.send(guest, b"abc");
+        vsock.send(Header { op: VSOCK_OP_CREDIT_REQUEST, ..guest }, &[]);
+        let (header, _) = vsock.expect(5000, VSOCK_OP_CREDIT_UPDATE);
+        assert_eq!(header.fwd_cnt, sent as u32 + 3);
+    }
+
+    /// Shrinks the send buffer of the worker's end of a connection, so the
+    /// host socket fills up after a few KiB.
+    fn shrink_send_buffer(stream: &UnixStream) {
+        let size: libc::c_int = 4096;
+        // SAFETY: `size` is a valid c_int for the duration of the call.
+        let ret = unsafe {
+            libc::setsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_SNDBUF,
+                &size as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(ret, 0);
+    }
+
+    #[test]
+    fn a_full_host_socket_queues_guest_data_until_writable() {
+        let mut vsock = TestVsock::new();
+        let listener = vsock.listen(1234);
+        let mut host = vsock.connect(5000, 1234, &listener);
+        shrink_send_buffer(&vsock.connection((1234, 5000)).stream);
+        let guest = Header::from_guest(5000, 1234, VSOCK_OP_RW);
+
+        // The host process is not reading: the worker must not wait for it.
+        let data: Vec<u8> = (0..2 * MAX_PKT_DATA).map(|i| (i % 251) as u8).collect();
+        let started = Instant::now();
+        for chunk in data.chunks(MAX_PKT_DATA as usize) {
+            vsock.send(guest, chunk);
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+        let conn = vsock.connection((1234, 5000));
+        assert!(!conn.pending.is_empty());
+        assert_eq!(conn.fwd_cnt.0 as usize + conn.pending.len(), data.len());
+
+        // The guest signals it is done; the host still gets everything, in
+        // order, as EPOLLOUT frees room, then EOF.
+        vsock.send(Header { op: VSOCK_OP_SHUTDOWN, flags: VSOCK_FLAGS_SHUTDOWN_SEND, ..guest }, &[]);
+        let mut received = Vec::new();
+        let mut buf = [0u8; 8192];
+        host.set_nonblocking(true).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match host.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => received.extend_from_slice(&buf[..read]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    assert!(Instant::now() < deadline, "host got {} of {} bytes", received.len(), data.len());
+                    vsock.worker.poll(&mut vsock.events, 10).unwrap();
+                }
+                Err(e) => panic!("host read failed: {}", e),
+            }
+        }
+        assert_eq!(received, data);
+        let conn = vsock.connection((1234, 5000));
+        assert!(conn.pending.is_empty());
+        assert_eq!(conn.fwd_cnt.0 as usize, data.len());
+    }
+
+    #[test]
+    fn guest_overrunning_its_credit_is_reset() {
+        let mut vsock = TestVsock::new();
+        let listener = vsock.listen(1234);
+        let _host = vsock.connect(5000, 1234, &listener);
+        shrink_send_buffer(&vsock.connection((1234, 5000)).stream);
+        let guest = Header::from_guest(5000, 1234, VSOCK_OP_RW);
+
+        let chunk = vec![0u8; MAX_PKT_DATA as usize];
+        for _ in 0..CONN_BUF_ALLOC / MAX_PKT_DATA {
+            vsock.send(guest, &chunk);
+        }
+        assert!(vsock.worker.conns.contains_key(&(1234, 5000)));
+        vsock.send(guest, &chunk);
+        assert!(vsock.worker.conns.is_empty());
+        vsock.expect(5000, VSOCK_OP_RST);
+    }
+
+    #[test]
+    fn host_connects_with_a_connect_line() {
+        let mut vsock = TestVsock::new();
+        let mut host = UnixStream::connect(&vsock.uds_path).unwrap();
+        host.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        host.write_all(b"CONNECT 52\n").unwrap();
+
+        let (header, _) = vsock.expect(52, VSOCK_OP_REQUEST);
+        assert_eq!(header.src_port, HOST_PORT_START);
+        assert_eq!(vsock.connection((HOST_PORT_START, 52)).state, ConnState::Connecting);
+
+        let guest = Header::from_guest(52, HOST_PORT_START, VSOCK_OP_RESPONSE);
+        vsock.send(guest, &[]);
+        let mut line = [0u8; 14];
+        host.read_exact(&mut line).unwrap();
+        assert_eq!(&line, b"OK 1073741824\n");
+
+        host.write_all(b"ping").unwrap();
+        let (_, data) = vsock.expect(52, VSOCK_OP_RW);
+        assert_eq!(data, b"ping");
+        vsock.send(Header { op: VSOCK_OP_RW, ..guest }, b"pong");
+        let mut pong = [0u8; 4];
+        host.read_exact(&mut pong).unwrap();
+        assert_eq!(&pong, b"pong");
+        // The reply line is not guest data.
+        assert_eq!(vsock.connection((HOST_PORT_START, 52)).fwd_cnt.0, 4);
+
+        // A full SHUTDOWN from the guest closes the host socket and is
+        // answered with RST.
+        let both = VSOCK_FLAGS_SHUTDOWN_RCV | VSOCK_FLAGS_SHUTDOWN_SEND;
+        vsock.send(Header { op: VSOCK_OP_SHUTDOWN, flags: both, ..guest }, &[]);
+        vsock.expect(52, VSOCK_OP_RST);
+        let mut rest = Vec::new();
+        host.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        assert!(vsock.worker.conns.is_empty());
+    }
+
+    #[test]
+    fn invalid_connect_lines_are_dropped() {
+        let mut vsock = TestVsock::new();
+        for line in [&b"HELLO\n"[..], b"CONNECT port\n", &[b'9'; MAX_CONNECT_LINE + 1]] {
+            let mut host = UnixStream::connect(&vsock.uds_path).unwrap();
+            host.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            host.write_all(line).unwrap();
+            assert!(vsock.try_recv(Duration::from_millis(50)).is_none());
+            let mut rest = Vec::new();
+            host.read_to_end(&mut rest).unwrap();
+            assert!(rest.is_empty());
+        }
+        assert!(vsock.worker.conns.is_empty() && vsock.worker.handshakes.is_empty());
+        assert_eq!(parse_connect(b"CONNECT 52\r"), Some(52));
+    }
+}
@@ -0,0 +1,54 @@
+use attester_flow::kbs_server::{KbsConfig, KbsServer};
+
+// Local Key Broker Service.
+//
+// Configuration is read from the environment:
+//   KBS_LISTEN        address to listen on (default 127.0.0.1:8080)
+//   KBS_POLICY        attestation policy file (default /etc/attester_flow/policy.toml)
+//   KBS_RESOURCE_DIR  resources laid out as <repo>/<type>/<tag>
+//                     (default /etc/attester_flow/resources)
+//   KBS_EXTRA_ROOTS   colon-separated PEM roots to pin in addition to the
+//                     AMD/Intel roots, e.g. $SIMULATOR_STATE_DIR/ark.pem
+//   KBS_RESOURCE_DB   Postgres connection string; stores resources there,
+//                     AES-256-GCM encrypted, instead of in KBS_RESOURCE_DIR
+//   KBS_RESOURCE_KEY_FILE  resource encryption key (32 bytes, raw or hex),
+//                     required with KBS_RESOURCE_DB
+//   KBS_NONCE_STORE   where issued challenge nonces are recorded: memory
+//                     (default), file:<path> or postgres:<connection string>
+//   KBS_DB_SSLMODE    TLS of the Postgres nonce and resource stores:
+//                     disable, prefer, require or verify-full (default)
+//   KBS_DB_CA_FILE    PEM CA bundle to verify the database against
+//   KBS_DB_CLIENT_CERT_FILE, KBS_DB_CLIENT_KEY_FILE
+//                     PEM client certificate and key, if the database
+//                     requires one
+//   KBS_ADMIN_TOKEN   bearer token of the /kbs/v0/admin endpoints; unset
+//                     disables them
+//   KBS_FAULTS        faults to inject for testing agents, e.g.
+//                     auth=503*2,attest=nonce,resource=delay:5000;
+//                     only read by builds with `--features test-faults`
+//
+// For local development with the simulator:
+//   SIMULATOR_STATE_DIR=/tmp/sim KBS_EXTRA_ROOTS=/tmp/sim/ark.pem \
+//   KBS_POLICY=attestation_policy.toml KBS_RESOURCE_DIR=./resources kbs
+fn main() {
+    let config = KbsConfig::from_env();
+    println!("### Key Broker Service ###");
+    println!("Policy: {}", config.policy_path.display());
+    match &config.resource_db {
+        Some(_) => println!("Resources: Postgres (encrypted at rest)"),
+        None => println!("Resources: {}", config.resource_dir.display()),
+    }
+
+    let server = match KbsServer::from_config(config) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("❌ FATAL ERROR: could not start the KBS: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = server.serve() {
+        eprintln!("❌ FATAL ERROR: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,361 @@
+use std::cell::Cell;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use attester_flow::attestation_data::RuntimeData;
+use attester_flow::attester::attester::Attester;
+use attester_flow::cert_chain::TrustAnchors;
+use attester_flow::error::AttestationError;
+use attester_flow::kbs_faults::FaultRule;
+use attester_flow::kbs_client::{KbsClient, KbsClientError};
+use attester_flow::kbs_protocol::{TeePubKey, ERROR_NONCE_REJECTED, ERROR_UNAUTHENTICATED_SESSION};
+use attester_flow::kbs_server::{KbsConfig, KbsServer};
+use attester_flow::policy::Policy;
+use attester_flow::retry::RetryPolicy;
+use attester_flow::simulator::{SimulatedAttester, SIMULATED_BOOT_IMAGE};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha384};
+
+// Drives `KbsClient` through the RCAR handshake against a KBS served on an
+// ephemeral port, with the simulator standing in for the TEE, and the agent's
+// `RetryPolicy` against faults that KBS injects.
+
+const SECRET_PATH: &str = "default/key/db-password";
+const SECRET: &[u8] = b"correct horse battery staple";
+
+/// A running KBS and the simulated TEE whose root it pins.
+struct TestKbs {
+    url: String,
+    attester: SimulatedAttester,
+    resource_dir: PathBuf,
+}
+
+impl TestKbs {
+    fn start(session_ttl: Duration) -> Self {
+        TestKbs::with_faults(session_ttl, "")
+    }
+
+    /// A KBS that fails requests as `faults` (`KBS_FAULTS` syntax) says.
+    fn with_faults(session_ttl: Duration, faults: &str) -> Self {
+        let attester = SimulatedAttester::new().unwrap();
+        let anchors = TrustAnchors::from_pem(attester.local_root_pem().unwrap().as_bytes()).unwrap();
+        let policy = Policy::from_toml_str(&format!(
+            "[[image]]\nname = \"simulated\"\nmeasurements = [\"{}\"]\n",
+            hex::encode(Sha384::digest(SIMULATED_BOOT_IMAGE))
+        ))
+        .unwrap();
+
+        let resource_dir = std::env::temp_dir().join(format!("kbs-client-test-{:016x}", OsRng.next_u64()));
+        let secret_file = resource_dir.join(SECRET_PATH);
+        std::fs::create_dir_all(secret_file.parent().unwrap()).unwrap();
+        std::fs::write(&secret_file, SECRET).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let config = KbsConfig {
+            resource_dir: resource_dir.clone(),
+            session_ttl,
+            faults: FaultRule::parse_list(faults).unwrap(),
+            ..KbsConfig::default()
+        };
+        std::thread::spawn(move || {
+            KbsServer::new(config, anchors, policy).serve_listener(listener).unwrap();
+        });
+
+        TestKbs { url, attester, resource_dir }
+    }
+
+    fn client(&self) -> KbsClient {
+        KbsClient::with_timeout(&self.url, Duration::from_secs(10))
+    }
+
+    /// Runs `auth` and `attest`, returning the TEE key the KBS encrypts to.
+    fn attest(&self, client: &KbsClient) -> p256::SecretKey {
+        self.try_attest(client).unwrap()
+    }
+
+    fn try_attest(&self, client: &KbsClient) -> Result<p256::SecretKey, KbsClientError> {
+        let challenge = client.auth(self.attester.tee())?;
+        let tee_key = p256::SecretKey::random(&mut OsRng);
+        let tee_pubkey = TeePubKey::from_p256(&tee_key.public_key());
+        let report = self
+            .attester
+            .generate_bound_evidence(&challenge, RuntimeData::new(tee_pubkey.to_binding_bytes()))
+            .unwrap();
+        let token = client.attest(&tee_pubkey, &report)?;
+        assert!(!token.is_empty());
+        Ok(tee_key)
+    }
+
+    /// One pass of the agent pipeline, each step retried under `retry`.
+    /// Counts the passes in `passes`.
+    fn fetch_secret(
+        &self,
+        client: &KbsClient,
+        retry: &RetryPolicy,
+        passes: &Cell<u32>,
+    ) -> Result<Vec<u8>, AttestationError> {
+        passes.set(passes.get() + 1);
+        let challenge = retry.retry("challenge", || Ok(client.auth(self.attester.tee())?))?;
+        let tee_key = p256::SecretKey::random(&mut OsRng);
+        let tee_pubkey = TeePubKey::from_p256(&tee_key.public_key());
+        let report = self
+            .attester
+            .generate_bound_evidence(&challenge, RuntimeData::new(tee_pubkey.to_binding_bytes()))?;
+        retry.retry("attest", || Ok(client.attest(&tee_pubkey, &report)?))?;
+        retry.retry("resource", || Ok(client.get_secret(SECRET_PATH, &tee_key)?))
+    }
+}
+
+impl Drop for TestKbs {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.resource_dir);
+    }
+}
+
+fn assert_unauthenticated(result: Result<Vec<u8>, KbsClientError>) {
+    match result {
+        Err(KbsClientError::Status { status: 401, error_type, .. }) => {
+            assert_eq!(error_type, ERROR_UNAUTHENTICATED_SESSION)
+        }
+        other => panic!("expected an unauthenticated session, got {:?}", other.map(String::from_utf8)),
+    }
+}
+
+#[test]
+fn releases_the_secret_to_an_attested_session() {
+    let kbs = TestKbs::start(Duration::from_secs(60));
+    let client = kbs.client();
+    let tee_key = kbs.attest(&client);
+
+    assert_eq!(client.get_secret(SECRET_PATH, &tee_key).unwrap(), SECRET);
+
+    // Only the attested TEE key can open the released JWE.
+    let other_key = p256::SecretKey::random(&mut OsRng);
+    assert!(matches!(client.get_secret(SECRET_PATH, &other_key), Err(KbsClientError::Decryption(_))));
+
+    match client.get_secret("default/key/missing", &tee_key) {
+        Err(KbsClientError::Status { status: 404, .. }) => {}
+        other => panic!("expected 404, got {:?}", other),
+    }
+}
+
+#[test]
+fn requires_a_session_cookie() {
+    let kbs = TestKbs::start(Duration::from_secs(60));
+
+    // Without `auth` the client has no cookie to send.
+    let client = kbs.client();
+    assert!(matches!(client.get_resource(SECRET_PATH), Err(KbsClientError::NoSession)));
+
+    // A session that never attested gets nothing.
+    client.auth(kbs.attester.tee()).unwrap();
+    assert_unauthenticated(client.get_resource(SECRET_PATH));
+
+    // Attesting one session does not release secrets to another.
+    let attested = kbs.client();
+    kbs.attest(&attested);
+    assert_unauthenticated(client.get_resource(SECRET_PATH));
+}
+
+#[test]
+fn rejects_evidence_for_another_tee() {
+    let kbs = TestKbs::start(Duration::from_secs(60));
+    let client = kbs.client();
+
+    // The simulator produces SNP evidence; a session opened for TDX must
+    // not accept it.
+    let challenge = client.auth("tdx").unwrap();
+    let tee_key = p256::SecretKey::random(&mut OsRng);
+    let tee_pubkey = TeePubKey::from_p256(&tee_key.public_key());
+    let report = kbs
+        .attester
+        .generate_bound_evidence(&challenge, RuntimeData::new(tee_pubkey.to_binding_bytes()))
+        .unwrap();
+    match client.attest(&tee_pubkey, &report) {
+        Err(KbsClientError::Status { status: 401, error_type, .. }) => assert_eq!(error_type, "AttestationFailed"),
+        other => panic!("expected the evidence to be rejected, got {:?}", other),
+    }
+    assert_unauthenticated(client.get_resource(SECRET_PATH));
+}
+
+#[test]
+fn a_new_auth_replaces_the_attested_session() {
+    let kbs = TestKbs::start(Duration::from_secs(60));
+    let client = kbs.client();
+    kbs.attest(&client);
+
+    // Re-running `auth` swaps in a fresh, unattested cookie and drops the
+    // old token.
+    client.auth(kbs.attester.tee()).unwrap();
+    assert_unauthenticated(client.get_resource(SECRET_PATH));
+
+    let tee_key = kbs.attest(&client);
+    assert_eq!(client.get_secret(SECRET_PATH, &tee_key).unwrap(), SECRET);
+}
+
+#[test]
+fn a_cloned_client_has_its_own_session() {
+    let kbs = TestKbs::start(Duration::from_secs(60));
+    let client = kbs.client();
+    let tee_key = kbs.attest(&client);
+
+    // The copy starts out attested, but a new `auth` through it leaves the
+    // original's session alone.
+    let copy = client.clone();
+    assert_eq!(copy.get_secret(SECRET_PATH, &tee_key).unwrap(), SECRET);
+    copy.auth(kbs.attester.tee()).unwrap();
+    assert_unauthenticated(copy.get_resource(SECRET_PATH));
+    assert_eq!(client.get_secret(SECRET_PATH, &tee_key).unwrap(), SECRET);
+}
+
+#[test]
+fn expired_sessions_must_attest_again() {
+    let ttl = Duration::from_secs(1);
+    let kbs = TestKbs::start(ttl);
+    let client = kbs.client();
+
+    // A challenge that outlives its session can no longer be answered.
+    let challenge = client.auth(kbs.attester.tee()).unwrap();
+    std::thread::sleep(ttl + Duration::from_millis(500));
+    let tee_key = p256::SecretKey::random(&mut OsRng);
+    let tee_pubkey = TeePubKey::from_p256(&tee_key.public_key());
+    let report = kbs
+        .attester
+        .generate_bound_evidence(&challenge, RuntimeData::new(tee_pubkey.to_binding_bytes()))
+        .unwrap();
+    match client.attest(&tee_pubkey, &report) {
+        Err(KbsClientError::Status { status: 401, error_type, .. }) => {
+            assert_eq!(error_type, ERROR_UNAUTHENTICATED_SESSION)
+        }
+        other => panic!("expected an unknown session, got {:?}", other),
+    }
+
+    // An attested session (and its token) lapses after the TTL as well.
+    let tee_key = kbs.attest(&client);
+    assert_eq!(client.get_secret(SECRET_PATH, &tee_key).unwrap(), SECRET);
+    std::thread::sleep(ttl + Duration::from_millis(500));
+    match client.get_secret(SECRET_PATH, &tee_key) {
+        Err(KbsClientError::Status { status: 401, .. }) => {}
+        other => panic!("expected the session to have expired, got {:?}", other),
+    }
+
+    let tee_key = kbs.attest(&client);
+    assert_eq!(client.get_secret(SECRET_PATH, &tee_key).unwrap(), SECRET);
+}
+
+// --- Retries against injected faults ---
+
+/// Retries quickly, so the tests do not sit out real backoffs.
+fn fast_retry(max_attempts: u32, max_restarts: u32) -> RetryPolicy {
+    RetryPolicy {
+        max_attempts,
+        max_restarts,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(5),
+        ..RetryPolicy::default()
+    }
+}
+
+fn assert_status(result: Result<impl std::fmt::Debug, AttestationError>, expected: u16) {
+    match result {
+        Err(AttestationError::Kbs(KbsClientError::Status { status, .. })) if status == expected => {}
+        other => panic!("expected a {} from the KBS, got {:?}", expected, other),
+    }
+}
+
+#[test]
+fn retries_transient_failures_of_a_step() {
+    let kbs = TestKbs::with_faults(Duration::from_secs(60), "auth=503*2,resource=500");
+    let client = kbs.client();
+    let retry = fast_retry(3, 0);
+
+    let attempts = Cell::new(0);
+    let challenge = retry.retry("challenge", || {
+        attempts.set(attempts.get() + 1);
+        Ok(client.auth(kbs.attester.tee())?)
+    });
+    assert!(challenge.is_ok());
+    assert_eq!(attempts.get(), 3);
+
+    // The whole pipeline rides out a failing resource request as well.
+    let passes = Cell::new(0);
+    let secret = retry.with_restarts(|| kbs.fetch_secret(&client, &retry, &passes)).unwrap();
+    assert_eq!(secret, SECRET);
+    assert_eq!(passes.get(), 1);
+}
+
+#[test]
+fn gives_up_after_max_attempts() {
+    let kbs = TestKbs::with_faults(Duration::from_secs(60), "auth=503*3");
+    let client = kbs.client();
+
+    let attempts = Cell::new(0);
+    let challenge = fast_retry(2, 0).retry("challenge", || {
+        attempts.set(attempts.get() + 1);
+        Ok(client.auth(kbs.attester.tee())?)
+    });
+    assert_status(challenge, 503);
+    assert_eq!(attempts.get(), 2);
+
+    // The third fault is still pending for the next caller.
+    assert!(matches!(client.auth(kbs.attester.tee()), Err(KbsClientError::Status { status: 503, .. })));
+    client.auth(kbs.attester.tee()).unwrap();
+}
+
+#[test]
+fn restarts_from_a_new_challenge_when_the_nonce_is_rejected() {
+    let kbs = TestKbs::with_faults(Duration::from_secs(60), "attest=nonce*2");
+    let client = kbs.client();
+
+    // A rejected nonce is not retried in place...
+    match kbs.try_attest(&client) {
+        Err(KbsClientError::Status { status: 401, error_type, .. }) => assert_eq!(error_type, ERROR_NONCE_REJECTED),
+        other => panic!("expected the nonce to be rejected, got {:?}", other.map(|_| ())),
+    }
+
+    // ...but starts the pipeline over, which then succeeds.
+    let retry = fast_retry(3, 2);
+    let passes = Cell::new(0);
+    let secret = retry.with_restarts(|| kbs.fetch_secret(&client, &retry, &passes)).unwrap();
+    assert_eq!(secret, SECRET);
+    assert_eq!(passes.get(), 2);
+}
+
+#[test]
+fn restarts_are_bounded() {
+    let kbs = TestKbs::with_faults(Duration::from_secs(60), "attest=nonce*3");
+    let client = kbs.client();
+
+    let retry = fast_retry(3, 1);
+    let passes = Cell::new(0);
+    assert_status(retry.with_restarts(|| kbs.fetch_secret(&client, &retry, &passes)), 401);
+    assert_eq!(passes.get(), 2);
+}
+
+#[test]
+fn does_not_retry_a_permanent_rejection() {
+    let kbs = TestKbs::with_faults(Duration::from_secs(60), "resource=403");
+    let client = kbs.client();
+    let retry = fast_retry(5, 3);
+
+    let passes = Cell::new(0);
+    assert_status(retry.with_restarts(|| kbs.fetch_secret(&client, &retry, &passes)), 403);
+    assert_eq!(passes.get(), 1);
+
+    // Evidence the KBS rejects ends the run at once too.
+    let attempts = Cell::new(0);
+    let rejected = retry.with_restarts(|| {
+        attempts.set(attempts.get() + 1);
+        let challenge = retry.retry("challenge", || Ok(client.auth("tdx")?))?;
+        let tee_key = p256::SecretKey::random(&mut OsRng);
+        let tee_pubkey = TeePubKey::from_p256(&tee_key.public_key());
+        let report = kbs
+            .attester
+            .generate_bound_evidence(&challenge, RuntimeData::new(tee_pubkey.to_binding_bytes()))?;
+        retry.retry("attest", || Ok(client.attest(&tee_pubkey, &report)?))
+    });
+    assert_status(rejected, 401);
+    assert_eq!(attempts.get(), 1);
+}
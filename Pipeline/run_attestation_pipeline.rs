@@ -1,52 +1,60 @@
 use attester_flow::{
-    attestation_data::{AttestationChallenge, AttestationReport},
-    attester::attester,
+    attestation_data::{AttestationChallenge, AttestationReport, RuntimeData},
+    attester::attester::{self, Attester},
+    cert_chain::TrustAnchors,
+    error::AttestationError,
+    kbs_client::{KbsClient, KbsClientError, KBS_URL_ENV},
+    kbs_protocol::TeePubKey,
+    policy::Policy,
+    retry::RetryPolicy,
     verifier::verifier,
 };
-use serde_json;
-use std::error::Error;
-
-// Define a simple custom error type for our pipeline
-#[derive(Debug)]
-enum PipelineError {
-    KBSCommunicationError(String),
-    VerificationFailed(String),
-    SerializationError(String),
-}
-
-impl std::fmt::Display for PipelineError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            PipelineError::KBSCommunicationError(e) => write!(f, "KBS Communication Error: {}", e),
-            PipelineError::VerificationFailed(e) => write!(f, "Verification Failed: {}", e),
-            PipelineError::SerializationError(e) => write!(f, "Serialization Error: {}", e),
-        }
-    }
-}
-
-impl Error for PipelineError {}
-
 
 /// A simple struct to represent the Attestation Agent running inside the CVM.
 struct AttestationAgent {
-    kbs_endpoint: String,
+    /// Client for the remote Key Broker Service.
+    kbs: KbsClient,
+    /// The TEE backend used to produce evidence.
+    attester: Box<dyn Attester>,
+    /// Pinned vendor roots used for the local integrity check.
+    trust_anchors: TrustAnchors,
+    /// The policy used for the local integrity check.
+    policy: Policy,
+    /// Retries, restarts and per-request timeouts of the pipeline.
+    retry: RetryPolicy,
 }
 
 impl AttestationAgent {
-    /// Simulates the entire RCAR (Remote Challenge Attestation & Resource) pipeline.
+    /// Runs the entire RCAR (Request, Challenge, Attestation, Response) pipeline.
     /// This is the core logic that an Attestation Agent would execute.
-    pub fn run_attestation_pipeline(&self, resource_path: &str) -> Result<String, PipelineError> {
+    ///
+    /// Transient failures (KBS unreachable or overloaded, a request timing
+    /// out) retry the failed step with backoff; a stale nonce or session
+    /// starts over from a new challenge; rejected evidence ends the run.
+    pub fn run_attestation_pipeline(&self, resource_path: &str) -> Result<String, AttestationError> {
+        self.retry.with_restarts(|| self.attest_once(resource_path))
+    }
+
+    /// One pass of the pipeline, from challenge to secret.
+    fn attest_once(&self, resource_path: &str) -> Result<String, AttestationError> {
         println!("\n--- Attestation Pipeline Starting ---");
-        println!("Key Broker Service Endpoint: {}", self.kbs_endpoint);
+        println!("Key Broker Service Endpoint: {}", self.kbs.base_url());
 
         // --- Step 1: Request Challenge from the KBS ---
-        let challenge = self.request_challenge()?;
+        let challenge = self.retry.retry("challenge", || self.request_challenge())?;
         
         // --- Step 2: Generate Attestation Evidence ---
-        let report = attester::generate_evidence(&challenge);
+        // An ephemeral TEE key is bound into report_data so the KBS can wrap
+        // secrets for this VM only.
+        let tee_key = p256::SecretKey::random(&mut rand_core::OsRng);
+        let tee_pubkey = TeePubKey::from_p256(&tee_key.public_key());
+        let runtime_data = RuntimeData::new(tee_pubkey.to_binding_bytes());
+        let report = self
+            .retry
+            .retry("evidence", || Ok(self.attester.generate_bound_evidence(&challenge, runtime_data.clone())?))?;
         
         // --- Step 3: Submit Evidence to the KBS (Attestation Phase) ---
-        let attestation_token = self.submit_evidence(&report)?;
+        let attestation_token = self.retry.retry("attest", || self.submit_evidence(&tee_pubkey, &report))?;
 
         // NOTE: In a production scenario, the KBS would handle the verification
         // (Steps 1, 2, and 3 combined on the server side). 
@@ -54,67 +62,57 @@ impl AttestationAgent {
         // as if the Agent is checking a local policy before submitting.
 
         // --- DEMO Step: Client-Side Verification Check ---
-        match verifier::verify_report(&challenge, &report) {
-            attester_flow::attestation_data::VerificationResult::Trustworthy(_) => {
-                println!("[Agent] Local integrity check passed.");
-            },
-            attester_flow::attestation_data::VerificationResult::Untrustworthy(e) => {
-                return Err(PipelineError::VerificationFailed(format!(
-                    "Local integrity check failed: {}", e
-                )));
-            },
+        let result = verifier::verify_report(&challenge, &report, &self.trust_anchors, &self.policy);
+        if !result.trustworthy {
+            return Err(result.into());
         }
+        println!("[Agent] Local integrity check passed.");
         // --- END DEMO Step ---
 
         // --- Step 4: Retrieve Resource (Secret) using the Token ---
-        let secret = self.retrieve_resource(&attestation_token, resource_path)?;
+        let secret = self
+            .retry
+            .retry("resource", || self.retrieve_resource(&attestation_token, resource_path, &tee_key))?;
 
         println!("--- Attestation Pipeline Complete ---");
         Ok(secret)
     }
 
-    // --- Private Methods Simulating Network/API Calls ---
+    // --- Private Methods Wrapping the KBS Protocol ---
 
-    /// Simulates an HTTP POST to the KBS /auth endpoint.
-    fn request_challenge(&self) -> Result<AttestationChallenge, PipelineError> {
+    /// `POST /kbs/v0/auth`: opens a KBS session and receives its challenge.
+    fn request_challenge(&self) -> Result<AttestationChallenge, AttestationError> {
         println!("\n[KBS Agent] 1. Requesting Attestation Challenge...");
-        
-        // Simulate network call success
-        let challenge_json = r#"{"nonce": "kbs-nonce-e5a9c1f2-7d3b-4e8c-9a0f-8b2d1c5e4a3"}"#;
-        
-        let challenge: AttestationChallenge = serde_json::from_str(challenge_json)
-            .map_err(|e| PipelineError::SerializationError(e.to_string()))?;
+
+        let challenge = self.kbs.auth(self.attester.tee())?;
 
         println!("[KBS Agent] Challenge received: {}", challenge.nonce);
         Ok(challenge)
     }
 
-    /// Simulates an HTTP POST to the KBS /attest endpoint.
-    fn submit_evidence(&self, report: &AttestationReport) -> Result<String, PipelineError> {
+    /// `POST /kbs/v0/attest`: submits the evidence and the bound TEE key.
+    fn submit_evidence(&self, tee_pubkey: &TeePubKey, report: &AttestationReport) -> Result<String, AttestationError> {
         println!("\n[KBS Agent] 2. Submitting Attestation Evidence...");
 
-        // Serialize the report to send over the network
-        let _evidence_payload = serde_json::to_string(report)
-            .map_err(|e| PipelineError::SerializationError(e.to_string()))?;
+        let token = self.kbs.attest(tee_pubkey, report)?;
 
-        // Simulate network call success and receiving an attestation token
-        let token = "kbs-auth-token-d4f7g8h1j2k3l4m5n6p7q8r9s0t1u2v3";
         println!("[KBS Agent] Evidence accepted. Received Attestation Token.");
-        Ok(token.to_string())
+        Ok(token)
     }
 
-    /// Simulates an HTTP GET to the KBS /resource endpoint using the token.
-    fn retrieve_resource(&self, token: &str, path: &str) -> Result<String, PipelineError> {
+    /// `GET /kbs/v0/resource/<repo>/<type>/<tag>` within the attested session.
+    /// The KBS wraps the secret for the TEE key, which only this agent holds.
+    fn retrieve_resource(&self, token: &str, path: &str, tee_key: &p256::SecretKey) -> Result<String, AttestationError> {
         println!("\n[KBS Agent] 3. Requesting Secret '{}' using Token...", path);
 
-        // Simulate network call failure (if token is invalid, etc.)
         if token.is_empty() {
-            return Err(PipelineError::KBSCommunicationError("No valid token provided.".to_string()));
+            return Err(KbsClientError::NoSession.into());
         }
 
-        // Simulate network call success: The KBS decrypts and returns the secret.
-        let secret_payload = format!("Decrypted Secret for {}: API_KEY__{}", path, self.kbs_endpoint.len() * 100);
-        
+        let secret_payload = self.kbs.get_secret(path, tee_key)?;
+        let secret_payload = String::from_utf8(secret_payload)
+            .map_err(|e| AttestationError::Serialization(e.to_string()))?;
+
         println!("[KBS Agent] Successfully retrieved resource!");
         Ok(secret_payload)
     }
@@ -122,9 +120,37 @@ impl AttestationAgent {
 
 
 fn main() {
+    // Select the TEE backend (ATTESTER_BACKEND overrides auto-detection).
+    let attester = match attester::from_env() {
+        Ok(attester) => attester,
+        Err(e) => {
+            eprintln!("❌ FATAL ERROR: no attester available: {}", e);
+            return;
+        }
+    };
+
+    let mut trust_anchors = TrustAnchors::load_default().unwrap_or_default();
+    if let Some(root) = attester.local_root_pem() {
+        let _ = trust_anchors.add_pem(root.as_bytes());
+    }
+
+    let policy = match Policy::load("attestation_policy.toml") {
+        Ok(policy) => policy,
+        Err(e) => {
+            eprintln!("❌ FATAL ERROR: could not load attestation policy: {}", e);
+            return;
+        }
+    };
+
     // Instantiate the agent with the remote service endpoint.
+    let kbs_url = std::env::var(KBS_URL_ENV).unwrap_or_else(|_| "https://kbs.cloud.provider.com".to_string());
+    let retry = RetryPolicy::from_env();
     let agent = AttestationAgent {
-        kbs_endpoint: "https://kbs.cloud.provider.com/api/v1".to_string(),
+        kbs: KbsClient::with_timeout(&kbs_url, retry.step_timeout),
+        attester,
+        trust_anchors,
+        policy,
+        retry,
     };
 
     let secret_to_fetch = "/keys/database-cred";
@@ -145,5 +171,5 @@ fn main() {
     }
 }
 
-// NOTE: To run this, ensure the `attester_flow` module structure from the previous 
-// response is correctly set up in your Rust project.
+// NOTE: Outside a CVM, run against the simulator:
+//   ATTESTER_BACKEND=sim cargo run -p attestation-agent --features sim
@@ -1,5 +1,11 @@
 use serde::{Serialize, Deserialize};
-use sha2::{Sha256, Digest};
+use serde_big_array::BigArray;
+use sha2::{Sha512, Digest};
+use std::collections::BTreeMap;
+
+use crate::snp_report::SnpAttestationReport;
+use crate::tdx_quote::{TdxQuote, TdxQuoteError};
+use crate::vtpm::TpmQuote;
 
 // --- Core Data Structures ---
 
@@ -13,18 +19,447 @@ pub struct AttestationChallenge {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AttestationReport {
     /// A cryptographic hash of the entire VM's boot state.
-    pub measurement: [u8; 32],
+    /// (48 bytes on SEV-SNP, where it is a SHA-384 launch digest.)
+    pub measurement: Vec<u8>,
     /// The nonce copied from the challenge to ensure freshness.
+    #[serde(with = "BigArray")]
     pub report_data: [u8; 64],
     /// The raw bytes of the hardware signature (e.g., VCEK-signed signature).
     pub signature: Vec<u8>,
     /// Public key or certificate chain needed to verify the signature.
     pub cert_chain: Vec<u8>,
+    /// The platform-specific evidence the fields above were extracted from.
+    pub evidence: TeeEvidence,
+    /// Runtime data bound into `report_data`, if any (see `RuntimeData`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub runtime_data: Option<RuntimeData>,
+}
+
+/// Data produced inside the VM at runtime and bound into the report.
+///
+/// When present, `report_data` is SHA-512(nonce || public_key || claims),
+/// where `claims` is the JSON encoding of the claims map (keys sorted).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuntimeData {
+    /// An ephemeral public key generated in the VM (e.g. SEC1 P-256 or a JWK).
+    pub public_key: Vec<u8>,
+    /// Additional runtime claims, e.g. `image_digest` of the workload.
+    #[serde(default)]
+    pub claims: BTreeMap<String, String>,
+}
+
+impl RuntimeData {
+    pub fn new(public_key: Vec<u8>) -> Self {
+        RuntimeData { public_key, claims: BTreeMap::new() }
+    }
+
+    pub fn with_claim(mut self, name: &str, value: &str) -> Self {
+        self.claims.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// The canonical byte encoding of `claims` that is hashed.
+    pub fn claims_bytes(&self) -> Vec<u8> {
+        if self.claims.is_empty() {
+            return Vec::new();
+        }
+        // A BTreeMap of strings always serializes, in key order.
+        serde_json::to_vec(&self.claims).unwrap_or_default()
+    }
+
+    /// Computes the 64-byte REPORT_DATA binding this data to `nonce`.
+    pub fn report_data(&self, nonce: &str) -> [u8; 64] {
+        let mut hasher = Sha512::new();
+        hasher.update(nonce.as_bytes());
+        hasher.update(&self.public_key);
+        hasher.update(self.claims_bytes());
+        hasher.finalize().into()
+    }
+}
+
+/// The TEE-specific evidence carried inside an `AttestationReport`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "tee", content = "raw", rename_all = "lowercase")]
+pub enum TeeEvidence {
+    /// Simulated evidence with no underlying hardware structure.
+    Mock,
+    /// An AMD SEV-SNP `ATTESTATION_REPORT`.
+    Snp(Box<SnpAttestationReport>),
+    /// An Intel TDX Quote (v4).
+    Tdx(Box<TdxQuote>),
+    /// A TPM2_Quote from a (virtual) TPM over PCRs 0-7.
+    Vtpm(Box<TpmQuote>),
+}
+
+impl TeeEvidence {
+    /// The short TEE name used in policies and logs.
+    pub fn tee_name(&self) -> &'static str {
+        match self {
+            TeeEvidence::Mock => "mock",
+            TeeEvidence::Snp(_) => "snp",
+            TeeEvidence::Tdx(_) => "tdx",
+            TeeEvidence::Vtpm(_) => "vtpm",
+        }
+    }
+}
+
+impl AttestationReport {
+    /// Builds the generic report from a decoded SEV-SNP `ATTESTATION_REPORT`
+    /// and the VCEK certificate chain fetched alongside it.
+    pub fn from_snp(snp: &SnpAttestationReport, cert_chain: Vec<u8>) -> Self {
+        let mut signature = snp.signature.r.to_vec();
+        signature.extend_from_slice(&snp.signature.s);
+
+        AttestationReport {
+            measurement: snp.measurement.to_vec(),
+            report_data: snp.report_data,
+            signature,
+            cert_chain,
+            evidence: TeeEvidence::Snp(Box::new(snp.clone())),
+            runtime_data: None,
+        }
+    }
+
+    /// Builds the generic report from a decoded TDX quote. MRTD serves as the
+    /// measurement and the PCK chain is taken from the quote's certification data.
+    pub fn from_tdx(quote: &TdxQuote) -> Result<Self, TdxQuoteError> {
+        Ok(AttestationReport {
+            measurement: quote.body.mr_td.to_vec(),
+            report_data: quote.body.report_data,
+            signature: quote.signature_data.quote_signature.to_vec(),
+            cert_chain: quote.signature_data.certification_data.pck_cert_chain()?,
+            evidence: TeeEvidence::Tdx(Box::new(quote.clone())),
+            runtime_data: None,
+        })
+    }
+}
+
+// --- Verification Results ---
+
+/// The individual checks the verifier performs, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckKind {
+    /// The report_data binds the challenge nonce (and any runtime data).
+    Freshness,
+    /// The generic report fields agree with the platform evidence.
+    Evidence,
+    /// The evidence is signed by a key chaining to a pinned vendor root.
+    Signature,
+    /// The measurement belongs to a trusted image.
+    Measurement,
+    /// Firmware and guest security versions meet the policy minimums.
+    Tcb,
+    /// Remaining policy clauses (guest policy bits, platforms, TEE types).
+    Policy,
+}
+
+/// Machine-readable reason a report was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureCode {
+    NonceMismatch,
+    /// The nonce was issued longer ago than the challenge TTL.
+    NonceExpired,
+    /// The nonce was already redeemed, or was never issued by this verifier.
+    NonceReplayed,
+    /// The nonce store could not be reached; the evidence was not judged.
+    NonceStoreUnavailable,
+    EvidenceInconsistent,
+    SignatureInvalid,
+    MeasurementUntrusted,
+    TcbOutdated,
+    PolicyViolation,
+}
+
+impl FailureCode {
+    /// The failure category a failed check maps to.
+    pub fn for_check(check: CheckKind) -> Self {
+        match check {
+            CheckKind::Freshness => FailureCode::NonceMismatch,
+            CheckKind::Evidence => FailureCode::EvidenceInconsistent,
+            CheckKind::Signature => FailureCode::SignatureInvalid,
+            CheckKind::Measurement => FailureCode::MeasurementUntrusted,
+            CheckKind::Tcb => FailureCode::TcbOutdated,
+            CheckKind::Policy => FailureCode::PolicyViolation,
+        }
+    }
+}
+
+/// The outcome of one check (or one policy clause within a check).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckResult {
+    pub check: CheckKind,
+    /// The policy clause evaluated, for policy-driven checks.
+    pub rule: Option<String>,
+    pub passed: bool,
+    pub detail: String,
+}
+
+/// Platform properties extracted from the evidence.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlatformClaims {
+    pub chip_id: Option<String>,
+    pub smt_enabled: Option<bool>,
+    pub tsme_enabled: Option<bool>,
+    pub debug_enabled: Option<bool>,
+    /// Raw SNP guest policy, or TDX TD_ATTRIBUTES, hex-encoded.
+    pub guest_policy: Option<String>,
+}
+
+/// The claims a verifier extracted from the evidence.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claims {
+    pub tee: String,
+    pub measurement: String,
+    pub report_data: String,
+    pub host_data: Option<String>,
+    /// Runtime data (ephemeral public key and claims) bound into report_data.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub runtime_data: Option<RuntimeData>,
+    /// Security version numbers by component (e.g. `snp`, `microcode`).
+    pub tcb: std::collections::BTreeMap<String, String>,
+    pub platform: PlatformClaims,
+}
+
+impl Claims {
+    pub fn from_report(report: &AttestationReport) -> Self {
+        let mut tcb = std::collections::BTreeMap::new();
+        let mut platform = PlatformClaims::default();
+        let mut host_data = None;
+
+        match &report.evidence {
+            TeeEvidence::Snp(snp) => {
+                let t = &snp.reported_tcb;
+                tcb.insert("boot_loader".to_string(), t.boot_loader.to_string());
+                tcb.insert("tee".to_string(), t.tee.to_string());
+                tcb.insert("snp".to_string(), t.snp.to_string());
+                tcb.insert("microcode".to_string(), t.microcode.to_string());
+                tcb.insert("guest_svn".to_string(), snp.guest_svn.to_string());
+                host_data = Some(hex::encode(snp.host_data));
+                platform = PlatformClaims {
+                    chip_id: Some(hex::encode(snp.chip_id)),
+                    smt_enabled: Some(snp.platform_info.smt_enabled()),
+                    tsme_enabled: Some(snp.platform_info.tsme_enabled()),
+                    debug_enabled: Some(snp.policy.debug_allowed()),
+                    guest_policy: Some(format!("{:#x}", snp.policy.0)),
+                };
+            }
+            TeeEvidence::Tdx(quote) => {
+                tcb.insert("tee_tcb_svn".to_string(), hex::encode(quote.body.tee_tcb_svn));
+                host_data = Some(hex::encode(quote.body.mr_config_id));
+                platform.debug_enabled = Some(quote.body.debug_enabled());
+                platform.guest_policy = Some(hex::encode(quote.body.td_attributes));
+            }
+            TeeEvidence::Vtpm(_) | TeeEvidence::Mock => {}
+        }
+
+        Claims {
+            tee: report.evidence.tee_name().to_string(),
+            measurement: hex::encode(&report.measurement),
+            report_data: hex::encode(report.report_data),
+            host_data,
+            runtime_data: report.runtime_data.clone(),
+            tcb,
+            platform,
+        }
+    }
 }
 
 /// The result of the verification process.
-#[derive(Debug, Serialize, Deserialize)]
-pub enum VerificationResult {
-    Trustworthy(String),
-    Untrustworthy(String),
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerificationResult {
+    pub trustworthy: bool,
+    /// The first failure category, if the report was rejected.
+    pub failure: Option<FailureCode>,
+    /// A human-readable summary of the decision.
+    pub message: String,
+    /// Every check that was performed, in order.
+    pub checks: Vec<CheckResult>,
+    /// Claims extracted once the evidence signature was verified.
+    pub claims: Option<Claims>,
+}
+
+impl VerificationResult {
+    pub fn new() -> Self {
+        VerificationResult {
+            trustworthy: false,
+            failure: None,
+            message: String::new(),
+            checks: Vec::new(),
+            claims: None,
+        }
+    }
+
+    /// Records the outcome of a check. The first failure sets `failure`.
+    pub fn record(&mut self, check: CheckKind, rule: Option<&str>, passed: bool, detail: String) {
+        if !passed && self.failure.is_none() {
+            self.failure = Some(FailureCode::for_check(check));
+        }
+        self.checks.push(CheckResult {
+            check,
+            rule: rule.map(str::to_string),
+            passed,
+            detail,
+        });
+    }
+
+    /// Records a failed check and finalises the result as untrustworthy.
+    pub fn reject(mut self, check: CheckKind, message: String) -> Self {
+        self.record(check, None, false, message.clone());
+        self.trustworthy = false;
+        self.message = message;
+        self
+    }
+
+    /// Like `reject`, but with an explicit failure code.
+    pub fn reject_with(mut self, check: CheckKind, code: FailureCode, message: String) -> Self {
+        self.failure = Some(code);
+        self.reject(check, message)
+    }
+
+    /// The outcomes recorded for one kind of check.
+    pub fn checks_of(&self, check: CheckKind) -> impl Iterator<Item = &CheckResult> {
+        self.checks.iter().filter(move |c| c.check == check)
+    }
+}
+
+impl Default for VerificationResult {
+    fn default() -> Self {
+        VerificationResult::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SNP_FIXTURE: &[u8] = include_bytes!("testdata/snp_report_v3.bin");
+    const TDX_FIXTURE: &[u8] = include_bytes!("testdata/tdx_quote_v4.bin");
+
+    #[test]
+    fn runtime_data_fills_report_data_with_its_sha512() {
+        let data = RuntimeData::new(vec![0x04; 65]).with_claim("image_digest", "sha256:ab").with_claim("app", "db");
+        assert_eq!(data.claims_bytes(), br#"{"app":"db","image_digest":"sha256:ab"}"#);
+
+        let mut expected = Sha512::new();
+        expected.update(b"nonce-1");
+        expected.update([0x04; 65]);
+        expected.update(data.claims_bytes());
+        let expected: [u8; 64] = expected.finalize().into();
+        assert_eq!(data.report_data("nonce-1"), expected);
+
+        // Every input is bound, and none can be shifted into another.
+        let others = [
+            data.report_data("nonce-2"),
+            RuntimeData { public_key: vec![0x05; 65], ..data.clone() }.report_data("nonce-1"),
+            data.clone().with_claim("app", "web").report_data("nonce-1"),
+            RuntimeData::new(vec![0x04; 65]).report_data("nonce-1"),
+            RuntimeData::new(Vec::new()).report_data("nonce-1\u{4}"),
+        ];
+        for other in others {
+            assert_ne!(other, expected);
+        }
+        assert!(RuntimeData::new(Vec::new()).claims_bytes().is_empty());
+    }
+
+    #[test]
+    fn failure_codes_follow_the_failed_check() {
+        for (check, code) in [
+            (CheckKind::Freshness, FailureCode::NonceMismatch),
+            (CheckKind::Evidence, FailureCode::EvidenceInconsistent),
+            (CheckKind::Signature, FailureCode::SignatureInvalid),
+            (CheckKind::Measurement, FailureCode::MeasurementUntrusted),
+            (CheckKind::Tcb, FailureCode::TcbOutdated),
+            (CheckKind::Policy, FailureCode::PolicyViolation),
+        ] {
+            assert_eq!(FailureCode::for_check(check), code);
+            assert_eq!(VerificationResult::new().reject(check, "no".to_string()).failure, Some(code));
+        }
+    }
+
+    #[test]
+    fn the_first_failure_is_kept() {
+        let mut result = VerificationResult::new();
+        result.record(CheckKind::Freshness, None, true, "fresh".to_string());
+        result.record(CheckKind::Tcb, Some("tcb.min_snp"), false, "old".to_string());
+        result.record(CheckKind::Policy, Some("platform.allowed_tees"), false, "tdx".to_string());
+        assert_eq!(result.failure, Some(FailureCode::TcbOutdated));
+        assert_eq!(result.checks.len(), 3);
+        assert_eq!(result.checks_of(CheckKind::Tcb).next().unwrap().rule.as_deref(), Some("tcb.min_snp"));
+    }
+
+    #[test]
+    fn reject_with_overrides_the_failure_code() {
+        let result = VerificationResult::new().reject_with(
+            CheckKind::Freshness,
+            FailureCode::NonceReplayed,
+            "nonce already used".to_string(),
+        );
+        assert!(!result.trustworthy);
+        assert_eq!(result.failure, Some(FailureCode::NonceReplayed));
+        assert_eq!(result.message, "nonce already used");
+        assert_eq!(
+            result.checks,
+            [CheckResult {
+                check: CheckKind::Freshness,
+                rule: None,
+                passed: false,
+                detail: "nonce already used".to_string(),
+            }]
+        );
+        assert!(result.claims.is_none());
+    }
+
+    #[test]
+    fn claims_from_snp_evidence() {
+        let snp = SnpAttestationReport::from_bytes(SNP_FIXTURE).unwrap();
+        let mut report = AttestationReport::from_snp(&snp, Vec::new());
+        report.runtime_data = Some(RuntimeData::new(vec![1, 2, 3]));
+        let claims = Claims::from_report(&report);
+
+        assert_eq!(claims.tee, "snp");
+        assert_eq!(claims.measurement, hex::encode(snp.measurement));
+        assert_eq!(claims.report_data, hex::encode(snp.report_data));
+        assert_eq!(claims.host_data, Some(hex::encode(snp.host_data)));
+        assert_eq!(claims.runtime_data, report.runtime_data);
+        let tcb: Vec<(&str, &str)> = claims.tcb.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(tcb, [("boot_loader", "3"), ("guest_svn", "1"), ("microcode", "115"), ("snp", "8"), ("tee", "0")]);
+        assert_eq!(
+            claims.platform,
+            PlatformClaims {
+                chip_id: Some(hex::encode(snp.chip_id)),
+                smt_enabled: Some(true),
+                tsme_enabled: Some(true),
+                debug_enabled: Some(false),
+                guest_policy: Some("0x30000".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn claims_from_tdx_and_mock_evidence() {
+        let quote = TdxQuote::from_bytes(TDX_FIXTURE).unwrap();
+        let claims = Claims::from_report(&AttestationReport::from_tdx(&quote).unwrap());
+        assert_eq!(claims.tee, "tdx");
+        assert_eq!(claims.measurement, hex::encode(quote.body.mr_td));
+        assert_eq!(claims.host_data, Some(hex::encode(quote.body.mr_config_id)));
+        assert_eq!(claims.tcb["tee_tcb_svn"], hex::encode(quote.body.tee_tcb_svn));
+        assert_eq!(claims.platform.debug_enabled, Some(false));
+        assert_eq!(claims.platform.guest_policy, Some(hex::encode(quote.body.td_attributes)));
+        assert_eq!(claims.platform.chip_id, None);
+
+        let mock = AttestationReport {
+            measurement: vec![0xAA; 4],
+            report_data: [0; 64],
+            signature: Vec::new(),
+            cert_chain: Vec::new(),
+            evidence: TeeEvidence::Mock,
+            runtime_data: None,
+        };
+        let claims = Claims::from_report(&mock);
+        assert_eq!((claims.tee.as_str(), claims.measurement.as_str()), ("mock", "aaaaaaaa"));
+        assert!(claims.tcb.is_empty() && claims.host_data.is_none());
+        assert_eq!(claims.platform, PlatformClaims::default());
+    }
 }
@@ -0,0 +1,593 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use p256::ecdsa::signature::{Signer, Verifier};
+use p256::ecdsa::{Signature, SigningKey, VerifyingKey};
+use p256::elliptic_curve::sec1::{EncodedPoint, FromEncodedPoint};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::attestation_data::{Claims, VerificationResult};
+use crate::kbs_protocol::TeePubKey;
+
+// --- Signed Attestation Results ---
+//
+// Verification results are issued as ES256-signed JWTs in the IETF EAT
+// Attestation Result (EAR) shape: `ear.status` carries the appraisal, the
+// verified evidence claims travel alongside it, and the TEE key bound into
+// report_data is stated as an RFC 7800 `cnf` confirmation key. Resource
+// endpoints accept a token only after `TokenValidator` checks it.
+
+/// The EAR profile identifier carried in `eat_profile`.
+pub const EAR_PROFILE: &str = "tag:github.com,2023:veraison/ear";
+
+/// The only JWS algorithm issued and accepted.
+pub const TOKEN_ALGORITHM: &str = "ES256";
+
+/// Default lifetime of an issued token.
+pub const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(300);
+
+/// Verification keys retained after rotation, including the current one.
+pub const DEFAULT_RETAINED_KEYS: usize = 2;
+
+/// Reasons a token cannot be issued or is rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenError {
+    /// Not a three-part compact JWS, or a part failed to decode.
+    Malformed(String),
+    UnsupportedAlgorithm(String),
+    /// The `kid` is not (or no longer) one of the issuer's keys.
+    UnknownKey(String),
+    BadSignature,
+    Expired,
+    /// `iat` lies in the future.
+    NotYetValid,
+    WrongIssuer(String),
+    WrongAudience(String),
+    /// The appraisal in `ear.status` is not `affirming`.
+    NotAffirming(String),
+    /// A required claim is missing or has an unexpected value.
+    ClaimMismatch(String),
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenError::Malformed(e) => write!(f, "Malformed token: {}", e),
+            TokenError::UnsupportedAlgorithm(a) => write!(f, "Unsupported token algorithm {}", a),
+            TokenError::UnknownKey(kid) => write!(f, "Token signed by unknown key '{}'", kid),
+            TokenError::BadSignature => write!(f, "Token signature is invalid"),
+            TokenError::Expired => write!(f, "Token has expired"),
+            TokenError::NotYetValid => write!(f, "Token is not valid yet"),
+            TokenError::WrongIssuer(i) => write!(f, "Token issued by unexpected issuer '{}'", i),
+            TokenError::WrongAudience(a) => write!(f, "Token is for audience '{}'", a),
+            TokenError::NotAffirming(s) => write!(f, "Attestation result is '{}'", s),
+            TokenError::ClaimMismatch(e) => write!(f, "Token claim mismatch: {}", e),
+        }
+    }
+}
+
+impl Error for TokenError {}
+
+/// The EAR appraisal of the attester.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EarStatus {
+    Affirming,
+    Contraindicated,
+}
+
+/// RFC 7800 proof-of-possession key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Confirmation {
+    pub jwk: TeePubKey,
+}
+
+/// The payload of an attestation token.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttestationResultClaims {
+    pub iss: String,
+    pub aud: String,
+    /// The attested session or workload the result applies to.
+    pub sub: String,
+    pub iat: u64,
+    pub exp: u64,
+    pub jti: String,
+    pub eat_profile: String,
+    #[serde(rename = "ear.status")]
+    pub status: EarStatus,
+    /// The claims the verifier extracted from authenticated evidence.
+    #[serde(rename = "ear.attester-claims", default, skip_serializing_if = "Option::is_none")]
+    pub tee: Option<Claims>,
+    /// The TEE key bound into report_data, to which secrets may be wrapped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<Confirmation>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JwsHeader {
+    alg: String,
+    typ: String,
+    kid: String,
+}
+
+/// A public signing key in JWK form, as published in a JWKS.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenJwk {
+    pub kty: String,
+    pub crv: String,
+    pub alg: String,
+    pub kid: String,
+    pub x: String,
+    pub y: String,
+}
+
+/// A JSON Web Key Set of the issuer's current and retained keys.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Jwks {
+    pub keys: Vec<TokenJwk>,
+}
+
+impl TokenJwk {
+    fn from_key(kid: &str, key: &VerifyingKey) -> Self {
+        let point = key.to_encoded_point(false);
+        TokenJwk {
+            kty: "EC".to_string(),
+            crv: "P-256".to_string(),
+            alg: TOKEN_ALGORITHM.to_string(),
+            kid: kid.to_string(),
+            x: URL_SAFE_NO_PAD.encode(point.x().map(|x| &x[..]).unwrap_or_default()),
+            y: URL_SAFE_NO_PAD.encode(point.y().map(|y| &y[..]).unwrap_or_default()),
+        }
+    }
+
+    fn to_key(&self) -> Result<VerifyingKey, TokenError> {
+        let invalid = || TokenError::Malformed(format!("invalid JWK '{}'", self.kid));
+        if self.kty != "EC" || self.crv != "P-256" {
+            return Err(invalid());
+        }
+        let x = URL_SAFE_NO_PAD.decode(&self.x).map_err(|_| invalid())?;
+        let y = URL_SAFE_NO_PAD.decode(&self.y).map_err(|_| invalid())?;
+        if x.len() != 32 || y.len() != 32 {
+            return Err(invalid());
+        }
+        let point = EncodedPoint::<p256::NistP256>::from_affine_coordinates(
+            x.as_slice().into(),
+            y.as_slice().into(),
+            false,
+        );
+        let public: Option<p256::PublicKey> = p256::PublicKey::from_encoded_point(&point).into();
+        public.map(VerifyingKey::from).ok_or_else(invalid)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn encode_part<T: Serialize>(value: &T) -> Result<String, TokenError> {
+    let json = serde_json::to_vec(value).map_err(|e| TokenError::Malformed(e.to_string()))?;
+    Ok(URL_SAFE_NO_PAD.encode(json))
+}
+
+fn random_id() -> String {
+    let mut bytes = [0u8; 8];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+// --- Issuing ---
+
+/// Mints attestation tokens with a rotatable set of ES256 keys.
+pub struct TokenIssuer {
+    issuer: String,
+    ttl: Duration,
+    retained: usize,
+    /// Oldest first; the last key signs new tokens.
+    keys: Vec<(String, SigningKey)>,
+}
+
+impl TokenIssuer {
+    /// Creates an issuer with one freshly generated key.
+    pub fn new(issuer: &str, ttl: Duration) -> Self {
+        let mut token_issuer = TokenIssuer {
+            issuer: issuer.to_string(),
+            ttl,
+            retained: DEFAULT_RETAINED_KEYS,
+            keys: Vec::new(),
+        };
+        token_issuer.rotate();
+        token_issuer
+    }
+
+    /// Keeps `count` keys (the current one included) in the JWKS.
+    pub fn with_retained_keys(mut self, count: usize) -> Self {
+        self.retained = count.max(1);
+        self
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// The `kid` of the key currently signing tokens.
+    pub fn current_kid(&self) -> &str {
+        self.keys.last().map(|(kid, _)| kid.as_str()).unwrap_or_default()
+    }
+
+    /// Starts signing with a new key. The previous keys stay in the JWKS (up
+    /// to the retention limit) so tokens they signed remain valid until expiry.
+    pub fn rotate(&mut self) -> &str {
+        self.keys.push((random_id(), SigningKey::random(&mut OsRng)));
+        let excess = self.keys.len().saturating_sub(self.retained);
+        self.keys.drain(..excess);
+        self.current_kid()
+    }
+
+    /// The public keys tokens may be verified with.
+    pub fn jwks(&self) -> Jwks {
+        Jwks {
+            keys: self
+                .keys
+                .iter()
+                .map(|(kid, key)| TokenJwk::from_key(kid, key.verifying_key()))
+                .collect(),
+        }
+    }
+
+    /// Builds the claims for a verification result.
+    pub fn claims_for(
+        &self,
+        subject: &str,
+        audience: &str,
+        result: &VerificationResult,
+        tee_pubkey: Option<&TeePubKey>,
+    ) -> AttestationResultClaims {
+        let iat = unix_now();
+        AttestationResultClaims {
+            iss: self.issuer.clone(),
+            aud: audience.to_string(),
+            sub: subject.to_string(),
+            iat,
+            exp: iat + self.ttl.as_secs(),
+            jti: random_id(),
+            eat_profile: EAR_PROFILE.to_string(),
+            status: if result.trustworthy { EarStatus::Affirming } else { EarStatus::Contraindicated },
+            tee: result.claims.clone(),
+            cnf: tee_pubkey.map(|jwk| Confirmation { jwk: jwk.clone() }),
+        }
+    }
+
+    /// Signs `claims` as a compact JWS with the current key.
+    pub fn sign(&self, claims: &AttestationResultClaims) -> Result<String, TokenError> {
+        let (kid, key) = self
+            .keys
+            .last()
+            .ok_or_else(|| TokenError::UnknownKey(String::new()))?;
+        let header = JwsHeader {
+            alg: TOKEN_ALGORITHM.to_string(),
+            typ: "JWT".to_string(),
+            kid: kid.clone(),
+        };
+        let signing_input = format!("{}.{}", encode_part(&header)?, encode_part(claims)?);
+        let signature: Signature = key.sign(signing_input.as_bytes());
+        Ok(format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature.to_bytes())))
+    }
+
+    /// Mints a token for a verification result.
+    pub fn issue(
+        &self,
+        subject: &str,
+        audience: &str,
+        result: &VerificationResult,
+        tee_pubkey: Option<&TeePubKey>,
+    ) -> Result<String, TokenError> {
+        self.sign(&self.claims_for(subject, audience, result, tee_pubkey))
+    }
+}
+
+// --- Validating ---
+
+/// Checks tokens presented to a resource endpoint.
+#[derive(Debug, Clone)]
+pub struct TokenValidator {
+    issuer: String,
+    audience: String,
+    keys: Jwks,
+    /// Clock skew tolerated on `exp` and `iat`.
+    pub leeway: Duration,
+    /// If set, the attested measurement must be one of these (hex).
+    pub allowed_measurements: Vec<String>,
+    /// If set, the evidence must come from one of these TEEs.
+    pub allowed_tees: Vec<String>,
+}
+
+impl TokenValidator {
+    pub fn new(issuer: &str, audience: &str, keys: Jwks) -> Self {
+        TokenValidator {
+            issuer: issuer.to_string(),
+            audience: audience.to_string(),
+            keys,
+            leeway: Duration::from_secs(30),
+            allowed_measurements: Vec::new(),
+            allowed_tees: Vec::new(),
+        }
+    }
+
+    /// A validator trusting the issuer's current and retained keys.
+    pub fn for_issuer(issuer: &TokenIssuer, audience: &str) -> Self {
+        TokenValidator::new(issuer.issuer(), audience, issuer.jwks())
+    }
+
+    /// Verifies the signature and every claim, returning the payload.
+    pub fn validate(&self, token: &str) -> Result<AttestationResultClaims, TokenError> {
+        let mut parts = token.split('.');
+        let (Some(header_b64), Some(claims_b64), Some(signature_b64), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(TokenError::Malformed("expected header.payload.signature".to_string()));
+        };
+        let decode = |part: &str| {
+            URL_SAFE_NO_PAD
+                .decode(part)
+                .map_err(|e| TokenError::Malformed(e.to_string()))
+        };
+
+        let header: JwsHeader = serde_json::from_slice(&decode(header_b64)?)
+            .map_err(|e| TokenError::Malformed(e.to_string()))?;
+        if header.alg != TOKEN_ALGORITHM {
+            return Err(TokenError::UnsupportedAlgorithm(header.alg));
+        }
+        let jwk = self
+            .keys
+            .keys
+            .iter()
+            .find(|k| k.kid == header.kid)
+            .ok_or_else(|| TokenError::UnknownKey(header.kid.clone()))?;
+        let signature = Signature::from_slice(&decode(signature_b64)?).map_err(|_| TokenError::BadSignature)?;
+        jwk.to_key()?
+            .verify(format!("{}.{}", header_b64, claims_b64).as_bytes(), &signature)
+            .map_err(|_| TokenError::BadSignature)?;
+
+        let claims: AttestationResultClaims = serde_json::from_slice(&decode(claims_b64)?)
+            .map_err(|e| TokenError::Malformed(e.to_string()))?;
+        self.check_claims(&claims)?;
+        Ok(claims)
+    }
+
+    fn check_claims(&self, claims: &AttestationResultClaims) -> Result<(), TokenError> {
+        let now = unix_now();
+        let leeway = self.leeway.as_secs();
+        if claims.exp + leeway <= now {
+            return Err(TokenError::Expired);
+        }
+        if claims.iat > now + leeway {
+            return Err(TokenError::NotYetValid);
+        }
+        if claims.iss != self.issuer {
+            return Err(TokenError::WrongIssuer(claims.iss.clone()));
+        }
+        if claims.aud != self.audience {
+            return Err(TokenError::WrongAudience(claims.aud.clone()));
+        }
+        if claims.eat_profile != EAR_PROFILE {
+            return Err(TokenError::ClaimMismatch(format!("eat_profile '{}'", claims.eat_profile)));
+        }
+        if claims.status != EarStatus::Affirming {
+            return Err(TokenError::NotAffirming(format!("{:?}", claims.status).to_lowercase()));
+        }
+
+        let tee = claims
+            .tee
+            .as_ref()
+            .ok_or_else(|| TokenError::ClaimMismatch("no attester claims".to_string()))?;
+        if !self.allowed_measurements.is_empty()
+            && !self.allowed_measurements.iter().any(|m| m.eq_ignore_ascii_case(&tee.measurement))
+        {
+            return Err(TokenError::ClaimMismatch(format!("measurement {}", tee.measurement)));
+        }
+        if !self.allowed_tees.is_empty() && !self.allowed_tees.contains(&tee.tee) {
+            return Err(TokenError::ClaimMismatch(format!("tee {}", tee.tee)));
+        }
+        Ok(())
+    }
+}
+
+// --- Reading ---
+
+/// Decodes the payload of a token without verifying it. Only for a holder
+/// inspecting its own token, e.g. to renew it before `exp`; never for
+/// authorization.
+pub fn unverified_claims(token: &str) -> Result<AttestationResultClaims, TokenError> {
+    let claims_b64 = token
+        .split('.')
+        .nth(1)
+        .ok_or_else(|| TokenError::Malformed("expected header.payload.signature".to_string()))?;
+    let json = URL_SAFE_NO_PAD
+        .decode(claims_b64)
+        .map_err(|e| TokenError::Malformed(e.to_string()))?;
+    serde_json::from_slice(&json).map_err(|e| TokenError::Malformed(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation_data::{AttestationReport, TeeEvidence};
+
+    const ISSUER: &str = "test-kbs";
+    const AUDIENCE: &str = "kbs-resource";
+
+    fn affirming_result() -> VerificationResult {
+        let report = AttestationReport {
+            measurement: vec![0xAB; 48],
+            report_data: [0x01; 64],
+            signature: Vec::new(),
+            cert_chain: Vec::new(),
+            evidence: TeeEvidence::Mock,
+            runtime_data: None,
+        };
+        let mut result = VerificationResult::new();
+        result.trustworthy = true;
+        result.claims = Some(Claims::from_report(&report));
+        result
+    }
+
+    fn tee_pubkey() -> TeePubKey {
+        TeePubKey::from_p256(&p256::SecretKey::random(&mut OsRng).public_key())
+    }
+
+    /// Signs `claims` after letting the test adjust them.
+    fn signed_with(issuer: &TokenIssuer, adjust: impl FnOnce(&mut AttestationResultClaims)) -> String {
+        let mut claims = issuer.claims_for("session-1", AUDIENCE, &affirming_result(), None);
+        adjust(&mut claims);
+        issuer.sign(&claims).unwrap()
+    }
+
+    /// Replaces one dot-separated part of a compact JWS.
+    fn with_part(token: &str, index: usize, part: String) -> String {
+        let mut parts: Vec<String> = token.split('.').map(str::to_string).collect();
+        parts[index] = part;
+        parts.join(".")
+    }
+
+    #[test]
+    fn issued_tokens_validate() {
+        let issuer = TokenIssuer::new(ISSUER, DEFAULT_TOKEN_TTL);
+        let pubkey = tee_pubkey();
+        let token = issuer.issue("session-1", AUDIENCE, &affirming_result(), Some(&pubkey)).unwrap();
+
+        let claims = TokenValidator::for_issuer(&issuer, AUDIENCE).validate(&token).unwrap();
+        assert_eq!(claims.iss, ISSUER);
+        assert_eq!(claims.aud, AUDIENCE);
+        assert_eq!(claims.sub, "session-1");
+        assert_eq!(claims.status, EarStatus::Affirming);
+        assert_eq!(claims.exp - claims.iat, DEFAULT_TOKEN_TTL.as_secs());
+        assert_eq!(claims.tee.as_ref().unwrap().measurement, hex::encode([0xAB; 48]));
+        assert_eq!(claims.cnf.unwrap().jwk, pubkey);
+        assert_eq!(unverified_claims(&token).unwrap().jti, claims.jti);
+
+        let header = URL_SAFE_NO_PAD.decode(token.split('.').next().unwrap()).unwrap();
+        let header: JwsHeader = serde_json::from_slice(&header).unwrap();
+        assert_eq!(header.alg, TOKEN_ALGORITHM);
+        assert_eq!(header.kid, issuer.current_kid());
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected_after_the_leeway() {
+        let issuer = TokenIssuer::new(ISSUER, DEFAULT_TOKEN_TTL);
+        let mut validator = TokenValidator::for_issuer(&issuer, AUDIENCE);
+        let now = unix_now();
+
+        let token = signed_with(&issuer, |c| {
+            c.iat = now - 600;
+            c.exp = now - 300;
+        });
+        assert_eq!(validator.validate(&token), Err(TokenError::Expired));
+
+        // Within the tolerated clock skew, then past it.
+        let token = signed_with(&issuer, |c| c.exp = now - 10);
+        validator.validate(&token).unwrap();
+        validator.leeway = Duration::ZERO;
+        assert_eq!(validator.validate(&token), Err(TokenError::Expired));
+
+        let token = signed_with(&issuer, |c| c.iat = now + 600);
+        assert_eq!(validator.validate(&token), Err(TokenError::NotYetValid));
+    }
+
+    #[test]
+    fn tokens_from_another_key_are_rejected() {
+        let issuer = TokenIssuer::new(ISSUER, DEFAULT_TOKEN_TTL);
+        let impostor = TokenIssuer::new(ISSUER, DEFAULT_TOKEN_TTL);
+        let validator = TokenValidator::for_issuer(&issuer, AUDIENCE);
+
+        let token = impostor.issue("session-1", AUDIENCE, &affirming_result(), None).unwrap();
+        assert_eq!(validator.validate(&token), Err(TokenError::UnknownKey(impostor.current_kid().to_string())));
+
+        // Claiming the issuer's kid does not help without its private key.
+        let mut jwk = impostor.jwks().keys.remove(0);
+        jwk.kid = issuer.current_kid().to_string();
+        let forged = TokenValidator::new(ISSUER, AUDIENCE, Jwks { keys: vec![jwk] });
+        let token = issuer.issue("session-1", AUDIENCE, &affirming_result(), None).unwrap();
+        assert_eq!(forged.validate(&token), Err(TokenError::BadSignature));
+    }
+
+    #[test]
+    fn rotated_keys_stay_valid_until_retired() {
+        let mut issuer = TokenIssuer::new(ISSUER, DEFAULT_TOKEN_TTL);
+        let old_kid = issuer.current_kid().to_string();
+        let old_token = issuer.issue("session-1", AUDIENCE, &affirming_result(), None).unwrap();
+
+        let new_kid = issuer.rotate().to_string();
+        assert_ne!(new_kid, old_kid);
+        let kids: Vec<String> = issuer.jwks().keys.into_iter().map(|k| k.kid).collect();
+        assert_eq!(kids, [old_kid.clone(), new_kid.clone()]);
+
+        // Tokens signed before the rotation are still accepted, and new ones
+        // are signed with the new key.
+        let validator = TokenValidator::for_issuer(&issuer, AUDIENCE);
+        validator.validate(&old_token).unwrap();
+        let new_token = issuer.issue("session-2", AUDIENCE, &affirming_result(), None).unwrap();
+        assert_eq!(validator.validate(&new_token).unwrap().sub, "session-2");
+
+        // Once the old key falls out of the retention window it is unknown.
+        issuer.rotate();
+        let validator = TokenValidator::for_issuer(&issuer, AUDIENCE);
+        assert_eq!(validator.validate(&old_token), Err(TokenError::UnknownKey(old_kid)));
+        validator.validate(&new_token).unwrap();
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let issuer = TokenIssuer::new(ISSUER, DEFAULT_TOKEN_TTL);
+        let validator = TokenValidator::for_issuer(&issuer, AUDIENCE);
+        let token = issuer.issue("session-1", AUDIENCE, &affirming_result(), None).unwrap();
+
+        // A re-encoded payload for another session keeps the old signature.
+        let mut claims = unverified_claims(&token).unwrap();
+        claims.sub = "session-2".to_string();
+        let tampered = with_part(&token, 1, encode_part(&claims).unwrap());
+        assert_eq!(validator.validate(&tampered), Err(TokenError::BadSignature));
+
+        let mut signature = URL_SAFE_NO_PAD.decode(token.split('.').nth(2).unwrap()).unwrap();
+        signature[10] ^= 1;
+        let tampered = with_part(&token, 2, URL_SAFE_NO_PAD.encode(signature));
+        assert_eq!(validator.validate(&tampered), Err(TokenError::BadSignature));
+
+        let kid = issuer.current_kid().to_string();
+        let header = JwsHeader { alg: "none".to_string(), typ: "JWT".to_string(), kid };
+        let unsigned = with_part(&token, 0, encode_part(&header).unwrap());
+        assert_eq!(validator.validate(&unsigned), Err(TokenError::UnsupportedAlgorithm("none".to_string())));
+
+        assert!(matches!(validator.validate("not-a-token"), Err(TokenError::Malformed(_))));
+        assert!(matches!(validator.validate(&format!("{}.extra", token)), Err(TokenError::Malformed(_))));
+    }
+
+    #[test]
+    fn claims_are_checked() {
+        let issuer = TokenIssuer::new(ISSUER, DEFAULT_TOKEN_TTL);
+        let mut validator = TokenValidator::for_issuer(&issuer, AUDIENCE);
+
+        let token = signed_with(&issuer, |c| c.aud = "other-service".to_string());
+        assert_eq!(validator.validate(&token), Err(TokenError::WrongAudience("other-service".to_string())));
+        let token = signed_with(&issuer, |c| c.iss = "other-kbs".to_string());
+        assert_eq!(validator.validate(&token), Err(TokenError::WrongIssuer("other-kbs".to_string())));
+        let token = signed_with(&issuer, |c| c.status = EarStatus::Contraindicated);
+        assert_eq!(validator.validate(&token), Err(TokenError::NotAffirming("contraindicated".to_string())));
+        let token = signed_with(&issuer, |c| c.tee = None);
+        assert!(matches!(validator.validate(&token), Err(TokenError::ClaimMismatch(_))));
+
+        let token = signed_with(&issuer, |_| {});
+        validator.allowed_tees = vec!["snp".to_string()];
+        assert_eq!(validator.validate(&token), Err(TokenError::ClaimMismatch("tee mock".to_string())));
+        validator.allowed_tees = vec!["mock".to_string()];
+        validator.allowed_measurements = vec![hex::encode([0xCD; 48])];
+        assert!(matches!(validator.validate(&token), Err(TokenError::ClaimMismatch(_))));
+        validator.allowed_measurements = vec![hex::encode_upper([0xAB; 48])];
+        validator.validate(&token).unwrap();
+    }
+}
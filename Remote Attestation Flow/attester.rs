@@ -1,40 +1,181 @@
-// In a real CVM, this would interact with /dev/sev-guest or a vTPM.
+// In a real CVM, this interacts with /dev/sev-guest, /dev/tdx_guest or a vTPM.
 pub mod attester {
-    use super::*;
-
-    /// Simulates calling the TEE hardware to generate a signed report.
-    ///
-    /// In a real implementation:
-    /// 1. The `report_data` field (which includes the nonce hash) would be
-    ///    passed to the hardware instruction (e.g., SNP_GET_REPORT).
-    /// 2. The hardware returns the report, signed by the TEE key (e.g., VCEK).
-    pub fn generate_evidence(challenge: &AttestationChallenge) -> AttestationReport {
-        println!("\n[Attester] Received Challenge: {}", challenge.nonce);
-
-        // 1. Calculate a deterministic measurement (hash of the running image)
-        //    (In a real scenario, this would be retrieved from the TEE hardware).
-        let mut hasher = Sha256::new();
-        hasher.update(b"VM_BOOT_MEASUREMENT_HASH"); // Mocked hash
-        let measurement: [u8; 32] = hasher.finalize().into();
-
-        // 2. Prepare the REPORT_DATA (must contain a hash of the nonce for binding)
+    use crate::attestation_data::*;
+    #[cfg(feature = "snp")]
+    use crate::sev_guest::{SevSnpAttester, SEV_GUEST_DEVICE};
+    #[cfg(feature = "sim")]
+    use crate::simulator::SimulatedAttester;
+    #[cfg(feature = "tdx")]
+    use crate::tdx_guest::{TdxAttester, TDX_GUEST_DEVICE};
+    #[cfg(feature = "vtpm")]
+    use crate::vtpm::{VtpmAttester, TPM_DEVICE};
+    use sha2::{Digest, Sha256};
+    use std::error::Error;
+    use std::fmt;
+    use std::path::Path;
+
+    /// Environment variable used to force a specific attester backend.
+    pub const ATTESTER_BACKEND_ENV: &str = "ATTESTER_BACKEND";
+
+    /// Errors raised while collecting evidence from a TEE backend.
+    #[derive(Debug)]
+    pub enum AttesterError {
+        /// The device node for the requested backend does not exist.
+        DeviceUnavailable(String),
+        /// An I/O or ioctl call on the device failed.
+        Io(std::io::Error),
+        /// The TEE firmware rejected the request with the given error code.
+        Firmware(u64),
+        /// The device returned data that could not be decoded.
+        MalformedResponse(String),
+        /// The backend name is unknown, or no backend could be detected.
+        UnknownBackend(String),
+        /// The simulator could not generate its signing keys.
+        KeyGeneration(String),
+        /// The certificate chain vouching for the backend's signing key is missing.
+        CertificatesUnavailable(String),
+    }
+
+    impl fmt::Display for AttesterError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                AttesterError::DeviceUnavailable(d) => write!(f, "TEE device {} is not available", d),
+                AttesterError::Io(e) => write!(f, "TEE device I/O error: {}", e),
+                AttesterError::Firmware(code) => write!(f, "TEE firmware returned error {:#x}", code),
+                AttesterError::MalformedResponse(e) => write!(f, "Malformed evidence from TEE: {}", e),
+                AttesterError::UnknownBackend(b) => write!(f, "Unknown attester backend: {}", b),
+                AttesterError::KeyGeneration(e) => write!(f, "Simulator key generation failed: {}", e),
+                AttesterError::CertificatesUnavailable(e) => write!(f, "Signing key certificates unavailable: {}", e),
+            }
+        }
+    }
+
+    impl Error for AttesterError {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            match self {
+                AttesterError::Io(e) => Some(e),
+                _ => None,
+            }
+        }
+    }
+
+    impl From<std::io::Error> for AttesterError {
+        fn from(error: std::io::Error) -> Self {
+            AttesterError::Io(error)
+        }
+    }
+
+    /// A source of hardware-signed evidence for the running guest.
+    pub trait Attester: Send + Sync {
+        /// A short, stable backend name (`snp`, `tdx`, `vtpm`, `sim`).
+        fn name(&self) -> &'static str;
+
+        /// The TEE type of the evidence this backend produces, as announced
+        /// to a KBS in `auth`. The KBS rejects evidence of any other type.
+        fn tee(&self) -> &'static str {
+            self.name()
+        }
+
+        /// Asks the TEE for a signed report carrying exactly `report_data`.
+        ///
+        /// Backends implement this; callers should use `generate_evidence`
+        /// or `generate_bound_evidence`, which derive `report_data` from the
+        /// challenge.
+        fn request_evidence(
+            &self,
+            challenge: &AttestationChallenge,
+            report_data: [u8; 64],
+        ) -> Result<AttestationReport, AttesterError>;
+
+        /// Asks the TEE for a signed report over the challenge nonce alone
+        /// (see `report_data_for`).
+        fn generate_evidence(
+            &self,
+            challenge: &AttestationChallenge,
+        ) -> Result<AttestationReport, AttesterError> {
+            self.request_evidence(challenge, report_data_for(challenge))
+        }
+
+        /// Asks the TEE for a signed report that also binds an ephemeral
+        /// public key and runtime claims, as
+        /// SHA-512(nonce || public_key || claims).
+        ///
+        /// The runtime data travels with the report so the verifier can
+        /// recompute the binding; a relying party can then encrypt secrets to
+        /// `public_key` knowing only the attested VM holds the private half.
+        fn generate_bound_evidence(
+            &self,
+            challenge: &AttestationChallenge,
+            runtime_data: RuntimeData,
+        ) -> Result<AttestationReport, AttesterError> {
+            let report_data = runtime_data.report_data(&challenge.nonce);
+            let mut report = self.request_evidence(challenge, report_data)?;
+            report.runtime_data = Some(runtime_data);
+            Ok(report)
+        }
+
+        /// The PEM root certificate for backends whose keys are not issued by
+        /// a hardware vendor (i.e. the simulator). Verifiers must pin it
+        /// explicitly for such evidence to be trusted.
+        fn local_root_pem(&self) -> Option<String> {
+            None
+        }
+    }
+
+    /// Computes the REPORT_DATA for a challenge with no runtime data bound:
+    /// SHA-256(nonce) in the first 32 bytes and zeroes in the rest. See
+    /// `RuntimeData::report_data` for the full 64-byte binding.
+    pub fn report_data_for(challenge: &AttestationChallenge) -> [u8; 64] {
         let mut report_data: [u8; 64] = [0; 64];
         let mut nonce_hasher = Sha256::new();
         nonce_hasher.update(challenge.nonce.as_bytes());
         report_data[0..32].copy_from_slice(&nonce_hasher.finalize());
-        // The rest of the report_data is often used for other claims (like a vTPM AK)
+        report_data
+    }
 
-        // 3. Simulate hardware signing (Mocked signature and cert chain)
-        let signature = b"MOCKED_HARDWARE_SIGNATURE".to_vec();
-        let cert_chain = b"MOCKED_VCEK_CERTIFICATE_CHAIN".to_vec();
+    /// Creates a backend by name. Backends whose cargo feature is disabled
+    /// are reported as unknown.
+    pub fn from_name(name: &str) -> Result<Box<dyn Attester>, AttesterError> {
+        match name {
+            #[cfg(feature = "snp")]
+            "snp" => Ok(Box::new(SevSnpAttester::open()?)),
+            #[cfg(feature = "tdx")]
+            "tdx" => Ok(Box::new(TdxAttester::open()?)),
+            #[cfg(feature = "vtpm")]
+            "vtpm" => Ok(Box::new(VtpmAttester::open()?)),
+            #[cfg(feature = "sim")]
+            "sim" => Ok(Box::new(SimulatedAttester::from_env()?)),
+            other => Err(AttesterError::UnknownBackend(other.to_string())),
+        }
+    }
 
-        println!("[Attester] Generated Report with Measurement: {:?}", &measurement[..8]);
+    /// Probes the guest for TEE devices, preferring confidential-computing
+    /// hardware over a vTPM. The simulator is never selected implicitly.
+    pub fn detect() -> Result<Box<dyn Attester>, AttesterError> {
+        let candidates: &[(&str, &str)] = &[
+            #[cfg(feature = "snp")]
+            (SEV_GUEST_DEVICE, "snp"),
+            #[cfg(feature = "tdx")]
+            (TDX_GUEST_DEVICE, "tdx"),
+            #[cfg(feature = "vtpm")]
+            (TPM_DEVICE, "vtpm"),
+        ];
+        for &(device, name) in candidates {
+            if Path::new(device).exists() {
+                println!("[Attester] Detected {} backend via {}", name, device);
+                return from_name(name);
+            }
+        }
+        Err(AttesterError::UnknownBackend(
+            "no TEE device found (set ATTESTER_BACKEND=sim to use the simulator)".to_string(),
+        ))
+    }
 
-        AttestationReport {
-            measurement,
-            report_data,
-            signature,
-            cert_chain,
+    /// Selects the backend named by `ATTESTER_BACKEND`, or auto-detects one.
+    pub fn from_env() -> Result<Box<dyn Attester>, AttesterError> {
+        match std::env::var(ATTESTER_BACKEND_ENV) {
+            Ok(name) => from_name(&name),
+            Err(_) => detect(),
         }
     }
 }
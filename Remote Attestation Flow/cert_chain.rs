@@ -0,0 +1,778 @@
+use der::asn1::{ObjectIdentifier, OctetString};
+use der::{Decode, Encode, Reader, SliceReader};
+use p256::ecdsa::signature::Verifier;
+use rsa::pkcs1::DecodeRsaPublicKey;
+use sha2::{Digest, Sha256, Sha384};
+use std::error::Error;
+use std::fmt;
+use std::time::SystemTime;
+use x509_cert::ext::pkix::{BasicConstraints, KeyUsage};
+use x509_cert::Certificate;
+
+use crate::snp_report::SnpAttestationReport;
+use crate::tdx_quote::TdxQuote;
+use crate::vtpm::{TpmQuote, TPM_ALG_ECDSA, TPM_ALG_RSASSA};
+
+// --- Well-Known Locations and OIDs ---
+
+/// Default location of the pinned AMD root (ARK) certificates, as published by
+/// the AMD Key Distribution Service at `/vcek/v1/{product}/cert_chain`.
+pub const AMD_ROOT_CERTS_PATH: &str = "/etc/attester_flow/amd_ark.pem";
+
+/// Default location of the pinned Intel SGX Root CA certificate.
+pub const INTEL_ROOT_CERTS_PATH: &str = "/etc/attester_flow/intel_sgx_root_ca.pem";
+
+const OID_ECDSA_WITH_SHA256: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.2");
+const OID_ECDSA_WITH_SHA384: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.3");
+const OID_RSASSA_PSS: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.10");
+
+// AMD VCEK extensions carrying the TCB the key was derived for.
+const OID_AMD_BL_SPL: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.3704.1.3.1");
+const OID_AMD_TEE_SPL: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.3704.1.3.2");
+const OID_AMD_SNP_SPL: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.3704.1.3.3");
+const OID_AMD_UCODE_SPL: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.3704.1.3.8");
+const OID_AMD_HW_ID: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.3704.1.4");
+
+// Standard extensions every issuing certificate must carry.
+const OID_BASIC_CONSTRAINTS: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.5.29.19");
+const OID_KEY_USAGE: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.5.29.15");
+
+/// `SIGNING_KEY` value in the report's key flags for a VLEK-signed report.
+/// VLEKs are not bound to a chip and carry no hardware ID extension.
+const SNP_SIGNING_KEY_VLEK: u32 = 1;
+
+/// Reasons a certificate chain or the evidence signature it backs can be rejected.
+#[derive(Debug)]
+pub enum CertChainError {
+    /// The chain could not be decoded as PEM or DER X.509.
+    Parse(String),
+    /// No certificates were supplied with the evidence.
+    EmptyChain,
+    /// The chain does not terminate in a pinned root certificate.
+    UntrustedRoot(String),
+    /// A certificate's signature did not verify under its issuer's key.
+    BadCertificateSignature(String),
+    /// A certificate is outside of its validity period.
+    Expired(String),
+    /// A key or signature algorithm the verifier does not implement.
+    UnsupportedAlgorithm(String),
+    /// A certificate that issued another one is not allowed to act as a CA.
+    NotACertificateAuthority(String),
+    /// The VCEK was issued for a different TCB or chip than the report claims.
+    TcbMismatch(String),
+    /// The VCEK lacks one of the AMD extensions binding it to a TCB or chip.
+    MissingExtension(String),
+    /// The signature over the evidence body did not verify.
+    EvidenceSignatureInvalid,
+    /// The TDX QE report does not bind the quote's attestation key.
+    QeReportBindingInvalid,
+    /// The evidence type carries no hardware signature at all.
+    NoHardwareSignature,
+}
+
+impl fmt::Display for CertChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CertChainError::Parse(e) => write!(f, "Could not parse certificate chain: {}", e),
+            CertChainError::EmptyChain => write!(f, "Certificate chain is empty"),
+            CertChainError::UntrustedRoot(s) => write!(f, "Chain does not end in a pinned root (last: {})", s),
+            CertChainError::BadCertificateSignature(s) => write!(f, "Invalid signature on certificate {}", s),
+            CertChainError::Expired(s) => write!(f, "Certificate {} is not currently valid", s),
+            CertChainError::UnsupportedAlgorithm(a) => write!(f, "Unsupported algorithm: {}", a),
+            CertChainError::NotACertificateAuthority(s) => write!(f, "Certificate {} is not a CA", s),
+            CertChainError::TcbMismatch(field) => write!(f, "VCEK does not match the reported {}", field),
+            CertChainError::MissingExtension(field) => write!(f, "VCEK carries no {} extension", field),
+            CertChainError::EvidenceSignatureInvalid => write!(f, "Evidence signature does not verify"),
+            CertChainError::QeReportBindingInvalid => write!(f, "QE report does not bind the attestation key"),
+            CertChainError::NoHardwareSignature => write!(f, "Evidence carries no hardware signature"),
+        }
+    }
+}
+
+impl Error for CertChainError {}
+
+impl From<der::Error> for CertChainError {
+    fn from(e: der::Error) -> Self {
+        CertChainError::Parse(e.to_string())
+    }
+}
+
+// --- Trust Anchors ---
+
+/// The set of pinned root certificates a chain must terminate in.
+#[derive(Debug, Clone, Default)]
+pub struct TrustAnchors {
+    roots: Vec<Certificate>,
+}
+
+impl TrustAnchors {
+    pub fn new() -> Self {
+        TrustAnchors::default()
+    }
+
+    /// Pins every certificate found in a PEM bundle.
+    pub fn from_pem(pem: &[u8]) -> Result<Self, CertChainError> {
+        let roots = Certificate::load_pem_chain(pem)?;
+        Ok(TrustAnchors { roots })
+    }
+
+    /// Loads and pins the roots from one or more PEM files.
+    pub fn load<P: AsRef<std::path::Path>>(paths: &[P]) -> Result<Self, CertChainError> {
+        let mut anchors = TrustAnchors::new();
+        for path in paths {
+            let pem = std::fs::read(path.as_ref())
+                .map_err(|e| CertChainError::Parse(format!("{}: {}", path.as_ref().display(), e)))?;
+            anchors.roots.extend(Certificate::load_pem_chain(&pem)?);
+        }
+        Ok(anchors)
+    }
+
+    /// Loads the AMD and Intel roots from whichever default locations exist.
+    pub fn load_default() -> Result<Self, CertChainError> {
+        let present: Vec<&str> = [AMD_ROOT_CERTS_PATH, INTEL_ROOT_CERTS_PATH]
+            .into_iter()
+            .filter(|path| std::path::Path::new(path).exists())
+            .collect();
+        TrustAnchors::load(&present)
+    }
+
+    pub fn add_root(&mut self, root: Certificate) {
+        self.roots.push(root);
+    }
+
+    /// Pins every certificate in a PEM bundle in addition to the existing roots.
+    pub fn add_pem(&mut self, pem: &[u8]) -> Result<(), CertChainError> {
+        self.roots.extend(Certificate::load_pem_chain(pem)?);
+        Ok(())
+    }
+
+    /// Finds the pinned root that is either `cert` itself or its issuer.
+    fn anchor_for(&self, cert: &Certificate) -> Option<&Certificate> {
+        let tbs = &cert.tbs_certificate;
+        self.roots
+            .iter()
+            .find(|root| root.tbs_certificate.subject_public_key_info == tbs.subject_public_key_info)
+            .or_else(|| self.roots.iter().find(|root| root.tbs_certificate.subject == tbs.issuer))
+    }
+}
+
+// --- Keys and Signatures ---
+
+/// An issuer public key in one of the forms used by AMD and Intel PKIs.
+enum PublicKey {
+    P256(p256::ecdsa::VerifyingKey),
+    P384(p384::ecdsa::VerifyingKey),
+    Rsa(rsa::RsaPublicKey),
+}
+
+impl PublicKey {
+    fn from_certificate(cert: &Certificate) -> Result<Self, CertChainError> {
+        let key = cert
+            .tbs_certificate
+            .subject_public_key_info
+            .subject_public_key
+            .raw_bytes();
+
+        // Uncompressed SEC1 points identify the curve by their length.
+        match key.len() {
+            65 => p256::ecdsa::VerifyingKey::from_sec1_bytes(key)
+                .map(PublicKey::P256)
+                .map_err(|e| CertChainError::Parse(e.to_string())),
+            97 => p384::ecdsa::VerifyingKey::from_sec1_bytes(key)
+                .map(PublicKey::P384)
+                .map_err(|e| CertChainError::Parse(e.to_string())),
+            _ => rsa::RsaPublicKey::from_pkcs1_der(key)
+                .map(PublicKey::Rsa)
+                .map_err(|_| CertChainError::UnsupportedAlgorithm("unknown public key type".to_string())),
+        }
+    }
+
+    /// Verifies an X.509-style signature (DER ECDSA or raw RSA-PSS).
+    fn verify(&self, alg: &ObjectIdentifier, msg: &[u8], sig: &[u8]) -> Result<(), CertChainError> {
+        let ok = match (self, *alg) {
+            (PublicKey::P256(key), OID_ECDSA_WITH_SHA256) => p256::ecdsa::Signature::from_der(sig)
+                .map(|s| key.verify(msg, &s).is_ok())
+                .unwrap_or(false),
+            (PublicKey::P384(key), OID_ECDSA_WITH_SHA384) => p384::ecdsa::Signature::from_der(sig)
+                .map(|s| key.verify(msg, &s).is_ok())
+                .unwrap_or(false),
+            // AMD signs ASK and VCEK certificates with RSASSA-PSS over SHA-384.
+            (PublicKey::Rsa(key), OID_RSASSA_PSS) => {
+                let key = rsa::pss::VerifyingKey::<Sha384>::new(key.clone());
+                rsa::pss::Signature::try_from(sig)
+                    .map(|s| key.verify(msg, &s).is_ok())
+                    .unwrap_or(false)
+            }
+            (_, other) => return Err(CertChainError::UnsupportedAlgorithm(other.to_string())),
+        };
+
+        if ok {
+            Ok(())
+        } else {
+            Err(CertChainError::EvidenceSignatureInvalid)
+        }
+    }
+}
+
+fn subject_of(cert: &Certificate) -> String {
+    cert.tbs_certificate.subject.to_string()
+}
+
+/// Checks that `cert` was signed by `issuer`.
+pub(crate) fn verify_issued_by(cert: &Certificate, issuer: &Certificate) -> Result<(), CertChainError> {
+    let tbs = cert.tbs_certificate.to_der()?;
+    let sig = cert
+        .signature
+        .as_bytes()
+        .ok_or_else(|| CertChainError::Parse("signature has unused bits".to_string()))?;
+
+    PublicKey::from_certificate(issuer)?
+        .verify(&cert.signature_algorithm.oid, &tbs, sig)
+        .map_err(|e| match e {
+            CertChainError::EvidenceSignatureInvalid => {
+                CertChainError::BadCertificateSignature(subject_of(cert))
+            }
+            other => other,
+        })
+}
+
+// --- Chain Validation ---
+
+/// Checks that `cert` may issue certificates: BasicConstraints must assert
+/// `cA`, a KeyUsage extension (if present) must allow keyCertSign, and a path
+/// length constraint must allow `depth` intermediate CAs below it.
+fn check_issuer(cert: &Certificate, depth: usize) -> Result<(), CertChainError> {
+    let not_ca = || CertChainError::NotACertificateAuthority(subject_of(cert));
+    let extensions = cert.tbs_certificate.extensions.as_deref().unwrap_or_default();
+
+    let constraints = extensions
+        .iter()
+        .find(|e| e.extn_id == OID_BASIC_CONSTRAINTS)
+        .ok_or_else(not_ca)?;
+    let constraints = BasicConstraints::from_der(constraints.extn_value.as_bytes())?;
+    if !constraints.ca {
+        return Err(not_ca());
+    }
+    if let Some(max) = constraints.path_len_constraint {
+        if depth > usize::from(max) {
+            return Err(not_ca());
+        }
+    }
+
+    if let Some(usage) = extensions.iter().find(|e| e.extn_id == OID_KEY_USAGE) {
+        let usage = KeyUsage::from_der(usage.extn_value.as_bytes())?;
+        if !usage.key_cert_sign() {
+            return Err(not_ca());
+        }
+    }
+    Ok(())
+}
+
+/// Decodes a chain supplied as concatenated PEM blocks or concatenated DER.
+pub fn parse_cert_chain(bytes: &[u8]) -> Result<Vec<Certificate>, CertChainError> {
+    let chain = if bytes.starts_with(b"-----BEGIN") {
+        Certificate::load_pem_chain(bytes)?
+    } else {
+        let mut certs = Vec::new();
+        let mut reader = SliceReader::new(bytes)?;
+        while !reader.is_finished() {
+            certs.push(Certificate::decode(&mut reader)?);
+        }
+        certs
+    };
+
+    if chain.is_empty() {
+        return Err(CertChainError::EmptyChain);
+    }
+    Ok(chain)
+}
+
+/// Validates a leaf-first chain against the pinned anchors.
+///
+/// Every certificate must be within its validity period and signed by the next
+/// one, and every certificate above the leaf must be a CA; the last
+/// certificate must either be a pinned root itself (and self-signed) or be
+/// issued by one.
+pub fn verify_chain(
+    chain: &[Certificate],
+    anchors: &TrustAnchors,
+    now: SystemTime,
+) -> Result<(), CertChainError> {
+    let last = chain.last().ok_or(CertChainError::EmptyChain)?;
+
+    for (i, cert) in chain.iter().enumerate() {
+        let validity = &cert.tbs_certificate.validity;
+        if now < validity.not_before.to_system_time() || now > validity.not_after.to_system_time() {
+            return Err(CertChainError::Expired(subject_of(cert)));
+        }
+        if i > 0 {
+            check_issuer(cert, i - 1)?;
+        }
+        if let Some(issuer) = chain.get(i + 1) {
+            verify_issued_by(cert, issuer)?;
+        }
+    }
+
+    let root = anchors
+        .anchor_for(last)
+        .ok_or_else(|| CertChainError::UntrustedRoot(subject_of(last)))?;
+    if root.tbs_certificate.subject_public_key_info != last.tbs_certificate.subject_public_key_info {
+        check_issuer(root, chain.len() - 1)?;
+    }
+    verify_issued_by(last, root)
+}
+
+// --- Platform-Specific Evidence Signatures ---
+
+/// Reads an AMD SPL extension (a DER INTEGER) from the VCEK.
+fn amd_spl(cert: &Certificate, oid: ObjectIdentifier) -> Option<u8> {
+    let ext = cert
+        .tbs_certificate
+        .extensions
+        .as_ref()?
+        .iter()
+        .find(|e| e.extn_id == oid)?;
+    u8::from_der(ext.extn_value.as_bytes()).ok()
+}
+
+/// Reads the 64-byte chip ID the VCEK was issued to.
+fn amd_hw_id(cert: &Certificate) -> Option<Vec<u8>> {
+    let ext = cert
+        .tbs_certificate
+        .extensions
+        .as_ref()?
+        .iter()
+        .find(|e| e.extn_id == OID_AMD_HW_ID)?;
+    let raw = ext.extn_value.as_bytes();
+    match OctetString::from_der(raw) {
+        Ok(inner) => Some(inner.as_bytes().to_vec()),
+        Err(_) => Some(raw.to_vec()),
+    }
+}
+
+/// Checks that a VCEK's TCB and hardware ID extensions match the report. A
+/// missing extension is a failure; only VLEK-signed reports may omit the
+/// hardware ID.
+fn check_vcek_tcb(vcek: &Certificate, snp: &SnpAttestationReport) -> Result<(), CertChainError> {
+    let tcb = &snp.reported_tcb;
+    let expected = [
+        (OID_AMD_BL_SPL, tcb.boot_loader, "boot loader SPL"),
+        (OID_AMD_TEE_SPL, tcb.tee, "TEE SPL"),
+        (OID_AMD_SNP_SPL, tcb.snp, "SNP SPL"),
+        (OID_AMD_UCODE_SPL, tcb.microcode, "microcode SPL"),
+    ];
+    for (oid, reported, name) in expected {
+        let spl = amd_spl(vcek, oid).ok_or_else(|| CertChainError::MissingExtension(name.to_string()))?;
+        if spl != reported {
+            return Err(CertChainError::TcbMismatch(name.to_string()));
+        }
+    }
+
+    match amd_hw_id(vcek) {
+        Some(hw_id) if hw_id != snp.chip_id => Err(CertChainError::TcbMismatch("chip ID".to_string())),
+        Some(_) => Ok(()),
+        None if (snp.key_flags >> 2) & 0x7 == SNP_SIGNING_KEY_VLEK => Ok(()),
+        None => Err(CertChainError::MissingExtension("hardware ID".to_string())),
+    }
+}
+
+/// Verifies an SNP report: VCEK→ASK→ARK chain, VCEK/TCB binding, and the
+/// ECDSA P-384 signature over the first 0x2A0 bytes of the report.
+pub fn verify_snp_report(
+    snp: &SnpAttestationReport,
+    cert_chain: &[u8],
+    anchors: &TrustAnchors,
+) -> Result<(), CertChainError> {
+    let chain = parse_cert_chain(cert_chain)?;
+    verify_chain(&chain, anchors, SystemTime::now())?;
+
+    let vcek = &chain[0];
+    check_vcek_tcb(vcek, snp)?;
+
+    let key = match PublicKey::from_certificate(vcek)? {
+        PublicKey::P384(key) => key,
+        _ => return Err(CertChainError::UnsupportedAlgorithm("VCEK is not a P-384 key".to_string())),
+    };
+    let (r, s) = snp.signature.to_be_scalars();
+    let signature = p384::ecdsa::Signature::from_scalars(r, s)
+        .map_err(|_| CertChainError::EvidenceSignatureInvalid)?;
+
+    key.verify(&snp.signed_bytes(), &signature)
+        .map_err(|_| CertChainError::EvidenceSignatureInvalid)
+}
+
+/// Builds a P-256 key from the raw (x || y) form used inside TDX quotes.
+fn p256_from_raw(raw: &[u8; 64]) -> Result<p256::ecdsa::VerifyingKey, CertChainError> {
+    let mut sec1 = [0u8; 65];
+    sec1[0] = 0x04;
+    sec1[1..].copy_from_slice(raw);
+    p256::ecdsa::VerifyingKey::from_sec1_bytes(&sec1).map_err(|e| CertChainError::Parse(e.to_string()))
+}
+
+/// Verifies a TDX quote: PCK chain to the Intel root, the PCK signature over
+/// the QE report, the QE report's binding of the attestation key, and the
+/// attestation key's signature over the quote header and body.
+pub fn verify_tdx_quote(quote: &TdxQuote, anchors: &TrustAnchors) -> Result<(), CertChainError> {
+    let sig_data = &quote.signature_data;
+    let qe = sig_data
+        .certification_data
+        .qe_report_data()
+        .map_err(|e| CertChainError::Parse(e.to_string()))?;
+
+    // 1. The PCK chain must lead to the pinned Intel root.
+    let chain = parse_cert_chain(&qe.pck_cert_data.data)?;
+    verify_chain(&chain, anchors, SystemTime::now())?;
+
+    // 2. The PCK leaf signs the QE report.
+    let pck = match PublicKey::from_certificate(&chain[0])? {
+        PublicKey::P256(key) => key,
+        _ => return Err(CertChainError::UnsupportedAlgorithm("PCK is not a P-256 key".to_string())),
+    };
+    let qe_sig = p256::ecdsa::Signature::from_slice(&qe.qe_report_signature)
+        .map_err(|_| CertChainError::EvidenceSignatureInvalid)?;
+    pck.verify(&qe.qe_report, &qe_sig)
+        .map_err(|_| CertChainError::EvidenceSignatureInvalid)?;
+
+    // 3. QE REPORTDATA[0..32] = SHA-256(attestation key || QE auth data).
+    let mut hasher = Sha256::new();
+    hasher.update(sig_data.attestation_key);
+    hasher.update(&qe.qe_auth_data);
+    // REPORTDATA sits at offset 320 of the 384-byte SGX report body.
+    if qe.qe_report[320..352] != hasher.finalize()[..] {
+        return Err(CertChainError::QeReportBindingInvalid);
+    }
+
+    // 4. The attestation key signs the quote header and TD report body.
+    let att_key = p256_from_raw(&sig_data.attestation_key)?;
+    let quote_sig = p256::ecdsa::Signature::from_slice(&sig_data.quote_signature)
+        .map_err(|_| CertChainError::EvidenceSignatureInvalid)?;
+    att_key
+        .verify(&quote.signed_bytes(), &quote_sig)
+        .map_err(|_| CertChainError::EvidenceSignatureInvalid)
+}
+
+/// Left-pads a big-endian TPM ECDSA scalar to the curve's field size.
+fn pad_scalar(raw: &[u8]) -> Result<[u8; 32], CertChainError> {
+    let trimmed = match raw.iter().position(|b| *b != 0) {
+        Some(start) => &raw[start..],
+        None => &[],
+    };
+    if trimmed.len() > 32 {
+        return Err(CertChainError::EvidenceSignatureInvalid);
+    }
+    let mut out = [0u8; 32];
+    out[32 - trimmed.len()..].copy_from_slice(trimmed);
+    Ok(out)
+}
+
+/// Verifies a TPM2_Quote: the AK certificate chain and the AK's RSASSA or
+/// ECDSA P-256 signature over the `TPMS_ATTEST` structure.
+pub fn verify_tpm_quote(
+    quote: &TpmQuote,
+    cert_chain: &[u8],
+    anchors: &TrustAnchors,
+) -> Result<(), CertChainError> {
+    let chain = parse_cert_chain(cert_chain)?;
+    verify_chain(&chain, anchors, SystemTime::now())?;
+
+    let (alg, parts) = quote.signature_parts().map_err(CertChainError::UnsupportedAlgorithm)?;
+    let ok = match (PublicKey::from_certificate(&chain[0])?, alg) {
+        (PublicKey::P256(key), TPM_ALG_ECDSA) => {
+            let signature = p256::ecdsa::Signature::from_scalars(pad_scalar(&parts[0])?, pad_scalar(&parts[1])?)
+                .map_err(|_| CertChainError::EvidenceSignatureInvalid)?;
+            key.verify(&quote.attest, &signature).is_ok()
+        }
+        (PublicKey::Rsa(key), TPM_ALG_RSASSA) => {
+            let key = rsa::pkcs1v15::VerifyingKey::<Sha256>::new(key);
+            rsa::pkcs1v15::Signature::try_from(parts[0].as_slice())
+                .map(|s| key.verify(&quote.attest, &s).is_ok())
+                .unwrap_or(false)
+        }
+        _ => {
+            return Err(CertChainError::UnsupportedAlgorithm(
+                "AK key type does not match the quote signature".to_string(),
+            ))
+        }
+    };
+
+    if ok {
+        Ok(())
+    } else {
+        Err(CertChainError::EvidenceSignatureInvalid)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use p384::ecdsa::signature::Signer;
+    use p384::pkcs8::DecodePrivateKey;
+    use rcgen::{BasicConstraints as Bc, CertificateParams, CustomExtension, IsCa, KeyPair, KeyUsagePurpose};
+
+    /// The VCEK-signed Milan report from the `snp_report` tests; it is re-signed
+    /// here by a VCEK under a self-generated ARK → ASK test PKI.
+    const FIXTURE: &[u8] = include_bytes!("testdata/snp_report_v3.bin");
+
+    struct Issued {
+        cert: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    /// A test ARK → ASK → VCEK chain plus a report signed by the VCEK.
+    pub(crate) struct Pki {
+        ark: Issued,
+        ask: Issued,
+        vcek: Issued,
+        pub(crate) report: SnpAttestationReport,
+    }
+
+    impl Pki {
+        /// Builds the chain, letting each test adjust the certificate templates.
+        fn build(
+            ark: impl FnOnce(&mut CertificateParams),
+            ask: impl FnOnce(&mut CertificateParams),
+            vcek: impl FnOnce(&mut CertificateParams),
+        ) -> Self {
+            let report = SnpAttestationReport::from_bytes(FIXTURE).unwrap();
+
+            let mut ark_params = ca_params("ARK-Milan-Test");
+            ark(&mut ark_params);
+            let ark_key = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P384_SHA384).unwrap();
+            let ark = Issued { cert: ark_params.self_signed(&ark_key).unwrap(), key: ark_key };
+
+            let mut ask_params = ca_params("SEV-Milan-Test");
+            ask(&mut ask_params);
+            let ask = issue(ask_params, &ark);
+
+            let mut vcek_params = vcek_params(&report);
+            vcek(&mut vcek_params);
+            let vcek = issue(vcek_params, &ask);
+
+            let mut pki = Pki { ark, ask, vcek, report };
+            pki.sign_report();
+            pki
+        }
+
+        pub(crate) fn standard() -> Self {
+            Pki::build(|_| {}, |_| {}, |_| {})
+        }
+
+        /// Signs the report like the PSP: little-endian r and s over 0..0x2A0.
+        pub(crate) fn sign_report(&mut self) {
+            let key = p384::ecdsa::SigningKey::from_pkcs8_der(&self.vcek.key.serialize_der()).unwrap();
+            let signature: p384::ecdsa::Signature = key.sign(&self.report.signed_bytes());
+            let (r, s) = signature.split_bytes();
+            for i in 0..48 {
+                self.report.signature.r[i] = r[47 - i];
+                self.report.signature.s[i] = s[47 - i];
+            }
+        }
+
+        /// The VCEK → ASK chain as a host would supply it with the report.
+        pub(crate) fn chain_pem(&self) -> Vec<u8> {
+            format!("{}{}", self.vcek.cert.pem(), self.ask.cert.pem()).into_bytes()
+        }
+
+        pub(crate) fn anchors(&self) -> TrustAnchors {
+            TrustAnchors::from_pem(self.ark.cert.pem().as_bytes()).unwrap()
+        }
+
+        fn verify(&self) -> Result<(), CertChainError> {
+            verify_snp_report(&self.report, &self.chain_pem(), &self.anchors())
+        }
+    }
+
+    fn ca_params(name: &str) -> CertificateParams {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name.push(rcgen::DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(Bc::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        params
+    }
+
+    /// A VCEK template certifying the report's TCB and chip ID.
+    fn vcek_params(report: &SnpAttestationReport) -> CertificateParams {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name.push(rcgen::DnType::CommonName, "SEV-VCEK");
+        let tcb = &report.reported_tcb;
+        for (oid, spl) in [
+            (OID_AMD_BL_SPL, tcb.boot_loader),
+            (OID_AMD_TEE_SPL, tcb.tee),
+            (OID_AMD_SNP_SPL, tcb.snp),
+            (OID_AMD_UCODE_SPL, tcb.microcode),
+        ] {
+            params.custom_extensions.push(amd_extension(oid, spl.to_der().unwrap()));
+        }
+        params.custom_extensions.push(amd_extension(OID_AMD_HW_ID, report.chip_id.to_vec()));
+        params
+    }
+
+    fn amd_extension(oid: ObjectIdentifier, content: Vec<u8>) -> CustomExtension {
+        let arcs: Vec<u64> = oid.arcs().map(u64::from).collect();
+        CustomExtension::from_oid_content(&arcs, content)
+    }
+
+    fn drop_extension(params: &mut CertificateParams, oid: ObjectIdentifier) {
+        let arcs: Vec<u64> = oid.arcs().map(u64::from).collect();
+        params.custom_extensions.retain(|e| e.oid_components().ne(arcs.iter().copied()));
+    }
+
+    fn issue(params: CertificateParams, issuer: &Issued) -> Issued {
+        let key = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P384_SHA384).unwrap();
+        let cert = params.signed_by(&key, &issuer.cert, &issuer.key).unwrap();
+        Issued { cert, key }
+    }
+
+    #[test]
+    fn accepts_a_vcek_chain_to_the_pinned_root() {
+        Pki::standard().verify().unwrap();
+    }
+
+    #[test]
+    fn accepts_a_chain_that_includes_the_pinned_root() {
+        let pki = Pki::standard();
+        let mut chain = pki.chain_pem();
+        chain.extend_from_slice(pki.ark.cert.pem().as_bytes());
+        verify_snp_report(&pki.report, &chain, &pki.anchors()).unwrap();
+    }
+
+    #[test]
+    fn accepts_concatenated_der() {
+        let pki = Pki::standard();
+        let mut chain = pki.vcek.cert.der().to_vec();
+        chain.extend_from_slice(pki.ask.cert.der());
+        assert_eq!(parse_cert_chain(&chain).unwrap().len(), 2);
+        verify_snp_report(&pki.report, &chain, &pki.anchors()).unwrap();
+    }
+
+    #[test]
+    fn rejects_an_unpinned_root() {
+        let pki = Pki::standard();
+        let genoa = |ark: &mut CertificateParams| ark.distinguished_name.push(rcgen::DnType::CommonName, "ARK-Genoa-Test");
+        let other = Pki::build(genoa, |_| {}, |_| {});
+        let err = verify_snp_report(&pki.report, &pki.chain_pem(), &other.anchors()).unwrap_err();
+        assert!(matches!(err, CertChainError::UntrustedRoot(_)), "{err}");
+        let err = verify_snp_report(&pki.report, &pki.chain_pem(), &TrustAnchors::new()).unwrap_err();
+        assert!(matches!(err, CertChainError::UntrustedRoot(_)), "{err}");
+    }
+
+    #[test]
+    fn rejects_a_certificate_signed_by_another_key() {
+        let pki = Pki::standard();
+        let impostor = Pki::standard();
+        // Same subject names, but the VCEK was issued by a different ASK.
+        let chain = format!("{}{}", impostor.vcek.cert.pem(), pki.ask.cert.pem());
+        let err = verify_snp_report(&impostor.report, chain.as_bytes(), &pki.anchors()).unwrap_err();
+        assert!(matches!(err, CertChainError::BadCertificateSignature(_)), "{err}");
+    }
+
+    #[test]
+    fn rejects_an_expired_intermediate() {
+        let pki = Pki::build(
+            |_| {},
+            |ask| {
+                ask.not_before = rcgen::date_time_ymd(2000, 1, 1);
+                ask.not_after = rcgen::date_time_ymd(2001, 1, 1);
+            },
+            |_| {},
+        );
+        assert!(matches!(pki.verify().unwrap_err(), CertChainError::Expired(_)));
+    }
+
+    #[test]
+    fn rejects_an_intermediate_without_basic_constraints() {
+        let pki = Pki::build(|_| {}, |ask| ask.is_ca = IsCa::NoCa, |_| {});
+        assert!(matches!(pki.verify().unwrap_err(), CertChainError::NotACertificateAuthority(_)));
+    }
+
+    #[test]
+    fn rejects_an_intermediate_that_is_not_a_ca() {
+        let pki = Pki::build(|_| {}, |ask| ask.is_ca = IsCa::ExplicitNoCa, |_| {});
+        assert!(matches!(pki.verify().unwrap_err(), CertChainError::NotACertificateAuthority(_)));
+    }
+
+    #[test]
+    fn rejects_an_intermediate_without_key_cert_sign() {
+        let pki = Pki::build(|_| {}, |ask| ask.key_usages = vec![KeyUsagePurpose::DigitalSignature], |_| {});
+        assert!(matches!(pki.verify().unwrap_err(), CertChainError::NotACertificateAuthority(_)));
+    }
+
+    #[test]
+    fn rejects_a_leaf_acting_as_an_issuer() {
+        // A certificate issued by the VCEK, presented as VCEK → ASK.
+        let pki = Pki::standard();
+        let mut params = vcek_params(&pki.report);
+        params.distinguished_name.push(rcgen::DnType::CommonName, "SEV-VCEK-Child");
+        let child = issue(params, &pki.vcek);
+        let chain = format!("{}{}{}", child.cert.pem(), pki.vcek.cert.pem(), pki.ask.cert.pem());
+        let err = verify_chain(&parse_cert_chain(chain.as_bytes()).unwrap(), &pki.anchors(), SystemTime::now())
+            .unwrap_err();
+        assert!(matches!(err, CertChainError::NotACertificateAuthority(_)), "{err}");
+    }
+
+    #[test]
+    fn enforces_the_root_path_length() {
+        let pki = Pki::build(|ark| ark.is_ca = IsCa::Ca(Bc::Constrained(0)), |_| {}, |_| {});
+        assert!(matches!(pki.verify().unwrap_err(), CertChainError::NotACertificateAuthority(_)));
+
+        let pki = Pki::build(|ark| ark.is_ca = IsCa::Ca(Bc::Constrained(1)), |_| {}, |_| {});
+        pki.verify().unwrap();
+    }
+
+    #[test]
+    fn rejects_a_root_that_is_not_a_ca() {
+        let pki = Pki::build(|ark| ark.is_ca = IsCa::ExplicitNoCa, |_| {}, |_| {});
+        assert!(matches!(pki.verify().unwrap_err(), CertChainError::NotACertificateAuthority(_)));
+    }
+
+    #[test]
+    fn rejects_a_vcek_without_tcb_extensions() {
+        for (oid, name) in [
+            (OID_AMD_BL_SPL, "boot loader SPL"),
+            (OID_AMD_TEE_SPL, "TEE SPL"),
+            (OID_AMD_SNP_SPL, "SNP SPL"),
+            (OID_AMD_UCODE_SPL, "microcode SPL"),
+            (OID_AMD_HW_ID, "hardware ID"),
+        ] {
+            let pki = Pki::build(|_| {}, |_| {}, |vcek| drop_extension(vcek, oid));
+            match pki.verify().unwrap_err() {
+                CertChainError::MissingExtension(field) => assert_eq!(field, name),
+                other => panic!("{oid}: {other}"),
+            }
+        }
+    }
+
+    #[test]
+    fn accepts_a_vlek_without_hardware_id() {
+        let mut pki = Pki::build(|_| {}, |_| {}, |vcek| drop_extension(vcek, OID_AMD_HW_ID));
+        pki.report.key_flags = SNP_SIGNING_KEY_VLEK << 2;
+        pki.sign_report();
+        pki.verify().unwrap();
+    }
+
+    #[test]
+    fn rejects_a_vcek_for_another_tcb_or_chip() {
+        let mut pki = Pki::standard();
+        pki.report.reported_tcb.microcode += 1;
+        pki.sign_report();
+        match pki.verify().unwrap_err() {
+            CertChainError::TcbMismatch(field) => assert_eq!(field, "microcode SPL"),
+            other => panic!("{other}"),
+        }
+
+        let mut pki = Pki::standard();
+        pki.report.chip_id[0] ^= 1;
+        pki.sign_report();
+        match pki.verify().unwrap_err() {
+            CertChainError::TcbMismatch(field) => assert_eq!(field, "chip ID"),
+            other => panic!("{other}"),
+        }
+    }
+
+    #[test]
+    fn rejects_a_modified_report() {
+        let mut pki = Pki::standard();
+        pki.report.report_data[0] ^= 1;
+        assert!(matches!(pki.verify().unwrap_err(), CertChainError::EvidenceSignatureInvalid));
+    }
+}
@@ -0,0 +1,312 @@
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::error::AttestationError;
+
+// --- TLS for Database Connections ---
+//
+// Builds the rustls client configuration for Postgres connections: the
+// attested-db pool with material the KBS released after attestation, and
+// the KBS's own nonce and resource stores with files from its config. The
+// material is a CA bundle and an optional client certificate and key.
+// `sslmode` follows libpq: `verify-full` (the default) checks the chain
+// against the CA and the certificate against the host name, while `prefer`
+// and `require` encrypt without authenticating the server, and `prefer` even
+// falls back to plaintext.
+
+/// libpq-style TLS modes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SslMode {
+    /// Never use TLS.
+    Disable,
+    /// Use TLS if the server supports it, without verifying it.
+    Prefer,
+    /// Always use TLS, without verifying the server.
+    Require,
+    /// Always use TLS and verify the chain and host name.
+    #[default]
+    VerifyFull,
+}
+
+impl SslMode {
+    /// Whether the database server is authenticated in this mode.
+    pub fn verifies_server(self) -> bool {
+        self == SslMode::VerifyFull
+    }
+
+    /// Rejects `prefer` together with a CA bundle: a server (or anyone in
+    /// between) could still downgrade the connection to plaintext, which is
+    /// never what configuring a CA asks for. `verify-full` needs the bundle.
+    pub fn check(self, ca_configured: bool) -> Result<(), AttestationError> {
+        if self == SslMode::Prefer && ca_configured {
+            return Err(AttestationError::Configuration(
+                "sslmode = \"prefer\" may fall back to plaintext; use \"verify-full\" with a CA bundle".to_string(),
+            ));
+        }
+        if self == SslMode::VerifyFull && !ca_configured {
+            return Err(AttestationError::Configuration(
+                "sslmode = \"verify-full\" needs a CA bundle to verify the server against".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The mode the Postgres client negotiates with: it only knows whether
+    /// TLS is optional or mandatory; verification is up to the rustls config.
+    pub fn negotiation(self) -> postgres::config::SslMode {
+        match self {
+            SslMode::Disable => postgres::config::SslMode::Disable,
+            SslMode::Prefer => postgres::config::SslMode::Prefer,
+            SslMode::Require | SslMode::VerifyFull => postgres::config::SslMode::Require,
+        }
+    }
+}
+
+impl fmt::Display for SslMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SslMode::Disable => "disable",
+            SslMode::Prefer => "prefer",
+            SslMode::Require => "require",
+            SslMode::VerifyFull => "verify-full",
+        })
+    }
+}
+
+impl FromStr for SslMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, String> {
+        match mode {
+            "disable" => Ok(SslMode::Disable),
+            "prefer" => Ok(SslMode::Prefer),
+            "require" => Ok(SslMode::Require),
+            "verify-full" => Ok(SslMode::VerifyFull),
+            _ => Err(format!("unknown sslmode '{}'", mode)),
+        }
+    }
+}
+
+/// PEM material of a connection: the CA bundle to verify the server
+/// against and the client's own certificate and key.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsMaterial {
+    pub ca_pem: Option<Vec<u8>>,
+    pub client_cert_pem: Option<Vec<u8>>,
+    pub client_key_pem: Option<Vec<u8>>,
+}
+
+/// Builds the client configuration for `mode`, or `None` for `disable`.
+pub fn client_config(mode: SslMode, material: &TlsMaterial) -> Result<Option<ClientConfig>, AttestationError> {
+    mode.check(material.ca_pem.is_some())?;
+    if mode == SslMode::Disable {
+        return Ok(None);
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(Arc::clone(&provider)).with_safe_default_protocol_versions()?;
+
+    let builder = if mode == SslMode::VerifyFull {
+        let ca_pem = material
+            .ca_pem
+            .as_deref()
+            .ok_or_else(|| AttestationError::Configuration("verify-full requires a CA bundle".to_string()))?;
+        let mut roots = RootCertStore::empty();
+        for cert in parse_certs(ca_pem)? {
+            roots.add(cert)?;
+        }
+        builder.with_root_certificates(roots)
+    } else {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(EncryptOnlyVerifier { provider }))
+    };
+
+    let config = match (&material.client_cert_pem, &material.client_key_pem) {
+        (Some(cert), Some(key)) => {
+            let key = rustls_pemfile::private_key(&mut key.as_slice())
+                .map_err(|e| AttestationError::Credentials(e.to_string()))?
+                .ok_or_else(|| AttestationError::Credentials("no private key in client key PEM".to_string()))?;
+            builder.with_client_auth_cert(parse_certs(cert)?, key)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err(AttestationError::Configuration("client certificate and key must be given together".to_string())),
+    };
+    Ok(Some(config))
+}
+
+/// Opens a blocking connection to `params` (a connection string without
+/// `sslmode`, which `mode` replaces).
+pub fn connect(params: &str, mode: SslMode, material: &TlsMaterial) -> Result<postgres::Client, AttestationError> {
+    let mut config: postgres::Config = params.parse()?;
+    config.ssl_mode(mode.negotiation());
+    let client = match client_config(mode, material)? {
+        Some(tls) => config.connect(MakeRustlsConnect::new(tls))?,
+        None => config.connect(postgres::NoTls)?,
+    };
+    Ok(client)
+}
+
+fn parse_certs(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, AttestationError> {
+    let certs = rustls_pemfile::certs(&mut &pem[..])
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| AttestationError::Credentials(e.to_string()))?;
+    if certs.is_empty() {
+        return Err(AttestationError::Credentials("no certificates in PEM bundle".to_string()));
+    }
+    Ok(certs)
+}
+
+/// `prefer`/`require`: accepts any server certificate but still checks the
+/// handshake signatures, so the session is encrypted to whoever holds the
+/// presented key.
+#[derive(Debug)]
+struct EncryptOnlyVerifier {
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for EncryptOnlyVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::PrivateKeyDer;
+    use rustls::{ServerConfig, ServerConnection, StreamOwned};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    /// `SSLRequest` code of the Postgres wire protocol.
+    const SSL_REQUEST_CODE: u32 = 80877103;
+
+    /// A fresh CA and the server config of a `localhost` certificate it
+    /// issued.
+    fn server_pki() -> (TlsMaterial, Arc<ServerConfig>) {
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let mut ca_params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.der().clone()], PrivateKeyDer::try_from(key.serialize_der()).unwrap())
+            .unwrap();
+        let material = TlsMaterial { ca_pem: Some(ca.pem().into_bytes()), ..TlsMaterial::default() };
+        (material, Arc::new(config))
+    }
+
+    /// Answers one connection like a trusting Postgres server that insists
+    /// on TLS. Returns whether the client got through the TLS startup.
+    fn serve_one(listener: TcpListener, config: Arc<ServerConfig>) -> JoinHandle<bool> {
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut head = [0u8; 8];
+            stream.read_exact(&mut head).unwrap();
+            if u32::from_be_bytes(head[4..8].try_into().unwrap()) != SSL_REQUEST_CODE {
+                return false;
+            }
+            stream.write_all(b"S").unwrap();
+            let mut stream = StreamOwned::new(ServerConnection::new(config).unwrap(), stream);
+            if stream.read_exact(&mut head).is_err() {
+                return false;
+            }
+            let len = u32::from_be_bytes(head[0..4].try_into().unwrap()) as usize;
+            let mut rest = vec![0u8; len.saturating_sub(8)];
+            stream.read_exact(&mut rest).unwrap();
+            stream.write_all(&[b'R', 0, 0, 0, 8, 0, 0, 0, 0]).unwrap(); // AuthenticationOk
+            stream.write_all(&[b'Z', 0, 0, 0, 5, b'I']).unwrap(); // ReadyForQuery
+            stream.flush().unwrap();
+            // Hold the connection until the client hangs up.
+            let _ = stream.read(&mut [0u8; 64]);
+            true
+        })
+    }
+
+    fn connect_to(server: Arc<ServerConfig>, mode: SslMode, material: &TlsMaterial) -> (bool, bool) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = serve_one(listener, server);
+        let params = format!("host=localhost hostaddr=127.0.0.1 port={} user=kbs dbname=kbs", port);
+        let client = connect(&params, mode, material).map(drop);
+        (client.is_ok(), server.join().unwrap())
+    }
+
+    #[test]
+    fn sslmode_names_round_trip() {
+        for mode in [SslMode::Disable, SslMode::Prefer, SslMode::Require, SslMode::VerifyFull] {
+            assert_eq!(mode.to_string().parse::<SslMode>().unwrap(), mode);
+        }
+        assert!("verify-ca".parse::<SslMode>().is_err());
+    }
+
+    #[test]
+    fn connects_to_a_server_issued_by_the_ca() {
+        let (material, server) = server_pki();
+        assert_eq!(connect_to(server, SslMode::VerifyFull, &material), (true, true));
+    }
+
+    #[test]
+    fn rejects_a_server_issued_by_another_ca() {
+        let (material, _) = server_pki();
+        let (_, impostor) = server_pki();
+        assert_eq!(connect_to(Arc::clone(&impostor), SslMode::VerifyFull, &material), (false, false));
+
+        // `require` encrypts to whoever answers.
+        assert_eq!(connect_to(impostor, SslMode::Require, &TlsMaterial::default()), (true, true));
+    }
+
+    #[test]
+    fn verify_full_needs_a_ca_bundle() {
+        let err = connect("host=localhost user=kbs", SslMode::VerifyFull, &TlsMaterial::default()).err().unwrap();
+        assert!(matches!(err, AttestationError::Configuration(_)), "{}", err);
+    }
+}
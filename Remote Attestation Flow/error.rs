@@ -0,0 +1,382 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::PoisonError;
+
+use crate::attestation_data::{FailureCode, VerificationResult};
+use crate::attestation_token::TokenError;
+use crate::attester::attester::AttesterError;
+use crate::cert_chain::CertChainError;
+use crate::jwe::JweError;
+#[cfg(feature = "kbs-client")]
+use crate::kbs_client::KbsClientError;
+#[cfg(feature = "kbs-client")]
+use crate::kbs_protocol::ERROR_NONCE_REJECTED;
+#[cfg(feature = "kbs-server")]
+use crate::kbs_server::KbsServerError;
+use crate::nonce_store::NonceError;
+use crate::policy::PolicyError;
+#[cfg(feature = "ra-tls")]
+use crate::ra_tls::RaTlsError;
+#[cfg(feature = "kbs-server")]
+use crate::resource_store::ResourceStoreError;
+use crate::sync_error::SyncError;
+
+// --- Crate Error Hierarchy ---
+//
+// Each module keeps its own error type; `AttestationError` wraps all of them
+// so agents, the KBS and the database layer can propagate any failure with
+// `?`. Every error carries a stable `ErrorCode` for logs and alerting, and
+// `source()` walks down to the module error (and its I/O or TLS cause).
+
+/// Stable error codes. The numbers and names are part of the public
+/// interface: never renumber or reuse them, only add new ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u16)]
+pub enum ErrorCode {
+    /// The KBS could not be reached.
+    KbsTransport = 1001,
+    /// The KBS refused the evidence, token or resource request.
+    KbsRejected = 1002,
+    /// The KBS failed or answered with an unexpected status.
+    KbsFailure = 1003,
+    /// A KBS call was made outside of an open session.
+    KbsSession = 1004,
+    /// A message or record could not be encoded or decoded.
+    Serialization = 1101,
+    /// The evidence failed verification.
+    VerificationFailed = 1201,
+    /// The challenge nonce was unknown, replayed or expired.
+    NonceRejected = 1202,
+    /// An attestation token is malformed, expired or not trusted.
+    TokenInvalid = 1203,
+    /// A certificate chain did not validate.
+    CertificateInvalid = 1204,
+    /// The nonce store could not be reached.
+    NonceStoreUnavailable = 1205,
+    /// The attestation policy could not be loaded.
+    Policy = 1301,
+    /// The TEE device or firmware failed.
+    Device = 1401,
+    /// A lock was poisoned or could not be acquired in time.
+    Lock = 1501,
+    Timeout = 1502,
+    Io = 1503,
+    Database = 1601,
+    /// Released credentials or TLS material are unusable.
+    Credentials = 1602,
+    /// A resource store operation failed.
+    ResourceStore = 1603,
+    /// The connection pool was shut down.
+    PoolClosed = 1604,
+    Tls = 1701,
+    /// Encrypting or decrypting a secret failed.
+    Crypto = 1702,
+    /// The configuration is missing or inconsistent.
+    Configuration = 1801,
+}
+
+impl ErrorCode {
+    /// The numeric code, e.g. `1001`.
+    pub fn number(self) -> u16 {
+        self as u16
+    }
+
+    /// The symbolic name, e.g. `KBS_TRANSPORT`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::KbsTransport => "KBS_TRANSPORT",
+            ErrorCode::KbsRejected => "KBS_REJECTED",
+            ErrorCode::KbsFailure => "KBS_FAILURE",
+            ErrorCode::KbsSession => "KBS_SESSION",
+            ErrorCode::Serialization => "SERIALIZATION",
+            ErrorCode::VerificationFailed => "VERIFICATION_FAILED",
+            ErrorCode::NonceRejected => "NONCE_REJECTED",
+            ErrorCode::TokenInvalid => "TOKEN_INVALID",
+            ErrorCode::CertificateInvalid => "CERTIFICATE_INVALID",
+            ErrorCode::NonceStoreUnavailable => "NONCE_STORE_UNAVAILABLE",
+            ErrorCode::Policy => "POLICY",
+            ErrorCode::Device => "DEVICE",
+            ErrorCode::Lock => "LOCK",
+            ErrorCode::Timeout => "TIMEOUT",
+            ErrorCode::Io => "IO",
+            ErrorCode::Database => "DATABASE",
+            ErrorCode::Credentials => "CREDENTIALS",
+            ErrorCode::ResourceStore => "RESOURCE_STORE",
+            ErrorCode::PoolClosed => "POOL_CLOSED",
+            ErrorCode::Tls => "TLS",
+            ErrorCode::Crypto => "CRYPTO",
+            ErrorCode::Configuration => "CONFIGURATION",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "E{} {}", self.number(), self.as_str())
+    }
+}
+
+/// Any error raised by the attestation flow.
+#[derive(Debug)]
+pub enum AttestationError {
+    #[cfg(feature = "kbs-client")]
+    Kbs(KbsClientError),
+    #[cfg(feature = "kbs-server")]
+    KbsServer(KbsServerError),
+    Serialization(String),
+    /// The evidence was checked and rejected.
+    Verification(Box<VerificationResult>),
+    Nonce(NonceError),
+    Token(TokenError),
+    CertChain(CertChainError),
+    Policy(PolicyError),
+    Device(AttesterError),
+    Lock(SyncError),
+    /// An operation did not complete in time; names the operation.
+    Timeout(String),
+    Io(std::io::Error),
+    Database(postgres::Error),
+    /// Released credentials or TLS material are unusable.
+    Credentials(String),
+    #[cfg(feature = "kbs-server")]
+    ResourceStore(ResourceStoreError),
+    /// The connection pool was closed while waiting for a connection.
+    PoolClosed,
+    Tls(rustls::Error),
+    #[cfg(feature = "ra-tls")]
+    RaTls(RaTlsError),
+    Jwe(JweError),
+    Configuration(String),
+}
+
+impl AttestationError {
+    /// The stable code of this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            #[cfg(feature = "kbs-client")]
+            AttestationError::Kbs(e) => match e {
+                KbsClientError::Transport(_) => ErrorCode::KbsTransport,
+                KbsClientError::Status { status: 401, error_type, .. } if error_type == ERROR_NONCE_REJECTED => {
+                    ErrorCode::NonceRejected
+                }
+                KbsClientError::Status { status: 401 | 403, .. } => ErrorCode::KbsRejected,
+                KbsClientError::Status { .. } | KbsClientError::InvalidResourcePath(_) => ErrorCode::KbsFailure,
+                KbsClientError::NoSession => ErrorCode::KbsSession,
+                KbsClientError::Serialization(_) => ErrorCode::Serialization,
+                KbsClientError::Decryption(_) => ErrorCode::Crypto,
+            },
+            #[cfg(feature = "kbs-server")]
+            AttestationError::KbsServer(e) => match e {
+                KbsServerError::Policy(_) => ErrorCode::Policy,
+                KbsServerError::TrustAnchors(_) => ErrorCode::CertificateInvalid,
+                KbsServerError::ResourceStore(_) => ErrorCode::ResourceStore,
+                KbsServerError::NonceStore(_) => ErrorCode::NonceStoreUnavailable,
+                KbsServerError::ResourceKey(_) | KbsServerError::Bind(_) | KbsServerError::DatabaseTls(_) => {
+                    ErrorCode::Configuration
+                }
+            },
+            AttestationError::Serialization(_) => ErrorCode::Serialization,
+            AttestationError::Verification(result) => match result.failure {
+                Some(FailureCode::NonceExpired | FailureCode::NonceReplayed) => ErrorCode::NonceRejected,
+                Some(FailureCode::NonceStoreUnavailable) => ErrorCode::NonceStoreUnavailable,
+                _ => ErrorCode::VerificationFailed,
+            },
+            AttestationError::Nonce(NonceError::Store(_)) => ErrorCode::NonceStoreUnavailable,
+            AttestationError::Nonce(_) => ErrorCode::NonceRejected,
+            AttestationError::Token(_) => ErrorCode::TokenInvalid,
+            AttestationError::CertChain(_) => ErrorCode::CertificateInvalid,
+            AttestationError::Policy(_) => ErrorCode::Policy,
+            AttestationError::Device(_) => ErrorCode::Device,
+            AttestationError::Lock(SyncError::LockTimeout) => ErrorCode::Timeout,
+            AttestationError::Lock(_) => ErrorCode::Lock,
+            AttestationError::Timeout(_) => ErrorCode::Timeout,
+            AttestationError::Io(_) => ErrorCode::Io,
+            AttestationError::Database(_) => ErrorCode::Database,
+            AttestationError::Credentials(_) => ErrorCode::Credentials,
+            #[cfg(feature = "kbs-server")]
+            AttestationError::ResourceStore(_) => ErrorCode::ResourceStore,
+            AttestationError::PoolClosed => ErrorCode::PoolClosed,
+            AttestationError::Tls(_) => ErrorCode::Tls,
+            #[cfg(feature = "ra-tls")]
+            AttestationError::RaTls(e) => match e {
+                RaTlsError::Untrusted(_) | RaTlsError::Binding(_) => ErrorCode::VerificationFailed,
+                RaTlsError::Evidence(_) => ErrorCode::Device,
+                RaTlsError::Tls(_) => ErrorCode::Tls,
+                RaTlsError::KeyGeneration(_) | RaTlsError::Certificate(_) => ErrorCode::CertificateInvalid,
+            },
+            AttestationError::Jwe(_) => ErrorCode::Crypto,
+            AttestationError::Configuration(_) => ErrorCode::Configuration,
+        }
+    }
+}
+
+impl fmt::Display for AttestationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] ", self.code())?;
+        match self {
+            #[cfg(feature = "kbs-client")]
+            AttestationError::Kbs(e) => write!(f, "{}", e),
+            #[cfg(feature = "kbs-server")]
+            AttestationError::KbsServer(e) => write!(f, "{}", e),
+            AttestationError::Serialization(e) => write!(f, "Serialization error: {}", e),
+            AttestationError::Verification(result) => {
+                write!(f, "Verification failed [{:?}]: {}", result.failure, result.message)
+            }
+            AttestationError::Nonce(e) => write!(f, "{}", e),
+            AttestationError::Token(e) => write!(f, "{}", e),
+            AttestationError::CertChain(e) => write!(f, "{}", e),
+            AttestationError::Policy(e) => write!(f, "{}", e),
+            AttestationError::Device(e) => write!(f, "{}", e),
+            AttestationError::Lock(e) => write!(f, "{}", e),
+            AttestationError::Timeout(operation) => write!(f, "Timed out: {}", operation),
+            AttestationError::Io(e) => write!(f, "I/O error: {}", e),
+            AttestationError::Database(e) => write!(f, "Database error: {}", e),
+            AttestationError::Credentials(e) => write!(f, "Unusable credentials: {}", e),
+            #[cfg(feature = "kbs-server")]
+            AttestationError::ResourceStore(e) => write!(f, "{}", e),
+            AttestationError::PoolClosed => write!(f, "Connection pool closed"),
+            AttestationError::Tls(e) => write!(f, "TLS error: {}", e),
+            #[cfg(feature = "ra-tls")]
+            AttestationError::RaTls(e) => write!(f, "{}", e),
+            AttestationError::Jwe(e) => write!(f, "{}", e),
+            AttestationError::Configuration(e) => write!(f, "Configuration error: {}", e),
+        }
+    }
+}
+
+impl Error for AttestationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            #[cfg(feature = "kbs-client")]
+            AttestationError::Kbs(e) => Some(e),
+            #[cfg(feature = "kbs-server")]
+            AttestationError::KbsServer(e) => Some(e),
+            AttestationError::Nonce(e) => Some(e),
+            AttestationError::Token(e) => Some(e),
+            AttestationError::CertChain(e) => Some(e),
+            AttestationError::Policy(e) => Some(e),
+            AttestationError::Device(e) => Some(e),
+            AttestationError::Lock(e) => Some(e),
+            AttestationError::Io(e) => Some(e),
+            AttestationError::Database(e) => Some(e),
+            #[cfg(feature = "kbs-server")]
+            AttestationError::ResourceStore(e) => Some(e),
+            AttestationError::Tls(e) => Some(e),
+            #[cfg(feature = "ra-tls")]
+            AttestationError::RaTls(e) => Some(e),
+            AttestationError::Jwe(e) => Some(e),
+            AttestationError::Serialization(_)
+            | AttestationError::Verification(_)
+            | AttestationError::Timeout(_)
+            | AttestationError::Credentials(_)
+            | AttestationError::PoolClosed
+            | AttestationError::Configuration(_) => None,
+        }
+    }
+}
+
+#[cfg(feature = "kbs-client")]
+impl From<KbsClientError> for AttestationError {
+    fn from(error: KbsClientError) -> Self {
+        AttestationError::Kbs(error)
+    }
+}
+
+#[cfg(feature = "kbs-server")]
+impl From<KbsServerError> for AttestationError {
+    fn from(error: KbsServerError) -> Self {
+        AttestationError::KbsServer(error)
+    }
+}
+
+impl From<serde_json::Error> for AttestationError {
+    fn from(error: serde_json::Error) -> Self {
+        AttestationError::Serialization(error.to_string())
+    }
+}
+
+impl From<VerificationResult> for AttestationError {
+    fn from(result: VerificationResult) -> Self {
+        AttestationError::Verification(Box::new(result))
+    }
+}
+
+impl From<NonceError> for AttestationError {
+    fn from(error: NonceError) -> Self {
+        AttestationError::Nonce(error)
+    }
+}
+
+impl From<TokenError> for AttestationError {
+    fn from(error: TokenError) -> Self {
+        AttestationError::Token(error)
+    }
+}
+
+impl From<CertChainError> for AttestationError {
+    fn from(error: CertChainError) -> Self {
+        AttestationError::CertChain(error)
+    }
+}
+
+impl From<PolicyError> for AttestationError {
+    fn from(error: PolicyError) -> Self {
+        AttestationError::Policy(error)
+    }
+}
+
+impl From<AttesterError> for AttestationError {
+    fn from(error: AttesterError) -> Self {
+        AttestationError::Device(error)
+    }
+}
+
+impl From<SyncError> for AttestationError {
+    fn from(error: SyncError) -> Self {
+        AttestationError::Lock(error)
+    }
+}
+
+impl<T> From<PoisonError<T>> for AttestationError {
+    fn from(_: PoisonError<T>) -> Self {
+        AttestationError::Lock(SyncError::LockPoisoned)
+    }
+}
+
+impl From<std::io::Error> for AttestationError {
+    fn from(error: std::io::Error) -> Self {
+        AttestationError::Io(error)
+    }
+}
+
+impl From<postgres::Error> for AttestationError {
+    fn from(error: postgres::Error) -> Self {
+        AttestationError::Database(error)
+    }
+}
+
+#[cfg(feature = "kbs-server")]
+impl From<ResourceStoreError> for AttestationError {
+    fn from(error: ResourceStoreError) -> Self {
+        AttestationError::ResourceStore(error)
+    }
+}
+
+impl From<rustls::Error> for AttestationError {
+    fn from(error: rustls::Error) -> Self {
+        AttestationError::Tls(error)
+    }
+}
+
+#[cfg(feature = "ra-tls")]
+impl From<RaTlsError> for AttestationError {
+    fn from(error: RaTlsError) -> Self {
+        AttestationError::RaTls(error)
+    }
+}
+
+impl From<JweError> for AttestationError {
+    fn from(error: JweError) -> Self {
+        AttestationError::Jwe(error)
+    }
+}
@@ -0,0 +1,309 @@
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use aes_kw::KekAes256;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fmt;
+
+use crate::kbs_protocol::{TeePubKey, TEE_KEY_ALGORITHM};
+
+// --- JWE Secret Wrapping ---
+//
+// Resources are released as JWE (RFC 7516) encrypted to the TEE public key
+// bound into the evidence: ECDH-ES+A256KW key agreement with an ephemeral
+// P-256 key (RFC 7518 §4.6) wraps a random A256GCM content key. Only the
+// attested guest holds the private half of the TEE key.
+
+/// The content encryption algorithm of every JWE.
+pub const JWE_CONTENT_ENCRYPTION: &str = "A256GCM";
+
+/// Errors raised while wrapping or unwrapping a secret.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JweError {
+    /// A field is not valid base64url or the header is not valid JSON.
+    Malformed(String),
+    /// The header names an algorithm other than ECDH-ES+A256KW / A256GCM.
+    UnsupportedAlgorithm(String),
+    /// The recipient or ephemeral key is not a valid P-256 key.
+    InvalidKey(String),
+    /// Key wrapping or content encryption failed.
+    EncryptionFailed,
+    /// Key unwrapping or content decryption failed authentication.
+    DecryptionFailed,
+}
+
+impl fmt::Display for JweError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JweError::Malformed(e) => write!(f, "Malformed JWE: {}", e),
+            JweError::UnsupportedAlgorithm(a) => write!(f, "Unsupported JWE algorithm {}", a),
+            JweError::InvalidKey(e) => write!(f, "Invalid JWE key: {}", e),
+            JweError::EncryptionFailed => write!(f, "JWE encryption failed"),
+            JweError::DecryptionFailed => write!(f, "JWE decryption failed"),
+        }
+    }
+}
+
+impl Error for JweError {}
+
+/// A JWE in flattened JSON serialization, as returned by the KBS
+/// `resource` endpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Jwe {
+    /// Base64url protected header.
+    pub protected: String,
+    pub encrypted_key: String,
+    pub iv: String,
+    pub ciphertext: String,
+    pub tag: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ProtectedHeader {
+    alg: String,
+    enc: String,
+    epk: EphemeralKey,
+}
+
+/// The sender's ephemeral public key (`epk`), without the `alg` member.
+#[derive(Debug, Serialize, Deserialize)]
+struct EphemeralKey {
+    kty: String,
+    crv: String,
+    x: String,
+    y: String,
+}
+
+/// Concat KDF (NIST SP 800-56A) as profiled for ECDH-ES in RFC 7518 §4.6.2.
+/// Only the first SHA-256 round is computed: keys of up to 256 bits are its
+/// leading `key_bits / 8` bytes.
+fn concat_kdf(shared_secret: &[u8], algorithm: &str, apu: &[u8], apv: &[u8], key_bits: u32) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(1u32.to_be_bytes());
+    hasher.update(shared_secret);
+    for field in [algorithm.as_bytes(), apu, apv] {
+        hasher.update((field.len() as u32).to_be_bytes());
+        hasher.update(field);
+    }
+    hasher.update(key_bits.to_be_bytes()); // SuppPubInfo
+    hasher.finalize().into()
+}
+
+/// The A256KW key encryption key, with empty PartyUInfo/PartyVInfo.
+fn derive_kek(shared_secret: &[u8]) -> [u8; 32] {
+    concat_kdf(shared_secret, TEE_KEY_ALGORITHM, &[], &[], 256)
+}
+
+fn decode(field: &str, value: &str) -> Result<Vec<u8>, JweError> {
+    URL_SAFE_NO_PAD
+        .decode(value)
+        .map_err(|e| JweError::Malformed(format!("{}: {}", field, e)))
+}
+
+/// Encrypts `plaintext` to the TEE key.
+pub fn encrypt(plaintext: &[u8], recipient: &TeePubKey) -> Result<Jwe, JweError> {
+    let recipient = recipient.to_p256().map_err(JweError::InvalidKey)?;
+
+    let ephemeral = p256::ecdh::EphemeralSecret::random(&mut OsRng);
+    let epk = TeePubKey::from_p256(&ephemeral.public_key());
+    let shared = ephemeral.diffie_hellman(&recipient);
+    let kek = KekAes256::from(derive_kek(shared.raw_secret_bytes()));
+
+    let mut cek = [0u8; 32];
+    OsRng.fill_bytes(&mut cek);
+    let mut encrypted_key = [0u8; 40];
+    kek.wrap(&cek, &mut encrypted_key).map_err(|_| JweError::EncryptionFailed)?;
+
+    let header = ProtectedHeader {
+        alg: TEE_KEY_ALGORITHM.to_string(),
+        enc: JWE_CONTENT_ENCRYPTION.to_string(),
+        epk: EphemeralKey { kty: epk.kty, crv: epk.crv, x: epk.x, y: epk.y },
+    };
+    let protected = URL_SAFE_NO_PAD.encode(
+        serde_json::to_vec(&header).map_err(|e| JweError::Malformed(e.to_string()))?,
+    );
+
+    let mut iv = [0u8; 12];
+    OsRng.fill_bytes(&mut iv);
+    let cipher = Aes256Gcm::new_from_slice(&cek).map_err(|_| JweError::EncryptionFailed)?;
+    let mut sealed = cipher
+        .encrypt(&Nonce::from(iv), Payload { msg: plaintext, aad: protected.as_bytes() })
+        .map_err(|_| JweError::EncryptionFailed)?;
+    let tag = sealed.split_off(sealed.len() - 16);
+
+    Ok(Jwe {
+        protected,
+        encrypted_key: URL_SAFE_NO_PAD.encode(encrypted_key),
+        iv: URL_SAFE_NO_PAD.encode(iv),
+        ciphertext: URL_SAFE_NO_PAD.encode(sealed),
+        tag: URL_SAFE_NO_PAD.encode(tag),
+    })
+}
+
+/// Decrypts a JWE with the TEE private key.
+pub fn decrypt(jwe: &Jwe, key: &p256::SecretKey) -> Result<Vec<u8>, JweError> {
+    let header: ProtectedHeader = serde_json::from_slice(&decode("protected", &jwe.protected)?)
+        .map_err(|e| JweError::Malformed(e.to_string()))?;
+    if header.alg != TEE_KEY_ALGORITHM {
+        return Err(JweError::UnsupportedAlgorithm(header.alg));
+    }
+    if header.enc != JWE_CONTENT_ENCRYPTION {
+        return Err(JweError::UnsupportedAlgorithm(header.enc));
+    }
+    let epk = TeePubKey {
+        kty: header.epk.kty,
+        alg: TEE_KEY_ALGORITHM.to_string(),
+        crv: header.epk.crv,
+        x: header.epk.x,
+        y: header.epk.y,
+    }
+    .to_p256()
+    .map_err(JweError::InvalidKey)?;
+
+    let shared = p256::ecdh::diffie_hellman(key.to_nonzero_scalar(), epk.as_affine());
+    let kek = KekAes256::from(derive_kek(shared.raw_secret_bytes()));
+    let encrypted_key = decode("encrypted_key", &jwe.encrypted_key)?;
+    if encrypted_key.len() != 40 {
+        return Err(JweError::Malformed("encrypted_key must wrap a 256-bit key".to_string()));
+    }
+    let mut cek = [0u8; 32];
+    kek.unwrap(&encrypted_key, &mut cek).map_err(|_| JweError::DecryptionFailed)?;
+
+    let iv: [u8; 12] = decode("iv", &jwe.iv)?
+        .try_into()
+        .map_err(|_| JweError::Malformed("iv must be 96 bits".to_string()))?;
+    let mut sealed = decode("ciphertext", &jwe.ciphertext)?;
+    sealed.extend_from_slice(&decode("tag", &jwe.tag)?);
+    let cipher = Aes256Gcm::new_from_slice(&cek).map_err(|_| JweError::DecryptionFailed)?;
+    cipher
+        .decrypt(&Nonce::from(iv), Payload { msg: &sealed, aad: jwe.protected.as_bytes() })
+        .map_err(|_| JweError::DecryptionFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tee_key() -> (p256::SecretKey, TeePubKey) {
+        let key = p256::SecretKey::random(&mut OsRng);
+        let public = TeePubKey::from_p256(&key.public_key());
+        (key, public)
+    }
+
+    fn secret_key(d: &str) -> p256::SecretKey {
+        p256::SecretKey::from_slice(&URL_SAFE_NO_PAD.decode(d).unwrap()).unwrap()
+    }
+
+    /// Re-encodes the protected header after `edit`.
+    fn with_header(jwe: &Jwe, edit: impl FnOnce(&mut serde_json::Value)) -> Jwe {
+        let header = decode("protected", &jwe.protected).unwrap();
+        let mut header: serde_json::Value = serde_json::from_slice(&header).unwrap();
+        edit(&mut header);
+        Jwe { protected: URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header).unwrap()), ..jwe.clone() }
+    }
+
+    /// Flips the low bit of the first byte of a base64url field.
+    fn flip(field: &str) -> String {
+        let mut bytes = URL_SAFE_NO_PAD.decode(field).unwrap();
+        bytes[0] ^= 1;
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    #[test]
+    fn concat_kdf_matches_rfc7518_appendix_c() {
+        // ECDH-ES key agreement between Alice's ephemeral and Bob's static
+        // key, deriving an A128GCM key with PartyUInfo "Alice" and
+        // PartyVInfo "Bob".
+        let alice = secret_key("0_NxaRPUMQoAJt50Gz8YiTr8gRTwyEaCumd-MToTmIo");
+        let bob = secret_key("VEmDZpDXXK8p8N0Cndsxs924q6nS1RXFASRl6BfUqdw");
+        let shared = p256::ecdh::diffie_hellman(alice.to_nonzero_scalar(), bob.public_key().as_affine());
+        let expected_z = [
+            158, 86, 217, 29, 129, 113, 53, 211, 114, 131, 66, 131, 191, 132, 38, 156, 251, 49, 110, 163, 218, 128,
+            106, 72, 246, 218, 167, 121, 140, 254, 144, 196,
+        ];
+        assert_eq!(shared.raw_secret_bytes()[..], expected_z);
+
+        let key = concat_kdf(&expected_z, "A128GCM", b"Alice", b"Bob", 128);
+        assert_eq!(URL_SAFE_NO_PAD.encode(&key[..16]), "VqqN6vgjbSBcIijNcacQGg");
+    }
+
+    #[test]
+    fn round_trips_to_the_tee_key() {
+        let (key, public) = tee_key();
+        for plaintext in [&b"database password"[..], &[], &[0xa5; 4096]] {
+            let jwe = encrypt(plaintext, &public).unwrap();
+            assert_eq!(decrypt(&jwe, &key).unwrap(), plaintext);
+        }
+
+        // A fresh ephemeral key, content key and IV every time.
+        let (first, second) = (encrypt(b"secret", &public).unwrap(), encrypt(b"secret", &public).unwrap());
+        assert_ne!(first.protected, second.protected);
+        assert_ne!(first.encrypted_key, second.encrypted_key);
+        assert_ne!(first.iv, second.iv);
+    }
+
+    #[test]
+    fn another_key_cannot_decrypt() {
+        let (_, public) = tee_key();
+        let (other, _) = tee_key();
+        let jwe = encrypt(b"secret", &public).unwrap();
+        assert_eq!(decrypt(&jwe, &other), Err(JweError::DecryptionFailed));
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let (key, public) = tee_key();
+        let jwe = encrypt(b"secret", &public).unwrap();
+
+        let tampered = [
+            Jwe { ciphertext: flip(&jwe.ciphertext), ..jwe.clone() },
+            Jwe { tag: flip(&jwe.tag), ..jwe.clone() },
+            Jwe { iv: flip(&jwe.iv), ..jwe.clone() },
+            Jwe { encrypted_key: flip(&jwe.encrypted_key), ..jwe.clone() },
+            // Still a valid header, but not the one the content was sealed with.
+            with_header(&jwe, |header| header["kid"] = "other".into()),
+        ];
+        for jwe in tampered {
+            assert_eq!(decrypt(&jwe, &key), Err(JweError::DecryptionFailed), "{:?}", jwe);
+        }
+
+        // Another ephemeral key derives another key encryption key.
+        let (_, other) = tee_key();
+        let swapped = with_header(&jwe, |header| {
+            header["epk"]["x"] = other.x.clone().into();
+            header["epk"]["y"] = other.y.clone().into();
+        });
+        assert_eq!(decrypt(&swapped, &key), Err(JweError::DecryptionFailed));
+    }
+
+    #[test]
+    fn rejects_unsupported_algorithms() {
+        let (key, public) = tee_key();
+        let jwe = encrypt(b"secret", &public).unwrap();
+
+        let alg = with_header(&jwe, |header| header["alg"] = "ECDH-ES+A128KW".into());
+        assert_eq!(decrypt(&alg, &key), Err(JweError::UnsupportedAlgorithm("ECDH-ES+A128KW".to_string())));
+        let enc = with_header(&jwe, |header| header["enc"] = "A128CBC-HS256".into());
+        assert_eq!(decrypt(&enc, &key), Err(JweError::UnsupportedAlgorithm("A128CBC-HS256".to_string())));
+        let crv = with_header(&jwe, |header| header["epk"]["crv"] = "P-384".into());
+        assert!(matches!(decrypt(&crv, &key), Err(JweError::InvalidKey(_))));
+    }
+
+    #[test]
+    fn rejects_malformed_fields() {
+        let (key, public) = tee_key();
+        let jwe = encrypt(b"secret", &public).unwrap();
+        for malformed in [
+            Jwe { protected: "not base64!".to_string(), ..jwe.clone() },
+            Jwe { protected: URL_SAFE_NO_PAD.encode("{}"), ..jwe.clone() },
+            Jwe { iv: URL_SAFE_NO_PAD.encode([0u8; 16]), ..jwe.clone() },
+            Jwe { encrypted_key: URL_SAFE_NO_PAD.encode([0u8; 32]), ..jwe.clone() },
+        ] {
+            assert!(matches!(decrypt(&malformed, &key), Err(JweError::Malformed(_))), "{:?}", malformed);
+        }
+    }
+}
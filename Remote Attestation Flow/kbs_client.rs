@@ -0,0 +1,231 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::attestation_data::{AttestationChallenge, AttestationReport};
+use crate::jwe::{self, Jwe, JweError};
+use crate::kbs_protocol::{
+    Attestation, AttestationToken, Challenge, ErrorInformation, Request, ResourcePath, TeePubKey,
+    KBS_API_PREFIX, KBS_PROTOCOL_VERSION, KBS_SESSION_COOKIE,
+};
+
+// --- KBS Protocol Client ---
+//
+// Drives the RCAR handshake against a Key Broker Service over HTTP:
+// `auth` opens a session and returns the challenge, `attest` submits evidence
+// for it, and `get_secret` fetches and decrypts secrets released to that
+// session.
+
+/// Environment variable overriding the KBS base URL.
+pub const KBS_URL_ENV: &str = "KBS_URL";
+
+/// Limit on each KBS request unless overridden with `with_timeout`.
+pub const DEFAULT_KBS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Limit on establishing the connection of a KBS request.
+pub const DEFAULT_KBS_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Errors raised while talking to the KBS.
+#[derive(Debug)]
+pub enum KbsClientError {
+    /// The request never produced an HTTP response (DNS, TCP, TLS...).
+    Transport(String),
+    /// The KBS answered with an error status. `error_type` is the
+    /// `ErrorInformation` type, empty if the body was not one.
+    Status { status: u16, error_type: String, detail: String },
+    /// A message could not be encoded or decoded.
+    Serialization(String),
+    /// `attest` or `get_resource` was called before `auth` opened a session.
+    NoSession,
+    /// The resource path is not `<repo>/<type>/<tag>`.
+    InvalidResourcePath(String),
+    /// A released resource could not be decrypted with the TEE key.
+    Decryption(JweError),
+}
+
+impl fmt::Display for KbsClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KbsClientError::Transport(e) => write!(f, "KBS unreachable: {}", e),
+            KbsClientError::Status { status, error_type, detail } if !error_type.is_empty() => {
+                write!(f, "KBS returned {}: {} ({})", status, detail, error_type)
+            }
+            KbsClientError::Status { status, detail, .. } => write!(f, "KBS returned {}: {}", status, detail),
+            KbsClientError::Serialization(e) => write!(f, "Malformed KBS message: {}", e),
+            KbsClientError::NoSession => write!(f, "No KBS session; call auth first"),
+            KbsClientError::InvalidResourcePath(e) => write!(f, "{}", e),
+            KbsClientError::Decryption(e) => write!(f, "Could not decrypt resource: {}", e),
+        }
+    }
+}
+
+impl Error for KbsClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KbsClientError::Decryption(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<JweError> for KbsClientError {
+    fn from(error: JweError) -> Self {
+        KbsClientError::Decryption(error)
+    }
+}
+
+impl From<ureq::Error> for KbsClientError {
+    fn from(error: ureq::Error) -> Self {
+        match error {
+            ureq::Error::Status(status, response) => {
+                let body = response.into_string().unwrap_or_default();
+                match serde_json::from_str::<ErrorInformation>(&body) {
+                    Ok(e) => KbsClientError::Status { status, error_type: e.error_type, detail: e.detail },
+                    Err(_) => KbsClientError::Status { status, error_type: String::new(), detail: body },
+                }
+            }
+            ureq::Error::Transport(t) => KbsClientError::Transport(t.to_string()),
+        }
+    }
+}
+
+/// A client for one KBS session.
+pub struct KbsClient {
+    base_url: String,
+    agent: ureq::Agent,
+    session_id: Mutex<Option<String>>,
+    token: Mutex<Option<String>>,
+}
+
+/// A copy of the session as it stands: requests made through the copy
+/// (a new `auth`, a token) do not change the original, and vice versa.
+/// Connections are still shared.
+impl Clone for KbsClient {
+    fn clone(&self) -> Self {
+        let copy = |slot: &Mutex<Option<String>>| Mutex::new(slot.lock().ok().and_then(|value| value.clone()));
+        KbsClient {
+            base_url: self.base_url.clone(),
+            agent: self.agent.clone(),
+            session_id: copy(&self.session_id),
+            token: copy(&self.token),
+        }
+    }
+}
+
+impl KbsClient {
+    /// Creates a client for the KBS at `base_url` (e.g. `http://127.0.0.1:8080`).
+    pub fn new(base_url: &str) -> Self {
+        KbsClient::with_timeout(base_url, DEFAULT_KBS_TIMEOUT)
+    }
+
+    /// Creates a client whose requests each fail with a transport error
+    /// after `timeout`.
+    pub fn with_timeout(base_url: &str, timeout: Duration) -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(timeout.min(DEFAULT_KBS_CONNECT_TIMEOUT))
+            .timeout(timeout)
+            .build();
+        KbsClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            agent,
+            session_id: Mutex::new(None),
+            token: Mutex::new(None),
+        }
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn url(&self, endpoint: &str) -> String {
+        format!("{}{}/{}", self.base_url, KBS_API_PREFIX, endpoint)
+    }
+
+    fn session_cookie(&self) -> Result<String, KbsClientError> {
+        let session = self.session_id.lock().map_err(|_| KbsClientError::NoSession)?;
+        session
+            .as_ref()
+            .map(|id| format!("{}={}", KBS_SESSION_COOKIE, id))
+            .ok_or(KbsClientError::NoSession)
+    }
+
+    /// `POST /kbs/v0/auth`: opens a session and returns its challenge.
+    pub fn auth(&self, tee: &str) -> Result<AttestationChallenge, KbsClientError> {
+        let request = Request {
+            version: KBS_PROTOCOL_VERSION.to_string(),
+            tee: tee.to_string(),
+            extra_params: String::new(),
+        };
+        let response = self.agent.post(&self.url("auth")).send_json(&request)?;
+
+        let session_id = response
+            .all("Set-Cookie")
+            .into_iter()
+            .filter_map(|cookie| cookie.split(';').next())
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| *name == KBS_SESSION_COOKIE)
+            .map(|(_, value)| value.to_string())
+            .ok_or(KbsClientError::NoSession)?;
+        *self.session_id.lock().map_err(|_| KbsClientError::NoSession)? = Some(session_id);
+        // A token from an earlier session must not outlive it.
+        if let Ok(mut slot) = self.token.lock() {
+            *slot = None;
+        }
+
+        let challenge: Challenge = response
+            .into_json()
+            .map_err(|e| KbsClientError::Serialization(e.to_string()))?;
+        Ok(AttestationChallenge { nonce: challenge.nonce })
+    }
+
+    /// `POST /kbs/v0/attest`: submits evidence for the session's challenge
+    /// and returns the attestation token.
+    pub fn attest(&self, tee_pubkey: &TeePubKey, report: &AttestationReport) -> Result<String, KbsClientError> {
+        let attestation = Attestation {
+            tee_pubkey: tee_pubkey.clone(),
+            tee_evidence: serde_json::to_string(report)
+                .map_err(|e| KbsClientError::Serialization(e.to_string()))?,
+        };
+        let response = self
+            .agent
+            .post(&self.url("attest"))
+            .set("Cookie", &self.session_cookie()?)
+            .send_json(&attestation)?;
+
+        let token: AttestationToken = response
+            .into_json()
+            .map_err(|e| KbsClientError::Serialization(e.to_string()))?;
+        if let Ok(mut slot) = self.token.lock() {
+            *slot = Some(token.token.clone());
+        }
+        Ok(token.token)
+    }
+
+    /// Fetches a resource and decrypts it with the TEE private key whose
+    /// public half was bound into the evidence passed to `attest`.
+    pub fn get_secret(&self, path: &str, tee_key: &p256::SecretKey) -> Result<Vec<u8>, KbsClientError> {
+        let body = self.get_resource(path)?;
+        let response: Jwe =
+            serde_json::from_slice(&body).map_err(|e| KbsClientError::Serialization(e.to_string()))?;
+        Ok(jwe::decrypt(&response, tee_key)?)
+    }
+
+    /// `GET /kbs/v0/resource/<repo>/<type>/<tag>`: fetches a resource
+    /// released to the attested session, still JWE-encrypted.
+    pub fn get_resource(&self, path: &str) -> Result<Vec<u8>, KbsClientError> {
+        let path = ResourcePath::parse(path).map_err(KbsClientError::InvalidResourcePath)?;
+        let mut request = self
+            .agent
+            .get(&self.url(&format!("resource/{}", path)))
+            .set("Cookie", &self.session_cookie()?);
+        if let Some(token) = self.token.lock().ok().and_then(|t| t.clone()) {
+            request = request.set("Authorization", &format!("Bearer {}", token));
+        }
+
+        let mut body = Vec::new();
+        std::io::Read::read_to_end(&mut request.call()?.into_reader(), &mut body)
+            .map_err(|e| KbsClientError::Transport(e.to_string()))?;
+        Ok(body)
+    }
+}
@@ -0,0 +1,144 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+// --- KBS Fault Injection ---
+//
+// Lets a local KBS fail or stall on purpose, to exercise the agents' retry,
+// timeout and restart handling against the real protocol. `KBS_FAULTS`
+// lists rules as `<endpoint>=<fault>[*<count>]`, e.g.
+// `auth=503*2,attest=nonce,resource=delay:5000`: the next two `auth` calls
+// get a 503, the next evidence is rejected as stale, and the next resource
+// request stalls for 5 s. Only built with the `test-faults` feature, which a
+// production KBS must never enable.
+
+/// Environment variable holding the fault rules.
+pub const KBS_FAULTS_ENV: &str = "KBS_FAULTS";
+
+/// The endpoints faults can be injected into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultEndpoint {
+    Auth,
+    Attest,
+    Resource,
+}
+
+impl FaultEndpoint {
+    /// The endpoint a request is routed to, if faults apply to it.
+    pub fn of(method: &str, endpoint: &str) -> Option<Self> {
+        match (method, endpoint) {
+            ("POST", "/auth") => Some(FaultEndpoint::Auth),
+            ("POST", "/attest") => Some(FaultEndpoint::Attest),
+            ("GET", resource) if resource.starts_with("/resource/") => Some(FaultEndpoint::Resource),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Answer with this HTTP status.
+    Status(u16),
+    /// Reject the evidence as if its nonce had expired.
+    NonceRejected,
+    /// Stall this long, then handle the request normally.
+    Delay(Duration),
+}
+
+/// Injects `fault` into the next `count` requests to `endpoint`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FaultRule {
+    pub endpoint: FaultEndpoint,
+    pub fault: Fault,
+    pub count: u32,
+}
+
+impl FaultRule {
+    /// Parses a comma-separated list of rules.
+    pub fn parse_list(spec: &str) -> Result<Vec<FaultRule>, String> {
+        spec.split(',').map(str::trim).filter(|r| !r.is_empty()).map(FaultRule::parse).collect()
+    }
+
+    /// Parses one `<endpoint>=<fault>[*<count>]` rule.
+    pub fn parse(rule: &str) -> Result<FaultRule, String> {
+        let invalid = || format!("invalid fault rule '{}'", rule);
+        let (endpoint, fault) = rule.split_once('=').ok_or_else(invalid)?;
+        let endpoint = match endpoint.trim() {
+            "auth" => FaultEndpoint::Auth,
+            "attest" => FaultEndpoint::Attest,
+            "resource" => FaultEndpoint::Resource,
+            _ => return Err(invalid()),
+        };
+        let (fault, count) = match fault.split_once('*') {
+            Some((fault, count)) => (fault, count.trim().parse().map_err(|_| invalid())?),
+            None => (fault, 1),
+        };
+        let fault = match fault.trim() {
+            "nonce" => Fault::NonceRejected,
+            delay if delay.starts_with("delay:") => {
+                Fault::Delay(Duration::from_millis(delay["delay:".len()..].parse().map_err(|_| invalid())?))
+            }
+            status => match status.parse() {
+                Ok(status @ 400..=599) => Fault::Status(status),
+                _ => return Err(invalid()),
+            },
+        };
+        Ok(FaultRule { endpoint, fault, count })
+    }
+}
+
+/// The rules of a running KBS, consumed as requests arrive.
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    rules: Mutex<Vec<FaultRule>>,
+}
+
+impl FaultInjector {
+    pub fn new(rules: Vec<FaultRule>) -> Self {
+        FaultInjector { rules: Mutex::new(rules) }
+    }
+
+    /// Takes the next fault for a request to `endpoint`, if any is left.
+    pub fn take(&self, endpoint: FaultEndpoint) -> Option<Fault> {
+        let mut rules = self.rules.lock().ok()?;
+        let rule = rules.iter_mut().find(|r| r.endpoint == endpoint && r.count > 0)?;
+        rule.count -= 1;
+        Some(rule.fault)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rule_lists() {
+        let rules = FaultRule::parse_list("auth=503*2, attest=nonce,resource=delay:5000,").unwrap();
+        assert_eq!(
+            rules,
+            vec![
+                FaultRule { endpoint: FaultEndpoint::Auth, fault: Fault::Status(503), count: 2 },
+                FaultRule { endpoint: FaultEndpoint::Attest, fault: Fault::NonceRejected, count: 1 },
+                FaultRule {
+                    endpoint: FaultEndpoint::Resource,
+                    fault: Fault::Delay(Duration::from_millis(5000)),
+                    count: 1
+                },
+            ]
+        );
+        for invalid in ["auth", "token=503", "auth=200", "auth=503*x", "resource=delay:soon"] {
+            assert!(FaultRule::parse(invalid).is_err(), "{} parsed", invalid);
+        }
+    }
+
+    #[test]
+    fn faults_are_consumed_in_order() {
+        let injector = FaultInjector::new(FaultRule::parse_list("auth=503*2,auth=nonce").unwrap());
+        assert_eq!(injector.take(FaultEndpoint::Attest), None);
+        assert_eq!(injector.take(FaultEndpoint::Auth), Some(Fault::Status(503)));
+        assert_eq!(injector.take(FaultEndpoint::Auth), Some(Fault::Status(503)));
+        assert_eq!(injector.take(FaultEndpoint::Auth), Some(Fault::NonceRejected));
+        assert_eq!(injector.take(FaultEndpoint::Auth), None);
+        assert_eq!(FaultEndpoint::of("GET", "/resource/default/key/a"), Some(FaultEndpoint::Resource));
+        assert_eq!(FaultEndpoint::of("GET", "/auth"), None);
+    }
+}
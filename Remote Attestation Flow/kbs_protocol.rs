@@ -0,0 +1,167 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use p256::elliptic_curve::sec1::{EncodedPoint, FromEncodedPoint, ToEncodedPoint};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// --- KBS Protocol Messages ---
+//
+// The Confidential Containers Key Broker Service protocol (RCAR: Request,
+// Challenge, Attestation, Response), shared by the agent-side client and the
+// KBS server.
+
+/// Protocol version sent in the `Request` message.
+pub const KBS_PROTOCOL_VERSION: &str = "0.1.0";
+
+/// Path prefix of every KBS endpoint.
+pub const KBS_API_PREFIX: &str = "/kbs/v0";
+
+/// Name of the cookie carrying the attestation session.
+pub const KBS_SESSION_COOKIE: &str = "kbs-session-id";
+
+/// The JWE key management algorithm the TEE key is used with.
+pub const TEE_KEY_ALGORITHM: &str = "ECDH-ES+A256KW";
+
+/// `ErrorInformation` type of a missing, unknown or expired session.
+pub const ERROR_UNAUTHENTICATED_SESSION: &str = "UnauthenticatedSession";
+
+/// `ErrorInformation` type of evidence bound to an expired or already
+/// redeemed nonce; the agent must start over with a new challenge.
+pub const ERROR_NONCE_REJECTED: &str = "NonceRejected";
+
+/// `ErrorInformation` type of an invalid or expired attestation token.
+pub const ERROR_INVALID_TOKEN: &str = "InvalidToken";
+
+/// `ErrorInformation` type of a challenge that could not be issued or
+/// redeemed because the nonce store is down; sent with status 503, so the
+/// agent retries the same step.
+pub const ERROR_NONCE_STORE_UNAVAILABLE: &str = "NonceStoreUnavailable";
+
+/// Body of `POST /kbs/v0/auth`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Request {
+    pub version: String,
+    /// TEE type of the agent (`snp`, `tdx`, `vtpm`, `sim`).
+    pub tee: String,
+    #[serde(rename = "extra-params", default)]
+    pub extra_params: String,
+}
+
+/// Response to `Request`: the nonce the evidence must be bound to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Challenge {
+    pub nonce: String,
+    #[serde(rename = "extra-params", default)]
+    pub extra_params: String,
+}
+
+/// Body of `POST /kbs/v0/attest`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attestation {
+    /// The ephemeral key bound into the evidence's report_data.
+    #[serde(rename = "tee-pubkey")]
+    pub tee_pubkey: TeePubKey,
+    /// The JSON-encoded `AttestationReport`.
+    #[serde(rename = "tee-evidence")]
+    pub tee_evidence: String,
+}
+
+/// Response to a successful `Attestation`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttestationToken {
+    pub token: String,
+}
+
+/// The error body returned by every KBS endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorInformation {
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub detail: String,
+}
+
+/// An EC P-256 public key in JWK form, as sent in `tee-pubkey`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TeePubKey {
+    pub kty: String,
+    pub alg: String,
+    pub crv: String,
+    /// Base64url (unpadded) affine X coordinate.
+    pub x: String,
+    /// Base64url (unpadded) affine Y coordinate.
+    pub y: String,
+}
+
+impl TeePubKey {
+    pub fn from_p256(key: &p256::PublicKey) -> Self {
+        let point = key.to_encoded_point(false);
+        TeePubKey {
+            kty: "EC".to_string(),
+            alg: TEE_KEY_ALGORITHM.to_string(),
+            crv: "P-256".to_string(),
+            x: URL_SAFE_NO_PAD.encode(point.x().map(|x| &x[..]).unwrap_or_default()),
+            y: URL_SAFE_NO_PAD.encode(point.y().map(|y| &y[..]).unwrap_or_default()),
+        }
+    }
+
+    pub fn to_p256(&self) -> Result<p256::PublicKey, String> {
+        if self.kty != "EC" || self.crv != "P-256" {
+            return Err(format!("unsupported TEE key type {} {}", self.kty, self.crv));
+        }
+        let decode = |c: &str| {
+            URL_SAFE_NO_PAD
+                .decode(c)
+                .ok()
+                .filter(|b| b.len() == 32)
+                .ok_or_else(|| "invalid TEE key coordinate".to_string())
+        };
+        let (x, y) = (decode(&self.x)?, decode(&self.y)?);
+        let point = EncodedPoint::<p256::NistP256>::from_affine_coordinates(
+            x.as_slice().into(),
+            y.as_slice().into(),
+            false,
+        );
+        Option::from(p256::PublicKey::from_encoded_point(&point))
+            .ok_or_else(|| "TEE key is not a valid P-256 point".to_string())
+    }
+
+    /// The bytes bound into report_data as `RuntimeData::public_key`.
+    pub fn to_binding_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap_or_default()
+    }
+}
+
+/// A KBS resource location: `<repository>/<type>/<tag>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourcePath {
+    pub repository: String,
+    pub resource_type: String,
+    pub tag: String,
+}
+
+impl ResourcePath {
+    /// Parses `repo/type/tag`, or `type/tag` in the `default` repository.
+    /// A leading slash is ignored, so `/keys/database-cred` is accepted.
+    pub fn parse(path: &str) -> Result<Self, String> {
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        if segments.iter().any(|s| s.is_empty() || *s == "." || *s == "..") {
+            return Err(format!("invalid resource path '{}'", path));
+        }
+        let (repository, resource_type, tag) = match segments.as_slice() {
+            [t, tag] => ("default", *t, *tag),
+            [repo, t, tag] => (*repo, *t, *tag),
+            _ => return Err(format!("resource path '{}' is not <repo>/<type>/<tag>", path)),
+        };
+        Ok(ResourcePath {
+            repository: repository.to_string(),
+            resource_type: resource_type.to_string(),
+            tag: tag.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for ResourcePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.repository, self.resource_type, self.tag)
+    }
+}
@@ -0,0 +1,1030 @@
+use rand_core::{OsRng, RngCore};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::attestation_data::{AttestationChallenge, AttestationReport, Claims, FailureCode};
+use crate::attestation_token::{TokenIssuer, TokenValidator};
+use crate::cert_chain::{CertChainError, TrustAnchors};
+use crate::jwe;
+use crate::db_tls::{SslMode, TlsMaterial};
+use crate::nonce_store::{FileNonceStore, InMemoryNonceStore, NonceError, NonceStore, PostgresNonceStore};
+#[cfg(feature = "test-faults")]
+use crate::kbs_faults::{Fault, FaultEndpoint, FaultInjector, FaultRule, KBS_FAULTS_ENV};
+use crate::kbs_protocol::{
+    Attestation, AttestationToken, Challenge, ErrorInformation, Request, ResourcePath, TeePubKey,
+    ERROR_INVALID_TOKEN, ERROR_NONCE_REJECTED, ERROR_NONCE_STORE_UNAVAILABLE, ERROR_UNAUTHENTICATED_SESSION,
+    KBS_API_PREFIX, KBS_PROTOCOL_VERSION, KBS_SESSION_COOKIE,
+};
+use crate::policy::{Policy, PolicyError, DEFAULT_POLICY_PATH};
+use crate::resource_store::{
+    AuditRecord, FileResourceStore, PostgresResourceStore, ResourcePolicy, ResourceStore, ResourceStoreError,
+};
+use crate::verifier::verifier::{self, ChallengeIssuer};
+
+// --- Key Broker Service ---
+//
+// Serves the RCAR endpoints on top of the verifier: `auth` issues a challenge
+// for a new session, `attest` verifies evidence against the configured policy
+// and issues a signed EAR attestation token, and `resource` releases secrets
+// from the resource store only to sessions whose attestation passed and whose
+// claims satisfy the resource's own policy, encrypted as JWE to the TEE key
+// bound into their evidence. The `admin` endpoints manage the store and read
+// its audit log; they are disabled unless an admin token is configured.
+
+/// Environment variables read by `KbsConfig::from_env`.
+pub const KBS_LISTEN_ENV: &str = "KBS_LISTEN";
+pub const KBS_POLICY_ENV: &str = "KBS_POLICY";
+pub const KBS_RESOURCE_DIR_ENV: &str = "KBS_RESOURCE_DIR";
+pub const KBS_EXTRA_ROOTS_ENV: &str = "KBS_EXTRA_ROOTS";
+pub const KBS_RESOURCE_DB_ENV: &str = "KBS_RESOURCE_DB";
+pub const KBS_RESOURCE_KEY_FILE_ENV: &str = "KBS_RESOURCE_KEY_FILE";
+pub const KBS_ADMIN_TOKEN_ENV: &str = "KBS_ADMIN_TOKEN";
+pub const KBS_NONCE_STORE_ENV: &str = "KBS_NONCE_STORE";
+pub const KBS_DB_SSLMODE_ENV: &str = "KBS_DB_SSLMODE";
+pub const KBS_DB_CA_FILE_ENV: &str = "KBS_DB_CA_FILE";
+pub const KBS_DB_CLIENT_CERT_FILE_ENV: &str = "KBS_DB_CLIENT_CERT_FILE";
+pub const KBS_DB_CLIENT_KEY_FILE_ENV: &str = "KBS_DB_CLIENT_KEY_FILE";
+
+/// Default directory of released resources, laid out as `<repo>/<type>/<tag>`.
+pub const DEFAULT_RESOURCE_DIR: &str = "/etc/attester_flow/resources";
+
+/// Number of audit records returned by `GET /admin/audit` without `limit`.
+pub const DEFAULT_AUDIT_LIMIT: usize = 100;
+
+/// How long an attested session (and its token) stays valid.
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(300);
+
+/// How long a token signing key is used before the KBS rotates it.
+pub const DEFAULT_TOKEN_KEY_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// `iss` of the attestation tokens this KBS issues.
+pub const KBS_TOKEN_ISSUER: &str = "attester-flow-kbs";
+
+/// `aud` of the attestation tokens this KBS issues for its own resources.
+pub const KBS_TOKEN_AUDIENCE: &str = "kbs-resource";
+
+/// Errors raised while starting the KBS.
+#[derive(Debug)]
+pub enum KbsServerError {
+    Policy(PolicyError),
+    TrustAnchors(CertChainError),
+    ResourceStore(ResourceStoreError),
+    /// The resource encryption key is missing or not 256 bits.
+    ResourceKey(String),
+    /// The listener could not be bound.
+    Bind(String),
+    NonceStore(NonceError),
+    /// The database TLS files could not be read.
+    DatabaseTls(String),
+}
+
+impl fmt::Display for KbsServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KbsServerError::Policy(e) => write!(f, "{}", e),
+            KbsServerError::TrustAnchors(e) => write!(f, "Could not load trust anchors: {}", e),
+            KbsServerError::ResourceStore(e) => write!(f, "Could not open resource store: {}", e),
+            KbsServerError::ResourceKey(e) => write!(f, "Invalid resource encryption key: {}", e),
+            KbsServerError::Bind(e) => write!(f, "Could not bind KBS listener: {}", e),
+            KbsServerError::NonceStore(e) => write!(f, "Could not open nonce store: {}", e),
+            KbsServerError::DatabaseTls(e) => write!(f, "Invalid database TLS settings: {}", e),
+        }
+    }
+}
+
+impl Error for KbsServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KbsServerError::Policy(e) => Some(e),
+            KbsServerError::TrustAnchors(e) => Some(e),
+            KbsServerError::ResourceStore(e) => Some(e),
+            KbsServerError::NonceStore(e) => Some(e),
+            KbsServerError::ResourceKey(_) | KbsServerError::Bind(_) | KbsServerError::DatabaseTls(_) => None,
+        }
+    }
+}
+
+impl From<PolicyError> for KbsServerError {
+    fn from(error: PolicyError) -> Self {
+        KbsServerError::Policy(error)
+    }
+}
+
+impl From<ResourceStoreError> for KbsServerError {
+    fn from(error: ResourceStoreError) -> Self {
+        KbsServerError::ResourceStore(error)
+    }
+}
+
+impl From<NonceError> for KbsServerError {
+    fn from(error: NonceError) -> Self {
+        KbsServerError::NonceStore(error)
+    }
+}
+
+impl From<CertChainError> for KbsServerError {
+    fn from(error: CertChainError) -> Self {
+        KbsServerError::TrustAnchors(error)
+    }
+}
+
+/// Where the KBS records the challenge nonces it issues.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum NonceStoreConfig {
+    /// Process-local; only for a single KBS instance.
+    #[default]
+    InMemory,
+    /// A JSON file shared by KBS processes on one host.
+    File(PathBuf),
+    /// A Postgres connection string, for KBS instances across hosts.
+    Postgres(String),
+}
+
+impl NonceStoreConfig {
+    /// Parses `memory`, `file:<path>` or `postgres:<connection string>`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        if spec == "memory" {
+            Ok(NonceStoreConfig::InMemory)
+        } else if let Some(path) = spec.strip_prefix("file:").filter(|p| !p.is_empty()) {
+            Ok(NonceStoreConfig::File(PathBuf::from(path)))
+        } else if let Some(params) = spec.strip_prefix("postgres:").filter(|p| !p.is_empty()) {
+            Ok(NonceStoreConfig::Postgres(params.to_string()))
+        } else {
+            Err(format!("invalid nonce store '{}'", spec))
+        }
+    }
+}
+
+/// Settings of a KBS instance.
+#[derive(Debug, Clone)]
+pub struct KbsConfig {
+    /// Address to listen on, e.g. `127.0.0.1:8080`.
+    pub listen: String,
+    pub policy_path: PathBuf,
+    pub resource_dir: PathBuf,
+    /// Postgres connection string; if set, resources are kept there
+    /// encrypted at rest instead of in `resource_dir`.
+    pub resource_db: Option<String>,
+    /// File holding the 256-bit resource encryption key (raw or hex).
+    pub resource_key_file: Option<PathBuf>,
+    pub nonce_store: NonceStoreConfig,
+    /// TLS of the Postgres nonce and resource stores; `verify-full` needs
+    /// `db_ca_file`.
+    pub db_sslmode: SslMode,
+    pub db_ca_file: Option<PathBuf>,
+    /// Client certificate and key files, if the database requires one.
+    pub db_client_cert_file: Option<PathBuf>,
+    pub db_client_key_file: Option<PathBuf>,
+    /// Bearer token of the admin endpoints; `None` disables them.
+    pub admin_token: Option<String>,
+    /// Additional PEM roots to pin besides the vendor roots (e.g. the
+    /// simulator's `ark.pem`).
+    pub extra_roots: Vec<PathBuf>,
+    pub session_ttl: Duration,
+    pub token_key_lifetime: Duration,
+    /// Faults to inject into requests; for testing agents only.
+    #[cfg(feature = "test-faults")]
+    pub faults: Vec<FaultRule>,
+}
+
+impl Default for KbsConfig {
+    fn default() -> Self {
+        KbsConfig {
+            listen: "127.0.0.1:8080".to_string(),
+            policy_path: PathBuf::from(DEFAULT_POLICY_PATH),
+            resource_dir: PathBuf::from(DEFAULT_RESOURCE_DIR),
+            resource_db: None,
+            resource_key_file: None,
+            nonce_store: NonceStoreConfig::default(),
+            db_sslmode: SslMode::default(),
+            db_ca_file: None,
+            db_client_cert_file: None,
+            db_client_key_file: None,
+            admin_token: None,
+            extra_roots: Vec::new(),
+            session_ttl: DEFAULT_SESSION_TTL,
+            token_key_lifetime: DEFAULT_TOKEN_KEY_LIFETIME,
+            #[cfg(feature = "test-faults")]
+            faults: Vec::new(),
+        }
+    }
+}
+
+impl KbsConfig {
+    /// Overrides the defaults with `KBS_LISTEN`, `KBS_POLICY`,
+    /// `KBS_RESOURCE_DIR`, `KBS_RESOURCE_DB`, `KBS_RESOURCE_KEY_FILE`,
+    /// `KBS_NONCE_STORE`, `KBS_DB_SSLMODE`, `KBS_DB_CA_FILE`,
+    /// `KBS_DB_CLIENT_CERT_FILE`, `KBS_DB_CLIENT_KEY_FILE`, `KBS_ADMIN_TOKEN`,
+    /// `KBS_EXTRA_ROOTS` (colon-separated) and, with the `test-faults`
+    /// feature, `KBS_FAULTS`.
+    pub fn from_env() -> Self {
+        let mut config = KbsConfig::default();
+        if let Ok(listen) = std::env::var(KBS_LISTEN_ENV) {
+            config.listen = listen;
+        }
+        if let Ok(path) = std::env::var(KBS_POLICY_ENV) {
+            config.policy_path = PathBuf::from(path);
+        }
+        if let Ok(dir) = std::env::var(KBS_RESOURCE_DIR_ENV) {
+            config.resource_dir = PathBuf::from(dir);
+        }
+        config.resource_db = std::env::var(KBS_RESOURCE_DB_ENV).ok();
+        config.resource_key_file = std::env::var(KBS_RESOURCE_KEY_FILE_ENV).ok().map(PathBuf::from);
+        if let Ok(spec) = std::env::var(KBS_NONCE_STORE_ENV) {
+            match NonceStoreConfig::parse(&spec) {
+                Ok(store) => config.nonce_store = store,
+                Err(e) => eprintln!("[KBS] Ignoring {}: {}", KBS_NONCE_STORE_ENV, e),
+            }
+        }
+        if let Ok(mode) = std::env::var(KBS_DB_SSLMODE_ENV) {
+            match mode.parse() {
+                Ok(mode) => config.db_sslmode = mode,
+                Err(e) => eprintln!("[KBS] Ignoring {}: {}", KBS_DB_SSLMODE_ENV, e),
+            }
+        }
+        config.db_ca_file = std::env::var(KBS_DB_CA_FILE_ENV).ok().map(PathBuf::from);
+        config.db_client_cert_file = std::env::var(KBS_DB_CLIENT_CERT_FILE_ENV).ok().map(PathBuf::from);
+        config.db_client_key_file = std::env::var(KBS_DB_CLIENT_KEY_FILE_ENV).ok().map(PathBuf::from);
+        config.admin_token = std::env::var(KBS_ADMIN_TOKEN_ENV).ok().filter(|t| !t.is_empty());
+        if let Ok(roots) = std::env::var(KBS_EXTRA_ROOTS_ENV) {
+            config.extra_roots = roots.split(':').filter(|r| !r.is_empty()).map(PathBuf::from).collect();
+        }
+        #[cfg(feature = "test-faults")]
+        if let Ok(spec) = std::env::var(KBS_FAULTS_ENV) {
+            match FaultRule::parse_list(&spec) {
+                Ok(faults) => config.faults = faults,
+                Err(e) => eprintln!("[KBS] Ignoring {}: {}", KBS_FAULTS_ENV, e),
+            }
+        }
+        config
+    }
+}
+
+/// A session opened by `auth`.
+struct Session {
+    tee: String,
+    challenge: AttestationChallenge,
+    created_at: SystemTime,
+    attested: Option<AttestedSession>,
+}
+
+/// The outcome of a successful `attest` for a session.
+struct AttestedSession {
+    claims: Option<Claims>,
+    /// The key bound into the evidence; resources are encrypted to it.
+    tee_pubkey: TeePubKey,
+    expires_at: SystemTime,
+}
+
+/// An HTTP response produced by the KBS, independent of the HTTP library.
+#[derive(Debug, Clone)]
+pub struct KbsResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+    pub set_cookie: Option<String>,
+}
+
+impl KbsResponse {
+    fn json<T: serde::Serialize>(status: u16, value: &T) -> Self {
+        KbsResponse {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_vec(value).unwrap_or_default(),
+            set_cookie: None,
+        }
+    }
+
+    fn error(status: u16, error_type: &str, detail: String) -> Self {
+        println!("[KBS] {} {}: {}", status, error_type, detail);
+        KbsResponse::json(
+            status,
+            &ErrorInformation { error_type: error_type.to_string(), detail },
+        )
+    }
+}
+
+/// The headers of an incoming request the KBS cares about.
+#[derive(Debug, Clone, Default)]
+pub struct KbsRequestHeaders {
+    pub cookie: Option<String>,
+    pub authorization: Option<String>,
+}
+
+impl KbsRequestHeaders {
+    fn session_id(&self) -> Option<&str> {
+        self.cookie.as_deref()?.split(';').find_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            (name == KBS_SESSION_COOKIE).then_some(value)
+        })
+    }
+
+    fn bearer_token(&self) -> Option<&str> {
+        self.authorization.as_deref()?.strip_prefix("Bearer ")
+    }
+}
+
+/// Body of `GET /admin/resources`.
+#[derive(Debug, serde::Serialize)]
+struct ResourceList {
+    resources: Vec<String>,
+}
+
+/// A Key Broker Service instance.
+pub struct KbsServer {
+    config: KbsConfig,
+    issuer: ChallengeIssuer,
+    anchors: TrustAnchors,
+    policy: Policy,
+    resources: Box<dyn ResourceStore>,
+    sessions: Mutex<HashMap<String, Session>>,
+    /// Signs attestation tokens; keys are regenerated on every start.
+    tokens: Mutex<TokenIssuer>,
+    token_key_rotated_at: Mutex<SystemTime>,
+    #[cfg(feature = "test-faults")]
+    faults: FaultInjector,
+}
+
+impl KbsServer {
+    /// Creates a KBS serving resources from `config.resource_dir`.
+    pub fn new(config: KbsConfig, anchors: TrustAnchors, policy: Policy) -> Self {
+        let resources = Box::new(FileResourceStore::new(&config.resource_dir));
+        KbsServer::with_resource_store(config, anchors, policy, resources)
+    }
+
+    pub fn with_resource_store(
+        config: KbsConfig,
+        anchors: TrustAnchors,
+        policy: Policy,
+        resources: Box<dyn ResourceStore>,
+    ) -> Self {
+        KbsServer::with_stores(config, anchors, policy, resources, Box::new(InMemoryNonceStore::new()))
+    }
+
+    pub fn with_stores(
+        config: KbsConfig,
+        anchors: TrustAnchors,
+        policy: Policy,
+        resources: Box<dyn ResourceStore>,
+        nonces: Box<dyn NonceStore>,
+    ) -> Self {
+        let tokens = TokenIssuer::new(KBS_TOKEN_ISSUER, config.session_ttl);
+        #[cfg(feature = "test-faults")]
+        if !config.faults.is_empty() {
+            println!("[KBS] Fault injection enabled: {:?}", config.faults);
+        }
+        #[cfg(feature = "test-faults")]
+        let faults = FaultInjector::new(config.faults.clone());
+        // A challenge cannot be answered once its session is gone.
+        let issuer = ChallengeIssuer::new(nonces, config.session_ttl);
+        KbsServer {
+            config,
+            issuer,
+            anchors,
+            policy,
+            resources,
+            sessions: Mutex::new(HashMap::new()),
+            tokens: Mutex::new(tokens),
+            token_key_rotated_at: Mutex::new(SystemTime::now()),
+            #[cfg(feature = "test-faults")]
+            faults,
+        }
+    }
+
+    /// Switches to a new token signing key. Tokens signed with the previous
+    /// key stay valid until they expire.
+    pub fn rotate_token_key(&self) {
+        if let (Ok(mut tokens), Ok(mut rotated_at)) = (self.tokens.lock(), self.token_key_rotated_at.lock()) {
+            let kid = tokens.rotate().to_string();
+            *rotated_at = SystemTime::now();
+            println!("[KBS] Rotated token signing key to {}", kid);
+        }
+    }
+
+    /// A validator for tokens this KBS issued for its own resources.
+    pub fn token_validator(&self) -> Option<TokenValidator> {
+        let tokens = self.tokens.lock().ok()?;
+        Some(TokenValidator::for_issuer(&tokens, KBS_TOKEN_AUDIENCE))
+    }
+
+    /// Loads the policy, the vendor roots and any extra roots named in
+    /// `config`, and opens the Postgres resource store if one is configured.
+    pub fn from_config(config: KbsConfig) -> Result<Self, KbsServerError> {
+        let policy = Policy::load(&config.policy_path)?;
+        let mut anchors = TrustAnchors::load_default()?;
+        for root in &config.extra_roots {
+            let pem = std::fs::read(root).map_err(|e| CertChainError::Parse(format!("{}: {}", root.display(), e)))?;
+            anchors.add_pem(&pem)?;
+        }
+        let nonces: Box<dyn NonceStore> = match &config.nonce_store {
+            NonceStoreConfig::InMemory => Box::new(InMemoryNonceStore::new()),
+            NonceStoreConfig::File(path) => {
+                println!("[KBS] Using nonce store file {}", path.display());
+                Box::new(FileNonceStore::new(path))
+            }
+            NonceStoreConfig::Postgres(params) => {
+                let store = PostgresNonceStore::connect(params, config.db_sslmode, &load_db_tls(&config)?)?;
+                println!("[KBS] Using Postgres nonce store ({})", config.db_sslmode);
+                Box::new(store)
+            }
+        };
+        let resources: Box<dyn ResourceStore> = match &config.resource_db {
+            None => Box::new(FileResourceStore::new(&config.resource_dir)),
+            Some(params) => {
+                let key = load_resource_key(config.resource_key_file.as_deref())?;
+                let store = PostgresResourceStore::connect(params, &key, config.db_sslmode, &load_db_tls(&config)?)?;
+                println!("[KBS] Using encrypted Postgres resource store ({})", config.db_sslmode);
+                Box::new(store)
+            }
+        };
+        Ok(KbsServer::with_stores(config, anchors, policy, resources, nonces))
+    }
+
+    /// Binds `config.listen` and accepts requests until the process exits.
+    pub fn serve(&self) -> Result<(), KbsServerError> {
+        let listener =
+            std::net::TcpListener::bind(&self.config.listen).map_err(|e| KbsServerError::Bind(e.to_string()))?;
+        self.serve_listener(listener)
+    }
+
+    /// Accepts requests on an already bound listener (e.g. an ephemeral port)
+    /// until the process exits.
+    pub fn serve_listener(&self, listener: std::net::TcpListener) -> Result<(), KbsServerError> {
+        let addr = listener.local_addr().map_err(|e| KbsServerError::Bind(e.to_string()))?;
+        let server = tiny_http::Server::from_listener(listener, None).map_err(|e| KbsServerError::Bind(e.to_string()))?;
+        println!("[KBS] Listening on http://{}{}", addr, KBS_API_PREFIX);
+
+        for mut request in server.incoming_requests() {
+            let mut headers = KbsRequestHeaders::default();
+            for header in request.headers() {
+                if header.field.equiv("Cookie") {
+                    headers.cookie = Some(header.value.to_string());
+                } else if header.field.equiv("Authorization") {
+                    headers.authorization = Some(header.value.to_string());
+                }
+            }
+            let mut body = Vec::new();
+            let response = match request.as_reader().read_to_end(&mut body) {
+                Ok(_) => self.handle(request.method().as_str(), request.url(), &headers, &body),
+                Err(e) => KbsResponse::error(400, "InvalidRequest", e.to_string()),
+            };
+
+            let mut http_response = tiny_http::Response::from_data(response.body).with_status_code(response.status);
+            if let Ok(h) = tiny_http::Header::from_bytes("Content-Type", response.content_type) {
+                http_response.add_header(h);
+            }
+            if let Some(cookie) = response.set_cookie {
+                if let Ok(h) = tiny_http::Header::from_bytes("Set-Cookie", cookie) {
+                    http_response.add_header(h);
+                }
+            }
+            if let Err(e) = request.respond(http_response) {
+                eprintln!("[KBS] Could not send response: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Routes one request.
+    pub fn handle(&self, method: &str, url: &str, headers: &KbsRequestHeaders, body: &[u8]) -> KbsResponse {
+        self.expire_sessions();
+        let key_expired = self
+            .token_key_rotated_at
+            .lock()
+            .map(|t| t.elapsed().unwrap_or_default() > self.config.token_key_lifetime)
+            .unwrap_or(false);
+        if key_expired {
+            self.rotate_token_key();
+        }
+        let path = url.split('?').next().unwrap_or_default();
+        let Some(endpoint) = path.strip_prefix(KBS_API_PREFIX) else {
+            return KbsResponse::error(404, "NotFound", format!("no such endpoint {}", path));
+        };
+        #[cfg(feature = "test-faults")]
+        if let Some(fault) = FaultEndpoint::of(method, endpoint).and_then(|e| self.faults.take(e)) {
+            match fault {
+                Fault::Status(status) => {
+                    return KbsResponse::error(status, "InjectedFault", format!("injected into {}", endpoint))
+                }
+                Fault::NonceRejected => {
+                    return KbsResponse::error(401, ERROR_NONCE_REJECTED, "injected: nonce expired".to_string())
+                }
+                Fault::Delay(delay) => std::thread::sleep(delay),
+            }
+        }
+        match (method, endpoint) {
+            ("POST", "/auth") => self.auth(body),
+            ("POST", "/attest") => self.attest(headers, body),
+            ("GET", resource) if resource.starts_with("/resource/") => {
+                self.resource(headers, &resource["/resource/".len()..])
+            }
+            (_, admin) if admin.starts_with("/admin/") => self.admin(method, url, headers, body),
+            _ => KbsResponse::error(404, "NotFound", format!("no such endpoint {} {}", method, path)),
+        }
+    }
+
+    // --- Endpoints ---
+
+    fn auth(&self, body: &[u8]) -> KbsResponse {
+        let request: Request = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(e) => return KbsResponse::error(400, "InvalidRequest", e.to_string()),
+        };
+        if request.version != KBS_PROTOCOL_VERSION {
+            return KbsResponse::error(
+                400,
+                "ProtocolVersion",
+                format!("unsupported protocol version {}", request.version),
+            );
+        }
+        let challenge = match self.issuer.issue() {
+            Ok(challenge) => challenge,
+            Err(e) => return KbsResponse::error(503, ERROR_NONCE_STORE_UNAVAILABLE, e.to_string()),
+        };
+
+        let session_id = random_hex(16);
+        println!("[KBS] New {} session {}", request.tee, session_id);
+        let mut response = KbsResponse::json(
+            200,
+            &Challenge { nonce: challenge.nonce.clone(), extra_params: String::new() },
+        );
+        response.set_cookie = Some(format!(
+            "{}={}; Path={}; HttpOnly; Max-Age={}",
+            KBS_SESSION_COOKIE,
+            session_id,
+            KBS_API_PREFIX,
+            self.config.session_ttl.as_secs()
+        ));
+        if let Ok(mut sessions) = self.sessions.lock() {
+            sessions.insert(
+                session_id,
+                Session { tee: request.tee, challenge, created_at: SystemTime::now(), attested: None },
+            );
+        }
+        response
+    }
+
+    fn attest(&self, headers: &KbsRequestHeaders, body: &[u8]) -> KbsResponse {
+        let Some(session_id) = headers.session_id() else {
+            return KbsResponse::error(401, ERROR_UNAUTHENTICATED_SESSION, "missing session cookie".to_string());
+        };
+        let attestation: Attestation = match serde_json::from_slice(body) {
+            Ok(attestation) => attestation,
+            Err(e) => return KbsResponse::error(400, "InvalidRequest", e.to_string()),
+        };
+        let report: AttestationReport = match serde_json::from_str(&attestation.tee_evidence) {
+            Ok(report) => report,
+            Err(e) => return KbsResponse::error(400, "InvalidEvidence", e.to_string()),
+        };
+
+        // The key secrets will be wrapped for must be the one bound into
+        // report_data; the verifier then checks that binding.
+        let bound_key = report
+            .runtime_data
+            .as_ref()
+            .and_then(|r| serde_json::from_slice::<TeePubKey>(&r.public_key).ok());
+        if bound_key.as_ref() != Some(&attestation.tee_pubkey) {
+            return KbsResponse::error(
+                401,
+                "AttestationFailed",
+                "tee-pubkey is not the key bound into the evidence".to_string(),
+            );
+        }
+
+        let Ok(mut sessions) = self.sessions.lock() else {
+            return KbsResponse::error(500, "InternalError", "session table poisoned".to_string());
+        };
+        let Some(session) = sessions.get_mut(session_id) else {
+            return KbsResponse::error(401, ERROR_UNAUTHENTICATED_SESSION, "unknown or expired session".to_string());
+        };
+
+        // The session was opened for one TEE type; evidence of another type
+        // is a different platform answering the challenge.
+        if report.evidence.tee_name() != session.tee {
+            let evidence_tee = report.evidence.tee_name();
+            let detail = format!("session is for {} but the evidence is from {}", session.tee, evidence_tee);
+            return KbsResponse::error(401, "AttestationFailed", detail);
+        }
+
+        println!("[KBS] Verifying {} evidence for session {}", session.tee, session_id);
+        let result = verifier::verify_fresh_report(&self.issuer, &session.challenge, &report, &self.anchors, &self.policy);
+        if !result.trustworthy {
+            let code = result.failure.map(|f| format!("{:?}", f)).unwrap_or_default();
+            // A stale nonce is not a verdict on the evidence: the agent may
+            // retry with a new challenge.
+            let (status, error_type) = match result.failure {
+                Some(FailureCode::NonceExpired | FailureCode::NonceReplayed) => (401, ERROR_NONCE_REJECTED),
+                Some(FailureCode::NonceStoreUnavailable) => (503, ERROR_NONCE_STORE_UNAVAILABLE),
+                _ => (401, "AttestationFailed"),
+            };
+            return KbsResponse::error(status, error_type, format!("{} ({})", result.message, code));
+        }
+
+        let token = match self.tokens.lock() {
+            Ok(tokens) => tokens.issue(session_id, KBS_TOKEN_AUDIENCE, &result, Some(&attestation.tee_pubkey)),
+            Err(_) => return KbsResponse::error(500, "InternalError", "token issuer poisoned".to_string()),
+        };
+        let token = match token {
+            Ok(token) => token,
+            Err(e) => return KbsResponse::error(500, "InternalError", e.to_string()),
+        };
+
+        session.attested = Some(AttestedSession {
+            claims: result.claims,
+            tee_pubkey: attestation.tee_pubkey,
+            expires_at: SystemTime::now() + self.config.session_ttl,
+        });
+        println!("[KBS] Session {} attested", session_id);
+        KbsResponse::json(200, &AttestationToken { token })
+    }
+
+    fn resource(&self, headers: &KbsRequestHeaders, path: &str) -> KbsResponse {
+        // A valid bearer token identifies the session; otherwise fall back to
+        // the session cookie.
+        let session_id = match headers.bearer_token() {
+            Some(token) => match self.token_validator().map(|v| v.validate(token)) {
+                Some(Ok(claims)) => claims.sub,
+                Some(Err(e)) => return KbsResponse::error(401, ERROR_INVALID_TOKEN, e.to_string()),
+                None => return KbsResponse::error(500, "InternalError", "token issuer poisoned".to_string()),
+            },
+            None => match headers.session_id() {
+                Some(session_id) => session_id.to_string(),
+                None => {
+                    return KbsResponse::error(401, ERROR_UNAUTHENTICATED_SESSION, "missing session cookie".to_string())
+                }
+            },
+        };
+
+        let attested = self.sessions.lock().ok().and_then(|sessions| {
+            sessions
+                .get(&session_id)
+                .and_then(|s| s.attested.as_ref())
+                .filter(|a| a.expires_at > SystemTime::now())
+                .map(|a| (a.claims.clone(), a.tee_pubkey.clone()))
+        });
+        let Some((claims, tee_pubkey)) = attested else {
+            let detail = "session has not passed attestation".to_string();
+            return KbsResponse::error(401, ERROR_UNAUTHENTICATED_SESSION, detail);
+        };
+
+        let resource = match ResourcePath::parse(path) {
+            Ok(resource) => resource,
+            Err(e) => return KbsResponse::error(400, "InvalidRequest", e),
+        };
+        let stored = match self.resources.get(&resource) {
+            Ok(Some(stored)) => stored,
+            Ok(None) => return KbsResponse::error(404, "ResourceNotFound", format!("no resource {}", resource)),
+            Err(e) => return KbsResponse::error(500, "InternalError", e.to_string()),
+        };
+
+        let measurement = claims.as_ref().map(|c| c.measurement.as_str());
+        let denied: Vec<String> = match &claims {
+            Some(claims) => stored
+                .policy
+                .evaluate(claims)
+                .into_iter()
+                .filter(|o| !o.passed)
+                .map(|o| format!("{}: {}", o.rule, o.detail))
+                .collect(),
+            None if stored.policy == ResourcePolicy::default() => Vec::new(),
+            None => vec!["session has no claims to evaluate".to_string()],
+        };
+        if !denied.is_empty() {
+            let detail = denied.join("; ");
+            self.audit(AuditRecord::new(&session_id, &resource, false, measurement, detail.clone()));
+            return KbsResponse::error(403, "PolicyDenied", format!("{} not released: {}", resource, detail));
+        }
+
+        // Only the attested guest holds the private half of the TEE key.
+        match jwe::encrypt(&stored.data, &tee_pubkey) {
+            Ok(response) => {
+                self.audit(AuditRecord::new(&session_id, &resource, true, measurement, "released".to_string()));
+                println!(
+                    "[KBS] Released {} to session {} (measurement {:.16}...)",
+                    resource,
+                    session_id,
+                    measurement.unwrap_or_default()
+                );
+                KbsResponse::json(200, &response)
+            }
+            Err(e) => KbsResponse::error(500, "InternalError", e.to_string()),
+        }
+    }
+
+    /// Records a release decision. A failing audit log is reported but does
+    /// not change the decision.
+    fn audit(&self, record: AuditRecord) {
+        if let Err(e) = self.resources.record_access(&record) {
+            eprintln!("[KBS] Could not audit access to {}: {}", record.resource, e);
+        }
+    }
+
+    // --- Admin ---
+
+    fn admin(&self, method: &str, url: &str, headers: &KbsRequestHeaders, body: &[u8]) -> KbsResponse {
+        let Some(expected) = &self.config.admin_token else {
+            return KbsResponse::error(403, "AdminDisabled", "no admin token configured".to_string());
+        };
+        let authorized = headers
+            .bearer_token()
+            .is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes()));
+        if !authorized {
+            return KbsResponse::error(401, "Unauthorized", "invalid admin token".to_string());
+        }
+
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        let endpoint = &path[KBS_API_PREFIX.len() + "/admin".len()..];
+        let result = match (method, endpoint) {
+            ("GET", "/resources") => self
+                .resources
+                .list()
+                .map(|paths| KbsResponse::json(200, &ResourceList { resources: paths.iter().map(|p| p.to_string()).collect() })),
+            ("GET", "/audit") => {
+                let limit = query
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("limit="))
+                    .and_then(|l| l.parse().ok())
+                    .unwrap_or(DEFAULT_AUDIT_LIMIT);
+                self.resources.audit_log(limit).map(|records| KbsResponse::json(200, &records))
+            }
+            (_, resource) if resource.starts_with("/resource/") => {
+                match ResourcePath::parse(&resource["/resource/".len()..]) {
+                    Ok(resource) => self.admin_resource(method, &resource, body),
+                    Err(e) => return KbsResponse::error(400, "InvalidRequest", e),
+                }
+            }
+            (_, policy) if policy.starts_with("/resource-policy/") => {
+                match ResourcePath::parse(&policy["/resource-policy/".len()..]) {
+                    Ok(resource) => self.admin_resource_policy(method, &resource, body),
+                    Err(e) => return KbsResponse::error(400, "InvalidRequest", e),
+                }
+            }
+            _ => return KbsResponse::error(404, "NotFound", format!("no such endpoint {} {}", method, path)),
+        };
+        result.unwrap_or_else(|e| KbsResponse::error(500, "InternalError", e.to_string()))
+    }
+
+    /// `PUT` stores the body as the resource, keeping its policy if it
+    /// already exists; `DELETE` removes it.
+    fn admin_resource(&self, method: &str, resource: &ResourcePath, body: &[u8]) -> Result<KbsResponse, ResourceStoreError> {
+        match method {
+            "PUT" => {
+                let policy = self.resources.get(resource)?.map(|r| r.policy).unwrap_or_default();
+                self.resources.put(resource, body, &policy)?;
+                println!("[KBS] Admin stored {}", resource);
+                Ok(KbsResponse::json(200, &policy))
+            }
+            "DELETE" => {
+                if !self.resources.delete(resource)? {
+                    return Ok(KbsResponse::error(404, "ResourceNotFound", format!("no resource {}", resource)));
+                }
+                println!("[KBS] Admin deleted {}", resource);
+                Ok(KbsResponse::json(200, &serde_json::json!({})))
+            }
+            _ => Ok(KbsResponse::error(405, "MethodNotAllowed", format!("{} on resource", method))),
+        }
+    }
+
+    /// `GET` returns the release policy of a resource; `PUT` replaces it.
+    fn admin_resource_policy(&self, method: &str, resource: &ResourcePath, body: &[u8]) -> Result<KbsResponse, ResourceStoreError> {
+        match method {
+            "GET" => Ok(match self.resources.get(resource)? {
+                Some(stored) => KbsResponse::json(200, &stored.policy),
+                None => KbsResponse::error(404, "ResourceNotFound", format!("no resource {}", resource)),
+            }),
+            "PUT" => {
+                let policy: ResourcePolicy = match serde_json::from_slice(body) {
+                    Ok(policy) => policy,
+                    Err(e) => return Ok(KbsResponse::error(400, "InvalidRequest", e.to_string())),
+                };
+                if !self.resources.set_policy(resource, &policy)? {
+                    return Ok(KbsResponse::error(404, "ResourceNotFound", format!("no resource {}", resource)));
+                }
+                println!("[KBS] Admin updated policy of {}", resource);
+                Ok(KbsResponse::json(200, &policy))
+            }
+            _ => Ok(KbsResponse::error(405, "MethodNotAllowed", format!("{} on resource policy", method))),
+        }
+    }
+
+    // --- Sessions ---
+
+    /// Drops sessions that were never attested within the TTL, or whose
+    /// attestation has expired, and the nonces that can no longer be redeemed.
+    fn expire_sessions(&self) {
+        let now = SystemTime::now();
+        let ttl = self.config.session_ttl;
+        if let Ok(mut sessions) = self.sessions.lock() {
+            sessions.retain(|_, s| match &s.attested {
+                Some(a) => a.expires_at > now,
+                None => s.created_at + ttl > now,
+            });
+        }
+        if let Err(e) = self.issuer.purge_expired() {
+            eprintln!("[KBS] Could not purge expired nonces: {}", e);
+        }
+    }
+}
+
+/// Reads the resource encryption key, given as 32 raw bytes or 64 hex digits.
+fn load_resource_key(path: Option<&std::path::Path>) -> Result<[u8; 32], KbsServerError> {
+    let path = path.ok_or_else(|| {
+        KbsServerError::ResourceKey(format!("{} is required with {}", KBS_RESOURCE_KEY_FILE_ENV, KBS_RESOURCE_DB_ENV))
+    })?;
+    let raw = std::fs::read(path).map_err(|e| KbsServerError::ResourceKey(format!("{}: {}", path.display(), e)))?;
+    let text = String::from_utf8_lossy(&raw);
+    let key = match hex::decode(text.trim()) {
+        Ok(decoded) if text.trim().len() == 64 => decoded,
+        _ => raw,
+    };
+    key.try_into()
+        .map_err(|k: Vec<u8>| KbsServerError::ResourceKey(format!("expected 32 bytes, got {}", k.len())))
+}
+
+/// Reads the database CA bundle and client identity files that are set.
+fn load_db_tls(config: &KbsConfig) -> Result<TlsMaterial, KbsServerError> {
+    fn read(path: Option<&Path>) -> Result<Option<Vec<u8>>, KbsServerError> {
+        let Some(path) = path else { return Ok(None) };
+        let pem = std::fs::read(path).map_err(|e| KbsServerError::DatabaseTls(format!("{}: {}", path.display(), e)))?;
+        Ok(Some(pem))
+    }
+    Ok(TlsMaterial {
+        ca_pem: read(config.db_ca_file.as_deref())?,
+        client_cert_pem: read(config.db_client_cert_file.as_deref())?,
+        client_key_pem: read(config.db_client_key_file.as_deref())?,
+    })
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation_data::{RuntimeData, TeeEvidence};
+    use crate::nonce_store::NonceStatus;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn server(session_ttl: Duration) -> KbsServer {
+        let config = KbsConfig {
+            resource_dir: std::env::temp_dir().join("kbs-server-test-no-resources"),
+            session_ttl,
+            ..KbsConfig::default()
+        };
+        KbsServer::new(config, TrustAnchors::new(), Policy::default())
+    }
+
+    /// Opens a session for `tee` and returns its cookie header.
+    fn auth(server: &KbsServer, tee: &str) -> KbsRequestHeaders {
+        let request =
+            Request { version: KBS_PROTOCOL_VERSION.to_string(), tee: tee.to_string(), extra_params: String::new() };
+        let body = serde_json::to_vec(&request).unwrap();
+        let response = server.handle("POST", "/kbs/v0/auth", &KbsRequestHeaders::default(), &body);
+        assert_eq!(response.status, 200);
+        let cookie = response.set_cookie.unwrap().split(';').next().unwrap().to_string();
+        KbsRequestHeaders { cookie: Some(cookie), authorization: None }
+    }
+
+    fn error_of(response: &KbsResponse) -> ErrorInformation {
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    #[test]
+    fn expired_sessions_release_their_nonces() {
+        let ttl = Duration::from_secs(1);
+        let server = server(ttl);
+        auth(&server, "snp");
+        auth(&server, "tdx");
+        assert_eq!(server.sessions.lock().unwrap().len(), 2);
+
+        // Nonces are kept at whole-second granularity.
+        std::thread::sleep(ttl * 2 + Duration::from_millis(100));
+        let response = server.handle("GET", "/kbs/v0/resource/default/key/x", &KbsRequestHeaders::default(), &[]);
+        assert_eq!(response.status, 401);
+
+        assert!(server.sessions.lock().unwrap().is_empty());
+        assert_eq!(server.issuer.purge_expired().unwrap(), 0, "expired nonces were left in the store");
+    }
+
+    #[test]
+    fn attest_rejects_evidence_from_another_tee() {
+        let server = server(DEFAULT_SESSION_TTL);
+        let headers = auth(&server, "snp");
+
+        let tee_key = p256::SecretKey::random(&mut OsRng);
+        let tee_pubkey = TeePubKey::from_p256(&tee_key.public_key());
+        let report = AttestationReport {
+            measurement: vec![0; 48],
+            report_data: [0; 64],
+            signature: Vec::new(),
+            cert_chain: Vec::new(),
+            evidence: TeeEvidence::Mock,
+            runtime_data: Some(RuntimeData::new(tee_pubkey.to_binding_bytes())),
+        };
+        let attestation = Attestation { tee_pubkey, tee_evidence: serde_json::to_string(&report).unwrap() };
+
+        let response = server.handle("POST", "/kbs/v0/attest", &headers, &serde_json::to_vec(&attestation).unwrap());
+        assert_eq!(response.status, 401);
+        let error = error_of(&response);
+        assert_eq!(error.error_type, "AttestationFailed");
+        assert!(error.detail.contains("session is for snp but the evidence is from mock"), "{}", error.detail);
+        assert!(server.sessions.lock().unwrap().values().all(|s| s.attested.is_none()));
+    }
+
+    /// An in-memory store that is down while `down` is set.
+    struct FlakyStore {
+        inner: InMemoryNonceStore,
+        down: Arc<AtomicBool>,
+    }
+
+    impl FlakyStore {
+        fn check(&self) -> Result<(), NonceError> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(NonceError::Store("connection refused".to_string()));
+            }
+            Ok(())
+        }
+    }
+
+    impl NonceStore for FlakyStore {
+        fn issue(&self, nonce: &str, issued_at: u64) -> Result<(), NonceError> {
+            self.check()?;
+            self.inner.issue(nonce, issued_at)
+        }
+
+        fn consume(&self, nonce: &str) -> Result<NonceStatus, NonceError> {
+            self.check()?;
+            self.inner.consume(nonce)
+        }
+
+        fn purge(&self, before: u64) -> Result<usize, NonceError> {
+            self.check()?;
+            self.inner.purge(before)
+        }
+    }
+
+    #[test]
+    fn nonce_store_specs() {
+        assert_eq!(NonceStoreConfig::parse("memory").unwrap(), NonceStoreConfig::InMemory);
+        assert_eq!(
+            NonceStoreConfig::parse("file:/var/lib/kbs/nonces.json").unwrap(),
+            NonceStoreConfig::File(PathBuf::from("/var/lib/kbs/nonces.json"))
+        );
+        assert_eq!(
+            NonceStoreConfig::parse("postgres:host=db user=kbs").unwrap(),
+            NonceStoreConfig::Postgres("host=db user=kbs".to_string())
+        );
+        for invalid in ["", "file:", "postgres:", "redis:host", "memory:"] {
+            assert!(NonceStoreConfig::parse(invalid).is_err(), "{} parsed", invalid);
+        }
+    }
+
+    #[test]
+    fn a_nonce_store_outage_is_answered_with_503() {
+        let down = Arc::new(AtomicBool::new(false));
+        let store = FlakyStore { inner: InMemoryNonceStore::new(), down: Arc::clone(&down) };
+        let config = KbsConfig {
+            resource_dir: std::env::temp_dir().join("kbs-server-test-no-resources"),
+            ..KbsConfig::default()
+        };
+        let resources = Box::new(FileResourceStore::new(&config.resource_dir));
+        let server = KbsServer::with_stores(config, TrustAnchors::new(), Policy::default(), resources, Box::new(store));
+        let headers = auth(&server, "mock");
+
+        // Neither a new challenge nor an answer to one can be checked.
+        down.store(true, Ordering::SeqCst);
+        let request =
+            Request { version: KBS_PROTOCOL_VERSION.to_string(), tee: "snp".to_string(), extra_params: String::new() };
+        let body = serde_json::to_vec(&request).unwrap();
+        let response = server.handle("POST", "/kbs/v0/auth", &KbsRequestHeaders::default(), &body);
+        assert_eq!(response.status, 503);
+        assert_eq!(error_of(&response).error_type, ERROR_NONCE_STORE_UNAVAILABLE);
+
+        let tee_key = p256::SecretKey::random(&mut OsRng);
+        let tee_pubkey = TeePubKey::from_p256(&tee_key.public_key());
+        let report = AttestationReport {
+            measurement: vec![0; 48],
+            report_data: [0; 64],
+            signature: Vec::new(),
+            cert_chain: Vec::new(),
+            evidence: TeeEvidence::Mock,
+            runtime_data: Some(RuntimeData::new(tee_pubkey.to_binding_bytes())),
+        };
+        let attestation = Attestation { tee_pubkey, tee_evidence: serde_json::to_string(&report).unwrap() };
+        let response = server.handle("POST", "/kbs/v0/attest", &headers, &serde_json::to_vec(&attestation).unwrap());
+        assert_eq!(response.status, 503);
+        assert_eq!(error_of(&response).error_type, ERROR_NONCE_STORE_UNAVAILABLE);
+    }
+}
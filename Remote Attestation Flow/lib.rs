@@ -0,0 +1,43 @@
+// --- attester_flow ---
+//
+// Remote attestation for confidential VMs: evidence collection inside the
+// guest (`attester`), its verification (`verifier`), and the KBS protocol
+// that releases secrets to attested guests. Guest backends, the KBS client
+// and server, and RA-TLS are behind cargo features; KBS fault injection is
+// only built with `test-faults`.
+
+pub mod attestation_data;
+pub mod attestation_token;
+// `attester::attester` and `verifier::verifier` are the public paths.
+#[allow(clippy::module_inception)]
+pub mod attester;
+pub mod cert_chain;
+pub mod db_tls;
+pub mod error;
+pub mod jwe;
+#[cfg(feature = "kbs-client")]
+pub mod kbs_client;
+#[cfg(feature = "test-faults")]
+pub mod kbs_faults;
+pub mod kbs_protocol;
+#[cfg(feature = "kbs-server")]
+pub mod kbs_server;
+pub mod nonce_store;
+pub mod policy;
+#[cfg(feature = "ra-tls")]
+pub mod ra_tls;
+#[cfg(feature = "kbs-server")]
+pub mod resource_store;
+pub mod retry;
+#[cfg(feature = "snp")]
+pub mod sev_guest;
+#[cfg(feature = "sim")]
+pub mod simulator;
+pub mod snp_report;
+pub mod sync_error;
+#[cfg(feature = "tdx")]
+pub mod tdx_guest;
+pub mod tdx_quote;
+#[allow(clippy::module_inception)]
+pub mod verifier;
+pub mod vtpm;
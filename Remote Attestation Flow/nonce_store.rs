@@ -0,0 +1,413 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::db_tls::{self, SslMode, TlsMaterial};
+
+// --- Nonce Replay Stores ---
+//
+// A store records every nonce the verifier issues together with its issue
+// time, and atomically marks it consumed the first time a report presents it.
+// Sharing a file- or Postgres-backed store lets several verifier instances
+// reject nonces replayed against any of them.
+
+/// Errors raised by the challenge issuer or its backing store.
+#[derive(Debug)]
+pub enum NonceError {
+    /// The nonce was never issued by this verifier (or has been purged).
+    Unknown,
+    /// The nonce was issued longer ago than the configured TTL.
+    Expired { age_secs: u64 },
+    /// The nonce has already been presented once.
+    Replayed,
+    /// The backing store failed.
+    Store(String),
+}
+
+impl fmt::Display for NonceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NonceError::Unknown => write!(f, "Nonce was not issued by this verifier"),
+            NonceError::Expired { age_secs } => write!(f, "Nonce expired ({}s old)", age_secs),
+            NonceError::Replayed => write!(f, "Nonce has already been used"),
+            NonceError::Store(e) => write!(f, "Nonce store error: {}", e),
+        }
+    }
+}
+
+impl Error for NonceError {}
+
+impl From<std::io::Error> for NonceError {
+    fn from(error: std::io::Error) -> Self {
+        NonceError::Store(error.to_string())
+    }
+}
+
+/// The state of a nonce prior to an attempt to consume it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NonceStatus {
+    Unknown,
+    /// Outstanding, issued at the given UNIX time (seconds).
+    Issued(u64),
+    AlreadyConsumed,
+}
+
+/// Shared replay state for issued nonces.
+pub trait NonceStore: Send + Sync {
+    /// Records a freshly issued nonce.
+    fn issue(&self, nonce: &str, issued_at: u64) -> Result<(), NonceError>;
+
+    /// Atomically marks the nonce consumed and returns its previous status.
+    fn consume(&self, nonce: &str) -> Result<NonceStatus, NonceError>;
+
+    /// Forgets every nonce issued before `before` (UNIX seconds), returning
+    /// how many were removed. Purged nonces are reported as `Unknown`.
+    fn purge(&self, before: u64) -> Result<usize, NonceError>;
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct NonceEntry {
+    issued_at: u64,
+    consumed: bool,
+}
+
+fn consume_entry(entries: &mut HashMap<String, NonceEntry>, nonce: &str) -> NonceStatus {
+    match entries.get_mut(nonce) {
+        None => NonceStatus::Unknown,
+        Some(entry) if entry.consumed => NonceStatus::AlreadyConsumed,
+        Some(entry) => {
+            entry.consumed = true;
+            NonceStatus::Issued(entry.issued_at)
+        }
+    }
+}
+
+fn purge_entries(entries: &mut HashMap<String, NonceEntry>, before: u64) -> usize {
+    let len = entries.len();
+    entries.retain(|_, e| e.issued_at >= before);
+    len - entries.len()
+}
+
+// --- In-Memory Store ---
+
+/// A process-local store, suitable for a single verifier instance.
+#[derive(Debug, Default)]
+pub struct InMemoryNonceStore {
+    entries: Mutex<HashMap<String, NonceEntry>>,
+}
+
+impl InMemoryNonceStore {
+    pub fn new() -> Self {
+        InMemoryNonceStore::default()
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, HashMap<String, NonceEntry>>, NonceError> {
+        self.entries
+            .lock()
+            .map_err(|_| NonceError::Store("nonce store lock poisoned".to_string()))
+    }
+}
+
+impl NonceStore for InMemoryNonceStore {
+    fn issue(&self, nonce: &str, issued_at: u64) -> Result<(), NonceError> {
+        self.lock()?
+            .insert(nonce.to_string(), NonceEntry { issued_at, consumed: false });
+        Ok(())
+    }
+
+    fn consume(&self, nonce: &str) -> Result<NonceStatus, NonceError> {
+        Ok(consume_entry(&mut *self.lock()?, nonce))
+    }
+
+    fn purge(&self, before: u64) -> Result<usize, NonceError> {
+        Ok(purge_entries(&mut *self.lock()?, before))
+    }
+}
+
+// --- File-Backed Store ---
+
+/// A JSON file shared by verifier processes on one host. Every operation
+/// holds an exclusive `flock` for its read-modify-write cycle.
+#[derive(Debug)]
+pub struct FileNonceStore {
+    path: PathBuf,
+}
+
+impl FileNonceStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FileNonceStore { path: path.into() }
+    }
+
+    fn with_locked<T>(
+        &self,
+        op: impl FnOnce(&mut HashMap<String, NonceEntry>) -> T,
+    ) -> Result<T, NonceError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)?;
+        let _lock = FileLock::exclusive(&file)?;
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let mut entries: HashMap<String, NonceEntry> = if contents.trim().is_empty() {
+            HashMap::new()
+        } else {
+            serde_json::from_str(&contents).map_err(|e| NonceError::Store(e.to_string()))?
+        };
+
+        let result = op(&mut entries);
+
+        let serialized = serde_json::to_vec(&entries).map_err(|e| NonceError::Store(e.to_string()))?;
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&serialized)?;
+        file.sync_data()?;
+        Ok(result)
+    }
+}
+
+/// An advisory lock on an open file, released on drop.
+struct FileLock {
+    fd: RawFd,
+}
+
+impl FileLock {
+    fn exclusive(file: &File) -> Result<Self, NonceError> {
+        let fd = file.as_raw_fd();
+        // SAFETY: flock on a valid, open file descriptor.
+        if unsafe { libc::flock(fd, libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(FileLock { fd })
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // SAFETY: the lock is dropped before the file it was taken on.
+        unsafe {
+            libc::flock(self.fd, libc::LOCK_UN);
+        }
+    }
+}
+
+impl NonceStore for FileNonceStore {
+    fn issue(&self, nonce: &str, issued_at: u64) -> Result<(), NonceError> {
+        self.with_locked(|entries| {
+            entries.insert(nonce.to_string(), NonceEntry { issued_at, consumed: false });
+        })
+    }
+
+    fn consume(&self, nonce: &str) -> Result<NonceStatus, NonceError> {
+        self.with_locked(|entries| consume_entry(entries, nonce))
+    }
+
+    fn purge(&self, before: u64) -> Result<usize, NonceError> {
+        self.with_locked(|entries| purge_entries(entries, before))
+    }
+}
+
+// --- Postgres-Backed Store ---
+
+/// Table layout used by `PostgresNonceStore`.
+pub const NONCE_TABLE_DDL: &str = "CREATE TABLE IF NOT EXISTS attestation_nonces (
+    nonce TEXT PRIMARY KEY,
+    issued_at BIGINT NOT NULL,
+    consumed BOOLEAN NOT NULL DEFAULT FALSE
+)";
+
+/// A store shared by verifier instances across hosts.
+pub struct PostgresNonceStore {
+    client: Mutex<postgres::Client>,
+}
+
+impl PostgresNonceStore {
+    /// Connects with `sslmode` and creates the nonce table if it does not
+    /// exist.
+    pub fn connect(params: &str, sslmode: SslMode, tls: &TlsMaterial) -> Result<Self, NonceError> {
+        let mut client = db_tls::connect(params, sslmode, tls).map_err(|e| NonceError::Store(e.to_string()))?;
+        client
+            .batch_execute(NONCE_TABLE_DDL)
+            .map_err(|e| NonceError::Store(e.to_string()))?;
+        Ok(PostgresNonceStore { client: Mutex::new(client) })
+    }
+
+    fn client(&self) -> Result<std::sync::MutexGuard<'_, postgres::Client>, NonceError> {
+        self.client
+            .lock()
+            .map_err(|_| NonceError::Store("nonce store lock poisoned".to_string()))
+    }
+}
+
+fn pg_err(e: postgres::Error) -> NonceError {
+    NonceError::Store(e.to_string())
+}
+
+impl NonceStore for PostgresNonceStore {
+    fn issue(&self, nonce: &str, issued_at: u64) -> Result<(), NonceError> {
+        self.client()?
+            .execute(
+                "INSERT INTO attestation_nonces (nonce, issued_at) VALUES ($1, $2)",
+                &[&nonce, &(issued_at as i64)],
+            )
+            .map_err(pg_err)?;
+        Ok(())
+    }
+
+    fn consume(&self, nonce: &str) -> Result<NonceStatus, NonceError> {
+        let mut client = self.client()?;
+
+        // The conditional UPDATE is atomic: only one instance can flip the flag.
+        let updated = client
+            .query_opt(
+                "UPDATE attestation_nonces SET consumed = TRUE \
+                 WHERE nonce = $1 AND NOT consumed RETURNING issued_at",
+                &[&nonce],
+            )
+            .map_err(pg_err)?;
+        if let Some(row) = updated {
+            let issued_at: i64 = row.get(0);
+            return Ok(NonceStatus::Issued(issued_at as u64));
+        }
+
+        let exists = client
+            .query_opt("SELECT 1 FROM attestation_nonces WHERE nonce = $1", &[&nonce])
+            .map_err(pg_err)?;
+        Ok(if exists.is_some() {
+            NonceStatus::AlreadyConsumed
+        } else {
+            NonceStatus::Unknown
+        })
+    }
+
+    fn purge(&self, before: u64) -> Result<usize, NonceError> {
+        let removed = self
+            .client()?
+            .execute("DELETE FROM attestation_nonces WHERE issued_at < $1", &[&(before as i64)])
+            .map_err(pg_err)?;
+        Ok(removed as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verifier::verifier::ChallengeIssuer;
+    use rand_core::{OsRng, RngCore};
+    use std::time::Duration;
+
+    /// Postgres connection string for the store tests; they are skipped
+    /// when it is unset.
+    const TEST_DB_ENV: &str = "KBS_TEST_NONCE_DB";
+
+    /// The suite every store must pass: the raw store operations, then
+    /// redemption through a `ChallengeIssuer` with a one-second TTL.
+    fn check_store(store: Box<dyn NonceStore>) {
+        assert_eq!(store.consume("never-issued").unwrap(), NonceStatus::Unknown);
+        store.issue("a", 1000).unwrap();
+        store.issue("b", 2000).unwrap();
+        assert_eq!(store.consume("a").unwrap(), NonceStatus::Issued(1000));
+        assert_eq!(store.consume("a").unwrap(), NonceStatus::AlreadyConsumed);
+
+        // Purging is by issue time, consumed or not, and forgets the nonce.
+        assert_eq!(store.purge(1000).unwrap(), 0);
+        assert_eq!(store.purge(2000).unwrap(), 1);
+        assert_eq!(store.consume("a").unwrap(), NonceStatus::Unknown);
+        assert_eq!(store.consume("b").unwrap(), NonceStatus::Issued(2000));
+        assert_eq!(store.purge(2001).unwrap(), 1);
+
+        let issuer = ChallengeIssuer::new(store, Duration::from_secs(1));
+        let fresh = issuer.issue().unwrap();
+        let stale = issuer.issue().unwrap();
+        issuer.redeem(&fresh).unwrap();
+        assert!(matches!(issuer.redeem(&fresh), Err(NonceError::Replayed)));
+
+        // Issue times have whole-second granularity.
+        std::thread::sleep(Duration::from_millis(2100));
+        match issuer.redeem(&stale) {
+            Err(NonceError::Expired { age_secs }) => assert!(age_secs >= 2, "{}", age_secs),
+            other => panic!("expected the nonce to expire, got {:?}", other),
+        }
+        assert!(matches!(issuer.redeem(&stale), Err(NonceError::Replayed)));
+        assert_eq!(issuer.purge_expired().unwrap(), 2);
+        assert!(matches!(issuer.redeem(&fresh), Err(NonceError::Unknown)));
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("nonce-store-{}-{}-{:08x}.json", name, std::process::id(), OsRng.next_u32()))
+    }
+
+    #[test]
+    fn in_memory_store() {
+        check_store(Box::new(InMemoryNonceStore::new()));
+    }
+
+    #[test]
+    fn file_store() {
+        let path = temp_path("suite");
+        check_store(Box::new(FileNonceStore::new(&path)));
+
+        // The state is in the file, not in the handle.
+        FileNonceStore::new(&path).issue("c", 3000).unwrap();
+        assert_eq!(FileNonceStore::new(&path).consume("c").unwrap(), NonceStatus::Issued(3000));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_store_consumes_a_nonce_once_across_handles() {
+        let path = temp_path("race");
+        const THREADS: usize = 8;
+        FileNonceStore::new(&path).issue("shared", 1000).unwrap();
+
+        // Each thread has its own handle, so only the flock serializes them.
+        let outcomes: Vec<(NonceStatus, NonceStatus)> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..THREADS)
+                .map(|i| {
+                    let path = &path;
+                    scope.spawn(move || {
+                        let store = FileNonceStore::new(path);
+                        store.issue(&format!("own-{}", i), 1000).unwrap();
+                        (store.consume("shared").unwrap(), store.consume(&format!("own-{}", i)).unwrap())
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
+
+        // Every thread's own nonce survived the others' read-modify-writes.
+        assert!(outcomes.iter().all(|(_, own)| *own == NonceStatus::Issued(1000)), "{:?}", outcomes);
+        let winners = outcomes.iter().filter(|(shared, _)| *shared == NonceStatus::Issued(1000)).count();
+        assert_eq!(winners, 1, "{:?}", outcomes);
+        assert!(outcomes.iter().all(|(shared, _)| *shared != NonceStatus::Unknown), "{:?}", outcomes);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn postgres_store() {
+        let Ok(params) = std::env::var(TEST_DB_ENV) else {
+            eprintln!("{} not set; skipping", TEST_DB_ENV);
+            return;
+        };
+        // A schema of its own keeps the purge counts exact.
+        let schema = format!("nonce_test_{}_{:08x}", std::process::id(), OsRng.next_u32());
+        let mut admin = db_tls::connect(&params, SslMode::Prefer, &TlsMaterial::default()).unwrap();
+        admin.batch_execute(&format!("CREATE SCHEMA {}", schema)).unwrap();
+
+        let scoped = format!("{} options='-c search_path={}'", params, schema);
+        let store = PostgresNonceStore::connect(&scoped, SslMode::Prefer, &TlsMaterial::default());
+        let result = std::panic::catch_unwind(|| check_store(Box::new(store.unwrap())));
+        admin.batch_execute(&format!("DROP SCHEMA {} CASCADE", schema)).unwrap();
+        if let Err(panic) = result {
+            std::panic::resume_unwind(panic);
+        }
+    }
+}
@@ -0,0 +1,542 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+
+use crate::attestation_data::{AttestationReport, CheckKind, TeeEvidence};
+
+// --- Declarative Attestation Policy ---
+//
+// A policy is loaded from TOML or JSON and evaluated clause by clause against
+// a report whose signature has already been verified. Every clause produces
+// its own `RuleOutcome` so operators can see exactly which one failed.
+
+/// Default location of the verifier's policy file.
+pub const DEFAULT_POLICY_PATH: &str = "/etc/attester_flow/policy.toml";
+
+/// Errors raised while loading a policy file.
+#[derive(Debug)]
+pub enum PolicyError {
+    Io(std::io::Error),
+    Parse(String),
+    /// The policy parsed but contains an invalid value (e.g. bad hex).
+    Invalid(String),
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PolicyError::Io(e) => write!(f, "Could not read policy: {}", e),
+            PolicyError::Parse(e) => write!(f, "Could not parse policy: {}", e),
+            PolicyError::Invalid(e) => write!(f, "Invalid policy: {}", e),
+        }
+    }
+}
+
+impl Error for PolicyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PolicyError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for PolicyError {
+    fn from(error: std::io::Error) -> Self {
+        PolicyError::Io(error)
+    }
+}
+
+/// A trusted VM image and the launch measurements it may produce.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImagePolicy {
+    pub name: String,
+    /// Hex-encoded measurements (SNP launch digest, TDX MRTD or PCR digest).
+    pub measurements: Vec<String>,
+}
+
+/// Minimum firmware security versions. Unset fields are not checked.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TcbPolicy {
+    pub min_boot_loader: Option<u8>,
+    pub min_tee: Option<u8>,
+    pub min_snp: Option<u8>,
+    pub min_microcode: Option<u8>,
+    /// Minimum SNP guest SVN.
+    pub min_guest_svn: Option<u32>,
+    /// Minimum TDX TEE_TCB_SVN, compared component-wise (hex, 16 bytes).
+    pub min_tdx_tee_tcb_svn: Option<String>,
+}
+
+/// Required guest policy bits.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GuestPolicyRules {
+    pub allow_debug: bool,
+    pub allow_smt: bool,
+    pub allow_migration_agent: bool,
+    pub require_single_socket: bool,
+}
+
+impl Default for GuestPolicyRules {
+    fn default() -> Self {
+        GuestPolicyRules {
+            allow_debug: false,
+            allow_smt: true,
+            allow_migration_agent: false,
+            require_single_socket: false,
+        }
+    }
+}
+
+/// Which platforms and TEE types may attest. Empty lists allow any.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlatformPolicy {
+    /// Hex-encoded SNP chip IDs.
+    pub allowed_chip_ids: Vec<String>,
+    /// TEE names as reported by `TeeEvidence::tee_name` (`snp`, `tdx`, `vtpm`).
+    pub allowed_tees: Vec<String>,
+}
+
+/// The complete attestation policy.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Policy {
+    #[serde(rename = "image")]
+    pub images: Vec<ImagePolicy>,
+    pub tcb: TcbPolicy,
+    pub guest_policy: GuestPolicyRules,
+    pub platform: PlatformPolicy,
+}
+
+/// The result of evaluating a single policy clause.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleOutcome {
+    /// Dotted clause name, e.g. `tcb.min_snp`.
+    pub rule: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl RuleOutcome {
+    fn check(rule: &str, passed: bool, detail: String) -> Self {
+        RuleOutcome { rule: rule.to_string(), passed, detail }
+    }
+
+    /// The verifier check category this clause belongs to.
+    pub fn check_kind(&self) -> CheckKind {
+        match self.rule.split('.').next() {
+            Some("image") => CheckKind::Measurement,
+            Some("tcb") => CheckKind::Tcb,
+            _ => CheckKind::Policy,
+        }
+    }
+}
+
+impl Policy {
+    pub fn from_toml_str(s: &str) -> Result<Self, PolicyError> {
+        let policy: Policy = toml::from_str(s).map_err(|e| PolicyError::Parse(e.to_string()))?;
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    pub fn from_json_str(s: &str) -> Result<Self, PolicyError> {
+        let policy: Policy = serde_json::from_str(s).map_err(|e| PolicyError::Parse(e.to_string()))?;
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    /// Loads a policy file, choosing the format from its extension.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, PolicyError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Policy::from_json_str(&contents),
+            _ => Policy::from_toml_str(&contents),
+        }
+    }
+
+    /// Rejects malformed hex values up front instead of at evaluation time.
+    fn validate(&self) -> Result<(), PolicyError> {
+        let hex_values = self
+            .images
+            .iter()
+            .flat_map(|i| i.measurements.iter())
+            .chain(self.platform.allowed_chip_ids.iter())
+            .chain(self.tcb.min_tdx_tee_tcb_svn.iter());
+        for value in hex_values {
+            hex::decode(value).map_err(|e| PolicyError::Invalid(format!("{}: {}", value, e)))?;
+        }
+        Ok(())
+    }
+
+    /// Evaluates every clause against the report.
+    pub fn evaluate(&self, report: &AttestationReport) -> Vec<RuleOutcome> {
+        let mut outcomes = vec![self.check_tee(report), self.check_measurement(report)];
+        match &report.evidence {
+            TeeEvidence::Snp(snp) => {
+                let tcb = &snp.reported_tcb;
+                let mins = [
+                    ("tcb.min_boot_loader", self.tcb.min_boot_loader, tcb.boot_loader),
+                    ("tcb.min_tee", self.tcb.min_tee, tcb.tee),
+                    ("tcb.min_snp", self.tcb.min_snp, tcb.snp),
+                    ("tcb.min_microcode", self.tcb.min_microcode, tcb.microcode),
+                ];
+                for (rule, min, actual) in mins {
+                    if let Some(min) = min {
+                        outcomes.push(RuleOutcome::check(
+                            rule,
+                            actual >= min,
+                            format!("reported {}, minimum {}", actual, min),
+                        ));
+                    }
+                }
+                if let Some(min) = self.tcb.min_guest_svn {
+                    outcomes.push(RuleOutcome::check(
+                        "tcb.min_guest_svn",
+                        snp.guest_svn >= min,
+                        format!("reported {}, minimum {}", snp.guest_svn, min),
+                    ));
+                }
+
+                let rules = &self.guest_policy;
+                let policy = snp.policy;
+                outcomes.push(RuleOutcome::check(
+                    "guest_policy.allow_debug",
+                    rules.allow_debug || !policy.debug_allowed(),
+                    format!("debug {}", if policy.debug_allowed() { "enabled" } else { "disabled" }),
+                ));
+                outcomes.push(RuleOutcome::check(
+                    "guest_policy.allow_smt",
+                    rules.allow_smt || !snp.platform_info.smt_enabled(),
+                    format!("host SMT {}", if snp.platform_info.smt_enabled() { "enabled" } else { "disabled" }),
+                ));
+                outcomes.push(RuleOutcome::check(
+                    "guest_policy.allow_migration_agent",
+                    rules.allow_migration_agent || !policy.migrate_ma_allowed(),
+                    format!("migration agent {}", if policy.migrate_ma_allowed() { "allowed" } else { "denied" }),
+                ));
+                outcomes.push(RuleOutcome::check(
+                    "guest_policy.require_single_socket",
+                    !rules.require_single_socket || policy.single_socket_required(),
+                    format!("single socket {}", if policy.single_socket_required() { "required" } else { "not required" }),
+                ));
+
+                if !self.platform.allowed_chip_ids.is_empty() {
+                    let chip_id = hex::encode(snp.chip_id);
+                    outcomes.push(RuleOutcome::check(
+                        "platform.allowed_chip_ids",
+                        self.platform.allowed_chip_ids.iter().any(|id| id.eq_ignore_ascii_case(&chip_id)),
+                        format!("chip ID {}", chip_id),
+                    ));
+                }
+            }
+            TeeEvidence::Tdx(quote) => {
+                if let Some(min) = &self.tcb.min_tdx_tee_tcb_svn {
+                    let min = hex::decode(min).unwrap_or_default();
+                    let actual = quote.body.tee_tcb_svn;
+                    outcomes.push(RuleOutcome::check(
+                        "tcb.min_tdx_tee_tcb_svn",
+                        min.len() == actual.len() && actual.iter().zip(&min).all(|(a, m)| a >= m),
+                        format!("reported {}, minimum {}", hex::encode(actual), hex::encode(&min)),
+                    ));
+                }
+                let debug = quote.body.debug_enabled();
+                outcomes.push(RuleOutcome::check(
+                    "guest_policy.allow_debug",
+                    self.guest_policy.allow_debug || !debug,
+                    format!("debug {}", if debug { "enabled" } else { "disabled" }),
+                ));
+                if !self.platform.allowed_chip_ids.is_empty() {
+                    outcomes.push(RuleOutcome::check(
+                        "platform.allowed_chip_ids",
+                        false,
+                        "TDX evidence carries no chip ID".to_string(),
+                    ));
+                }
+            }
+            TeeEvidence::Vtpm(_) | TeeEvidence::Mock => {}
+        }
+        outcomes
+    }
+
+    fn check_tee(&self, report: &AttestationReport) -> RuleOutcome {
+        let tee = report.evidence.tee_name();
+        let allowed = &self.platform.allowed_tees;
+        RuleOutcome::check(
+            "platform.allowed_tees",
+            allowed.is_empty() || allowed.iter().any(|t| t == tee),
+            format!("evidence from {}", tee),
+        )
+    }
+
+    fn check_measurement(&self, report: &AttestationReport) -> RuleOutcome {
+        let actual = hex::encode(&report.measurement);
+        match self
+            .images
+            .iter()
+            .find(|image| image.measurements.iter().any(|m| m.eq_ignore_ascii_case(&actual)))
+        {
+            Some(image) => RuleOutcome::check("image.measurements", true, format!("matches image '{}'", image.name)),
+            None => RuleOutcome::check(
+                "image.measurements",
+                false,
+                format!("measurement {} matches no trusted image", actual),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snp_report::{GuestPolicy, SnpAttestationReport};
+    use crate::tdx_quote::TdxQuote;
+
+    /// Milan report: TCB bl=3 tee=0 snp=8 ucode=115, guest SVN 1, SMT on.
+    const SNP_FIXTURE: &[u8] = include_bytes!("testdata/snp_report_v3.bin");
+    /// Quote v4 with TEE_TCB_SVN 03 00 05 00.., debug off.
+    const TDX_FIXTURE: &[u8] = include_bytes!("testdata/tdx_quote_v4.bin");
+
+    fn snp_report_with(f: impl FnOnce(&mut SnpAttestationReport)) -> AttestationReport {
+        let mut snp = SnpAttestationReport::from_bytes(SNP_FIXTURE).unwrap();
+        f(&mut snp);
+        AttestationReport::from_snp(&snp, Vec::new())
+    }
+
+    fn snp_report() -> AttestationReport {
+        snp_report_with(|_| {})
+    }
+
+    fn tdx_report_with(f: impl FnOnce(&mut TdxQuote)) -> AttestationReport {
+        let mut quote = TdxQuote::from_bytes(TDX_FIXTURE).unwrap();
+        f(&mut quote);
+        AttestationReport::from_tdx(&quote).unwrap()
+    }
+
+    /// A policy that trusts exactly the measurement of `report`.
+    fn trusting(report: &AttestationReport) -> Policy {
+        Policy {
+            images: vec![ImagePolicy {
+                name: "fixture".to_string(),
+                measurements: vec![hex::encode(&report.measurement)],
+            }],
+            ..Policy::default()
+        }
+    }
+
+    /// The names of the clauses that failed.
+    fn failed(outcomes: &[RuleOutcome]) -> Vec<&str> {
+        outcomes.iter().filter(|o| !o.passed).map(|o| o.rule.as_str()).collect()
+    }
+
+    fn outcome<'a>(outcomes: &'a [RuleOutcome], rule: &str) -> &'a RuleOutcome {
+        outcomes.iter().find(|o| o.rule == rule).unwrap_or_else(|| panic!("no outcome for {}", rule))
+    }
+
+    fn scratch_file(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("policy-test-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    const TOML_POLICY: &str = r#"
+        [[image]]
+        name = "guest"
+        measurements = ["aabb"]
+
+        [tcb]
+        min_snp = 8
+        min_tdx_tee_tcb_svn = "03000500000000000000000000000000"
+
+        [guest_policy]
+        allow_smt = false
+
+        [platform]
+        allowed_tees = ["snp"]
+    "#;
+
+    const JSON_POLICY: &str = r#"{
+        "image": [{ "name": "guest", "measurements": ["aabb"] }],
+        "tcb": { "min_snp": 8, "min_tdx_tee_tcb_svn": "03000500000000000000000000000000" },
+        "guest_policy": { "allow_smt": false },
+        "platform": { "allowed_tees": ["snp"] }
+    }"#;
+
+    #[test]
+    fn load_picks_the_format_from_the_extension() {
+        let from_toml = Policy::load(scratch_file("policy.toml", TOML_POLICY)).unwrap();
+        let from_json = Policy::load(scratch_file("policy.json", JSON_POLICY)).unwrap();
+        assert_eq!(serde_json::to_value(&from_toml).unwrap(), serde_json::to_value(&from_json).unwrap());
+        assert_eq!(from_toml.images[0].measurements, ["aabb"]);
+        assert_eq!(from_toml.tcb.min_snp, Some(8));
+        assert_eq!(from_toml.tcb.min_boot_loader, None);
+        assert!(!from_toml.guest_policy.allow_smt);
+        // Unset guest policy fields keep their defaults.
+        assert!(!from_toml.guest_policy.allow_debug);
+
+        // JSON is not accepted under a TOML name, nor the other way round.
+        assert!(matches!(Policy::load(scratch_file("json.toml", JSON_POLICY)), Err(PolicyError::Parse(_))));
+        assert!(matches!(Policy::load(scratch_file("toml.json", TOML_POLICY)), Err(PolicyError::Parse(_))));
+        assert!(matches!(Policy::load(std::env::temp_dir().join("no-such-policy.toml")), Err(PolicyError::Io(_))));
+    }
+
+    #[test]
+    fn loads_the_shipped_policy() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../attestation_policy.toml");
+        let policy = Policy::load(path).unwrap();
+        assert_eq!(policy.images.len(), 1);
+        assert_eq!(policy.platform.allowed_tees, ["snp", "tdx", "vtpm"]);
+    }
+
+    #[test]
+    fn validate_rejects_malformed_hex() {
+        for bad in [
+            "[[image]]\nname = \"x\"\nmeasurements = [\"not-hex\"]",
+            "[platform]\nallowed_chip_ids = [\"abc\"]",
+            "[tcb]\nmin_tdx_tee_tcb_svn = \"zz\"",
+        ] {
+            assert!(matches!(Policy::from_toml_str(bad), Err(PolicyError::Invalid(_))), "{}", bad);
+        }
+        assert!(matches!(
+            Policy::from_json_str(r#"{ "image": [{ "name": "x", "measurements": ["0g"] }] }"#),
+            Err(PolicyError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn measurement_must_match_a_trusted_image() {
+        let report = snp_report();
+        let mut policy = trusting(&report);
+        assert!(failed(&policy.evaluate(&report)).is_empty());
+
+        // Measurements compare case-insensitively.
+        policy.images[0].measurements[0] = hex::encode_upper(&report.measurement);
+        assert!(failed(&policy.evaluate(&report)).is_empty());
+
+        policy.images[0].measurements[0] = hex::encode([0u8; 48]);
+        let outcomes = policy.evaluate(&report);
+        assert_eq!(failed(&outcomes), ["image.measurements"]);
+        assert_eq!(outcome(&outcomes, "image.measurements").check_kind(), CheckKind::Measurement);
+    }
+
+    #[test]
+    fn no_trusted_images_fails_closed() {
+        let outcomes = Policy::default().evaluate(&snp_report());
+        assert_eq!(failed(&outcomes), ["image.measurements"]);
+    }
+
+    #[test]
+    fn snp_tcb_minimums_are_checked_per_component() {
+        let report = snp_report();
+        type SetMin = fn(&mut TcbPolicy, u8);
+        let components: [(&str, SetMin); 4] = [
+            ("tcb.min_boot_loader", |t, v| t.min_boot_loader = Some(v)),
+            ("tcb.min_tee", |t, v| t.min_tee = Some(v)),
+            ("tcb.min_snp", |t, v| t.min_snp = Some(v)),
+            ("tcb.min_microcode", |t, v| t.min_microcode = Some(v)),
+        ];
+        let reported = [3, 0, 8, 115];
+
+        for ((rule, set), actual) in components.into_iter().zip(reported) {
+            let mut policy = trusting(&report);
+            set(&mut policy.tcb, actual);
+            let outcomes = policy.evaluate(&report);
+            assert!(outcome(&outcomes, rule).passed, "{} at the reported version", rule);
+            assert!(failed(&outcomes).is_empty());
+
+            set(&mut policy.tcb, actual + 1);
+            let outcomes = policy.evaluate(&report);
+            assert_eq!(failed(&outcomes), [rule]);
+            assert_eq!(outcome(&outcomes, rule).check_kind(), CheckKind::Tcb);
+        }
+
+        let mut policy = trusting(&report);
+        policy.tcb.min_guest_svn = Some(2);
+        assert_eq!(failed(&policy.evaluate(&report)), ["tcb.min_guest_svn"]);
+        policy.tcb.min_guest_svn = Some(1);
+        assert!(failed(&policy.evaluate(&report)).is_empty());
+    }
+
+    #[test]
+    fn tdx_tee_tcb_svn_is_compared_component_wise() {
+        let report = tdx_report_with(|_| {});
+        let mut policy = trusting(&report);
+        let svn = |bytes: &[u8]| {
+            let mut svn = [0u8; 16];
+            svn[..bytes.len()].copy_from_slice(bytes);
+            Some(hex::encode(svn))
+        };
+
+        for passing in [&[3, 0, 5][..], &[3, 0, 4], &[0, 0, 0]] {
+            policy.tcb.min_tdx_tee_tcb_svn = svn(passing);
+            assert!(failed(&policy.evaluate(&report)).is_empty(), "{:?}", passing);
+        }
+        // 02 01 00 is below 03 00 05 as a number, but its second component is
+        // above the reported one.
+        for failing in [&[2, 1, 0][..], &[3, 0, 6], &[4, 0, 0]] {
+            policy.tcb.min_tdx_tee_tcb_svn = svn(failing);
+            assert_eq!(failed(&policy.evaluate(&report)), ["tcb.min_tdx_tee_tcb_svn"], "{:?}", failing);
+        }
+        // A minimum of the wrong length never passes.
+        policy.tcb.min_tdx_tee_tcb_svn = Some("0300".to_string());
+        assert_eq!(failed(&policy.evaluate(&report)), ["tcb.min_tdx_tee_tcb_svn"]);
+    }
+
+    #[test]
+    fn debug_guests_need_allow_debug() {
+        let report = snp_report_with(|snp| snp.policy = GuestPolicy(snp.policy.0 | 1 << 19));
+        let mut policy = trusting(&report);
+        assert_eq!(failed(&policy.evaluate(&report)), ["guest_policy.allow_debug"]);
+        policy.guest_policy.allow_debug = true;
+        assert!(failed(&policy.evaluate(&report)).is_empty());
+
+        let report = tdx_report_with(|quote| quote.body.td_attributes[0] |= 1);
+        let mut policy = trusting(&report);
+        let outcomes = policy.evaluate(&report);
+        assert_eq!(failed(&outcomes), ["guest_policy.allow_debug"]);
+        assert_eq!(outcome(&outcomes, "guest_policy.allow_debug").check_kind(), CheckKind::Policy);
+        policy.guest_policy.allow_debug = true;
+        assert!(failed(&policy.evaluate(&report)).is_empty());
+    }
+
+    #[test]
+    fn smt_hosts_need_allow_smt() {
+        let report = snp_report();
+        let mut policy = trusting(&report);
+        policy.guest_policy.allow_smt = false;
+        assert_eq!(failed(&policy.evaluate(&report)), ["guest_policy.allow_smt"]);
+
+        let report = snp_report_with(|snp| snp.platform_info.0 &= !1);
+        assert!(failed(&policy.evaluate(&report)).is_empty());
+    }
+
+    #[test]
+    fn platform_allowlists() {
+        let report = snp_report();
+        let chip_id = match &report.evidence {
+            TeeEvidence::Snp(snp) => snp.chip_id,
+            _ => unreachable!(),
+        };
+        let mut policy = trusting(&report);
+        policy.platform.allowed_chip_ids = vec![hex::encode([0u8; 64]), hex::encode_upper(chip_id)];
+        assert!(failed(&policy.evaluate(&report)).is_empty());
+
+        policy.platform.allowed_chip_ids = vec![hex::encode([0u8; 64])];
+        assert_eq!(failed(&policy.evaluate(&report)), ["platform.allowed_chip_ids"]);
+
+        // TDX evidence cannot satisfy a chip ID allowlist.
+        let tdx = tdx_report_with(|_| {});
+        policy.images = trusting(&tdx).images;
+        assert_eq!(failed(&policy.evaluate(&tdx)), ["platform.allowed_chip_ids"]);
+
+        let mut policy = trusting(&report);
+        policy.platform.allowed_tees = vec!["tdx".to_string()];
+        assert_eq!(failed(&policy.evaluate(&report)), ["platform.allowed_tees"]);
+        policy.platform.allowed_tees.push("snp".to_string());
+        assert!(failed(&policy.evaluate(&report)).is_empty());
+    }
+}
@@ -0,0 +1,526 @@
+use der::asn1::OctetString;
+use der::{Decode, Encode};
+use rand_core::{OsRng, RngCore};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, DistinguishedName, OtherError, ServerConfig,
+    SignatureScheme,
+};
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use x509_cert::Certificate;
+
+use crate::attestation_data::{AttestationChallenge, AttestationReport, RuntimeData, VerificationResult};
+use crate::attester::attester::{Attester, AttesterError};
+use crate::cert_chain::{self, TrustAnchors};
+use crate::policy::Policy;
+use crate::verifier::verifier;
+
+// --- Attested TLS (RA-TLS) ---
+//
+// An attested service presents a self-signed certificate whose extension
+// carries an `AttestationReport`. The report's runtime data binds the
+// certificate's SubjectPublicKeyInfo, and its challenge nonce is the
+// certificate serial number, so the evidence cannot be moved to another key
+// or certificate. Peers verify that evidence with `verifier::verify_report`
+// during the handshake instead of checking a CA chain, which lets two CVMs
+// authenticate each other without a broker.
+
+/// OID of the evidence extension (a private-enterprise arc of this project).
+pub const RA_TLS_EVIDENCE_OID: &[u64] = &[1, 3, 6, 1, 4, 1, 58888, 1, 1];
+
+/// Default lifetime of an RA-TLS certificate. Re-generate it (and thus the
+/// evidence) at least this often.
+pub const DEFAULT_RA_TLS_VALIDITY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Clock skew tolerated when checking certificate validity.
+pub const RA_TLS_CLOCK_LEEWAY: Duration = Duration::from_secs(60);
+
+/// Errors raised while creating or verifying an RA-TLS certificate.
+#[derive(Debug)]
+pub enum RaTlsError {
+    KeyGeneration(String),
+    Evidence(AttesterError),
+    /// The certificate is malformed or has no evidence extension.
+    Certificate(String),
+    /// The evidence does not bind the certificate key, or the certificate
+    /// is outside its validity period.
+    Binding(String),
+    /// The evidence failed verification.
+    Untrusted(Box<VerificationResult>),
+    Tls(rustls::Error),
+}
+
+impl fmt::Display for RaTlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RaTlsError::KeyGeneration(e) => write!(f, "Could not generate RA-TLS key: {}", e),
+            RaTlsError::Evidence(e) => write!(f, "Could not generate RA-TLS evidence: {}", e),
+            RaTlsError::Certificate(e) => write!(f, "Invalid RA-TLS certificate: {}", e),
+            RaTlsError::Binding(e) => write!(f, "RA-TLS evidence is not bound to the certificate: {}", e),
+            RaTlsError::Untrusted(result) => {
+                write!(f, "RA-TLS evidence rejected [{:?}]: {}", result.failure, result.message)
+            }
+            RaTlsError::Tls(e) => write!(f, "TLS configuration error: {}", e),
+        }
+    }
+}
+
+impl Error for RaTlsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RaTlsError::Evidence(e) => Some(e),
+            RaTlsError::Tls(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<AttesterError> for RaTlsError {
+    fn from(error: AttesterError) -> Self {
+        RaTlsError::Evidence(error)
+    }
+}
+
+impl From<rustls::Error> for RaTlsError {
+    fn from(error: rustls::Error) -> Self {
+        RaTlsError::Tls(error)
+    }
+}
+
+// --- Certificate Generation ---
+
+/// A certificate carrying evidence for its own key, and that key.
+pub struct RaTlsIdentity {
+    pub cert_der: Vec<u8>,
+    /// PKCS#8 DER of the P-256 certificate key. It never leaves the VM.
+    key_der: Vec<u8>,
+    pub report: AttestationReport,
+}
+
+impl RaTlsIdentity {
+    /// Generates a key, obtains evidence binding it from `attester`, and
+    /// self-signs a certificate for `subject` valid for `validity`.
+    pub fn generate(attester: &dyn Attester, subject: &str, validity: Duration) -> Result<Self, RaTlsError> {
+        let keygen = |e: rcgen::Error| RaTlsError::KeyGeneration(e.to_string());
+        let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).map_err(keygen)?;
+
+        // A positive serial without leading zero bytes encodes unchanged in
+        // DER, so the verifier recovers exactly this nonce.
+        let mut serial = [0u8; 16];
+        OsRng.fill_bytes(&mut serial);
+        serial[0] = (serial[0] & 0x7f) | 0x40;
+        let challenge = AttestationChallenge { nonce: hex::encode(serial) };
+
+        let runtime_data = RuntimeData::new(key.public_key_der());
+        let report = attester.generate_bound_evidence(&challenge, runtime_data)?;
+        let evidence = serde_json::to_vec(&report).map_err(|e| RaTlsError::Certificate(e.to_string()))?;
+        let extension = OctetString::new(evidence)
+            .and_then(|octets| octets.to_der())
+            .map_err(|e| RaTlsError::Certificate(e.to_string()))?;
+
+        let mut params = rcgen::CertificateParams::new(vec![subject.to_string()]).map_err(keygen)?;
+        params.distinguished_name.push(rcgen::DnType::CommonName, subject);
+        params.serial_number = Some(rcgen::SerialNumber::from_slice(&serial));
+        let now = time::OffsetDateTime::now_utc();
+        params.not_before = now;
+        params.not_after = now + validity;
+        params.custom_extensions.push(rcgen::CustomExtension::from_oid_content(RA_TLS_EVIDENCE_OID, extension));
+        let cert = params.self_signed(&key).map_err(keygen)?;
+
+        println!("[RA-TLS] Issued attested certificate for {} (serial {})", subject, challenge.nonce);
+        Ok(RaTlsIdentity { cert_der: cert.der().to_vec(), key_der: key.serialize_der(), report })
+    }
+
+    fn cert_chain(&self) -> Vec<CertificateDer<'static>> {
+        vec![CertificateDer::from(self.cert_der.clone())]
+    }
+
+    fn private_key(&self) -> PrivateKeyDer<'static> {
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.key_der.clone()))
+    }
+
+    /// A server config presenting this identity and requiring an attested
+    /// client certificate accepted by `verifier`.
+    pub fn server_config(&self, verifier: Arc<RaTlsVerifier>) -> Result<ServerConfig, RaTlsError> {
+        Ok(ServerConfig::builder_with_provider(Arc::clone(&verifier.provider))
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(self.cert_chain(), self.private_key())?)
+    }
+
+    /// A client config presenting this identity and accepting only attested
+    /// servers. The server name is not checked: the evidence identifies the
+    /// peer.
+    pub fn client_config(&self, verifier: Arc<RaTlsVerifier>) -> Result<ClientConfig, RaTlsError> {
+        Ok(ClientConfig::builder_with_provider(Arc::clone(&verifier.provider))
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(verifier)
+            .with_client_auth_cert(self.cert_chain(), self.private_key())?)
+    }
+}
+
+// --- Certificate Verification ---
+
+/// Verifies RA-TLS peer certificates, as a server or client verifier.
+#[derive(Debug)]
+pub struct RaTlsVerifier {
+    anchors: TrustAnchors,
+    policy: Policy,
+    provider: Arc<CryptoProvider>,
+}
+
+impl RaTlsVerifier {
+    pub fn new(anchors: TrustAnchors, policy: Policy) -> Self {
+        RaTlsVerifier { anchors, policy, provider: Arc::new(rustls::crypto::ring::default_provider()) }
+    }
+
+    /// Checks the certificate's validity period and self-signature, that its
+    /// evidence binds its key and serial, and that the evidence passes
+    /// `verify_report`.
+    pub fn verify_certificate(&self, cert_der: &[u8], now: SystemTime) -> Result<VerificationResult, RaTlsError> {
+        let cert = Certificate::from_der(cert_der).map_err(|e| RaTlsError::Certificate(e.to_string()))?;
+        let tbs = &cert.tbs_certificate;
+
+        // Only the holder of the bound key may have issued the certificate.
+        cert_chain::verify_issued_by(&cert, &cert)
+            .map_err(|e| RaTlsError::Binding(format!("certificate is not signed by its own key: {}", e)))?;
+
+        let not_before = tbs.validity.not_before.to_system_time();
+        let not_after = tbs.validity.not_after.to_system_time();
+        if now + RA_TLS_CLOCK_LEEWAY < not_before || now > not_after + RA_TLS_CLOCK_LEEWAY {
+            return Err(RaTlsError::Binding(format!(
+                "certificate valid from {} to {}",
+                unix_secs(not_before),
+                unix_secs(not_after)
+            )));
+        }
+
+        let oid = RA_TLS_EVIDENCE_OID.iter().map(u64::to_string).collect::<Vec<_>>().join(".");
+        let extension = tbs
+            .extensions
+            .iter()
+            .flatten()
+            .find(|ext| ext.extn_id.to_string() == oid)
+            .ok_or_else(|| RaTlsError::Certificate("no evidence extension".to_string()))?;
+        let evidence = OctetString::from_der(extension.extn_value.as_bytes())
+            .map_err(|e| RaTlsError::Certificate(e.to_string()))?;
+        let report: AttestationReport = serde_json::from_slice(evidence.as_bytes())
+            .map_err(|e| RaTlsError::Certificate(format!("malformed evidence: {}", e)))?;
+
+        let spki = tbs
+            .subject_public_key_info
+            .to_der()
+            .map_err(|e| RaTlsError::Certificate(e.to_string()))?;
+        if report.runtime_data.as_ref().map(|r| &r.public_key) != Some(&spki) {
+            return Err(RaTlsError::Binding("evidence binds a different key".to_string()));
+        }
+
+        // The serial is the challenge; verify_report checks that report_data
+        // binds it together with the key.
+        let challenge = AttestationChallenge { nonce: hex::encode(tbs.serial_number.as_bytes()) };
+        let result = verifier::verify_report(&challenge, &report, &self.anchors, &self.policy);
+        if !result.trustworthy {
+            return Err(RaTlsError::Untrusted(Box::new(result)));
+        }
+        Ok(result)
+    }
+
+    fn verify_peer(&self, end_entity: &CertificateDer<'_>, now: UnixTime) -> Result<(), rustls::Error> {
+        let now = UNIX_EPOCH + Duration::from_secs(now.as_secs());
+        match self.verify_certificate(end_entity, now) {
+            Ok(result) => {
+                if let Some(claims) = &result.claims {
+                    println!("[RA-TLS] Peer attested: tee={} measurement={:.16}...", claims.tee, claims.measurement);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                println!("[RA-TLS] Rejecting peer: {}", e);
+                Err(rustls::Error::InvalidCertificate(CertificateError::Other(OtherError(Arc::new(e)))))
+            }
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl ServerCertVerifier for RaTlsVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.verify_peer(end_entity, now)?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for RaTlsVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.verify_peer(end_entity, now)?;
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod tests {
+    use super::*;
+    use crate::simulator::{SimulatedAttester, SIMULATED_BOOT_IMAGE};
+    use sha2::{Digest, Sha384};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    /// A simulated TEE and a verifier pinning its root and measurement.
+    fn setup() -> (SimulatedAttester, Arc<RaTlsVerifier>) {
+        let attester = SimulatedAttester::new().unwrap();
+        let verifier = verifier_for(&attester);
+        (attester, verifier)
+    }
+
+    fn verifier_for(attester: &SimulatedAttester) -> Arc<RaTlsVerifier> {
+        let anchors = TrustAnchors::from_pem(attester.local_root_pem().unwrap().as_bytes()).unwrap();
+        let policy = Policy::from_toml_str(&format!(
+            "[[image]]\nname = \"simulated\"\nmeasurements = [\"{}\"]\n",
+            hex::encode(Sha384::digest(SIMULATED_BOOT_IMAGE))
+        ))
+        .unwrap();
+        Arc::new(RaTlsVerifier::new(anchors, policy))
+    }
+
+    /// The DER of an evidence extension carrying `report`.
+    fn evidence_extension(report: &AttestationReport) -> Vec<u8> {
+        OctetString::new(serde_json::to_vec(report).unwrap()).unwrap().to_der().unwrap()
+    }
+
+    /// A certificate for `key` like `generate` issues, with the given serial
+    /// and evidence extension content, signed by `signer`.
+    fn certificate(
+        key: &rcgen::KeyPair,
+        serial: &[u8],
+        extension: Option<Vec<u8>>,
+        signer: &rcgen::KeyPair,
+    ) -> Vec<u8> {
+        let mut params = rcgen::CertificateParams::new(vec!["forged.internal".to_string()]).unwrap();
+        params.serial_number = Some(rcgen::SerialNumber::from_slice(serial));
+        let now = time::OffsetDateTime::now_utc();
+        params.not_before = now;
+        params.not_after = now + DEFAULT_RA_TLS_VALIDITY;
+        if let Some(extension) = extension {
+            params.custom_extensions.push(rcgen::CustomExtension::from_oid_content(RA_TLS_EVIDENCE_OID, extension));
+        }
+        if std::ptr::eq(key, signer) {
+            params.self_signed(key).unwrap().der().to_vec()
+        } else {
+            let issuer = rcgen::CertificateParams::new(Vec::new()).unwrap().self_signed(signer).unwrap();
+            params.signed_by(key, &issuer, signer).unwrap().der().to_vec()
+        }
+    }
+
+    fn identity_key(identity: &RaTlsIdentity) -> rcgen::KeyPair {
+        rcgen::KeyPair::try_from(identity.key_der.as_slice()).unwrap()
+    }
+
+    fn serial_of(identity: &RaTlsIdentity) -> Vec<u8> {
+        Certificate::from_der(&identity.cert_der).unwrap().tbs_certificate.serial_number.as_bytes().to_vec()
+    }
+
+    fn other_key() -> rcgen::KeyPair {
+        rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap()
+    }
+
+    #[test]
+    fn accepts_a_generated_certificate() {
+        let (attester, verifier) = setup();
+        let identity = RaTlsIdentity::generate(&attester, "db.internal", DEFAULT_RA_TLS_VALIDITY).unwrap();
+        let result = verifier.verify_certificate(&identity.cert_der, SystemTime::now()).unwrap();
+        assert!(result.trustworthy);
+        assert_eq!(result.claims.unwrap().tee, "snp");
+    }
+
+    #[test]
+    fn rejects_evidence_moved_to_another_key() {
+        let (attester, verifier) = setup();
+        let identity = RaTlsIdentity::generate(&attester, "db.internal", DEFAULT_RA_TLS_VALIDITY).unwrap();
+        let key = other_key();
+        let moved = certificate(&key, &serial_of(&identity), Some(evidence_extension(&identity.report)), &key);
+        let err = verifier.verify_certificate(&moved, SystemTime::now()).err().unwrap();
+        assert!(matches!(err, RaTlsError::Binding(ref e) if e.contains("different key")), "{}", err);
+    }
+
+    #[test]
+    fn rejects_a_certificate_not_signed_by_its_key() {
+        let (attester, verifier) = setup();
+        let identity = RaTlsIdentity::generate(&attester, "db.internal", DEFAULT_RA_TLS_VALIDITY).unwrap();
+        let key = identity_key(&identity);
+        let extension = Some(evidence_extension(&identity.report));
+        let forged = certificate(&key, &serial_of(&identity), extension, &other_key());
+        let err = verifier.verify_certificate(&forged, SystemTime::now()).err().unwrap();
+        assert!(matches!(err, RaTlsError::Binding(ref e) if e.contains("own key")), "{}", err);
+    }
+
+    #[test]
+    fn rejects_a_certificate_outside_its_validity() {
+        let (attester, verifier) = setup();
+        let validity = Duration::from_secs(3600);
+        let identity = RaTlsIdentity::generate(&attester, "db.internal", validity).unwrap();
+        let now = SystemTime::now();
+        let expired = now + validity + 2 * RA_TLS_CLOCK_LEEWAY;
+        assert!(matches!(verifier.verify_certificate(&identity.cert_der, expired), Err(RaTlsError::Binding(_))));
+        let early = now - 2 * RA_TLS_CLOCK_LEEWAY;
+        assert!(matches!(verifier.verify_certificate(&identity.cert_der, early), Err(RaTlsError::Binding(_))));
+        // Within the leeway is fine.
+        assert!(verifier.verify_certificate(&identity.cert_der, now + validity + RA_TLS_CLOCK_LEEWAY / 2).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_missing_or_malformed_evidence_extension() {
+        let (attester, verifier) = setup();
+        let identity = RaTlsIdentity::generate(&attester, "db.internal", DEFAULT_RA_TLS_VALIDITY).unwrap();
+        let key = identity_key(&identity);
+        let serial = serial_of(&identity);
+        let not_json = OctetString::new(b"not a report".to_vec()).unwrap().to_der().unwrap();
+        for extension in [None, Some(b"\x04\x09short".to_vec()), Some(not_json)] {
+            let cert = certificate(&key, &serial, extension, &key);
+            let err = verifier.verify_certificate(&cert, SystemTime::now()).err().unwrap();
+            assert!(matches!(err, RaTlsError::Certificate(_)), "{}", err);
+        }
+        assert!(matches!(verifier.verify_certificate(b"garbage", SystemTime::now()), Err(RaTlsError::Certificate(_))));
+    }
+
+    #[test]
+    fn rejects_a_serial_that_is_not_the_nonce() {
+        let (attester, verifier) = setup();
+        let identity = RaTlsIdentity::generate(&attester, "db.internal", DEFAULT_RA_TLS_VALIDITY).unwrap();
+        let key = identity_key(&identity);
+        let mut serial = serial_of(&identity);
+        serial[15] ^= 1;
+        let cert = certificate(&key, &serial, Some(evidence_extension(&identity.report)), &key);
+        let err = verifier.verify_certificate(&cert, SystemTime::now()).err().unwrap();
+        assert!(matches!(err, RaTlsError::Untrusted(_)), "{}", err);
+    }
+
+    /// Runs a handshake and a ping over TCP; returns the client's result.
+    fn exchange(server: ServerConfig, client: ClientConfig) -> Result<Vec<u8>, std::io::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || -> Result<(), std::io::Error> {
+            let (mut socket, _) = listener.accept()?;
+            let mut connection = rustls::ServerConnection::new(Arc::new(server)).map_err(std::io::Error::other)?;
+            let mut tls = rustls::Stream::new(&mut connection, &mut socket);
+            let mut request = [0u8; 4];
+            tls.read_exact(&mut request)?;
+            tls.write_all(b"pong")
+        });
+
+        let mut socket = TcpStream::connect(addr)?;
+        let name = ServerName::try_from("db.internal").unwrap();
+        let mut connection = rustls::ClientConnection::new(Arc::new(client), name).map_err(std::io::Error::other)?;
+        let mut tls = rustls::Stream::new(&mut connection, &mut socket);
+        let reply = tls.write_all(b"ping").and_then(|_| {
+            let mut reply = vec![0u8; 4];
+            tls.read_exact(&mut reply).map(|_| reply)
+        });
+        // Closing the socket unblocks a server still waiting for the ping.
+        drop(socket);
+        let served = server.join().unwrap();
+        reply.and_then(|reply| served.map(|_| reply))
+    }
+
+    #[test]
+    fn attested_peers_complete_a_handshake() {
+        let (attester, verifier) = setup();
+        let server = RaTlsIdentity::generate(&attester, "db.internal", DEFAULT_RA_TLS_VALIDITY).unwrap();
+        let client = RaTlsIdentity::generate(&attester, "app.internal", DEFAULT_RA_TLS_VALIDITY).unwrap();
+        let reply = exchange(
+            server.server_config(Arc::clone(&verifier)).unwrap(),
+            client.client_config(verifier).unwrap(),
+        );
+        assert_eq!(reply.unwrap(), b"pong");
+    }
+
+    #[test]
+    fn a_peer_under_another_root_fails_the_handshake() {
+        let (attester, verifier) = setup();
+        let (stranger, stranger_verifier) = setup();
+        let server = RaTlsIdentity::generate(&attester, "db.internal", DEFAULT_RA_TLS_VALIDITY).unwrap();
+        let client = RaTlsIdentity::generate(&stranger, "app.internal", DEFAULT_RA_TLS_VALIDITY).unwrap();
+
+        // The client does not trust the server's root...
+        let reply = exchange(
+            server.server_config(Arc::clone(&verifier)).unwrap(),
+            client.client_config(Arc::clone(&stranger_verifier)).unwrap(),
+        );
+        assert!(reply.is_err());
+        // ...nor, with the roles of the verifiers swapped, the server the client's.
+        let reply = exchange(
+            server.server_config(Arc::clone(&verifier)).unwrap(),
+            client.client_config(verifier_for(&attester)).unwrap(),
+        );
+        assert!(reply.is_err());
+    }
+}
@@ -0,0 +1,635 @@
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::attestation_data::Claims;
+use crate::db_tls::{self, SslMode, TlsMaterial};
+use crate::kbs_protocol::ResourcePath;
+use crate::policy::RuleOutcome;
+
+// --- KBS Resource Repository ---
+//
+// Secrets are stored per `<repo>/<type>/<tag>` path together with their own
+// release policy, which is evaluated against the attested session's claims
+// on top of the KBS-wide attestation policy. Every release decision is
+// appended to an audit log.
+
+/// Suffix of the policy sidecar files of `FileResourceStore`.
+const POLICY_SUFFIX: &str = ".policy.json";
+
+/// Name of the audit log of `FileResourceStore`, relative to its root.
+const AUDIT_LOG_FILE: &str = "audit.log";
+
+/// Errors raised by a resource store.
+#[derive(Debug)]
+pub enum ResourceStoreError {
+    Io(std::io::Error),
+    Database(String),
+    /// Encryption at rest failed, or a stored value failed authentication.
+    Crypto(String),
+    Serialization(String),
+    /// The path is reserved by the store (e.g. ends in `.policy.json`).
+    InvalidPath(String),
+}
+
+impl fmt::Display for ResourceStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResourceStoreError::Io(e) => write!(f, "Resource store I/O error: {}", e),
+            ResourceStoreError::Database(e) => write!(f, "Resource database error: {}", e),
+            ResourceStoreError::Crypto(e) => write!(f, "Resource encryption error: {}", e),
+            ResourceStoreError::Serialization(e) => write!(f, "Malformed resource record: {}", e),
+            ResourceStoreError::InvalidPath(p) => write!(f, "Invalid resource path '{}'", p),
+        }
+    }
+}
+
+impl Error for ResourceStoreError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ResourceStoreError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ResourceStoreError {
+    fn from(error: std::io::Error) -> Self {
+        ResourceStoreError::Io(error)
+    }
+}
+
+impl From<postgres::Error> for ResourceStoreError {
+    fn from(error: postgres::Error) -> Self {
+        ResourceStoreError::Database(error.to_string())
+    }
+}
+
+impl From<serde_json::Error> for ResourceStoreError {
+    fn from(error: serde_json::Error) -> Self {
+        ResourceStoreError::Serialization(error.to_string())
+    }
+}
+
+/// Release conditions of one resource. Empty fields are not checked.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResourcePolicy {
+    /// Hex measurements the attested VM must have.
+    pub allowed_measurements: Vec<String>,
+    /// TEE names (`snp`, `tdx`, `vtpm`) the evidence must come from.
+    pub allowed_tees: Vec<String>,
+    /// Minimum values of numeric TCB claims, e.g. `snp = 8`.
+    pub min_tcb: BTreeMap<String, u64>,
+    /// If set, the VM must have bound this runtime claim, e.g.
+    /// `image_digest = "sha256:..."`.
+    pub required_runtime_claims: BTreeMap<String, String>,
+}
+
+impl ResourcePolicy {
+    /// Evaluates every clause against the claims of an attested session.
+    pub fn evaluate(&self, claims: &Claims) -> Vec<RuleOutcome> {
+        let mut outcomes = Vec::new();
+        if !self.allowed_measurements.is_empty() {
+            outcomes.push(RuleOutcome {
+                rule: "resource.allowed_measurements".to_string(),
+                passed: self.allowed_measurements.iter().any(|m| m.eq_ignore_ascii_case(&claims.measurement)),
+                detail: format!("measurement {}", claims.measurement),
+            });
+        }
+        if !self.allowed_tees.is_empty() {
+            outcomes.push(RuleOutcome {
+                rule: "resource.allowed_tees".to_string(),
+                passed: self.allowed_tees.contains(&claims.tee),
+                detail: format!("evidence from {}", claims.tee),
+            });
+        }
+        for (component, min) in &self.min_tcb {
+            let actual = claims.tcb.get(component).and_then(|v| v.parse::<u64>().ok());
+            outcomes.push(RuleOutcome {
+                rule: format!("resource.min_tcb.{}", component),
+                passed: actual.is_some_and(|a| a >= *min),
+                detail: match actual {
+                    Some(a) => format!("reported {}, minimum {}", a, min),
+                    None => format!("no numeric {} in claims", component),
+                },
+            });
+        }
+        let runtime_claims = claims.runtime_data.as_ref().map(|r| &r.claims);
+        for (name, expected) in &self.required_runtime_claims {
+            let actual = runtime_claims.and_then(|c| c.get(name));
+            outcomes.push(RuleOutcome {
+                rule: format!("resource.required_runtime_claims.{}", name),
+                passed: actual == Some(expected),
+                detail: format!("bound {}", actual.map(String::as_str).unwrap_or("nothing")),
+            });
+        }
+        outcomes
+    }
+}
+
+/// A stored secret and its release policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredResource {
+    pub data: Vec<u8>,
+    pub policy: ResourcePolicy,
+}
+
+/// One release decision.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// UNIX time in seconds.
+    pub at: u64,
+    pub session_id: String,
+    pub resource: String,
+    pub released: bool,
+    pub measurement: Option<String>,
+    pub detail: String,
+}
+
+impl AuditRecord {
+    pub fn new(session_id: &str, resource: &ResourcePath, released: bool, measurement: Option<&str>, detail: String) -> Self {
+        AuditRecord {
+            at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            session_id: session_id.to_string(),
+            resource: resource.to_string(),
+            released,
+            measurement: measurement.map(str::to_string),
+            detail,
+        }
+    }
+}
+
+/// Storage for KBS resources, their policies and the release audit log.
+pub trait ResourceStore: Send + Sync {
+    fn get(&self, path: &ResourcePath) -> Result<Option<StoredResource>, ResourceStoreError>;
+
+    /// Creates or replaces a resource together with its policy.
+    fn put(&self, path: &ResourcePath, data: &[u8], policy: &ResourcePolicy) -> Result<(), ResourceStoreError>;
+
+    /// Replaces the policy of an existing resource. Returns false if the
+    /// resource does not exist.
+    fn set_policy(&self, path: &ResourcePath, policy: &ResourcePolicy) -> Result<bool, ResourceStoreError>;
+
+    /// Returns false if the resource did not exist.
+    fn delete(&self, path: &ResourcePath) -> Result<bool, ResourceStoreError>;
+
+    fn list(&self) -> Result<Vec<ResourcePath>, ResourceStoreError>;
+
+    fn record_access(&self, record: &AuditRecord) -> Result<(), ResourceStoreError>;
+
+    /// The most recent `limit` audit records, oldest first.
+    fn audit_log(&self, limit: usize) -> Result<Vec<AuditRecord>, ResourceStoreError>;
+}
+
+// --- Filesystem Backend ---
+
+/// Resources as files under `<root>/<repo>/<type>/<tag>`, each with an
+/// optional `<tag>.policy.json` sidecar, and an append-only `audit.log`.
+#[derive(Debug)]
+pub struct FileResourceStore {
+    root: PathBuf,
+    /// Serialises writers of `audit.log` within this process.
+    audit_lock: Mutex<()>,
+}
+
+impl FileResourceStore {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        FileResourceStore { root: root.into(), audit_lock: Mutex::new(()) }
+    }
+
+    fn data_path(&self, path: &ResourcePath) -> Result<PathBuf, ResourceStoreError> {
+        if path.tag.ends_with(POLICY_SUFFIX) {
+            return Err(ResourceStoreError::InvalidPath(path.to_string()));
+        }
+        Ok(self.root.join(&path.repository).join(&path.resource_type).join(&path.tag))
+    }
+
+    fn policy_path(&self, path: &ResourcePath) -> Result<PathBuf, ResourceStoreError> {
+        let mut file = self.data_path(path)?.into_os_string();
+        file.push(POLICY_SUFFIX);
+        Ok(PathBuf::from(file))
+    }
+
+    fn write_policy(&self, path: &ResourcePath, policy: &ResourcePolicy) -> Result<(), ResourceStoreError> {
+        fs::write(self.policy_path(path)?, serde_json::to_vec_pretty(policy)?)?;
+        Ok(())
+    }
+
+    fn subdirectories(dir: &std::path::Path) -> Result<Vec<(String, PathBuf)>, ResourceStoreError> {
+        let mut entries = Vec::new();
+        if !dir.is_dir() {
+            return Ok(entries);
+        }
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if let Some(name) = entry.file_name().to_str() {
+                entries.push((name.to_string(), entry.path()));
+            }
+        }
+        entries.sort();
+        Ok(entries)
+    }
+}
+
+impl ResourceStore for FileResourceStore {
+    fn get(&self, path: &ResourcePath) -> Result<Option<StoredResource>, ResourceStoreError> {
+        let data = match fs::read(self.data_path(path)?) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let policy = match fs::read(self.policy_path(path)?) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ResourcePolicy::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(StoredResource { data, policy }))
+    }
+
+    fn put(&self, path: &ResourcePath, data: &[u8], policy: &ResourcePolicy) -> Result<(), ResourceStoreError> {
+        let file = self.data_path(path)?;
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&file, data)?;
+        self.write_policy(path, policy)
+    }
+
+    fn set_policy(&self, path: &ResourcePath, policy: &ResourcePolicy) -> Result<bool, ResourceStoreError> {
+        if !self.data_path(path)?.is_file() {
+            return Ok(false);
+        }
+        self.write_policy(path, policy)?;
+        Ok(true)
+    }
+
+    fn delete(&self, path: &ResourcePath) -> Result<bool, ResourceStoreError> {
+        match fs::remove_file(self.data_path(path)?) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+        match fs::remove_file(self.policy_path(path)?) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(true),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<ResourcePath>, ResourceStoreError> {
+        let mut paths = Vec::new();
+        for (repository, repo_dir) in Self::subdirectories(&self.root)? {
+            for (resource_type, type_dir) in Self::subdirectories(&repo_dir)? {
+                for (tag, file) in Self::subdirectories(&type_dir)? {
+                    if file.is_file() && !tag.ends_with(POLICY_SUFFIX) {
+                        paths.push(ResourcePath {
+                            repository: repository.clone(),
+                            resource_type: resource_type.clone(),
+                            tag,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(paths)
+    }
+
+    fn record_access(&self, record: &AuditRecord) -> Result<(), ResourceStoreError> {
+        let _guard = self.audit_lock.lock();
+        fs::create_dir_all(&self.root)?;
+        let mut log = OpenOptions::new().create(true).append(true).open(self.root.join(AUDIT_LOG_FILE))?;
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        log.write_all(&line)?;
+        Ok(())
+    }
+
+    fn audit_log(&self, limit: usize) -> Result<Vec<AuditRecord>, ResourceStoreError> {
+        let file = match fs::File::open(self.root.join(AUDIT_LOG_FILE)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut records = Vec::new();
+        for line in BufReader::new(file).lines() {
+            records.push(serde_json::from_str(&line?)?);
+        }
+        let skip = records.len().saturating_sub(limit);
+        Ok(records.split_off(skip))
+    }
+}
+
+// --- Postgres Backend ---
+
+/// Tables used by `PostgresResourceStore`.
+pub const RESOURCE_TABLES_DDL: &str = "CREATE TABLE IF NOT EXISTS kbs_resources (
+    path TEXT PRIMARY KEY,
+    nonce BYTEA NOT NULL,
+    ciphertext BYTEA NOT NULL,
+    policy TEXT NOT NULL,
+    updated_at BIGINT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS kbs_resource_audit (
+    id BIGSERIAL PRIMARY KEY,
+    at BIGINT NOT NULL,
+    session_id TEXT NOT NULL,
+    resource TEXT NOT NULL,
+    released BOOLEAN NOT NULL,
+    measurement TEXT,
+    detail TEXT NOT NULL
+)";
+
+/// Resources in Postgres, encrypted at rest with AES-256-GCM under a key
+/// the database never sees. The resource path and the serialized release
+/// policy are authenticated as AAD, so neither can be swapped or edited in
+/// the database without the row failing to open.
+pub struct PostgresResourceStore {
+    client: Mutex<postgres::Client>,
+    cipher: Aes256Gcm,
+}
+
+impl PostgresResourceStore {
+    /// Connects with `sslmode` and creates the tables if they do not exist.
+    /// `key` is the 256-bit encryption key for resource data.
+    pub fn connect(
+        params: &str,
+        key: &[u8; 32],
+        sslmode: SslMode,
+        tls: &TlsMaterial,
+    ) -> Result<Self, ResourceStoreError> {
+        let mut client =
+            db_tls::connect(params, sslmode, tls).map_err(|e| ResourceStoreError::Database(e.to_string()))?;
+        client.batch_execute(RESOURCE_TABLES_DDL)?;
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(|e| ResourceStoreError::Crypto(e.to_string()))?;
+        Ok(PostgresResourceStore { client: Mutex::new(client), cipher })
+    }
+
+    fn client(&self) -> Result<std::sync::MutexGuard<'_, postgres::Client>, ResourceStoreError> {
+        self.client
+            .lock()
+            .map_err(|_| ResourceStoreError::Database("resource store lock poisoned".to_string()))
+    }
+
+}
+
+/// The AAD of a row: `path || 0x00 || policy_json`, with `policy_json`
+/// exactly as stored in the `policy` column.
+fn row_aad(path: &str, policy_json: &str) -> Result<Vec<u8>, ResourceStoreError> {
+    if path.contains('\0') {
+        return Err(ResourceStoreError::InvalidPath(path.to_string()));
+    }
+    let mut aad = Vec::with_capacity(path.len() + 1 + policy_json.len());
+    aad.extend_from_slice(path.as_bytes());
+    aad.push(0);
+    aad.extend_from_slice(policy_json.as_bytes());
+    Ok(aad)
+}
+
+/// Encrypts a row's data under a fresh nonce.
+fn seal(
+    cipher: &Aes256Gcm,
+    path: &str,
+    policy_json: &str,
+    data: &[u8],
+) -> Result<([u8; 12], Vec<u8>), ResourceStoreError> {
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+    let aad = row_aad(path, policy_json)?;
+    let ciphertext = cipher
+        .encrypt(&Nonce::from(nonce), Payload { msg: data, aad: &aad })
+        .map_err(|e| ResourceStoreError::Crypto(e.to_string()))?;
+    Ok((nonce, ciphertext))
+}
+
+/// Decrypts a row's data, failing if its path or policy was altered.
+fn open(
+    cipher: &Aes256Gcm,
+    path: &str,
+    policy_json: &str,
+    nonce: &[u8],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, ResourceStoreError> {
+    let nonce: [u8; 12] = nonce
+        .try_into()
+        .map_err(|_| ResourceStoreError::Crypto(format!("bad nonce length for {}", path)))?;
+    let aad = row_aad(path, policy_json)?;
+    cipher
+        .decrypt(&Nonce::from(nonce), Payload { msg: ciphertext, aad: &aad })
+        .map_err(|_| ResourceStoreError::Crypto(format!("{} or its policy failed authentication", path)))
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+impl ResourceStore for PostgresResourceStore {
+    fn get(&self, path: &ResourcePath) -> Result<Option<StoredResource>, ResourceStoreError> {
+        let key = path.to_string();
+        let row = self.client()?.query_opt(
+            "SELECT nonce, ciphertext, policy FROM kbs_resources WHERE path = $1",
+            &[&key],
+        )?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let nonce: Vec<u8> = row.get(0);
+        let ciphertext: Vec<u8> = row.get(1);
+        let policy: String = row.get(2);
+        // Only a policy that authenticates with the data is parsed at all.
+        let data = open(&self.cipher, &key, &policy, &nonce, &ciphertext)?;
+        Ok(Some(StoredResource { data, policy: serde_json::from_str(&policy)? }))
+    }
+
+    fn put(&self, path: &ResourcePath, data: &[u8], policy: &ResourcePolicy) -> Result<(), ResourceStoreError> {
+        let key = path.to_string();
+        let policy = serde_json::to_string(policy)?;
+        let (nonce, ciphertext) = seal(&self.cipher, &key, &policy, data)?;
+        self.client()?.execute(
+            "INSERT INTO kbs_resources (path, nonce, ciphertext, policy, updated_at) VALUES ($1, $2, $3, $4, $5)
+             ON CONFLICT (path) DO UPDATE SET nonce = EXCLUDED.nonce, ciphertext = EXCLUDED.ciphertext,
+             policy = EXCLUDED.policy, updated_at = EXCLUDED.updated_at",
+            &[&key, &nonce.as_slice(), &ciphertext, &policy, &unix_now()],
+        )?;
+        Ok(())
+    }
+
+    /// Re-seals the row under a fresh nonce so the new policy is bound to the
+    /// data; a row whose current policy fails authentication is not updated.
+    fn set_policy(&self, path: &ResourcePath, policy: &ResourcePolicy) -> Result<bool, ResourceStoreError> {
+        let key = path.to_string();
+        let policy = serde_json::to_string(policy)?;
+        let mut client = self.client()?;
+        let mut tx = client.transaction()?;
+        let row = tx.query_opt(
+            "SELECT nonce, ciphertext, policy FROM kbs_resources WHERE path = $1 FOR UPDATE",
+            &[&key],
+        )?;
+        let Some(row) = row else {
+            return Ok(false);
+        };
+        let nonce: Vec<u8> = row.get(0);
+        let ciphertext: Vec<u8> = row.get(1);
+        let old_policy: String = row.get(2);
+        let data = open(&self.cipher, &key, &old_policy, &nonce, &ciphertext)?;
+
+        let (nonce, ciphertext) = seal(&self.cipher, &key, &policy, &data)?;
+        tx.execute(
+            "UPDATE kbs_resources SET nonce = $2, ciphertext = $3, policy = $4, updated_at = $5 WHERE path = $1",
+            &[&key, &nonce.as_slice(), &ciphertext, &policy, &unix_now()],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn delete(&self, path: &ResourcePath) -> Result<bool, ResourceStoreError> {
+        let deleted = self
+            .client()?
+            .execute("DELETE FROM kbs_resources WHERE path = $1", &[&path.to_string()])?;
+        Ok(deleted > 0)
+    }
+
+    fn list(&self) -> Result<Vec<ResourcePath>, ResourceStoreError> {
+        let rows = self.client()?.query("SELECT path FROM kbs_resources ORDER BY path", &[])?;
+        rows.iter()
+            .map(|row| {
+                let path: String = row.get(0);
+                ResourcePath::parse(&path).map_err(ResourceStoreError::Serialization)
+            })
+            .collect()
+    }
+
+    fn record_access(&self, record: &AuditRecord) -> Result<(), ResourceStoreError> {
+        self.client()?.execute(
+            "INSERT INTO kbs_resource_audit (at, session_id, resource, released, measurement, detail)
+             VALUES ($1, $2, $3, $4, $5, $6)",
+            &[
+                &(record.at as i64),
+                &record.session_id,
+                &record.resource,
+                &record.released,
+                &record.measurement,
+                &record.detail,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn audit_log(&self, limit: usize) -> Result<Vec<AuditRecord>, ResourceStoreError> {
+        let rows = self.client()?.query(
+            "SELECT at, session_id, resource, released, measurement, detail FROM
+             (SELECT * FROM kbs_resource_audit ORDER BY id DESC LIMIT $1) recent ORDER BY id",
+            &[&(limit as i64)],
+        )?;
+        Ok(rows
+            .iter()
+            .map(|row| AuditRecord {
+                at: row.get::<_, i64>(0) as u64,
+                session_id: row.get(1),
+                resource: row.get(2),
+                released: row.get(3),
+                measurement: row.get(4),
+                detail: row.get(5),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Postgres connection string for the store tests; they are skipped
+    /// when it is unset.
+    const TEST_DB_ENV: &str = "KBS_TEST_RESOURCE_DB";
+
+    const KEY: [u8; 32] = [0x42; 32];
+
+    fn cipher() -> Aes256Gcm {
+        Aes256Gcm::new_from_slice(&KEY).unwrap()
+    }
+
+    fn tee_policy(tee: &str) -> ResourcePolicy {
+        ResourcePolicy { allowed_tees: vec![tee.to_string()], ..ResourcePolicy::default() }
+    }
+
+    #[test]
+    fn sealed_rows_authenticate_path_and_policy() {
+        let cipher = cipher();
+        let policy = serde_json::to_string(&tee_policy("snp")).unwrap();
+        let (nonce, ciphertext) = seal(&cipher, "default/key/a", &policy, b"secret").unwrap();
+        assert_eq!(open(&cipher, "default/key/a", &policy, &nonce, &ciphertext).unwrap(), b"secret");
+
+        // Another path, a relaxed policy, or a re-serialized one all fail.
+        assert!(open(&cipher, "default/key/b", &policy, &nonce, &ciphertext).is_err());
+        let relaxed = serde_json::to_string(&ResourcePolicy::default()).unwrap();
+        assert!(open(&cipher, "default/key/a", &relaxed, &nonce, &ciphertext).is_err());
+        let pretty = serde_json::to_string_pretty(&tee_policy("snp")).unwrap();
+        assert!(open(&cipher, "default/key/a", &pretty, &nonce, &ciphertext).is_err());
+
+        // The separator keeps the path and policy from sliding into each other.
+        assert!(matches!(row_aad("default/key/a\0", &policy), Err(ResourceStoreError::InvalidPath(_))));
+        assert_eq!(row_aad("a/b/c", "{}").unwrap(), b"a/b/c\0{}");
+    }
+
+    #[test]
+    fn every_seal_uses_a_fresh_nonce() {
+        let cipher = cipher();
+        let (first, _) = seal(&cipher, "default/key/a", "{}", b"secret").unwrap();
+        let (second, _) = seal(&cipher, "default/key/a", "{}", b"secret").unwrap();
+        assert_ne!(first, second);
+        assert!(matches!(open(&cipher, "default/key/a", "{}", &first[..8], b""), Err(ResourceStoreError::Crypto(_))));
+    }
+
+    #[test]
+    fn postgres_store_binds_the_policy() {
+        let Ok(params) = std::env::var(TEST_DB_ENV) else {
+            eprintln!("{} not set; skipping", TEST_DB_ENV);
+            return;
+        };
+        let store = PostgresResourceStore::connect(&params, &KEY, SslMode::Prefer, &TlsMaterial::default()).unwrap();
+        let path = ResourcePath::parse(&format!("test/key/{:016x}", OsRng.next_u64())).unwrap();
+
+        store.put(&path, b"secret", &tee_policy("snp")).unwrap();
+        let stored = store.get(&path).unwrap().unwrap();
+        assert_eq!(stored.data, b"secret");
+        assert_eq!(stored.policy, tee_policy("snp"));
+
+        // `set_policy` re-seals the row under a fresh nonce.
+        let nonce_of = |store: &PostgresResourceStore| -> Vec<u8> {
+            let mut client = store.client().unwrap();
+            let row = client.query_one("SELECT nonce FROM kbs_resources WHERE path = $1", &[&path.to_string()]);
+            row.unwrap().get(0)
+        };
+        let before = nonce_of(&store);
+        assert!(store.set_policy(&path, &tee_policy("tdx")).unwrap());
+        assert_ne!(nonce_of(&store), before);
+        let stored = store.get(&path).unwrap().unwrap();
+        assert_eq!(stored.data, b"secret");
+        assert_eq!(stored.policy, tee_policy("tdx"));
+
+        // Relaxing the policy behind the store's back makes the row unusable,
+        // for reads and for further policy updates alike.
+        let relaxed = serde_json::to_string(&ResourcePolicy::default()).unwrap();
+        store
+            .client()
+            .unwrap()
+            .execute("UPDATE kbs_resources SET policy = $2 WHERE path = $1", &[&path.to_string(), &relaxed])
+            .unwrap();
+        assert!(matches!(store.get(&path), Err(ResourceStoreError::Crypto(_))));
+        assert!(matches!(store.set_policy(&path, &tee_policy("snp")), Err(ResourceStoreError::Crypto(_))));
+
+        let missing = ResourcePath::parse("test/key/missing").unwrap();
+        assert!(!store.set_policy(&missing, &tee_policy("snp")).unwrap());
+        assert!(store.delete(&path).unwrap());
+    }
+}
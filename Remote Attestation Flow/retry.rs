@@ -0,0 +1,384 @@
+use rand_core::{OsRng, RngCore};
+#[cfg(feature = "async-retry")]
+use std::future::Future;
+use std::time::Duration;
+
+use crate::attestation_data::FailureCode;
+use crate::attester::attester::AttesterError;
+use crate::error::AttestationError;
+#[cfg(feature = "kbs-client")]
+use crate::kbs_client::KbsClientError;
+#[cfg(feature = "kbs-client")]
+use crate::kbs_protocol::{ERROR_INVALID_TOKEN, ERROR_NONCE_REJECTED, ERROR_UNAUTHENTICATED_SESSION};
+use crate::nonce_store::NonceError;
+use crate::sync_error::SyncError;
+
+// --- Retry, Timeout and Backoff ---
+//
+// One failed request should not end an attestation. Errors fall into three
+// classes: transient ones (the KBS is unreachable or overloaded, a step
+// timed out) retry the failed step; a stale challenge (the nonce expired or
+// was consumed, the session or token is gone) restarts the pipeline from a
+// new challenge; anything else, notably rejected evidence or a policy
+// denial, is permanent and returned at once. Retries back off exponentially
+// with jitter so a fleet of guests does not hit a recovering KBS in step.
+
+/// Environment variables read by `RetryPolicy::from_env`.
+pub const ATTESTATION_MAX_ATTEMPTS_ENV: &str = "ATTESTATION_MAX_ATTEMPTS";
+pub const ATTESTATION_MAX_RESTARTS_ENV: &str = "ATTESTATION_MAX_RESTARTS";
+pub const ATTESTATION_STEP_TIMEOUT_MS_ENV: &str = "ATTESTATION_STEP_TIMEOUT_MS";
+pub const ATTESTATION_BACKOFF_MS_ENV: &str = "ATTESTATION_BACKOFF_MS";
+pub const ATTESTATION_MAX_BACKOFF_MS_ENV: &str = "ATTESTATION_MAX_BACKOFF_MS";
+
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+pub const DEFAULT_MAX_RESTARTS: u32 = 3;
+pub const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(10);
+pub const DEFAULT_STEP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How an error affects the pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryClass {
+    /// Retry the failed step after a backoff.
+    Transient,
+    /// Start over with a new challenge.
+    RestartFromChallenge,
+    /// Give up.
+    Permanent,
+}
+
+impl AttestationError {
+    /// Whether retrying can help, and from where.
+    pub fn retry_class(&self) -> RetryClass {
+        match self {
+            #[cfg(feature = "kbs-client")]
+            AttestationError::Kbs(e) => match e {
+                KbsClientError::Transport(_) => RetryClass::Transient,
+                KbsClientError::Status { status: 401, error_type, .. }
+                    if [ERROR_NONCE_REJECTED, ERROR_UNAUTHENTICATED_SESSION, ERROR_INVALID_TOKEN]
+                        .contains(&error_type.as_str()) =>
+                {
+                    RetryClass::RestartFromChallenge
+                }
+                KbsClientError::Status { status: 408 | 429 | 500..=599, .. } => RetryClass::Transient,
+                KbsClientError::NoSession => RetryClass::RestartFromChallenge,
+                _ => RetryClass::Permanent,
+            },
+            AttestationError::Verification(result) => match result.failure {
+                Some(FailureCode::NonceExpired | FailureCode::NonceReplayed) => RetryClass::RestartFromChallenge,
+                Some(FailureCode::NonceStoreUnavailable) => RetryClass::Transient,
+                _ => RetryClass::Permanent,
+            },
+            AttestationError::Nonce(NonceError::Store(_)) => RetryClass::Transient,
+            AttestationError::Nonce(_) => RetryClass::RestartFromChallenge,
+            // e.g. EAGAIN while the firmware is busy with another request.
+            AttestationError::Device(AttesterError::Io(_)) => RetryClass::Transient,
+            AttestationError::Lock(SyncError::LockTimeout) | AttestationError::Timeout(_) | AttestationError::Io(_) => {
+                RetryClass::Transient
+            }
+            _ => RetryClass::Permanent,
+        }
+    }
+}
+
+/// Per-step retries, restarts and timeouts of an attestation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Tries of each step, including the first.
+    pub max_attempts: u32,
+    /// Times the pipeline may start over from a new challenge.
+    pub max_restarts: u32,
+    /// Delay before the first retry of a step.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Growth of the delay from one retry to the next.
+    pub multiplier: f64,
+    /// Fraction of each delay that is randomised: 0 waits exactly the
+    /// backoff, 1 waits anywhere between zero and the backoff.
+    pub jitter: f64,
+    /// Limit on each KBS request and, where the runtime allows, on
+    /// evidence generation.
+    pub step_timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            max_restarts: DEFAULT_MAX_RESTARTS,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            multiplier: 2.0,
+            jitter: 0.5,
+            step_timeout: DEFAULT_STEP_TIMEOUT,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A single pass: no retries and no restarts.
+    pub fn no_retry() -> Self {
+        RetryPolicy { max_attempts: 1, max_restarts: 0, ..RetryPolicy::default() }
+    }
+
+    /// Overrides the defaults with `ATTESTATION_MAX_ATTEMPTS`,
+    /// `ATTESTATION_MAX_RESTARTS`, `ATTESTATION_STEP_TIMEOUT_MS`,
+    /// `ATTESTATION_BACKOFF_MS` and `ATTESTATION_MAX_BACKOFF_MS`.
+    pub fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().and_then(|v| v.parse::<u64>().ok());
+        let mut policy = RetryPolicy::default();
+        if let Some(attempts) = var(ATTESTATION_MAX_ATTEMPTS_ENV) {
+            policy.max_attempts = attempts.clamp(1, u32::MAX as u64) as u32;
+        }
+        if let Some(restarts) = var(ATTESTATION_MAX_RESTARTS_ENV) {
+            policy.max_restarts = restarts.min(u32::MAX as u64) as u32;
+        }
+        if let Some(ms) = var(ATTESTATION_STEP_TIMEOUT_MS_ENV) {
+            policy.step_timeout = Duration::from_millis(ms.max(1));
+        }
+        if let Some(ms) = var(ATTESTATION_BACKOFF_MS_ENV) {
+            policy.initial_backoff = Duration::from_millis(ms);
+        }
+        if let Some(ms) = var(ATTESTATION_MAX_BACKOFF_MS_ENV) {
+            policy.max_backoff = Duration::from_millis(ms);
+        }
+        policy
+    }
+
+    /// The delay before retry number `retry` (1 for the first): the
+    /// exponential backoff, capped, with its jittered fraction randomised.
+    /// A `multiplier` below 1 or not a number is taken as 1, a `jitter`
+    /// outside 0..=1 is clamped and one that is not a number is taken as 0.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let multiplier = if self.multiplier.is_nan() { 1.0 } else { self.multiplier.max(1.0) };
+        let jitter = if self.jitter.is_nan() { 0.0 } else { self.jitter.clamp(0.0, 1.0) };
+        let exponent = retry.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = self.initial_backoff.as_secs_f64() * multiplier.powi(exponent);
+        let delay = delay.min(self.max_backoff.as_secs_f64());
+        let random = OsRng.next_u32() as f64 / u32::MAX as f64;
+        Duration::try_from_secs_f64(delay * (1.0 - jitter * random)).unwrap_or(self.max_backoff)
+    }
+
+    /// How long to wait before retrying a step that failed with `error` on
+    /// its `attempt`-th try (1-based), or `None` to stop retrying it.
+    pub fn retry_delay(&self, attempt: u32, error: &AttestationError) -> Option<Duration> {
+        (error.retry_class() == RetryClass::Transient && attempt < self.max_attempts).then(|| self.backoff(attempt))
+    }
+
+    /// Whether the pipeline may start over after `error`, having already
+    /// restarted `restarts` times.
+    pub fn may_restart(&self, restarts: u32, error: &AttestationError) -> bool {
+        error.retry_class() == RetryClass::RestartFromChallenge && restarts < self.max_restarts
+    }
+
+    /// Runs one step, retrying transient failures. Blocking.
+    pub fn retry<T>(
+        &self,
+        step: &str,
+        mut run: impl FnMut() -> Result<T, AttestationError>,
+    ) -> Result<T, AttestationError> {
+        let mut attempt = 1;
+        loop {
+            match run() {
+                Err(e) => match self.retry_delay(attempt, &e) {
+                    Some(delay) => {
+                        println!(
+                            "[Retry] {} failed (attempt {}/{}): {}; retrying in {:?}",
+                            step, attempt, self.max_attempts, e, delay
+                        );
+                        std::thread::sleep(delay);
+                        attempt += 1;
+                    }
+                    None => return Err(e),
+                },
+                ok => return ok,
+            }
+        }
+    }
+
+    /// Runs a whole pipeline, starting it over when its challenge went
+    /// stale. Blocking.
+    pub fn with_restarts<T>(
+        &self,
+        mut pipeline: impl FnMut() -> Result<T, AttestationError>,
+    ) -> Result<T, AttestationError> {
+        let mut restarts = 0;
+        loop {
+            match pipeline() {
+                Err(e) if self.may_restart(restarts, &e) => {
+                    restarts += 1;
+                    println!(
+                        "[Retry] {}; restarting from a new challenge ({}/{})",
+                        e, restarts, self.max_restarts
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Runs one step, retrying transient failures; the async counterpart
+    /// of `retry`, waiting on the tokio timer.
+    #[cfg(feature = "async-retry")]
+    pub async fn retry_async<T, F, Fut>(&self, step: &str, mut run: F) -> Result<T, AttestationError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, AttestationError>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match run().await {
+                Err(e) => match self.retry_delay(attempt, &e) {
+                    Some(delay) => {
+                        println!(
+                            "[Retry] {} failed (attempt {}/{}): {}; retrying in {:?}",
+                            step, attempt, self.max_attempts, e, delay
+                        );
+                        tokio::time::sleep(delay).await;
+                        attempt += 1;
+                    }
+                    None => return Err(e),
+                },
+                ok => return ok,
+            }
+        }
+    }
+
+    /// Runs a whole pipeline, starting it over when its challenge went
+    /// stale; the async counterpart of `with_restarts`.
+    #[cfg(feature = "async-retry")]
+    pub async fn with_restarts_async<T, F, Fut>(&self, mut pipeline: F) -> Result<T, AttestationError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, AttestationError>>,
+    {
+        let mut restarts = 0;
+        loop {
+            match pipeline().await {
+                Err(e) if self.may_restart(restarts, &e) => {
+                    restarts += 1;
+                    println!(
+                        "[Retry] {}; restarting from a new challenge ({}/{})",
+                        e, restarts, self.max_restarts
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transient() -> AttestationError {
+        AttestationError::Timeout("test step".to_string())
+    }
+
+    #[test]
+    fn backoff_grows_up_to_the_cap() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(350),
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        };
+        let delays: Vec<_> = (1..=4).map(|retry| policy.backoff(retry).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 350, 350]);
+    }
+
+    #[test]
+    fn backoff_survives_nonsensical_parameters() {
+        let cap = Duration::from_secs(1);
+        for (multiplier, jitter) in [
+            (f64::NAN, f64::NAN),
+            (f64::INFINITY, f64::INFINITY),
+            (f64::NEG_INFINITY, f64::NEG_INFINITY),
+            (-3.0, -1.0),
+            (2.0, 7.0),
+        ] {
+            let policy = RetryPolicy { multiplier, jitter, max_backoff: cap, ..RetryPolicy::default() };
+            for retry in [0, 1, 2, 64, u32::MAX] {
+                assert!(policy.backoff(retry) <= cap, "multiplier {} jitter {}", multiplier, jitter);
+            }
+        }
+        let exact = RetryPolicy { jitter: f64::NAN, ..RetryPolicy::default() };
+        assert_eq!(exact.backoff(1), DEFAULT_INITIAL_BACKOFF);
+    }
+
+    #[test]
+    fn retry_gives_up_after_max_attempts() {
+        let policy = RetryPolicy { max_attempts: 3, initial_backoff: Duration::ZERO, ..RetryPolicy::default() };
+        let mut calls = 0;
+        let result: Result<(), _> = policy.retry("down", || {
+            calls += 1;
+            Err(transient())
+        });
+        assert!(matches!(result, Err(AttestationError::Timeout(_))));
+        assert_eq!(calls, 3);
+    }
+
+    #[cfg(feature = "async-retry")]
+    #[tokio::test(start_paused = true)]
+    async fn retry_async_retries_transient_failures_with_backoff() {
+        let policy = RetryPolicy { max_attempts: 3, jitter: 0.0, ..RetryPolicy::default() };
+        let start = tokio::time::Instant::now();
+        let mut calls = 0;
+        let result = policy
+            .retry_async("flaky", || {
+                calls += 1;
+                let outcome = if calls < 3 { Err(transient()) } else { Ok(calls) };
+                async move { outcome }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(start.elapsed(), policy.backoff(1) + policy.backoff(2));
+
+        let mut calls = 0;
+        let result: Result<(), _> = policy
+            .retry_async("down", || {
+                calls += 1;
+                async { Err(transient()) }
+            })
+            .await;
+        assert!(matches!(result, Err(AttestationError::Timeout(_))));
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result: Result<(), _> = policy
+            .retry_async("refused", || {
+                calls += 1;
+                async { Err(AttestationError::Configuration("bad".to_string())) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1, "permanent failures are not retried");
+    }
+
+    #[cfg(feature = "async-retry")]
+    #[tokio::test]
+    async fn with_restarts_async_starts_over_on_a_stale_challenge() {
+        let policy = RetryPolicy { max_restarts: 2, ..RetryPolicy::default() };
+        let stale = || AttestationError::Nonce(NonceError::Replayed);
+        let mut passes = 0;
+        let result = policy
+            .with_restarts_async(|| {
+                passes += 1;
+                let outcome = if passes < 3 { Err(stale()) } else { Ok(passes) };
+                async move { outcome }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 3);
+
+        let mut passes = 0;
+        let result: Result<(), _> = policy
+            .with_restarts_async(|| {
+                passes += 1;
+                async { Err(stale()) }
+            })
+            .await;
+        assert!(matches!(result, Err(AttestationError::Nonce(NonceError::Replayed))));
+        assert_eq!(passes, 3);
+    }
+}
@@ -0,0 +1,183 @@
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+
+use crate::attestation_data::{AttestationChallenge, AttestationReport};
+use crate::attester::attester::{Attester, AttesterError};
+use crate::snp_report::{SnpAttestationReport, SNP_REPORT_SIZE};
+
+// --- /dev/sev-guest ioctl ABI (include/uapi/linux/sev-guest.h) ---
+
+/// Device node exposed by the `sev-guest` driver inside an SNP guest.
+pub const SEV_GUEST_DEVICE: &str = "/dev/sev-guest";
+
+/// `_IOWR('S', 0x2, struct snp_guest_request_ioctl)`
+const SNP_GET_EXT_REPORT: libc::c_ulong = 0xC020_5302;
+
+/// `exitinfo2` VMM error: the certificate buffer was too small.
+const SNP_GUEST_VMM_ERR_INVALID_LEN: u32 = 1;
+
+/// Initial certificate buffer size; the host tells us if it needs more.
+const INITIAL_CERTS_LEN: usize = 4 * 4096;
+
+/// Offset of the report inside `struct msg_report_resp`.
+const MSG_REPORT_RSP_HEADER: usize = 0x20;
+
+// GUIDs of the certificate table entries supplied by the host (GHCB spec).
+const GUID_VCEK: &str = "63da758d-e664-4564-adc5-f4b93be8accd";
+const GUID_VLEK: &str = "a8074bc2-a25a-483e-aae6-39c045a0b8a1";
+const GUID_ASK: &str = "4ab7b379-bbac-4fe4-a02f-05aef327c782";
+const GUID_ARK: &str = "c0b406a4-a803-4952-9743-3fb6014cd0ae";
+
+#[repr(C)]
+struct SnpGuestRequestIoctl {
+    msg_version: u8,
+    req_data: u64,
+    resp_data: u64,
+    exitinfo2: u64,
+}
+
+#[repr(C)]
+struct SnpReportReq {
+    user_data: [u8; 64],
+    vmpl: u32,
+    rsvd: [u8; 28],
+}
+
+#[repr(C)]
+struct SnpExtReportReq {
+    data: SnpReportReq,
+    certs_address: u64,
+    certs_len: u32,
+}
+
+#[repr(C)]
+struct SnpReportResp {
+    data: [u8; 4000],
+}
+
+/// Encodes a textual GUID in the mixed-endian binary form used on the wire.
+fn guid_bytes(guid: &str) -> [u8; 16] {
+    let hex: String = guid.chars().filter(|c| *c != '-').collect();
+    let raw = hex::decode(hex).expect("GUID constants are valid hex");
+    let mut out = [0u8; 16];
+    out.copy_from_slice(&raw);
+    out[0..4].reverse();
+    out[4..6].reverse();
+    out[6..8].reverse();
+    out
+}
+
+/// Extracts the VCEK (or VLEK), ASK and ARK from the host-provided certificate
+/// table and concatenates them leaf-first as DER.
+fn cert_chain_from_table(table: &[u8]) -> Vec<u8> {
+    let mut entries = Vec::new();
+    for entry in table.chunks_exact(24) {
+        let guid = &entry[0..16];
+        if guid.iter().all(|b| *b == 0) {
+            break;
+        }
+        let offset = u32::from_le_bytes(entry[16..20].try_into().unwrap()) as usize;
+        let length = u32::from_le_bytes(entry[20..24].try_into().unwrap()) as usize;
+        if let Some(cert) = table.get(offset..offset + length) {
+            entries.push((guid.to_vec(), cert));
+        }
+    }
+
+    let mut chain = Vec::new();
+    for wanted in [[GUID_VCEK, GUID_VLEK], [GUID_ASK, GUID_ASK], [GUID_ARK, GUID_ARK]] {
+        let ids = wanted.map(guid_bytes);
+        if let Some((_, cert)) = entries.iter().find(|(g, _)| ids.iter().any(|id| g == id)) {
+            chain.extend_from_slice(cert);
+        }
+    }
+    chain
+}
+
+/// Collects SNP evidence through the `SNP_GET_EXT_REPORT` ioctl.
+pub struct SevSnpAttester {
+    device: File,
+    /// The VMPL the report is requested for (0 for the most privileged level).
+    pub vmpl: u32,
+}
+
+impl SevSnpAttester {
+    pub fn open() -> Result<Self, AttesterError> {
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(SEV_GUEST_DEVICE)
+            .map_err(|_| AttesterError::DeviceUnavailable(SEV_GUEST_DEVICE.to_string()))?;
+        Ok(SevSnpAttester { device, vmpl: 0 })
+    }
+
+    /// Issues the ioctl, growing the certificate buffer once if the host asks.
+    fn get_ext_report(&self, user_data: [u8; 64]) -> Result<(Vec<u8>, Vec<u8>), AttesterError> {
+        let mut certs = vec![0u8; INITIAL_CERTS_LEN];
+
+        for _ in 0..2 {
+            let mut req = SnpExtReportReq {
+                data: SnpReportReq { user_data, vmpl: self.vmpl, rsvd: [0; 28] },
+                certs_address: certs.as_mut_ptr() as u64,
+                certs_len: certs.len() as u32,
+            };
+            let mut resp = SnpReportResp { data: [0; 4000] };
+            let mut ioctl_req = SnpGuestRequestIoctl {
+                msg_version: 1,
+                req_data: &mut req as *mut _ as u64,
+                resp_data: &mut resp as *mut _ as u64,
+                exitinfo2: 0,
+            };
+
+            // SAFETY: all pointers reference live, correctly sized buffers for
+            // the duration of the call, matching the kernel's uapi structs.
+            let rc = unsafe { libc::ioctl(self.device.as_raw_fd(), SNP_GET_EXT_REPORT, &mut ioctl_req) };
+            if rc < 0 {
+                let vmm_error = (ioctl_req.exitinfo2 >> 32) as u32;
+                if vmm_error == SNP_GUEST_VMM_ERR_INVALID_LEN {
+                    certs = vec![0u8; req.certs_len as usize];
+                    continue;
+                }
+                if ioctl_req.exitinfo2 != 0 {
+                    return Err(AttesterError::Firmware(ioctl_req.exitinfo2));
+                }
+                return Err(AttesterError::Io(std::io::Error::last_os_error()));
+            }
+
+            let status = u32::from_le_bytes(resp.data[0..4].try_into().unwrap());
+            if status != 0 {
+                return Err(AttesterError::Firmware(status as u64));
+            }
+            let report = resp.data[MSG_REPORT_RSP_HEADER..MSG_REPORT_RSP_HEADER + SNP_REPORT_SIZE].to_vec();
+            return Ok((report, certs));
+        }
+
+        Err(AttesterError::MalformedResponse(
+            "host kept rejecting the certificate buffer length".to_string(),
+        ))
+    }
+}
+
+impl Attester for SevSnpAttester {
+    fn name(&self) -> &'static str {
+        "snp"
+    }
+
+    fn request_evidence(
+        &self,
+        challenge: &AttestationChallenge,
+        report_data: [u8; 64],
+    ) -> Result<AttestationReport, AttesterError> {
+        println!("\n[Attester:snp] Received Challenge: {}", challenge.nonce);
+
+        let (raw_report, cert_table) = self.get_ext_report(report_data)?;
+        let snp = SnpAttestationReport::from_bytes(&raw_report)
+            .map_err(|e| AttesterError::MalformedResponse(e.to_string()))?;
+
+        // An empty table means the host does not cache certificates; the
+        // chain must then be supplied out of band (e.g. from the AMD KDS).
+        let cert_chain = cert_chain_from_table(&cert_table);
+
+        println!("[Attester:snp] Generated Report with Measurement: {:?}", &snp.measurement[..8]);
+        Ok(AttestationReport::from_snp(&snp, cert_chain))
+    }
+}
@@ -0,0 +1,194 @@
+use der::Encode;
+use p384::ecdsa::signature::Signer;
+use p384::ecdsa::{Signature, SigningKey};
+use p384::pkcs8::{DecodePrivateKey, EncodePrivateKey};
+use rand_core::OsRng;
+use sha2::{Digest, Sha384};
+use std::fs;
+use std::path::Path;
+
+use crate::attestation_data::{AttestationChallenge, AttestationReport};
+use crate::attester::attester::{Attester, AttesterError};
+use crate::snp_report::{
+    GuestPolicy, SnpAttestationReport, SNP_REPORT_SIZE, SNP_SIG_ALGO_ECDSA_P384_SHA384,
+};
+
+// --- Software TEE Simulator ---
+//
+// Produces SEV-SNP shaped reports signed by a locally generated "VCEK" that
+// chains to a locally generated "ARK". Pin `local_root_pem()` in the verifier's
+// trust anchors to exercise the full pipeline without TEE hardware.
+
+/// The boot image the simulator pretends to have measured.
+pub const SIMULATED_BOOT_IMAGE: &[u8] = b"VM_BOOT_MEASUREMENT_HASH";
+
+/// Directory in which the simulator persists its keys, so that a verifier in
+/// another process (e.g. the KBS) can pin its root. Unset means ephemeral keys.
+pub const SIMULATOR_STATE_DIR_ENV: &str = "SIMULATOR_STATE_DIR";
+
+/// File names of the persisted simulator state.
+pub const SIMULATOR_ROOT_FILE: &str = "ark.pem";
+const SIMULATOR_CHAIN_FILE: &str = "cert_chain.pem";
+const SIMULATOR_VCEK_KEY_FILE: &str = "vcek_key.der";
+
+/// Guest policy of the simulated VM: ABI 0.0, SMT allowed, reserved bit 17
+/// set, debugging disabled.
+const SIMULATED_GUEST_POLICY: u64 = 0x3_0000;
+
+/// Chip ID of the simulated processor, also certified in the VCEK.
+const SIMULATED_CHIP_ID: [u8; 64] = [0x5A; 64];
+
+/// AMD VCEK extension OIDs: the boot loader, TEE, SNP and microcode SPLs
+/// (all zero in simulated reports) and the hardware ID.
+const AMD_SPL_OIDS: [&[u64]; 4] = [
+    &[1, 3, 6, 1, 4, 1, 3704, 1, 3, 1],
+    &[1, 3, 6, 1, 4, 1, 3704, 1, 3, 2],
+    &[1, 3, 6, 1, 4, 1, 3704, 1, 3, 3],
+    &[1, 3, 6, 1, 4, 1, 3704, 1, 3, 8],
+];
+const AMD_HW_ID_OID: &[u64] = &[1, 3, 6, 1, 4, 1, 3704, 1, 4];
+
+/// A software attester that signs SNP-format reports with a local key.
+pub struct SimulatedAttester {
+    vcek: SigningKey,
+    cert_chain_pem: String,
+    root_pem: String,
+    /// The launch measurement reported for every challenge.
+    pub measurement: [u8; 48],
+    /// The chip ID reported for every challenge.
+    pub chip_id: [u8; 64],
+}
+
+impl SimulatedAttester {
+    /// Generates a fresh ARK → VCEK certificate chain.
+    pub fn new() -> Result<Self, AttesterError> {
+        let keygen = |e: rcgen::Error| AttesterError::KeyGeneration(e.to_string());
+
+        let ark_key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P384_SHA384).map_err(keygen)?;
+        let mut ark_params = rcgen::CertificateParams::new(Vec::new()).map_err(keygen)?;
+        ark_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        ark_params.key_usages = vec![rcgen::KeyUsagePurpose::KeyCertSign];
+        ark_params.distinguished_name.push(rcgen::DnType::CommonName, "ARK-Simulated");
+        let ark = ark_params.self_signed(&ark_key).map_err(keygen)?;
+
+        let vcek = SigningKey::random(&mut OsRng);
+        let vcek_der = vcek
+            .to_pkcs8_der()
+            .map_err(|e| AttesterError::KeyGeneration(e.to_string()))?;
+        let vcek_key = rcgen::KeyPair::try_from(vcek_der.as_bytes()).map_err(keygen)?;
+        let mut vcek_params = rcgen::CertificateParams::new(Vec::new()).map_err(keygen)?;
+        vcek_params.distinguished_name.push(rcgen::DnType::CommonName, "SEV-VCEK-Simulated");
+        let spl = 0u8.to_der().map_err(|e| AttesterError::KeyGeneration(e.to_string()))?;
+        for oid in AMD_SPL_OIDS {
+            vcek_params.custom_extensions.push(rcgen::CustomExtension::from_oid_content(oid, spl.clone()));
+        }
+        vcek_params
+            .custom_extensions
+            .push(rcgen::CustomExtension::from_oid_content(AMD_HW_ID_OID, SIMULATED_CHIP_ID.to_vec()));
+        let vcek_cert = vcek_params.signed_by(&vcek_key, &ark, &ark_key).map_err(keygen)?;
+
+        Ok(SimulatedAttester::with_keys(
+            vcek,
+            format!("{}{}", vcek_cert.pem(), ark.pem()),
+            ark.pem(),
+        ))
+    }
+
+    /// Loads the keys persisted in `dir`, generating and saving a fresh chain
+    /// on first use. `<dir>/ark.pem` is the root verifiers must pin.
+    pub fn persistent<P: AsRef<Path>>(dir: P) -> Result<Self, AttesterError> {
+        let dir = dir.as_ref();
+        let key_path = dir.join(SIMULATOR_VCEK_KEY_FILE);
+        if key_path.exists() {
+            let vcek = SigningKey::from_pkcs8_der(&fs::read(&key_path)?)
+                .map_err(|e| AttesterError::KeyGeneration(e.to_string()))?;
+            let cert_chain_pem = fs::read_to_string(dir.join(SIMULATOR_CHAIN_FILE))?;
+            let root_pem = fs::read_to_string(dir.join(SIMULATOR_ROOT_FILE))?;
+            println!("[Attester:sim] Loaded simulator keys from {}", dir.display());
+            return Ok(SimulatedAttester::with_keys(vcek, cert_chain_pem, root_pem));
+        }
+
+        let attester = SimulatedAttester::new()?;
+        let vcek_der = attester
+            .vcek
+            .to_pkcs8_der()
+            .map_err(|e| AttesterError::KeyGeneration(e.to_string()))?;
+        fs::create_dir_all(dir)?;
+        fs::write(&key_path, vcek_der.as_bytes())?;
+        fs::write(dir.join(SIMULATOR_CHAIN_FILE), &attester.cert_chain_pem)?;
+        fs::write(dir.join(SIMULATOR_ROOT_FILE), &attester.root_pem)?;
+        println!("[Attester:sim] Saved new simulator keys to {}", dir.display());
+        Ok(attester)
+    }
+
+    /// Uses `SIMULATOR_STATE_DIR` if set, otherwise ephemeral keys.
+    pub fn from_env() -> Result<Self, AttesterError> {
+        match std::env::var(SIMULATOR_STATE_DIR_ENV) {
+            Ok(dir) => SimulatedAttester::persistent(dir),
+            Err(_) => SimulatedAttester::new(),
+        }
+    }
+
+    fn with_keys(vcek: SigningKey, cert_chain_pem: String, root_pem: String) -> Self {
+        let mut measurement = [0u8; 48];
+        measurement.copy_from_slice(&Sha384::digest(SIMULATED_BOOT_IMAGE));
+
+        SimulatedAttester {
+            vcek,
+            cert_chain_pem,
+            root_pem,
+            measurement,
+            chip_id: SIMULATED_CHIP_ID,
+        }
+    }
+
+    fn build_report(&self, report_data: [u8; 64]) -> Result<SnpAttestationReport, AttesterError> {
+        let mut raw = vec![0u8; SNP_REPORT_SIZE];
+        raw[0..4].copy_from_slice(&2u32.to_le_bytes());
+        let mut report = SnpAttestationReport::from_bytes(&raw)
+            .map_err(|e| AttesterError::MalformedResponse(e.to_string()))?;
+
+        report.policy = GuestPolicy(SIMULATED_GUEST_POLICY);
+        report.signature_algo = SNP_SIG_ALGO_ECDSA_P384_SHA384;
+        report.report_data = report_data;
+        report.measurement = self.measurement;
+        report.chip_id = self.chip_id;
+
+        // Sign like the PSP: ECDSA P-384/SHA-384 over bytes 0..0x2A0, with
+        // r and s stored little-endian.
+        let signature: Signature = self.vcek.sign(&report.signed_bytes());
+        let (r, s) = signature.split_bytes();
+        for i in 0..48 {
+            report.signature.r[i] = r[47 - i];
+            report.signature.s[i] = s[47 - i];
+        }
+        Ok(report)
+    }
+}
+
+impl Attester for SimulatedAttester {
+    fn name(&self) -> &'static str {
+        "sim"
+    }
+
+    fn tee(&self) -> &'static str {
+        "snp"
+    }
+
+    fn request_evidence(
+        &self,
+        challenge: &AttestationChallenge,
+        report_data: [u8; 64],
+    ) -> Result<AttestationReport, AttesterError> {
+        println!("\n[Attester:sim] Received Challenge: {}", challenge.nonce);
+
+        let report = self.build_report(report_data)?;
+
+        println!("[Attester:sim] Generated Report with Measurement: {:?}", &report.measurement[..8]);
+        Ok(AttestationReport::from_snp(&report, self.cert_chain_pem.clone().into_bytes()))
+    }
+
+    fn local_root_pem(&self) -> Option<String> {
+        Some(self.root_pem.clone())
+    }
+}
@@ -0,0 +1,471 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::error::Error;
+use std::fmt;
+
+// --- AMD SEV-SNP ATTESTATION_REPORT Layout ---
+//
+// Offsets follow Table 22 of the SEV Secure Nested Paging Firmware ABI
+// Specification. All multi-byte integers are little-endian.
+
+/// Total size of an SNP `ATTESTATION_REPORT` structure.
+pub const SNP_REPORT_SIZE: usize = 0x4A0;
+
+/// Number of leading bytes covered by the report signature.
+pub const SNP_SIGNED_LENGTH: usize = 0x2A0;
+
+/// Signature algorithm identifier for ECDSA P-384 with SHA-384.
+pub const SNP_SIG_ALGO_ECDSA_P384_SHA384: u32 = 1;
+
+/// Oldest report version this parser understands.
+const MIN_SUPPORTED_VERSION: u32 = 2;
+
+/// Errors produced while decoding an SNP report blob.
+#[derive(Debug)]
+pub enum SnpReportError {
+    /// The blob is not exactly `SNP_REPORT_SIZE` bytes long.
+    InvalidLength { expected: usize, actual: usize },
+    /// The report was produced by firmware using an older, unsupported layout.
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for SnpReportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnpReportError::InvalidLength { expected, actual } => write!(
+                f,
+                "Invalid SNP report length: expected {} bytes, got {}",
+                expected, actual
+            ),
+            SnpReportError::UnsupportedVersion(v) => {
+                write!(f, "Unsupported SNP report version: {}", v)
+            }
+        }
+    }
+}
+
+impl Error for SnpReportError {}
+
+// --- Packed Sub-Structures ---
+
+/// A TCB_VERSION value: the security version numbers of each firmware component.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TcbVersion {
+    pub boot_loader: u8,
+    pub tee: u8,
+    /// Bytes 2..6 are reserved on Milan and Genoa parts.
+    pub reserved: [u8; 4],
+    pub snp: u8,
+    pub microcode: u8,
+}
+
+impl TcbVersion {
+    pub fn from_u64(raw: u64) -> Self {
+        let b = raw.to_le_bytes();
+        TcbVersion {
+            boot_loader: b[0],
+            tee: b[1],
+            reserved: [b[2], b[3], b[4], b[5]],
+            snp: b[6],
+            microcode: b[7],
+        }
+    }
+
+    pub fn to_u64(&self) -> u64 {
+        let r = self.reserved;
+        u64::from_le_bytes([
+            self.boot_loader, self.tee, r[0], r[1], r[2], r[3], self.snp, self.microcode,
+        ])
+    }
+}
+
+/// The guest policy the VM was launched with (SNP_LAUNCH_START `POLICY`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GuestPolicy(pub u64);
+
+impl GuestPolicy {
+    pub fn abi_minor(&self) -> u8 {
+        (self.0 & 0xFF) as u8
+    }
+
+    pub fn abi_major(&self) -> u8 {
+        ((self.0 >> 8) & 0xFF) as u8
+    }
+
+    /// Bit 16: the guest may run on a host with SMT enabled.
+    pub fn smt_allowed(&self) -> bool {
+        self.0 & (1 << 16) != 0
+    }
+
+    /// Bit 18: association with a migration agent is allowed.
+    pub fn migrate_ma_allowed(&self) -> bool {
+        self.0 & (1 << 18) != 0
+    }
+
+    /// Bit 19: the hypervisor may debug the guest (must be clear in production).
+    pub fn debug_allowed(&self) -> bool {
+        self.0 & (1 << 19) != 0
+    }
+
+    /// Bit 20: the guest may only be activated on one socket.
+    pub fn single_socket_required(&self) -> bool {
+        self.0 & (1 << 20) != 0
+    }
+}
+
+/// Information about the platform the report was generated on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PlatformInfo(pub u64);
+
+impl PlatformInfo {
+    pub fn smt_enabled(&self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    pub fn tsme_enabled(&self) -> bool {
+        self.0 & (1 << 1) != 0
+    }
+}
+
+/// The ECDSA P-384 signature block at offset 0x2A0.
+///
+/// `r` and `s` are stored little-endian and zero-extended to 72 bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnpSignature {
+    pub r: [u8; 72],
+    pub s: [u8; 72],
+    /// Preserved verbatim so that `to_bytes` round-trips the original blob.
+    pub reserved: [u8; 368],
+}
+
+impl SnpSignature {
+    /// Returns the (r, s) scalars as 48-byte big-endian integers, the form
+    /// expected by standard ECDSA implementations.
+    pub fn to_be_scalars(&self) -> ([u8; 48], [u8; 48]) {
+        let mut r = [0u8; 48];
+        let mut s = [0u8; 48];
+        for i in 0..48 {
+            r[i] = self.r[47 - i];
+            s[i] = self.s[47 - i];
+        }
+        (r, s)
+    }
+}
+
+// --- The Decoded Report ---
+
+/// A fully decoded SEV-SNP `ATTESTATION_REPORT`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnpAttestationReport {
+    pub version: u32,
+    pub guest_svn: u32,
+    pub policy: GuestPolicy,
+    pub family_id: [u8; 16],
+    pub image_id: [u8; 16],
+    pub vmpl: u32,
+    pub signature_algo: u32,
+    pub current_tcb: TcbVersion,
+    pub platform_info: PlatformInfo,
+    /// Bit 0: AUTHOR_KEY_EN, bit 1: MASK_CHIP_KEY, bits 2-4: SIGNING_KEY.
+    pub key_flags: u32,
+    pub reserved0: u32,
+    pub report_data: [u8; 64],
+    /// SHA-384 launch digest of the guest's initial memory and VMSA state.
+    pub measurement: [u8; 48],
+    pub host_data: [u8; 32],
+    pub id_key_digest: [u8; 48],
+    pub author_key_digest: [u8; 48],
+    pub report_id: [u8; 32],
+    pub report_id_ma: [u8; 32],
+    pub reported_tcb: TcbVersion,
+    /// Holds the CPUID family/model/stepping on version 3+ reports.
+    pub reserved1: [u8; 24],
+    pub chip_id: [u8; 64],
+    pub committed_tcb: TcbVersion,
+    pub current_build: u8,
+    pub current_minor: u8,
+    pub current_major: u8,
+    pub reserved2: u8,
+    pub committed_build: u8,
+    pub committed_minor: u8,
+    pub committed_major: u8,
+    pub reserved3: u8,
+    pub launch_tcb: TcbVersion,
+    pub reserved4: [u8; 168],
+    pub signature: SnpSignature,
+}
+
+/// A little-endian cursor over a fixed-size report buffer.
+struct ReportReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ReportReader<'a> {
+    fn array<const N: usize>(&mut self) -> [u8; N] {
+        let mut out = [0u8; N];
+        out.copy_from_slice(&self.buf[self.pos..self.pos + N]);
+        self.pos += N;
+        out
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.array::<1>()[0]
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.array())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.array())
+    }
+}
+
+impl SnpAttestationReport {
+    /// Decodes a raw `ATTESTATION_REPORT` as returned by `SNP_GET_REPORT`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SnpReportError> {
+        if bytes.len() != SNP_REPORT_SIZE {
+            return Err(SnpReportError::InvalidLength {
+                expected: SNP_REPORT_SIZE,
+                actual: bytes.len(),
+            });
+        }
+
+        let mut r = ReportReader { buf: bytes, pos: 0 };
+        let version = r.u32();
+        if version < MIN_SUPPORTED_VERSION {
+            return Err(SnpReportError::UnsupportedVersion(version));
+        }
+
+        let report = SnpAttestationReport {
+            version,
+            guest_svn: r.u32(),
+            policy: GuestPolicy(r.u64()),
+            family_id: r.array(),
+            image_id: r.array(),
+            vmpl: r.u32(),
+            signature_algo: r.u32(),
+            current_tcb: TcbVersion::from_u64(r.u64()),
+            platform_info: PlatformInfo(r.u64()),
+            key_flags: r.u32(),
+            reserved0: r.u32(),
+            report_data: r.array(),
+            measurement: r.array(),
+            host_data: r.array(),
+            id_key_digest: r.array(),
+            author_key_digest: r.array(),
+            report_id: r.array(),
+            report_id_ma: r.array(),
+            reported_tcb: TcbVersion::from_u64(r.u64()),
+            reserved1: r.array(),
+            chip_id: r.array(),
+            committed_tcb: TcbVersion::from_u64(r.u64()),
+            current_build: r.u8(),
+            current_minor: r.u8(),
+            current_major: r.u8(),
+            reserved2: r.u8(),
+            committed_build: r.u8(),
+            committed_minor: r.u8(),
+            committed_major: r.u8(),
+            reserved3: r.u8(),
+            launch_tcb: TcbVersion::from_u64(r.u64()),
+            reserved4: r.array(),
+            signature: SnpSignature {
+                r: r.array(),
+                s: r.array(),
+                reserved: r.array(),
+            },
+        };
+        debug_assert_eq!(r.pos, SNP_REPORT_SIZE);
+
+        Ok(report)
+    }
+
+    /// Re-encodes the report into its exact on-wire representation.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(SNP_REPORT_SIZE);
+        out.extend_from_slice(&self.version.to_le_bytes());
+        out.extend_from_slice(&self.guest_svn.to_le_bytes());
+        out.extend_from_slice(&self.policy.0.to_le_bytes());
+        out.extend_from_slice(&self.family_id);
+        out.extend_from_slice(&self.image_id);
+        out.extend_from_slice(&self.vmpl.to_le_bytes());
+        out.extend_from_slice(&self.signature_algo.to_le_bytes());
+        out.extend_from_slice(&self.current_tcb.to_u64().to_le_bytes());
+        out.extend_from_slice(&self.platform_info.0.to_le_bytes());
+        out.extend_from_slice(&self.key_flags.to_le_bytes());
+        out.extend_from_slice(&self.reserved0.to_le_bytes());
+        out.extend_from_slice(&self.report_data);
+        out.extend_from_slice(&self.measurement);
+        out.extend_from_slice(&self.host_data);
+        out.extend_from_slice(&self.id_key_digest);
+        out.extend_from_slice(&self.author_key_digest);
+        out.extend_from_slice(&self.report_id);
+        out.extend_from_slice(&self.report_id_ma);
+        out.extend_from_slice(&self.reported_tcb.to_u64().to_le_bytes());
+        out.extend_from_slice(&self.reserved1);
+        out.extend_from_slice(&self.chip_id);
+        out.extend_from_slice(&self.committed_tcb.to_u64().to_le_bytes());
+        out.extend_from_slice(&[
+            self.current_build,
+            self.current_minor,
+            self.current_major,
+            self.reserved2,
+            self.committed_build,
+            self.committed_minor,
+            self.committed_major,
+            self.reserved3,
+        ]);
+        out.extend_from_slice(&self.launch_tcb.to_u64().to_le_bytes());
+        out.extend_from_slice(&self.reserved4);
+        out.extend_from_slice(&self.signature.r);
+        out.extend_from_slice(&self.signature.s);
+        out.extend_from_slice(&self.signature.reserved);
+        out
+    }
+
+    /// The portion of the report covered by the VCEK/VLEK signature.
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.to_bytes();
+        bytes.truncate(SNP_SIGNED_LENGTH);
+        bytes
+    }
+}
+
+// --- Serde (hex-encoded raw report) ---
+
+impl Serialize for SnpAttestationReport {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(self.to_bytes()))
+    }
+}
+
+impl<'de> Deserialize<'de> for SnpAttestationReport {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let bytes = hex::decode(encoded).map_err(de::Error::custom)?;
+        SnpAttestationReport::from_bytes(&bytes).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A version 3 report laid out like `SNP_GET_REPORT` output on a Milan
+    /// host (TCB bl=3 tee=0 snp=8 ucode=115, firmware 1.55.21, VCEK-signed).
+    /// The digests, IDs and signature are synthetic.
+    const FIXTURE: &[u8] = include_bytes!("testdata/snp_report_v3.bin");
+
+    #[test]
+    fn parses_fixture() {
+        let report = SnpAttestationReport::from_bytes(FIXTURE).unwrap();
+        assert_eq!(report.version, 3);
+        assert_eq!(report.guest_svn, 1);
+        assert_eq!(report.policy, GuestPolicy(0x30000));
+        assert!(report.policy.smt_allowed());
+        assert!(!report.policy.debug_allowed());
+        assert!(!report.policy.migrate_ma_allowed());
+        assert_eq!(report.vmpl, 0);
+        assert_eq!(report.signature_algo, SNP_SIG_ALGO_ECDSA_P384_SHA384);
+        assert!(report.platform_info.smt_enabled());
+        assert!(report.platform_info.tsme_enabled());
+        assert_eq!(report.key_flags, 0);
+
+        let tcb = TcbVersion { boot_loader: 3, tee: 0, reserved: [0; 4], snp: 8, microcode: 115 };
+        assert_eq!(report.current_tcb, tcb);
+        assert_eq!(report.reported_tcb, tcb);
+        assert_eq!(report.committed_tcb, tcb);
+        assert_eq!(report.launch_tcb, tcb);
+        assert_eq!(
+            (report.current_major, report.current_minor, report.current_build),
+            (1, 55, 21)
+        );
+
+        assert_eq!(
+            hex::encode(report.measurement),
+            "9e36ef263bb7bfab5bbae8de1f9daeb072a25469b04c342d46d5875732e7a2b2\
+             a7ba39f0d9682d832f1a9b5d7a6468b2"
+        );
+        assert_eq!(hex::encode(&report.report_data[..8]), "9e8b8f432c452dc8");
+        assert_eq!(hex::encode(&report.chip_id[..8]), "8b518ff18e64592b");
+        assert_eq!(report.report_id_ma, [0xff; 32]);
+        assert_eq!(report.reserved1[..3], [0x19, 0x01, 0x01]);
+    }
+
+    #[test]
+    fn fields_come_from_their_abi_offsets() {
+        let report = SnpAttestationReport::from_bytes(FIXTURE).unwrap();
+        assert_eq!(report.report_data[..], FIXTURE[0x50..0x90]);
+        assert_eq!(report.measurement[..], FIXTURE[0x90..0xC0]);
+        assert_eq!(report.chip_id[..], FIXTURE[0x1A0..0x1E0]);
+        assert_eq!(report.signature.r[..], FIXTURE[0x2A0..0x2E8]);
+        assert_eq!(report.signature.s[..], FIXTURE[0x2E8..0x330]);
+    }
+
+    #[test]
+    fn round_trips_byte_for_byte() {
+        let report = SnpAttestationReport::from_bytes(FIXTURE).unwrap();
+        assert_eq!(report.to_bytes(), FIXTURE);
+        assert_eq!(report.signed_bytes(), &FIXTURE[..SNP_SIGNED_LENGTH]);
+
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(serde_json::from_str::<SnpAttestationReport>(&json).unwrap(), report);
+    }
+
+    #[test]
+    fn modified_fields_are_encoded_in_place() {
+        let mut report = SnpAttestationReport::from_bytes(FIXTURE).unwrap();
+        report.report_data = [0xAB; 64];
+        report.current_tcb.snp = 9;
+        let bytes = report.to_bytes();
+        assert_eq!(bytes[0x50..0x90], [0xAB; 64]);
+        assert_eq!(bytes[0x38 + 6], 9);
+        assert_eq!(SnpAttestationReport::from_bytes(&bytes).unwrap(), report);
+    }
+
+    #[test]
+    fn signature_scalars_are_big_endian() {
+        let report = SnpAttestationReport::from_bytes(FIXTURE).unwrap();
+        let (r, s) = report.signature.to_be_scalars();
+        assert_eq!(r[0], report.signature.r[47]);
+        assert_eq!(r[47], report.signature.r[0]);
+        assert_eq!(s[0], report.signature.s[47]);
+        assert!(report.signature.r[48..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn rejects_truncated_and_oversized_input() {
+        for len in [0, 4, SNP_SIGNED_LENGTH, SNP_REPORT_SIZE - 1] {
+            match SnpAttestationReport::from_bytes(&FIXTURE[..len]) {
+                Err(SnpReportError::InvalidLength { expected, actual }) => {
+                    assert_eq!((expected, actual), (SNP_REPORT_SIZE, len));
+                }
+                other => panic!("{} bytes: unexpected {:?}", len, other),
+            }
+        }
+        let mut long = FIXTURE.to_vec();
+        long.push(0);
+        assert!(matches!(
+            SnpAttestationReport::from_bytes(&long),
+            Err(SnpReportError::InvalidLength { actual, .. }) if actual == SNP_REPORT_SIZE + 1
+        ));
+    }
+
+    #[test]
+    fn rejects_old_versions() {
+        let mut old = FIXTURE.to_vec();
+        old[..4].copy_from_slice(&1u32.to_le_bytes());
+        assert!(matches!(
+            SnpAttestationReport::from_bytes(&old),
+            Err(SnpReportError::UnsupportedVersion(1))
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_hex_in_serde() {
+        let truncated = format!("\"{}\"", hex::encode(&FIXTURE[..SNP_REPORT_SIZE - 1]));
+        assert!(serde_json::from_str::<SnpAttestationReport>(&truncated).is_err());
+        assert!(serde_json::from_str::<SnpAttestationReport>("\"zz\"").is_err());
+    }
+}
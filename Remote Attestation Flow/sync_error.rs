@@ -1,6 +1,6 @@
 use std::fmt;
 use std::error::Error;
-use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::sync::PoisonError;
 
 // --- Custom Synchronization Error Definition ---
 
@@ -40,13 +40,10 @@ impl Error for SyncError {
 }
 
 // 3. Conversion from a standard PoisonError
-// This allows the use of the `?` operator to easily convert a Mutex PoisonError
-// into your custom SyncError.
-impl<T> From<PoisonError<MutexGuard<'static, T>>> for SyncError
-where
-    T: fmt::Debug + 'static, // Needs to satisfy trait bounds for PoisonError
-{
-    fn from(_: PoisonError<MutexGuard<'static, T>>) -> Self {
+// This allows the use of the `?` operator to easily convert a poisoned Mutex
+// or RwLock guard into your custom SyncError.
+impl<T> From<PoisonError<T>> for SyncError {
+    fn from(_: PoisonError<T>) -> Self {
         SyncError::LockPoisoned
     }
 }
@@ -0,0 +1,94 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::attestation_data::{AttestationChallenge, AttestationReport};
+use crate::attester::attester::{Attester, AttesterError};
+use crate::tdx_quote::TdxQuote;
+
+// --- TDX Quote Generation ---
+//
+// `/dev/tdx_guest` only returns a locally-MACed TDREPORT, which is not
+// remotely verifiable. Quotes are obtained through the kernel's configfs-tsm
+// interface, which the `tdx_guest` driver backs by forwarding the TDREPORT to
+// the host Quote Generation Service.
+
+/// Device node exposed by the `tdx_guest` driver inside a TD.
+pub const TDX_GUEST_DEVICE: &str = "/dev/tdx_guest";
+
+/// Root of the configfs-tsm report interface.
+pub const TSM_REPORT_DIR: &str = "/sys/kernel/config/tsm/report";
+
+/// Provider name reported by configfs-tsm when backed by TDX.
+const TSM_PROVIDER_TDX: &str = "tdx_guest";
+
+static REPORT_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Collects TDX Quote v4 evidence via configfs-tsm.
+pub struct TdxAttester {
+    tsm_dir: PathBuf,
+}
+
+impl TdxAttester {
+    pub fn open() -> Result<Self, AttesterError> {
+        if !Path::new(TDX_GUEST_DEVICE).exists() {
+            return Err(AttesterError::DeviceUnavailable(TDX_GUEST_DEVICE.to_string()));
+        }
+        if !Path::new(TSM_REPORT_DIR).is_dir() {
+            return Err(AttesterError::DeviceUnavailable(TSM_REPORT_DIR.to_string()));
+        }
+        Ok(TdxAttester { tsm_dir: PathBuf::from(TSM_REPORT_DIR) })
+    }
+
+    /// Creates a one-shot report entry, writes REPORTDATA and reads the quote.
+    fn get_quote(&self, report_data: &[u8; 64]) -> Result<Vec<u8>, AttesterError> {
+        let entry = self.tsm_dir.join(format!(
+            "attester-{}-{}",
+            process::id(),
+            REPORT_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir(&entry)?;
+
+        let result = (|| {
+            fs::write(entry.join("inblob"), report_data)?;
+            let quote = fs::read(entry.join("outblob"))?;
+            let provider = fs::read_to_string(entry.join("provider"))?;
+            if provider.trim() != TSM_PROVIDER_TDX {
+                return Err(AttesterError::MalformedResponse(format!(
+                    "configfs-tsm provider is {}, expected {}",
+                    provider.trim(),
+                    TSM_PROVIDER_TDX
+                )));
+            }
+            Ok(quote)
+        })();
+
+        // configfs entries are removed with rmdir regardless of the outcome.
+        let _ = fs::remove_dir(&entry);
+        result
+    }
+}
+
+impl Attester for TdxAttester {
+    fn name(&self) -> &'static str {
+        "tdx"
+    }
+
+    fn request_evidence(
+        &self,
+        challenge: &AttestationChallenge,
+        report_data: [u8; 64],
+    ) -> Result<AttestationReport, AttesterError> {
+        println!("\n[Attester:tdx] Received Challenge: {}", challenge.nonce);
+
+        let raw_quote = self.get_quote(&report_data)?;
+        let quote = TdxQuote::from_bytes(&raw_quote)
+            .map_err(|e| AttesterError::MalformedResponse(e.to_string()))?;
+        let report = AttestationReport::from_tdx(&quote)
+            .map_err(|e| AttesterError::MalformedResponse(e.to_string()))?;
+
+        println!("[Attester:tdx] Generated Quote with MRTD: {:?}", &quote.body.mr_td[..8]);
+        Ok(report)
+    }
+}
@@ -0,0 +1,479 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::error::Error;
+use std::fmt;
+
+// --- Intel TDX Quote v4 Layout ---
+//
+// Follows the Intel TDX DCAP Quote Generation Library and Quote Verification
+// Library reference ("Quote Format", version 4). All integers are little-endian.
+
+/// Quote format version emitted by the TDX Quoting Enclave.
+pub const TDX_QUOTE_VERSION_4: u16 = 4;
+
+/// `tee_type` value identifying a TDX (as opposed to SGX) quote.
+pub const TEE_TYPE_TDX: u32 = 0x81;
+
+/// Attestation key type for ECDSA-256-with-P-256.
+pub const ATT_KEY_TYPE_ECDSA_P256: u16 = 2;
+
+/// Certification data type carrying a PCK certificate chain in PEM.
+pub const CERT_TYPE_PCK_CHAIN: u16 = 5;
+
+/// Certification data type carrying the QE report, its signature and a nested
+/// certification data block.
+pub const CERT_TYPE_QE_REPORT: u16 = 6;
+
+const HEADER_SIZE: usize = 48;
+const TD_REPORT_BODY_SIZE: usize = 584;
+const QE_REPORT_SIZE: usize = 384;
+
+/// Errors produced while decoding a TDX quote.
+#[derive(Debug)]
+pub enum TdxQuoteError {
+    /// The buffer ended before a field could be read.
+    Truncated { offset: usize, needed: usize },
+    /// The quote header declares a version other than 4.
+    UnsupportedVersion(u16),
+    /// The quote header declares a non-TDX TEE.
+    UnsupportedTeeType(u32),
+    /// The certification data is not of the type the caller asked for.
+    UnexpectedCertType { expected: u16, actual: u16 },
+    /// Bytes remained after the signature data was consumed.
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for TdxQuoteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TdxQuoteError::Truncated { offset, needed } => write!(
+                f,
+                "TDX quote truncated: needed {} more bytes at offset {}",
+                needed, offset
+            ),
+            TdxQuoteError::UnsupportedVersion(v) => write!(f, "Unsupported TDX quote version: {}", v),
+            TdxQuoteError::UnsupportedTeeType(t) => write!(f, "Unsupported TEE type: {:#x}", t),
+            TdxQuoteError::UnexpectedCertType { expected, actual } => write!(
+                f,
+                "Unexpected certification data type: expected {}, got {}",
+                expected, actual
+            ),
+            TdxQuoteError::TrailingBytes(n) => write!(f, "{} trailing bytes after TDX quote", n),
+        }
+    }
+}
+
+impl Error for TdxQuoteError {}
+
+/// A bounds-checked little-endian cursor.
+struct QuoteReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> QuoteReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], TdxQuoteError> {
+        if self.buf.len() - self.pos < n {
+            return Err(TdxQuoteError::Truncated {
+                offset: self.pos,
+                needed: n - (self.buf.len() - self.pos),
+            });
+        }
+        let slice = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], TdxQuoteError> {
+        let mut out = [0u8; N];
+        out.copy_from_slice(self.take(N)?);
+        Ok(out)
+    }
+
+    fn u16(&mut self) -> Result<u16, TdxQuoteError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, TdxQuoteError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+}
+
+// --- Quote Components ---
+
+/// The 48-byte quote header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuoteHeader {
+    pub version: u16,
+    pub att_key_type: u16,
+    pub tee_type: u32,
+    pub reserved: [u8; 4],
+    pub qe_vendor_id: [u8; 16],
+    pub user_data: [u8; 20],
+}
+
+/// The TD report body (TDREPORT's TDINFO and TEE_TCB_INFO subset) that the
+/// Quoting Enclave signs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TdReportBody {
+    pub tee_tcb_svn: [u8; 16],
+    pub mr_seam: [u8; 48],
+    pub mr_signer_seam: [u8; 48],
+    pub seam_attributes: [u8; 8],
+    pub td_attributes: [u8; 8],
+    pub xfam: [u8; 8],
+    /// Measurement of the initial TD contents (the TDX analogue of the SNP
+    /// launch digest).
+    pub mr_td: [u8; 48],
+    pub mr_config_id: [u8; 48],
+    pub mr_owner: [u8; 48],
+    pub mr_owner_config: [u8; 48],
+    pub rtmr: [[u8; 48]; 4],
+    pub report_data: [u8; 64],
+}
+
+impl TdReportBody {
+    /// TD_ATTRIBUTES bit 0: the TD is running in debug mode.
+    pub fn debug_enabled(&self) -> bool {
+        self.td_attributes[0] & 1 != 0
+    }
+}
+
+/// A typed certification data block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificationData {
+    pub cert_type: u16,
+    pub data: Vec<u8>,
+}
+
+/// The contents of a type-6 certification data block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QeReportCertificationData {
+    pub qe_report: [u8; QE_REPORT_SIZE],
+    pub qe_report_signature: [u8; 64],
+    pub qe_auth_data: Vec<u8>,
+    pub pck_cert_data: CertificationData,
+}
+
+/// ECDSA signature data appended to the quote body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuoteSignatureData {
+    /// ECDSA P-256 signature (r || s) over the header and TD report body.
+    pub quote_signature: [u8; 64],
+    /// Raw (x || y) ECDSA attestation public key of the Quoting Enclave.
+    pub attestation_key: [u8; 64],
+    pub certification_data: CertificationData,
+}
+
+/// A fully decoded TDX Quote v4.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TdxQuote {
+    pub header: QuoteHeader,
+    pub body: TdReportBody,
+    pub signature_data: QuoteSignatureData,
+}
+
+impl CertificationData {
+    fn read(r: &mut QuoteReader) -> Result<Self, TdxQuoteError> {
+        let cert_type = r.u16()?;
+        let size = r.u32()? as usize;
+        Ok(CertificationData {
+            cert_type,
+            data: r.take(size)?.to_vec(),
+        })
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.cert_type.to_le_bytes());
+        out.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.data);
+    }
+
+    /// Decodes a type-6 block into the QE report and nested PCK data.
+    pub fn qe_report_data(&self) -> Result<QeReportCertificationData, TdxQuoteError> {
+        if self.cert_type != CERT_TYPE_QE_REPORT {
+            return Err(TdxQuoteError::UnexpectedCertType {
+                expected: CERT_TYPE_QE_REPORT,
+                actual: self.cert_type,
+            });
+        }
+
+        let mut r = QuoteReader { buf: &self.data, pos: 0 };
+        let qe_report = r.array()?;
+        let qe_report_signature = r.array()?;
+        let auth_len = r.u16()? as usize;
+        let qe_auth_data = r.take(auth_len)?.to_vec();
+        let pck_cert_data = CertificationData::read(&mut r)?;
+
+        Ok(QeReportCertificationData {
+            qe_report,
+            qe_report_signature,
+            qe_auth_data,
+            pck_cert_data,
+        })
+    }
+
+    /// Returns the PEM PCK certificate chain, unwrapping a QE report block
+    /// if necessary.
+    pub fn pck_cert_chain(&self) -> Result<Vec<u8>, TdxQuoteError> {
+        let pck = match self.cert_type {
+            CERT_TYPE_QE_REPORT => self.qe_report_data()?.pck_cert_data,
+            _ => self.clone(),
+        };
+        if pck.cert_type != CERT_TYPE_PCK_CHAIN {
+            return Err(TdxQuoteError::UnexpectedCertType {
+                expected: CERT_TYPE_PCK_CHAIN,
+                actual: pck.cert_type,
+            });
+        }
+        Ok(pck.data)
+    }
+}
+
+impl TdxQuote {
+    /// Decodes a raw quote as returned by the TDX Quoting Enclave.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TdxQuoteError> {
+        let mut r = QuoteReader { buf: bytes, pos: 0 };
+
+        // --- Header ---
+        let header = QuoteHeader {
+            version: r.u16()?,
+            att_key_type: r.u16()?,
+            tee_type: r.u32()?,
+            reserved: r.array()?,
+            qe_vendor_id: r.array()?,
+            user_data: r.array()?,
+        };
+        if header.version != TDX_QUOTE_VERSION_4 {
+            return Err(TdxQuoteError::UnsupportedVersion(header.version));
+        }
+        if header.tee_type != TEE_TYPE_TDX {
+            return Err(TdxQuoteError::UnsupportedTeeType(header.tee_type));
+        }
+
+        // --- TD Report Body ---
+        let body = TdReportBody {
+            tee_tcb_svn: r.array()?,
+            mr_seam: r.array()?,
+            mr_signer_seam: r.array()?,
+            seam_attributes: r.array()?,
+            td_attributes: r.array()?,
+            xfam: r.array()?,
+            mr_td: r.array()?,
+            mr_config_id: r.array()?,
+            mr_owner: r.array()?,
+            mr_owner_config: r.array()?,
+            rtmr: [r.array()?, r.array()?, r.array()?, r.array()?],
+            report_data: r.array()?,
+        };
+
+        // --- Signature Data ---
+        let sig_len = r.u32()? as usize;
+        let mut s = QuoteReader { buf: r.take(sig_len)?, pos: 0 };
+        let signature_data = QuoteSignatureData {
+            quote_signature: s.array()?,
+            attestation_key: s.array()?,
+            certification_data: CertificationData::read(&mut s)?,
+        };
+
+        if r.remaining() != 0 {
+            return Err(TdxQuoteError::TrailingBytes(r.remaining()));
+        }
+
+        Ok(TdxQuote { header, body, signature_data })
+    }
+
+    /// Re-encodes the quote into its exact on-wire representation.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = self.signed_bytes();
+
+        let mut sig = Vec::new();
+        sig.extend_from_slice(&self.signature_data.quote_signature);
+        sig.extend_from_slice(&self.signature_data.attestation_key);
+        self.signature_data.certification_data.write(&mut sig);
+
+        out.extend_from_slice(&(sig.len() as u32).to_le_bytes());
+        out.extend_from_slice(&sig);
+        out
+    }
+
+    /// The header and TD report body: the bytes covered by `quote_signature`.
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        let h = &self.header;
+        let b = &self.body;
+        let mut out = Vec::with_capacity(HEADER_SIZE + TD_REPORT_BODY_SIZE);
+
+        out.extend_from_slice(&h.version.to_le_bytes());
+        out.extend_from_slice(&h.att_key_type.to_le_bytes());
+        out.extend_from_slice(&h.tee_type.to_le_bytes());
+        out.extend_from_slice(&h.reserved);
+        out.extend_from_slice(&h.qe_vendor_id);
+        out.extend_from_slice(&h.user_data);
+
+        out.extend_from_slice(&b.tee_tcb_svn);
+        out.extend_from_slice(&b.mr_seam);
+        out.extend_from_slice(&b.mr_signer_seam);
+        out.extend_from_slice(&b.seam_attributes);
+        out.extend_from_slice(&b.td_attributes);
+        out.extend_from_slice(&b.xfam);
+        out.extend_from_slice(&b.mr_td);
+        out.extend_from_slice(&b.mr_config_id);
+        out.extend_from_slice(&b.mr_owner);
+        out.extend_from_slice(&b.mr_owner_config);
+        for rtmr in &b.rtmr {
+            out.extend_from_slice(rtmr);
+        }
+        out.extend_from_slice(&b.report_data);
+        out
+    }
+}
+
+// --- Serde (hex-encoded raw quote) ---
+
+impl Serialize for TdxQuote {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(self.to_bytes()))
+    }
+}
+
+impl<'de> Deserialize<'de> for TdxQuote {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let bytes = hex::decode(encoded).map_err(de::Error::custom)?;
+        TdxQuote::from_bytes(&bytes).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A Quote v4 laid out like DCAP QE output: ECDSA-P256 key, type-6
+    /// certification data wrapping the QE report and a type-5 PEM chain.
+    /// Measurements, keys, signatures and certificates are synthetic.
+    const FIXTURE: &[u8] = include_bytes!("testdata/tdx_quote_v4.bin");
+    /// Where the signature data length and the certification data type sit.
+    const SIG_LEN_OFFSET: usize = HEADER_SIZE + TD_REPORT_BODY_SIZE;
+    const CERT_TYPE_OFFSET: usize = SIG_LEN_OFFSET + 4 + 128;
+
+    #[test]
+    fn parses_fixture() {
+        let quote = TdxQuote::from_bytes(FIXTURE).unwrap();
+        assert_eq!(quote.header.version, TDX_QUOTE_VERSION_4);
+        assert_eq!(quote.header.att_key_type, ATT_KEY_TYPE_ECDSA_P256);
+        assert_eq!(quote.header.tee_type, TEE_TYPE_TDX);
+        assert_eq!(hex::encode(quote.header.qe_vendor_id), "939a7233f79c4ca9940a0db3957f0607");
+
+        assert_eq!(quote.body.tee_tcb_svn[..3], [3, 0, 5]);
+        assert!(!quote.body.debug_enabled());
+        assert_eq!(quote.body.xfam, [0xe7, 0x02, 0x06, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            hex::encode(quote.body.mr_td),
+            "15882bd10aeaaec812f4335214715e1bf3793611520e35bc432a2f2a47913219\
+             287d1818be80caf9822abb6fa24ab002"
+        );
+        assert_eq!(quote.body.mr_td[..], FIXTURE[184..232]);
+        assert_eq!(quote.body.report_data[..], FIXTURE[568..632]);
+        assert_eq!(hex::encode(&quote.body.report_data[..8]), "fd1d3bd7647ca6b5");
+        assert_eq!(quote.signature_data.attestation_key[..], FIXTURE[700..764]);
+    }
+
+    #[test]
+    fn unwraps_certification_data() {
+        let quote = TdxQuote::from_bytes(FIXTURE).unwrap();
+        let cert_data = &quote.signature_data.certification_data;
+        assert_eq!(cert_data.cert_type, CERT_TYPE_QE_REPORT);
+
+        let qe = cert_data.qe_report_data().unwrap();
+        assert_eq!(qe.qe_report[..], FIXTURE[770..770 + QE_REPORT_SIZE]);
+        assert_eq!(qe.qe_auth_data, (0..32).collect::<Vec<u8>>());
+        assert_eq!(qe.pck_cert_data.cert_type, CERT_TYPE_PCK_CHAIN);
+
+        let chain = cert_data.pck_cert_chain().unwrap();
+        assert_eq!(chain, qe.pck_cert_data.data);
+        let pem = String::from_utf8_lossy(&chain);
+        assert!(pem.starts_with("-----BEGIN CERTIFICATE-----"));
+        assert_eq!(pem.matches("-----BEGIN CERTIFICATE-----").count(), 3);
+
+        // A bare type-5 block is already the chain, and has no QE report.
+        let bare = qe.pck_cert_data.clone();
+        assert_eq!(bare.pck_cert_chain().unwrap(), chain);
+        assert!(matches!(
+            bare.qe_report_data(),
+            Err(TdxQuoteError::UnexpectedCertType { expected: CERT_TYPE_QE_REPORT, actual: CERT_TYPE_PCK_CHAIN })
+        ));
+        let other = CertificationData { cert_type: 1, data: vec![0; 8] };
+        assert!(matches!(
+            other.pck_cert_chain(),
+            Err(TdxQuoteError::UnexpectedCertType { expected: CERT_TYPE_PCK_CHAIN, actual: 1 })
+        ));
+    }
+
+    #[test]
+    fn round_trips_byte_for_byte() {
+        let quote = TdxQuote::from_bytes(FIXTURE).unwrap();
+        assert_eq!(quote.to_bytes(), FIXTURE);
+        assert_eq!(quote.signed_bytes(), &FIXTURE[..SIG_LEN_OFFSET]);
+
+        let json = serde_json::to_string(&quote).unwrap();
+        assert_eq!(serde_json::from_str::<TdxQuote>(&json).unwrap(), quote);
+
+        let mut modified = quote.clone();
+        modified.body.report_data = [0x5A; 64];
+        modified.signature_data.certification_data.data.truncate(500);
+        assert_eq!(TdxQuote::from_bytes(&modified.to_bytes()).unwrap(), modified);
+    }
+
+    #[test]
+    fn every_truncation_is_reported() {
+        for len in 0..FIXTURE.len() {
+            match TdxQuote::from_bytes(&FIXTURE[..len]) {
+                Err(TdxQuoteError::Truncated { offset, needed }) => {
+                    assert!(offset <= len && needed > 0, "{} bytes: offset {} needed {}", len, offset, needed);
+                }
+                other => panic!("{} bytes: unexpected {:?}", len, other),
+            }
+        }
+        assert!(matches!(
+            TdxQuote::from_bytes(&FIXTURE[..SIG_LEN_OFFSET]),
+            Err(TdxQuoteError::Truncated { offset: SIG_LEN_OFFSET, needed: 4 })
+        ));
+    }
+
+    #[test]
+    fn length_fields_stay_in_bounds() {
+        // Certification data claiming more than the signature data holds.
+        let mut oversized = FIXTURE.to_vec();
+        oversized[CERT_TYPE_OFFSET + 2..CERT_TYPE_OFFSET + 6].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(TdxQuote::from_bytes(&oversized), Err(TdxQuoteError::Truncated { .. })));
+
+        // A signature length larger than the quote.
+        let mut long_sig = FIXTURE.to_vec();
+        long_sig[SIG_LEN_OFFSET..SIG_LEN_OFFSET + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(TdxQuote::from_bytes(&long_sig), Err(TdxQuoteError::Truncated { .. })));
+
+        // A QE auth data length running past the type-6 block.
+        let quote = TdxQuote::from_bytes(FIXTURE).unwrap();
+        let mut cert_data = quote.signature_data.certification_data.clone();
+        cert_data.data[QE_REPORT_SIZE + 64..QE_REPORT_SIZE + 66].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(matches!(cert_data.qe_report_data(), Err(TdxQuoteError::Truncated { .. })));
+    }
+
+    #[test]
+    fn rejects_trailing_bytes_and_foreign_headers() {
+        let mut trailing = FIXTURE.to_vec();
+        trailing.extend_from_slice(&[0, 0]);
+        assert!(matches!(TdxQuote::from_bytes(&trailing), Err(TdxQuoteError::TrailingBytes(2))));
+
+        let mut v3 = FIXTURE.to_vec();
+        v3[..2].copy_from_slice(&3u16.to_le_bytes());
+        assert!(matches!(TdxQuote::from_bytes(&v3), Err(TdxQuoteError::UnsupportedVersion(3))));
+
+        let mut sgx = FIXTURE.to_vec();
+        sgx[4..8].copy_from_slice(&0u32.to_le_bytes());
+        assert!(matches!(TdxQuote::from_bytes(&sgx), Err(TdxQuoteError::UnsupportedTeeType(0))));
+    }
+}
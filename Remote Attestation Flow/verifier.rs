@@ -1,59 +1,498 @@
 // This is the remote service running outside the CVM.
 pub mod verifier {
-    use super::*;
+    use crate::attestation_data::*;
+    use crate::cert_chain::{self, CertChainError, TrustAnchors};
+    use crate::nonce_store::{InMemoryNonceStore, NonceError, NonceStatus, NonceStore};
+    use crate::policy::Policy;
+    use crate::snp_report::SNP_SIG_ALGO_ECDSA_P384_SHA384;
+    use crate::tdx_quote::{ATT_KEY_TYPE_ECDSA_P256, TDX_QUOTE_VERSION_4, TEE_TYPE_TDX};
+    use crate::vtpm;
+    use rand_core::{OsRng, RngCore};
+    use sha2::{Digest, Sha256};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-    /// Defines the expected boot state hash for a trusted VM image.
-    const EXPECTED_MEASUREMENT_HASH: &str = "733dd8952b1b7027b4b12185a53907c03af5183424040954b071e67e335b3760"; // Mocked hash
+    /// How long an issued nonce may be redeemed for, unless overridden.
+    pub const DEFAULT_NONCE_TTL: Duration = Duration::from_secs(300);
+
+    // --- Challenge Issuance ---
+
+    /// Issues random challenge nonces and enforces their single use.
+    ///
+    /// Every nonce is recorded in a `NonceStore` with its issue time. A nonce
+    /// can be redeemed exactly once, and only within `ttl` of being issued.
+    pub struct ChallengeIssuer {
+        store: Box<dyn NonceStore>,
+        ttl: Duration,
+    }
+
+    impl ChallengeIssuer {
+        pub fn new(store: Box<dyn NonceStore>, ttl: Duration) -> Self {
+            ChallengeIssuer { store, ttl }
+        }
+
+        /// An issuer with a process-local store and the default TTL.
+        pub fn in_memory() -> Self {
+            ChallengeIssuer::new(Box::new(InMemoryNonceStore::new()), DEFAULT_NONCE_TTL)
+        }
+
+        pub fn ttl(&self) -> Duration {
+            self.ttl
+        }
+
+        /// Generates a 256-bit random nonce and records its issue time.
+        pub fn issue(&self) -> Result<AttestationChallenge, NonceError> {
+            let mut bytes = [0u8; 32];
+            OsRng.fill_bytes(&mut bytes);
+            let nonce = hex::encode(bytes);
+            self.store.issue(&nonce, unix_now())?;
+            println!("[Verifier] Issued challenge nonce {}...", &nonce[..16]);
+            Ok(AttestationChallenge { nonce })
+        }
+
+        /// Marks the challenge's nonce used, failing if it is unknown,
+        /// already used, or older than the TTL.
+        pub fn redeem(&self, challenge: &AttestationChallenge) -> Result<(), NonceError> {
+            match self.store.consume(&challenge.nonce)? {
+                NonceStatus::Unknown => Err(NonceError::Unknown),
+                NonceStatus::AlreadyConsumed => Err(NonceError::Replayed),
+                NonceStatus::Issued(issued_at) => {
+                    let age_secs = unix_now().saturating_sub(issued_at);
+                    if age_secs > self.ttl.as_secs() {
+                        Err(NonceError::Expired { age_secs })
+                    } else {
+                        Ok(())
+                    }
+                }
+            }
+        }
+
+        /// Drops store entries that can no longer be redeemed anyway.
+        pub fn purge_expired(&self) -> Result<usize, NonceError> {
+            self.store.purge(unix_now().saturating_sub(self.ttl.as_secs()))
+        }
+    }
+
+    fn unix_now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+
+    /// Redeems the challenge nonce with `issuer` and then verifies the report.
+    ///
+    /// Use this instead of `verify_report` whenever the challenge was issued by
+    /// a `ChallengeIssuer`: a nonce that expired or was already presented is
+    /// rejected before any other check runs.
+    pub fn verify_fresh_report(
+        issuer: &ChallengeIssuer,
+        challenge: &AttestationChallenge,
+        report: &AttestationReport,
+        anchors: &TrustAnchors,
+        policy: &Policy,
+    ) -> VerificationResult {
+        if let Err(e) = issuer.redeem(challenge) {
+            println!("\n[Verifier] Rejecting challenge: {}", e);
+            let code = match e {
+                NonceError::Expired { .. } => FailureCode::NonceExpired,
+                NonceError::Unknown | NonceError::Replayed => FailureCode::NonceReplayed,
+                NonceError::Store(_) => FailureCode::NonceStoreUnavailable,
+            };
+            return VerificationResult::new().reject_with(
+                CheckKind::Freshness,
+                code,
+                format!("Freshness check failed: {}", e),
+            );
+        }
+        verify_report(challenge, report, anchors, policy)
+    }
 
     /// The main function for verifying the attestation evidence.
     ///
-    /// In a real implementation, this would involve:
-    /// 1. Cryptographic validation of the signature using the cert chain (PKI).
-    /// 2. Policy lookup based on platform ID and TCB.
-    /// 3. Comparison of reported measurements against known trusted values.
+    /// This involves:
+    /// 1. Freshness: `report_data` must bind the challenge nonce, and the
+    ///    report's runtime data (ephemeral public key and claims) if present.
+    /// 2. Cryptographic validation of the signature using the cert chain (PKI),
+    ///    anchored in the pinned vendor roots in `anchors`.
+    /// 3. Evaluation of `policy`: trusted measurements, minimum TCB, guest
+    ///    policy bits and allowed platforms.
     pub fn verify_report(
         challenge: &AttestationChallenge,
         report: &AttestationReport,
+        anchors: &TrustAnchors,
+        policy: &Policy,
     ) -> VerificationResult {
         println!("\n[Verifier] Starting verification process...");
+        let mut result = VerificationResult::new();
 
-        // --- Step 1: Verify Freshness (Nonce Binding) ---
-        let mut nonce_hasher = Sha256::new();
-        nonce_hasher.update(challenge.nonce.as_bytes());
-        let expected_report_data: [u8; 32] = nonce_hasher.finalize().into();
+        // --- Step 1: Verify Freshness (Nonce and Runtime Data Binding) ---
+        match &report.runtime_data {
+            // All 64 bytes must be SHA-512(nonce || public_key || claims).
+            Some(runtime_data) => {
+                if report.report_data != runtime_data.report_data(&challenge.nonce) {
+                    return result.reject(
+                        CheckKind::Freshness,
+                        "Freshness check failed: Report data does not bind the challenge nonce and runtime data."
+                            .to_string(),
+                    );
+                }
+                result.record(
+                    CheckKind::Freshness,
+                    None,
+                    true,
+                    format!(
+                        "Report data binds the challenge nonce, a {}-byte public key and {} runtime claim(s).",
+                        runtime_data.public_key.len(),
+                        runtime_data.claims.len()
+                    ),
+                );
+            }
+            None => {
+                let mut nonce_hasher = Sha256::new();
+                nonce_hasher.update(challenge.nonce.as_bytes());
+                let expected_report_data: [u8; 32] = nonce_hasher.finalize().into();
 
-        if report.report_data[0..32] != expected_report_data {
-            return VerificationResult::Untrustworthy(
-                "Freshness check failed: Report data does not match challenge nonce hash."
-                    .to_string(),
-            );
+                if report.report_data[0..32] != expected_report_data {
+                    return result.reject(
+                        CheckKind::Freshness,
+                        "Freshness check failed: Report data does not match challenge nonce hash."
+                            .to_string(),
+                    );
+                }
+                result.record(CheckKind::Freshness, None, true, "Report data matches the challenge nonce hash.".to_string());
+            }
         }
         println!("[Verifier] Nonce check successful. Report is fresh.");
 
+        // --- Step 1b: Verify Platform Evidence (TEE-specific fields) ---
+        if let Err(e) = check_tee_evidence(report) {
+            return result.reject(CheckKind::Evidence, format!("Evidence check failed: {}", e));
+        }
+        result.record(CheckKind::Evidence, None, true, "Platform evidence is consistent with the report.".to_string());
+        println!("[Verifier] Platform evidence is consistent with the report.");
+
         // --- Step 2: Verify Signature (Hardware Authenticity) ---
-        // Mocked check: In a real flow, this is where a complex PKI check happens.
-        if report.signature != b"MOCKED_HARDWARE_SIGNATURE" {
-            return VerificationResult::Untrustworthy(
-                "Signature check failed: Could not verify hardware authenticity."
-                    .to_string(),
-            );
+        // The cert chain must lead to a pinned vendor root, and its leaf key
+        // must have signed the platform evidence.
+        let signature_check = match &report.evidence {
+            TeeEvidence::Snp(snp) => cert_chain::verify_snp_report(snp, &report.cert_chain, anchors),
+            TeeEvidence::Tdx(quote) => cert_chain::verify_tdx_quote(quote, anchors),
+            TeeEvidence::Vtpm(quote) => cert_chain::verify_tpm_quote(quote, &report.cert_chain, anchors),
+            TeeEvidence::Mock => Err(CertChainError::NoHardwareSignature),
+        };
+        if let Err(e) = signature_check {
+            return result.reject(CheckKind::Signature, format!("Signature check failed: {}", e));
         }
+        result.record(CheckKind::Signature, None, true, "Evidence signature chains to a pinned root.".to_string());
         println!("[Verifier] Signature check successful. Report is authentic.");
 
-        // --- Step 3: Verify Integrity (Measurement Policy) ---
-        let actual_hash_hex = hex::encode(report.measurement);
+        // The evidence is authentic from here on, so its claims can be reported.
+        result.claims = Some(Claims::from_report(report));
+
+        // --- Step 3: Verify Integrity (Measurement, TCB and Platform Policy) ---
+        for outcome in policy.evaluate(report) {
+            println!(
+                "[Policy] {} {}: {}",
+                if outcome.passed { "PASS" } else { "FAIL" },
+                outcome.rule,
+                outcome.detail
+            );
+            result.record(outcome.check_kind(), Some(&outcome.rule), outcome.passed, outcome.detail);
+        }
 
-        // Compare the reported boot state measurement against the trusted policy.
-        if actual_hash_hex == EXPECTED_MEASUREMENT_HASH {
-            VerificationResult::Trustworthy(
-                "Attestation successful! VM is running the expected image."
-                    .to_string(),
-            )
+        let failed: Vec<String> = result
+            .checks
+            .iter()
+            .filter(|c| !c.passed)
+            .map(|c| format!("{} ({})", c.rule.as_deref().unwrap_or("-"), c.detail))
+            .collect();
+        if failed.is_empty() {
+            result.trustworthy = true;
+            result.message = "Attestation successful! VM is running the expected image.".to_string();
         } else {
-            VerificationResult::Untrustworthy(format!(
-                "Integrity check failed. Actual measurement: {}, Expected: {}",
-                actual_hash_hex, EXPECTED_MEASUREMENT_HASH
-            ))
+            result.message = format!(
+                "Integrity check failed. Policy clauses not satisfied: {}",
+                failed.join(", ")
+            );
+        }
+        result
+    }
+
+    /// Checks that the generic report fields agree with the platform evidence
+    /// they were extracted from, and that the evidence itself is well-formed.
+    ///
+    /// This keeps the rest of `verify_report` TEE-agnostic: once this passes,
+    /// `measurement` and `report_data` can be trusted to reflect the hardware
+    /// structure regardless of which platform produced it.
+    fn check_tee_evidence(report: &AttestationReport) -> Result<(), String> {
+        match &report.evidence {
+            TeeEvidence::Mock => Ok(()),
+            TeeEvidence::Snp(snp) => {
+                if snp.signature_algo != SNP_SIG_ALGO_ECDSA_P384_SHA384 {
+                    return Err(format!(
+                        "unsupported SNP signature algorithm {}",
+                        snp.signature_algo
+                    ));
+                }
+                if report.measurement != snp.measurement {
+                    return Err("measurement does not match the SNP report".to_string());
+                }
+                if report.report_data != snp.report_data {
+                    return Err("report_data does not match the SNP report".to_string());
+                }
+                Ok(())
+            }
+            TeeEvidence::Tdx(quote) => {
+                if quote.header.version != TDX_QUOTE_VERSION_4 {
+                    return Err(format!("unsupported TDX quote version {}", quote.header.version));
+                }
+                if quote.header.tee_type != TEE_TYPE_TDX {
+                    return Err(format!("quote TEE type {:#x} is not TDX", quote.header.tee_type));
+                }
+                if quote.header.att_key_type != ATT_KEY_TYPE_ECDSA_P256 {
+                    return Err(format!(
+                        "unsupported attestation key type {}",
+                        quote.header.att_key_type
+                    ));
+                }
+                if report.measurement != quote.body.mr_td {
+                    return Err("measurement does not match the quote MRTD".to_string());
+                }
+                if report.report_data != quote.body.report_data {
+                    return Err("report_data does not match the quote REPORTDATA".to_string());
+                }
+                Ok(())
+            }
+            TeeEvidence::Vtpm(quote) => {
+                let info = quote.quote_info()?;
+                if report.measurement != info.pcr_digest {
+                    return Err("measurement does not match the quoted PCR digest".to_string());
+                }
+                if info.extra_data[..] != vtpm::qualifying_data(&report.report_data)[..] {
+                    return Err("report_data does not match the quote's qualifying data".to_string());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::cert_chain::tests::Pki;
+        use crate::error::{AttestationError, ErrorCode};
+        use crate::policy::ImagePolicy;
+        use crate::retry::RetryClass;
+        use crate::vtpm::tests::quote_attest;
+        use crate::vtpm::TpmQuote;
+
+        const NONCE: &str = "0f1e2d3c4b5a69788796a5b4c3d2e1f0";
+
+        fn runtime_data() -> RuntimeData {
+            RuntimeData::new(vec![0x04; 65]).with_claim("image_digest", "sha256:ab")
+        }
+
+        /// An SNP report binding `runtime_data` to `NONCE`, signed under a test
+        /// PKI, with the anchors and a policy that trust it.
+        fn bound_report(runtime_data: RuntimeData) -> (AttestationReport, TrustAnchors, Policy) {
+            let mut pki = Pki::standard();
+            pki.report.report_data = runtime_data.report_data(NONCE);
+            pki.sign_report();
+            let mut report = AttestationReport::from_snp(&pki.report, pki.chain_pem());
+            report.runtime_data = Some(runtime_data);
+            let policy = Policy {
+                images: vec![ImagePolicy {
+                    name: "fixture".to_string(),
+                    measurements: vec![hex::encode(&report.measurement)],
+                }],
+                ..Policy::default()
+            };
+            (report, pki.anchors(), policy)
+        }
+
+        fn challenge(nonce: &str) -> AttestationChallenge {
+            AttestationChallenge { nonce: nonce.to_string() }
+        }
+
+        fn assert_rejected(result: &VerificationResult, check: CheckKind, code: FailureCode) {
+            assert!(!result.trustworthy, "{}", result.message);
+            assert_eq!(result.failure, Some(code), "{}", result.message);
+            let failed: Vec<CheckKind> = result.checks.iter().filter(|c| !c.passed).map(|c| c.check).collect();
+            assert_eq!(failed.first(), Some(&check), "{:?}", result.checks);
+        }
+
+        #[test]
+        fn accepts_a_report_binding_the_nonce_and_runtime_data() {
+            let (report, anchors, policy) = bound_report(runtime_data());
+            let result = verify_report(&challenge(NONCE), &report, &anchors, &policy);
+            assert!(result.trustworthy, "{}", result.message);
+            assert_eq!(result.failure, None);
+            let claims = result.claims.unwrap();
+            assert_eq!(claims.runtime_data, Some(runtime_data()));
+        }
+
+        #[test]
+        fn rejects_a_report_binding_other_runtime_data() {
+            let (report, anchors, policy) = bound_report(runtime_data());
+
+            // The evidence is genuine, but the runtime data presented with it
+            // (or the nonce it is checked against) is not what it binds.
+            let other_key = RuntimeData { public_key: vec![0x05; 65], ..runtime_data() };
+            let other_claims = runtime_data().with_claim("image_digest", "sha256:cd");
+            for runtime_data in [other_key, other_claims, RuntimeData::new(vec![0x04; 65])] {
+                let report = AttestationReport { runtime_data: Some(runtime_data), ..clone_report(&report) };
+                let result = verify_report(&challenge(NONCE), &report, &anchors, &policy);
+                assert_rejected(&result, CheckKind::Freshness, FailureCode::NonceMismatch);
+                assert!(result.claims.is_none());
+            }
+
+            let result = verify_report(&challenge("another-nonce"), &report, &anchors, &policy);
+            assert_rejected(&result, CheckKind::Freshness, FailureCode::NonceMismatch);
+
+            // Without runtime data the first 32 bytes must be SHA-256(nonce).
+            let report = AttestationReport { runtime_data: None, ..clone_report(&report) };
+            let result = verify_report(&challenge(NONCE), &report, &anchors, &policy);
+            assert_rejected(&result, CheckKind::Freshness, FailureCode::NonceMismatch);
+        }
+
+        #[test]
+        fn each_failed_check_maps_to_its_failure_code() {
+            let (report, anchors, policy) = bound_report(runtime_data());
+            let challenge = challenge(NONCE);
+
+            let mut inconsistent = clone_report(&report);
+            inconsistent.measurement[0] ^= 1;
+            let result = verify_report(&challenge, &inconsistent, &anchors, &policy);
+            assert_rejected(&result, CheckKind::Evidence, FailureCode::EvidenceInconsistent);
+
+            let result = verify_report(&challenge, &report, &Pki::standard().anchors(), &policy);
+            assert_rejected(&result, CheckKind::Signature, FailureCode::SignatureInvalid);
+            assert!(result.claims.is_none());
+
+            let result = verify_report(&challenge, &report, &anchors, &Policy::default());
+            assert_rejected(&result, CheckKind::Measurement, FailureCode::MeasurementUntrusted);
+            assert!(result.claims.is_some());
+
+            let mut outdated = policy.clone();
+            outdated.tcb.min_snp = Some(9);
+            let result = verify_report(&challenge, &report, &anchors, &outdated);
+            assert_rejected(&result, CheckKind::Tcb, FailureCode::TcbOutdated);
+
+            let mut tdx_only = policy.clone();
+            tdx_only.platform.allowed_tees = vec!["tdx".to_string()];
+            let result = verify_report(&challenge, &report, &anchors, &tdx_only);
+            assert_rejected(&result, CheckKind::Policy, FailureCode::PolicyViolation);
+            assert!(result.message.contains("platform.allowed_tees"), "{}", result.message);
+        }
+
+        /// A nonce store that reports every nonce as `status`.
+        struct FixedStore(NonceStatus);
+
+        impl NonceStore for FixedStore {
+            fn issue(&self, _nonce: &str, _issued_at: u64) -> Result<(), NonceError> {
+                Ok(())
+            }
+
+            fn consume(&self, _nonce: &str) -> Result<NonceStatus, NonceError> {
+                Ok(self.0)
+            }
+
+            fn purge(&self, _before: u64) -> Result<usize, NonceError> {
+                Ok(0)
+            }
+        }
+
+        #[test]
+        fn redeem_enforces_single_use_and_the_ttl() {
+            let ttl = Duration::from_secs(60);
+            let redeem = |status| ChallengeIssuer::new(Box::new(FixedStore(status)), ttl).redeem(&challenge(NONCE));
+            let now = unix_now();
+
+            redeem(NonceStatus::Issued(now)).unwrap();
+            redeem(NonceStatus::Issued(now - 59)).unwrap();
+            // A nonce stamped ahead of this clock is not expired.
+            redeem(NonceStatus::Issued(now + 30)).unwrap();
+            match redeem(NonceStatus::Issued(now - 120)) {
+                Err(NonceError::Expired { age_secs }) => assert!((120..125).contains(&age_secs), "{}", age_secs),
+                other => panic!("expected the nonce to expire, got {:?}", other),
+            }
+            assert!(matches!(redeem(NonceStatus::Unknown), Err(NonceError::Unknown)));
+            assert!(matches!(redeem(NonceStatus::AlreadyConsumed), Err(NonceError::Replayed)));
+        }
+
+        #[test]
+        fn fresh_reports_are_redeemed_once() {
+            let issuer = ChallengeIssuer::in_memory();
+            let challenge = issuer.issue().unwrap();
+            let (mut report, anchors, policy) = bound_report(runtime_data());
+            report.runtime_data = None;
+
+            // The nonce is spent even though the report does not bind it.
+            let result = verify_fresh_report(&issuer, &challenge, &report, &anchors, &policy);
+            assert_rejected(&result, CheckKind::Freshness, FailureCode::NonceMismatch);
+            let result = verify_fresh_report(&issuer, &challenge, &report, &anchors, &policy);
+            assert_rejected(&result, CheckKind::Freshness, FailureCode::NonceReplayed);
+            let unknown = AttestationChallenge { nonce: "00".repeat(32) };
+            let result = verify_fresh_report(&issuer, &unknown, &report, &anchors, &policy);
+            assert_rejected(&result, CheckKind::Freshness, FailureCode::NonceReplayed);
+        }
+
+        /// A nonce store whose backend is down.
+        struct UnavailableStore;
+
+        impl NonceStore for UnavailableStore {
+            fn issue(&self, _nonce: &str, _issued_at: u64) -> Result<(), NonceError> {
+                Err(NonceError::Store("connection refused".to_string()))
+            }
+
+            fn consume(&self, _nonce: &str) -> Result<NonceStatus, NonceError> {
+                Err(NonceError::Store("connection refused".to_string()))
+            }
+
+            fn purge(&self, _before: u64) -> Result<usize, NonceError> {
+                Err(NonceError::Store("connection refused".to_string()))
+            }
+        }
+
+        #[test]
+        fn a_nonce_store_outage_is_retryable_and_no_verdict() {
+            let (report, anchors, policy) = bound_report(runtime_data());
+            let issuer = ChallengeIssuer::new(Box::new(UnavailableStore), DEFAULT_NONCE_TTL);
+            assert!(matches!(issuer.issue(), Err(NonceError::Store(_))));
+
+            let result = verify_fresh_report(&issuer, &challenge(NONCE), &report, &anchors, &policy);
+            assert_rejected(&result, CheckKind::Freshness, FailureCode::NonceStoreUnavailable);
+            let error = AttestationError::from(result);
+            assert_eq!(error.code(), ErrorCode::NonceStoreUnavailable);
+            assert_eq!(error.retry_class(), RetryClass::Transient);
+        }
+
+        fn clone_report(report: &AttestationReport) -> AttestationReport {
+            serde_json::from_value(serde_json::to_value(report).unwrap()).unwrap()
+        }
+
+        fn vtpm_report(extra_data: &[u8], report_data: [u8; 64]) -> AttestationReport {
+            let quote = TpmQuote { attest: quote_attest(extra_data, &[0x77; 32]), signature: Vec::new() };
+            AttestationReport {
+                measurement: vec![0x77; 32],
+                report_data,
+                signature: Vec::new(),
+                cert_chain: Vec::new(),
+                evidence: TeeEvidence::Vtpm(Box::new(quote)),
+                runtime_data: None,
+            }
+        }
+
+        #[test]
+        fn vtpm_quote_binds_the_report_data_digest() {
+            let report_data = [0x42; 64];
+            let report = vtpm_report(&vtpm::qualifying_data(&report_data), report_data);
+            check_tee_evidence(&report).unwrap();
+
+            // The raw REPORT_DATA, or the digest of different data, is rejected.
+            assert!(check_tee_evidence(&vtpm_report(&report_data, report_data)).is_err());
+            let other = vtpm::qualifying_data(&[0x43; 64]);
+            assert!(check_tee_evidence(&vtpm_report(&other, report_data)).is_err());
         }
     }
 }
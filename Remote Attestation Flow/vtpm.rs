@@ -0,0 +1,335 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::sync::Mutex;
+
+use crate::attestation_data::{AttestationChallenge, AttestationReport, TeeEvidence};
+use crate::attester::attester::{Attester, AttesterError};
+
+// --- TPM 2.0 Quote Backend ---
+//
+// Speaks the raw TPM 2.0 command protocol to the kernel resource manager. All
+// TPM structures are big-endian.
+
+/// Resource-managed TPM device node.
+pub const TPM_DEVICE: &str = "/dev/tpmrm0";
+
+/// Default persistent handle of the attestation key (AK).
+pub const DEFAULT_AK_HANDLE: u32 = 0x8101_0002;
+
+/// Default location of the AK certificate issued by the vTPM provider.
+pub const VTPM_AK_CERT_PATH: &str = "/etc/attester_flow/ak_cert.pem";
+
+const TPM_ST_SESSIONS: u16 = 0x8002;
+const TPM_CC_QUOTE: u32 = 0x0000_0158;
+const TPM_RS_PW: u32 = 0x4000_0009;
+const TPM_ALG_SHA256: u16 = 0x000B;
+const TPM_ALG_NULL: u16 = 0x0010;
+pub const TPM_ALG_RSASSA: u16 = 0x0014;
+pub const TPM_ALG_ECDSA: u16 = 0x0018;
+const TPM_GENERATED_VALUE: u32 = 0xFF54_4347;
+const TPM_ST_ATTEST_QUOTE: u16 = 0x8018;
+
+/// PCRs 0-7 (firmware and boot loader measurements) in the SHA-256 bank.
+const QUOTED_PCR_SELECT: [u8; 3] = [0xFF, 0x00, 0x00];
+
+/// The qualifying data quoted for `report_data`.
+///
+/// `TPM2B_DATA` holds at most a `TPMT_HA` (48 bytes on a SHA-384 TPM), so the
+/// 64-byte REPORT_DATA is quoted as its SHA-256 digest.
+pub fn qualifying_data(report_data: &[u8; 64]) -> [u8; 32] {
+    Sha256::digest(report_data).into()
+}
+
+/// A TPM2_Quote result: the signed `TPMS_ATTEST` and its `TPMT_SIGNATURE`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TpmQuote {
+    pub attest: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// The fields of a `TPMS_ATTEST` quote the verifier relies on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TpmQuoteInfo {
+    /// The caller's qualifying data (`qualifying_data(REPORT_DATA)`).
+    pub extra_data: Vec<u8>,
+    /// Digest over the selected PCR values.
+    pub pcr_digest: Vec<u8>,
+}
+
+/// A big-endian cursor over TPM structures.
+struct TpmReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> TpmReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let out = self
+            .buf
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| format!("TPM structure truncated at offset {}", self.pos))?;
+        self.pos += n;
+        Ok(out)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn tpm2b(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+impl TpmQuote {
+    /// Decodes the quote-relevant fields of `attest`.
+    pub fn quote_info(&self) -> Result<TpmQuoteInfo, String> {
+        let mut r = TpmReader { buf: &self.attest, pos: 0 };
+        if r.u32()? != TPM_GENERATED_VALUE {
+            return Err("TPMS_ATTEST was not generated by a TPM".to_string());
+        }
+        if r.u16()? != TPM_ST_ATTEST_QUOTE {
+            return Err("TPMS_ATTEST is not a quote".to_string());
+        }
+        r.tpm2b()?; // qualifiedSigner
+        let extra_data = r.tpm2b()?.to_vec();
+        r.take(17)?; // clockInfo
+        r.take(8)?; // firmwareVersion
+
+        let selections = r.u32()?;
+        for _ in 0..selections {
+            r.u16()?;
+            let size = r.u8()? as usize;
+            r.take(size)?;
+        }
+        let pcr_digest = r.tpm2b()?.to_vec();
+
+        Ok(TpmQuoteInfo { extra_data, pcr_digest })
+    }
+
+    /// Splits `signature` into its algorithm and raw components: the RSA
+    /// signature, or the ECDSA (r, s) pair.
+    pub fn signature_parts(&self) -> Result<(u16, Vec<Vec<u8>>), String> {
+        let mut r = TpmReader { buf: &self.signature, pos: 0 };
+        let alg = r.u16()?;
+        let _hash = r.u16()?;
+        let parts = match alg {
+            TPM_ALG_RSASSA => vec![r.tpm2b()?.to_vec()],
+            TPM_ALG_ECDSA => vec![r.tpm2b()?.to_vec(), r.tpm2b()?.to_vec()],
+            other => return Err(format!("unsupported TPM signature algorithm {:#x}", other)),
+        };
+        Ok((alg, parts))
+    }
+}
+
+/// Collects TPM2_Quote evidence from a (virtual) TPM.
+pub struct VtpmAttester {
+    device: Mutex<File>,
+    /// Persistent handle of the restricted signing key used for quotes.
+    pub ak_handle: u32,
+    /// PEM/DER certificate chain for the AK, leaf first.
+    pub ak_cert_chain: Vec<u8>,
+}
+
+impl VtpmAttester {
+    /// Opens the TPM and loads the AK certificate chain from
+    /// `VTPM_AK_CERT_PATH`; quotes without it cannot be verified.
+    pub fn open() -> Result<Self, AttesterError> {
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(TPM_DEVICE)
+            .map_err(|_| AttesterError::DeviceUnavailable(TPM_DEVICE.to_string()))?;
+        let ak_cert_chain = std::fs::read(VTPM_AK_CERT_PATH)
+            .map_err(|e| AttesterError::CertificatesUnavailable(format!("{}: {}", VTPM_AK_CERT_PATH, e)))?;
+        if ak_cert_chain.is_empty() {
+            return Err(AttesterError::CertificatesUnavailable(format!("{} is empty", VTPM_AK_CERT_PATH)));
+        }
+
+        Ok(VtpmAttester {
+            device: Mutex::new(device),
+            ak_handle: DEFAULT_AK_HANDLE,
+            ak_cert_chain,
+        })
+    }
+
+    fn quote_command(&self, qualifying_data: &[u8]) -> Vec<u8> {
+        let mut params = Vec::new();
+        params.extend_from_slice(&(qualifying_data.len() as u16).to_be_bytes());
+        params.extend_from_slice(qualifying_data);
+        params.extend_from_slice(&TPM_ALG_NULL.to_be_bytes()); // use the AK's scheme
+        params.extend_from_slice(&1u32.to_be_bytes());
+        params.extend_from_slice(&TPM_ALG_SHA256.to_be_bytes());
+        params.push(QUOTED_PCR_SELECT.len() as u8);
+        params.extend_from_slice(&QUOTED_PCR_SELECT);
+
+        // Empty-password session for the AK.
+        let mut auth = Vec::new();
+        auth.extend_from_slice(&TPM_RS_PW.to_be_bytes());
+        auth.extend_from_slice(&0u16.to_be_bytes());
+        auth.push(0);
+        auth.extend_from_slice(&0u16.to_be_bytes());
+
+        let mut cmd = Vec::new();
+        cmd.extend_from_slice(&TPM_ST_SESSIONS.to_be_bytes());
+        cmd.extend_from_slice(&0u32.to_be_bytes()); // patched below
+        cmd.extend_from_slice(&TPM_CC_QUOTE.to_be_bytes());
+        cmd.extend_from_slice(&self.ak_handle.to_be_bytes());
+        cmd.extend_from_slice(&(auth.len() as u32).to_be_bytes());
+        cmd.extend_from_slice(&auth);
+        cmd.extend_from_slice(&params);
+        let size = cmd.len() as u32;
+        cmd[2..6].copy_from_slice(&size.to_be_bytes());
+        cmd
+    }
+
+    fn quote(&self, qualifying_data: &[u8]) -> Result<TpmQuote, AttesterError> {
+        let cmd = self.quote_command(qualifying_data);
+        let mut resp = vec![0u8; 4096];
+        let len = {
+            let mut device = self
+                .device
+                .lock()
+                .map_err(|_| AttesterError::DeviceUnavailable(TPM_DEVICE.to_string()))?;
+            device.write_all(&cmd)?;
+            device.read(&mut resp)?
+        };
+
+        let malformed = |e: String| AttesterError::MalformedResponse(e);
+        let mut r = TpmReader { buf: &resp[..len], pos: 0 };
+        r.u16().map_err(malformed)?; // tag
+        r.u32().map_err(malformed)?; // size
+        let rc = r.u32().map_err(malformed)?;
+        if rc != 0 {
+            return Err(AttesterError::Firmware(rc as u64));
+        }
+        r.u32().map_err(malformed)?; // parameterSize
+
+        let attest = r.tpm2b().map_err(malformed)?.to_vec();
+        let sig_start = r.pos;
+        let alg = r.u16().map_err(malformed)?;
+        r.u16().map_err(malformed)?;
+        let parts = if alg == TPM_ALG_ECDSA { 2 } else { 1 };
+        for _ in 0..parts {
+            r.tpm2b().map_err(malformed)?;
+        }
+        let signature = resp[sig_start..r.pos].to_vec();
+
+        Ok(TpmQuote { attest, signature })
+    }
+}
+
+impl Attester for VtpmAttester {
+    fn name(&self) -> &'static str {
+        "vtpm"
+    }
+
+    fn request_evidence(
+        &self,
+        challenge: &AttestationChallenge,
+        report_data: [u8; 64],
+    ) -> Result<AttestationReport, AttesterError> {
+        println!("\n[Attester:vtpm] Received Challenge: {}", challenge.nonce);
+
+        let quote = self.quote(&qualifying_data(&report_data))?;
+        let info = quote.quote_info().map_err(AttesterError::MalformedResponse)?;
+
+        println!("[Attester:vtpm] Generated Quote with PCR digest: {:?}", &info.pcr_digest[..8]);
+        Ok(AttestationReport {
+            measurement: info.pcr_digest,
+            report_data,
+            signature: quote.signature.clone(),
+            cert_chain: self.ak_cert_chain.clone(),
+            evidence: TeeEvidence::Vtpm(Box::new(quote)),
+            runtime_data: None,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Builds a `TPMS_ATTEST` quote over PCRs 0-7 carrying `extra_data`.
+    pub(crate) fn quote_attest(extra_data: &[u8], pcr_digest: &[u8; 32]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&TPM_GENERATED_VALUE.to_be_bytes());
+        out.extend_from_slice(&TPM_ST_ATTEST_QUOTE.to_be_bytes());
+        out.extend_from_slice(&34u16.to_be_bytes()); // qualifiedSigner: SHA-256 name
+        out.extend_from_slice(&TPM_ALG_SHA256.to_be_bytes());
+        out.extend_from_slice(&[0x11; 32]);
+        out.extend_from_slice(&(extra_data.len() as u16).to_be_bytes());
+        out.extend_from_slice(extra_data);
+        out.extend_from_slice(&[0; 17]); // clockInfo
+        out.extend_from_slice(&[0; 8]); // firmwareVersion
+        out.extend_from_slice(&1u32.to_be_bytes());
+        out.extend_from_slice(&TPM_ALG_SHA256.to_be_bytes());
+        out.push(QUOTED_PCR_SELECT.len() as u8);
+        out.extend_from_slice(&QUOTED_PCR_SELECT);
+        out.extend_from_slice(&32u16.to_be_bytes());
+        out.extend_from_slice(pcr_digest);
+        out
+    }
+
+    #[test]
+    fn quote_command_carries_the_report_data_digest() {
+        let attester = VtpmAttester {
+            device: Mutex::new(File::open("/dev/null").unwrap()),
+            ak_handle: DEFAULT_AK_HANDLE,
+            ak_cert_chain: Vec::new(),
+        };
+        let report_data = [0xAB; 64];
+        let cmd = attester.quote_command(&qualifying_data(&report_data));
+
+        assert_eq!(u32::from_be_bytes(cmd[2..6].try_into().unwrap()) as usize, cmd.len());
+        assert_eq!(&cmd[10..14], &DEFAULT_AK_HANDLE.to_be_bytes());
+        // Header (10), AK handle (4), auth size (4) and the password session (9).
+        let params = &cmd[27..];
+        assert_eq!(&params[..2], &32u16.to_be_bytes());
+        assert_eq!(&params[2..34], &Sha256::digest(report_data)[..]);
+    }
+
+    #[test]
+    fn quote_info_decodes_the_qualifying_data() {
+        let extra_data = qualifying_data(&[0x42; 64]);
+        let quote = TpmQuote { attest: quote_attest(&extra_data, &[0x77; 32]), signature: Vec::new() };
+        let info = quote.quote_info().unwrap();
+        assert_eq!(info.extra_data, extra_data);
+        assert_eq!(info.pcr_digest, [0x77; 32]);
+
+        let truncated = TpmQuote { attest: quote.attest[..quote.attest.len() - 1].to_vec(), signature: Vec::new() };
+        assert!(truncated.quote_info().is_err());
+    }
+
+    #[test]
+    fn signature_parts_splits_ecdsa_and_rsa() {
+        let mut ecdsa = Vec::new();
+        ecdsa.extend_from_slice(&TPM_ALG_ECDSA.to_be_bytes());
+        ecdsa.extend_from_slice(&TPM_ALG_SHA256.to_be_bytes());
+        for part in [[1u8; 32], [2u8; 32]] {
+            ecdsa.extend_from_slice(&32u16.to_be_bytes());
+            ecdsa.extend_from_slice(&part);
+        }
+        let quote = TpmQuote { attest: Vec::new(), signature: ecdsa };
+        assert_eq!(quote.signature_parts().unwrap(), (TPM_ALG_ECDSA, vec![vec![1; 32], vec![2; 32]]));
+
+        let mut rsa = Vec::new();
+        rsa.extend_from_slice(&TPM_ALG_RSASSA.to_be_bytes());
+        rsa.extend_from_slice(&TPM_ALG_SHA256.to_be_bytes());
+        rsa.extend_from_slice(&4u16.to_be_bytes());
+        rsa.extend_from_slice(&[9; 4]);
+        let quote = TpmQuote { attest: Vec::new(), signature: rsa };
+        assert_eq!(quote.signature_parts().unwrap(), (TPM_ALG_RSASSA, vec![vec![9; 4]]));
+    }
+}
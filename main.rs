@@ -1,53 +1,176 @@
 use attester_flow::{
-    attestation_data::*,
-    attester::attester,
-    verifier::verifier,
+    attestation_data::RuntimeData,
+    attester::attester::{self, Attester},
+    cert_chain::TrustAnchors,
+    error::AttestationError,
+    policy::Policy,
+    ra_tls::{RaTlsIdentity, RaTlsVerifier, DEFAULT_RA_TLS_VALIDITY},
+    verifier::verifier::{self, ChallengeIssuer},
 };
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
 
 fn main() {
-    // --- Setup: Define the trusted environment hash ---
-    // This value is pre-calculated from the known-good VM image and should be
-    // securely stored in the Verifier's policy database.
-    let trusted_image_hash = "733dd8952b1b7027b4b12185a53907c03af5183424040954b071e67e335b3760";
+    // --- Setup: Load the Verifier's policy ---
+    // Trusted measurements are pre-calculated from the known-good VM image and
+    // listed in the policy file together with TCB and platform requirements.
+    let policy = match Policy::load("attestation_policy.toml") {
+        Ok(policy) => policy,
+        Err(e) => {
+            println!("\n❌ Could not load attestation policy: {}", e);
+            return;
+        }
+    };
+
+    // The Guest VM's TEE backend (ATTESTER_BACKEND=sim selects the simulator).
+    let guest = match attester::from_env() {
+        Ok(guest) => guest,
+        Err(e) => {
+            println!("\n❌ No attester available: {}", e);
+            return;
+        }
+    };
+
+    // The pinned AMD (ARK) and Intel root certificates that evidence must chain up to.
+    let mut anchors = TrustAnchors::load_default().unwrap_or_else(|e| {
+        eprintln!("[Verifier] Could not load pinned roots ({}); no evidence will be trusted.", e);
+        TrustAnchors::new()
+    });
+    // A simulated TEE signs with a local root that must be pinned explicitly.
+    if let Some(root) = guest.local_root_pem() {
+        if let Err(e) = anchors.add_pem(root.as_bytes()) {
+            eprintln!("[Verifier] Could not pin the simulator root: {}", e);
+        }
+    }
+
+    // Random, single-use nonces with a TTL (crucial for freshness).
+    let issuer = ChallengeIssuer::in_memory();
 
     // 1. The remote Verifier initiates the request.
     println!("\n### Verifier Initiates Attestation ###");
-    let challenge = AttestationChallenge {
-        nonce: "unique-session-nonce-12345".to_string(), // Crucial for freshness
+    let challenge = match issuer.issue() {
+        Ok(challenge) => challenge,
+        Err(e) => {
+            println!("\n❌ Could not issue a challenge: {}", e);
+            return;
+        }
     };
 
-    // 2. The Guest VM Attester generates the evidence.
-    let attestation_report = attester::generate_evidence(&challenge);
+    // 2. The Guest VM Attester generates the evidence, binding an ephemeral
+    //    key so secrets can later be encrypted to this VM only.
+    let ephemeral_key = p256::SecretKey::random(&mut rand_core::OsRng);
+    let runtime_data = RuntimeData::new(ephemeral_key.public_key().to_sec1_bytes().to_vec())
+        .with_claim("image_digest", "sha256:4f53cda18c2baa0c0354bb5f9a3ecbe5ed12ab4d8e11ba873c2f11161202b945");
+    let attestation_report = match guest.generate_bound_evidence(&challenge, runtime_data) {
+        Ok(report) => report,
+        Err(e) => {
+            println!("\n❌ Evidence generation failed: {}", e);
+            return;
+        }
+    };
 
     // 3. The Verifier receives the report and performs validation.
     println!("\n### Verifier Validates Report ###");
-    let result = verifier::verify_report(&challenge, &attestation_report);
+    let result = verifier::verify_fresh_report(&issuer, &challenge, &attestation_report, &anchors, &policy);
 
     // 4. The Verifier makes a trust decision.
-    match result {
-        VerificationResult::Trustworthy(msg) => {
-            println!("\n✅ TRUST ESTABLISHED: {}", msg);
-            // Securely provision secrets (e.g., decrypt application keys).
-        }
-        VerificationResult::Untrustworthy(msg) => {
-            println!("\n❌ TRUST FAILED: {}", msg);
-            // Abort the connection and refuse to provision secrets.
+    if result.trustworthy {
+        println!("\n✅ TRUST ESTABLISHED: {}", result.message);
+        if let Some(claims) = &result.claims {
+            println!("   Claims: tee={} measurement={}", claims.tee, claims.measurement);
+            if let Some(runtime_data) = &claims.runtime_data {
+                println!("   Bound key: {}", hex::encode(&runtime_data.public_key));
+            }
         }
+        // Securely provision secrets (e.g., decrypt application keys).
+    } else {
+        println!("\n❌ TRUST FAILED [{:?}]: {}", result.failure, result.message);
+        // Abort the connection and refuse to provision secrets.
+    }
+
+    // --- Simulating a Failure (Replayed Report) ---
+    println!("\n--- Simulating a Replayed Report ---");
+    let replayed_result = verifier::verify_fresh_report(&issuer, &challenge, &attestation_report, &anchors, &policy);
+    if replayed_result.trustworthy {
+        println!("\n✅ TRUST ESTABLISHED (Should not happen!): {}", replayed_result.message);
+    } else {
+        println!(
+            "\n❌ TRUST FAILED (Expected outcome) [{:?}]: {}",
+            replayed_result.failure, replayed_result.message
+        );
     }
 
     // --- Simulating a Failure (Tampered VM) ---
     println!("\n--- Simulating a Tampered VM State ---");
-    let mut tampered_report = attester::generate_evidence(&challenge);
+    let tampered_challenge = match issuer.issue() {
+        Ok(challenge) => challenge,
+        Err(e) => {
+            println!("\n❌ Could not issue a challenge: {}", e);
+            return;
+        }
+    };
+    let mut tampered_report = match guest.generate_evidence(&tampered_challenge) {
+        Ok(report) => report,
+        Err(e) => {
+            println!("\n❌ Evidence generation failed: {}", e);
+            return;
+        }
+    };
 
     // Simulate a hypervisor or attacker changing the boot measurement.
-    tampered_report.measurement = *b"TAMPERED_VM_BOOT_STATE_HASH_123456";
+    tampered_report.measurement = b"TAMPERED_VM_BOOT_STATE_HASH_123456".to_vec();
 
-    let tampered_result = verifier::verify_report(&challenge, &tampered_report);
+    let tampered_result =
+        verifier::verify_fresh_report(&issuer, &tampered_challenge, &tampered_report, &anchors, &policy);
 
-    match tampered_result {
-        VerificationResult::Trustworthy(msg) => println!("\n✅ TRUST ESTABLISHED (Should not happen!): {}", msg),
-        VerificationResult::Untrustworthy(msg) => {
-            println!("\n❌ TRUST FAILED (Expected outcome): {}", msg);
-        }
+    if tampered_result.trustworthy {
+        println!("\n✅ TRUST ESTABLISHED (Should not happen!): {}", tampered_result.message);
+    } else {
+        println!(
+            "\n❌ TRUST FAILED (Expected outcome) [{:?}]: {}",
+            tampered_result.failure, tampered_result.message
+        );
     }
+
+    // --- Mutually Attested TLS (RA-TLS) ---
+    // Two services in CVMs authenticate each other by the evidence embedded
+    // in their certificates, without a KBS in the path.
+    println!("\n### Mutually Attested TLS (RA-TLS) ###");
+    match ra_tls_exchange(guest.as_ref(), &anchors, &policy) {
+        Ok(reply) => println!("\n✅ RA-TLS CHANNEL ESTABLISHED: server replied '{}'", reply),
+        Err(e) => println!("\n❌ RA-TLS handshake failed: {}", e),
+    }
+}
+
+/// Runs an RA-TLS client and server over a local socket pair and exchanges
+/// one message.
+fn ra_tls_exchange(guest: &dyn Attester, anchors: &TrustAnchors, policy: &Policy) -> Result<String, AttestationError> {
+    let verifier = Arc::new(RaTlsVerifier::new(anchors.clone(), policy.clone()));
+    let server_identity = RaTlsIdentity::generate(guest, "db.internal", DEFAULT_RA_TLS_VALIDITY)?;
+    let client_identity = RaTlsIdentity::generate(guest, "app.internal", DEFAULT_RA_TLS_VALIDITY)?;
+    let server_config = Arc::new(server_identity.server_config(Arc::clone(&verifier))?);
+    let client_config = Arc::new(client_identity.client_config(verifier)?);
+    let (mut client_socket, mut server_socket) = UnixStream::pair()?;
+
+    let server = std::thread::spawn(move || -> Result<(), AttestationError> {
+        let mut connection = rustls::ServerConnection::new(server_config)?;
+        let mut tls = rustls::Stream::new(&mut connection, &mut server_socket);
+        let mut request = [0u8; 4];
+        tls.read_exact(&mut request)?;
+        Ok(tls.write_all(b"pong")?)
+    });
+
+    let server_name = "db.internal"
+        .try_into()
+        .map_err(|e| AttestationError::Configuration(format!("invalid server name: {}", e)))?;
+    let mut connection = rustls::ClientConnection::new(client_config, server_name)?;
+    let mut tls = rustls::Stream::new(&mut connection, &mut client_socket);
+    tls.write_all(b"ping")?;
+    let mut reply = [0u8; 4];
+    tls.read_exact(&mut reply)?;
+    server
+        .join()
+        .map_err(|_| AttestationError::Io(std::io::Error::other("RA-TLS server thread panicked")))??;
+    Ok(String::from_utf8_lossy(&reply).into_owned())
 }
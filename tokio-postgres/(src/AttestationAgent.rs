@@ -1,118 +1,725 @@
 use attester_flow::{
-    attestation_data::{AttestationChallenge, AttestationReport},
-    attester::attester,
-    verifier::verifier,
+    attestation_data::{AttestationChallenge, AttestationReport, RuntimeData},
+    attestation_token,
+    attester::attester::Attester,
+    error::{AttestationError, ErrorCode},
+    kbs_client::{KbsClient, KbsClientError},
+    kbs_protocol::TeePubKey,
+    retry::{RetryClass, RetryPolicy},
 };
-use tokio_postgres::NoTls;
-use serde_json;
-use std::error::Error;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
 
-// Define a simple custom error type for our pipeline
-// (Same as before, assuming it's in a shared location)
-// ... (PipelineError definition) ...
+// --- Continuous Attestation ---
+//
+// Secrets released at startup must not outlive the evidence they were
+// released against. The agent re-attests in the background before its
+// token expires (or on a fixed schedule), caches what the KBS releases and
+// tells its subscribers when the resources rotate or when the KBS refuses
+// the guest, so they can switch credentials or revoke access. Its current
+// trust state can be queried at any time.
+
+/// Capacity of the event channel; slower subscribers see `Lagged` and
+/// should resync from `released` and `trust_state`.
+const EVENT_CAPACITY: usize = 16;
+
+/// How much of the guest's trust is still current.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrustLevel {
+    /// No attestation has succeeded yet.
+    Unattested,
+    /// The last attestation succeeded and its token has not expired.
+    Trusted,
+    /// Resources from an earlier attestation are held, but the last attempt
+    /// failed or their token has expired.
+    Stale,
+    /// The KBS refused the last attestation; its resources were revoked.
+    Denied,
+}
+
+/// A snapshot of the agent's trust state.
+#[derive(Debug, Clone)]
+pub struct TrustState {
+    pub level: TrustLevel,
+    /// Bumped every time the KBS releases different resources.
+    pub generation: u64,
+    /// When the current resources were last (re-)released.
+    pub attested_at: Option<SystemTime>,
+    /// Expiry of the attestation token they were released under.
+    pub expires_at: Option<SystemTime>,
+    /// Why the last attempt failed, if it did.
+    pub last_error: Option<String>,
+}
+
+/// The resources one attestation released, in the order they were
+/// requested. Holds secrets, so it is deliberately not `Debug`.
+pub struct Released {
+    pub paths: Vec<String>,
+    pub resources: Vec<Vec<u8>>,
+    pub generation: u64,
+}
+
+/// What subscribers are told.
+#[derive(Clone)]
+pub enum AttestationEvent {
+    /// The KBS released resources that differ from the previous ones.
+    Rotated(Arc<Released>),
+    /// The KBS refused to attest the guest; drop anything it released.
+    Denied { reason: String },
+}
+
+/// When the background task re-attests.
+#[derive(Debug, Clone, Copy)]
+pub struct ReattestConfig {
+    /// Longest time between two attestations.
+    pub interval: Duration,
+    /// How long before the token expires to renew it.
+    pub renew_before: Duration,
+}
 
 /// A simple struct to represent the Attestation Agent running inside the CVM.
-struct AttestationAgent {
-    kbs_endpoint: String,
+///
+/// Every run opens a fresh KBS session with a fresh TEE key, so credentials
+/// are only ever released against current evidence.
+pub struct AttestationAgent {
+    pub kbs_endpoint: String,
+    /// The TEE backend used to produce evidence.
+    pub attester: Box<dyn Attester>,
+    /// Retries, restarts and per-step timeouts of the pipeline.
+    pub retry: RetryPolicy,
+    state: RwLock<TrustState>,
+    released: RwLock<Option<Arc<Released>>>,
+    events: broadcast::Sender<AttestationEvent>,
 }
 
 impl AttestationAgent {
-    /// Simulates the entire RCAR pipeline, culminating in a live DB connection.
-    pub async fn run_attestation_pipeline(&self, resource_path: &str) -> Result<(), Box<dyn Error>> {
+    pub fn new(kbs_endpoint: String, attester: Box<dyn Attester>, retry: RetryPolicy) -> Self {
+        AttestationAgent {
+            kbs_endpoint,
+            attester,
+            retry,
+            state: RwLock::new(TrustState {
+                level: TrustLevel::Unattested,
+                generation: 0,
+                attested_at: None,
+                expires_at: None,
+                last_error: None,
+            }),
+            released: RwLock::new(None),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+
+    /// The current trust state. A `Trusted` state whose token has expired
+    /// is reported as `Stale`.
+    pub fn trust_state(&self) -> TrustState {
+        let mut state = match self.state.read() {
+            Ok(state) => state.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+        if state.level == TrustLevel::Trusted && state.expires_at.is_some_and(|exp| exp <= SystemTime::now()) {
+            state.level = TrustLevel::Stale;
+        }
+        state
+    }
+
+    /// The resources released by the last successful attestation, unless
+    /// they were revoked since.
+    pub fn released(&self) -> Option<Arc<Released>> {
+        self.released.read().ok().and_then(|released| released.clone())
+    }
+
+    /// Rotation and denial events from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<AttestationEvent> {
+        self.events.subscribe()
+    }
+
+    /// Runs the RCAR pipeline and returns the resources at `resource_paths`
+    /// (connection string, TLS material), all released to the same attested
+    /// session. The outcome updates the trust state and is announced to
+    /// subscribers.
+    ///
+    /// Each step runs on the blocking pool under `retry.step_timeout`.
+    /// Transient failures retry the step with backoff, a stale nonce or
+    /// session starts over from a new challenge, and rejected evidence or a
+    /// policy denial is returned at once.
+    pub async fn run_attestation_pipeline(
+        self: &Arc<Self>,
+        resource_paths: &[String],
+    ) -> Result<Arc<Released>, AttestationError> {
+        let outcome = self.retry.with_restarts_async(move || self.attest_once(resource_paths)).await;
+        match outcome {
+            Ok((resources, expires_at)) => Ok(self.record_success(resource_paths, resources, expires_at)),
+            Err(e) => {
+                self.record_failure(&e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Re-attests for `resource_paths` until the agent is dropped: every
+    /// `interval`, or `renew_before` the token expires if that is sooner.
+    /// Failed attempts are retried with backoff; after a denial the agent
+    /// keeps trying on schedule in case the policy is relaxed.
+    pub fn spawn_reattestation(
+        self: &Arc<Self>,
+        resource_paths: Vec<String>,
+        config: ReattestConfig,
+    ) -> tokio::task::JoinHandle<()> {
+        let agent: Weak<AttestationAgent> = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut failures = 0;
+            while let Some(delay) = agent.upgrade().map(|agent| agent.next_attestation(&config, failures)) {
+                tokio::time::sleep(delay).await;
+                let Some(agent) = agent.upgrade() else {
+                    break;
+                };
+                match agent.run_attestation_pipeline(&resource_paths).await {
+                    Ok(_) => failures = 0,
+                    Err(e) if is_denial(&e) => failures = 0,
+                    Err(e) => {
+                        failures += 1;
+                        eprintln!("[KBS Agent] Background re-attestation failed: {}", e);
+                    }
+                }
+            }
+        })
+    }
+
+    /// How long to wait before the next background attestation.
+    fn next_attestation(&self, config: &ReattestConfig, failures: u32) -> Duration {
+        if failures > 0 {
+            return self.retry.backoff(failures).min(config.interval);
+        }
+        let state = self.trust_state();
+        if state.level == TrustLevel::Denied {
+            return config.interval;
+        }
+        let Some(attested_at) = state.attested_at else {
+            return Duration::ZERO;
+        };
+        let mut due = attested_at + config.interval;
+        if let Some(renew_at) = state.expires_at.and_then(|exp| exp.checked_sub(config.renew_before)) {
+            due = due.min(renew_at);
+        }
+        due.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO)
+    }
+
+    fn record_success(
+        &self,
+        paths: &[String],
+        resources: Vec<Vec<u8>>,
+        expires_at: Option<SystemTime>,
+    ) -> Arc<Released> {
+        let mut state = match self.state.write() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let unchanged = self.released().filter(|current| current.paths == paths && current.resources == resources);
+        let rotated = unchanged.is_none();
+        let released = unchanged.unwrap_or_else(|| {
+            state.generation += 1;
+            Arc::new(Released { paths: paths.to_vec(), resources, generation: state.generation })
+        });
+        state.level = TrustLevel::Trusted;
+        state.attested_at = Some(SystemTime::now());
+        state.expires_at = expires_at;
+        state.last_error = None;
+        drop(state);
+
+        if rotated {
+            if let Ok(mut slot) = self.released.write() {
+                *slot = Some(Arc::clone(&released));
+            }
+            println!("[KBS Agent] Released resources are at generation {}", released.generation);
+            // No subscribers is fine.
+            let _ = self.events.send(AttestationEvent::Rotated(Arc::clone(&released)));
+        }
+        released
+    }
+
+    fn record_failure(&self, error: &AttestationError) {
+        let mut state = match self.state.write() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        state.last_error = Some(error.to_string());
+        if !is_denial(error) {
+            if state.level == TrustLevel::Trusted {
+                state.level = TrustLevel::Stale;
+            }
+            return;
+        }
+
+        state.level = TrustLevel::Denied;
+        state.attested_at = None;
+        state.expires_at = None;
+        drop(state);
+        if let Ok(mut slot) = self.released.write() {
+            *slot = None;
+        }
+        println!("[KBS Agent] Attestation denied; revoking released resources: {}", error);
+        let _ = self.events.send(AttestationEvent::Denied { reason: error.to_string() });
+    }
+
+    /// One pass of the pipeline, from challenge to resources and the expiry
+    /// of the token they were released under.
+    async fn attest_once(
+        self: &Arc<Self>,
+        resource_paths: &[String],
+    ) -> Result<(Vec<Vec<u8>>, Option<SystemTime>), AttestationError> {
         println!("\n--- Attestation Pipeline Starting ---");
+        let mut kbs = KbsClient::with_timeout(&self.kbs_endpoint, self.retry.step_timeout);
 
         // 1. Request Challenge from the KBS
-        let challenge = self.request_challenge().await?;
-        
-        // 2. Generate Attestation Evidence
-        let report = attester::generate_evidence(&challenge);
-        
+        let challenge = {
+            let agent = Arc::clone(self);
+            Arc::new(self.step("challenge", &mut kbs, move |kbs| agent.request_challenge(kbs)).await?)
+        };
+
+        // 2. Generate Attestation Evidence bound to an ephemeral TEE key
+        let tee_key = Arc::new(p256::SecretKey::random(&mut rand_core::OsRng));
+        let tee_pubkey = Arc::new(TeePubKey::from_p256(&tee_key.public_key()));
+        let report = {
+            let agent = Arc::clone(self);
+            let runtime_data = RuntimeData::new(tee_pubkey.to_binding_bytes());
+            Arc::new(
+                self.step("evidence", &mut kbs, move |_| {
+                    Ok(agent.attester.generate_bound_evidence(&challenge, runtime_data.clone())?)
+                })
+                .await?,
+            )
+        };
+
         // 3. Submit Evidence and get Attestation Token (KBS verification happens here)
-        let attestation_token = self.submit_evidence(&report).await?;
+        let attestation_token = {
+            let (agent, tee_pubkey) = (Arc::clone(self), Arc::clone(&tee_pubkey));
+            let token = self.step("attest", &mut kbs, move |kbs| agent.submit_evidence(kbs, &tee_pubkey, &report));
+            Arc::new(token.await?)
+        };
+
+        // 4. Retrieve Resources (The DB Connection String and TLS Material)
+        // They are only returned if the KBS successfully verified the report.
+        let mut resources = Vec::with_capacity(resource_paths.len());
+        for path in resource_paths {
+            let (agent, token, tee_key) = (Arc::clone(self), Arc::clone(&attestation_token), Arc::clone(&tee_key));
+            let path = path.clone();
+            resources.push(
+                self.step("resource", &mut kbs, move |kbs| agent.retrieve_resource(kbs, &token, &path, &tee_key))
+                    .await?,
+            );
+        }
 
-        // 4. Retrieve Resource (The DB Connection String Secret)
-        // The secret is only returned if the KBS successfully verified the report.
-        let db_connection_string = self.retrieve_resource(&attestation_token, resource_path).await?;
+        // The agent only reads its own token's expiry to know when to renew.
+        let expires_at = attestation_token::unverified_claims(&attestation_token)
+            .ok()
+            .map(|claims| UNIX_EPOCH + Duration::from_secs(claims.exp));
 
-        // 5. Use the SECURELY RETRIEVED secret to establish the DB connection
-        let client = self.connect_to_db(&db_connection_string).await?;
+        println!("--- Attestation Pipeline Complete ---");
+        Ok((resources, expires_at))
+    }
 
-        println!("\n✅ Successfully secured and connected to DB.");
-        
-        // 6. Execute a sample query using the client
-        let rows = client
-            .query("SELECT current_database()", &[])
+    /// Runs one blocking step under the step timeout, retrying transient
+    /// failures with backoff.
+    ///
+    /// A timed-out call keeps running on the blocking pool, so each attempt
+    /// gets its own copy of the session in `kbs`; only the copy of the
+    /// attempt that succeeds replaces it.
+    async fn step<T, F>(&self, name: &str, kbs: &mut KbsClient, run: F) -> Result<T, AttestationError>
+    where
+        T: Send + 'static,
+        F: Fn(&KbsClient) -> Result<T, AttestationError> + Send + Sync + 'static,
+    {
+        let run = Arc::new(run);
+        let timeout = self.retry.step_timeout;
+        let session: &KbsClient = kbs;
+        let (value, session) = self
+            .retry
+            .retry_async(name, || {
+                let (call, session) = (Arc::clone(&run), session.clone());
+                let task = tokio::task::spawn_blocking(move || call(&session).map(|value| (value, session)));
+                async move {
+                    match tokio::time::timeout(timeout, task).await {
+                        Ok(Ok(result)) => result,
+                        Ok(Err(e)) => {
+                            Err(AttestationError::Io(std::io::Error::other(format!("{} task failed: {}", name, e))))
+                        }
+                        Err(_) => Err(AttestationError::Timeout(format!("{} step after {:?}", name, timeout))),
+                    }
+                }
+            })
             .await?;
+        *kbs = session;
+        Ok(value)
+    }
 
-        let db_name: &str = rows[0].get(0);
-        println!("Database Client is connected to: **{}**", db_name);
+    // --- Private Methods Wrapping the KBS Protocol ---
 
-        Ok(())
+    fn request_challenge(&self, kbs: &KbsClient) -> Result<AttestationChallenge, AttestationError> {
+        println!("[KBS Agent] 1. Requesting Attestation Challenge from {}...", kbs.base_url());
+        let challenge = kbs.auth(self.attester.tee())?;
+        println!("[KBS Agent] Challenge received: {}", challenge.nonce);
+        Ok(challenge)
     }
 
-    // --- Private Methods (Simplified/Simulated) ---
+    fn submit_evidence(
+        &self,
+        kbs: &KbsClient,
+        tee_pubkey: &TeePubKey,
+        report: &AttestationReport,
+    ) -> Result<String, AttestationError> {
+        println!("[KBS Agent] 2. Submitting Attestation Evidence...");
+        let token = kbs.attest(tee_pubkey, report)?;
+        println!("[KBS Agent] Evidence accepted. Received Attestation Token.");
+        Ok(token)
+    }
 
-    // ... (request_challenge, submit_evidence - Same as before, just async now) ...
+    fn retrieve_resource(
+        &self,
+        kbs: &KbsClient,
+        token: &str,
+        path: &str,
+        tee_key: &p256::SecretKey,
+    ) -> Result<Vec<u8>, AttestationError> {
+        println!("[KBS Agent] 3. Requesting Secret '{}'...", path);
+        if token.is_empty() {
+            return Err(KbsClientError::NoSession.into());
+        }
 
-    async fn request_challenge(&self) -> Result<AttestationChallenge, PipelineError> {
-        // ... (Simulated logic to get the nonce) ...
-        Ok(AttestationChallenge {
-            nonce: "kbs-nonce-e5a9c1f2-7d3b-4e8c-9a0f-8b2d1c5e4a3".to_string(), 
-        })
+        let secret = kbs.get_secret(path, tee_key)?;
+
+        // Never log the secret itself: it carries the database password.
+        println!("[KBS Agent] Successfully retrieved resource!");
+        Ok(secret)
+    }
+}
+
+/// The KBS or its verifier refused the guest, as opposed to failing to
+/// answer or asking for a new challenge.
+fn is_denial(error: &AttestationError) -> bool {
+    error.retry_class() == RetryClass::Permanent
+        && matches!(error.code(), ErrorCode::KbsRejected | ErrorCode::VerificationFailed)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use attester_flow::cert_chain::TrustAnchors;
+    use attester_flow::kbs_server::{KbsConfig, KbsServer};
+    use attester_flow::policy::{Policy, PolicyError};
+    use attester_flow::resource_store::ResourcePolicy;
+    use attester_flow::simulator::{SimulatedAttester, SIMULATED_BOOT_IMAGE};
+    use rand_core::RngCore;
+    use sha2::{Digest, Sha384};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+
+    /// A KBS on an ephemeral port serving files from a scratch directory
+    /// to agents whose simulated TEE shares its pinned root.
+    pub(crate) struct TestKbs {
+        url: String,
+        dir: PathBuf,
     }
-    
-    async fn submit_evidence(&self, _report: &AttestationReport) -> Result<String, PipelineError> {
-        // ... (Simulated logic to send report and get token) ...
-        Ok("kbs-auth-token-d4f7g8h1j2k3l4m5n6p7q8r9s0t1u2v3".to_string())
+
+    impl TestKbs {
+        /// Tokens (and so the agent's trust) last `session_ttl`.
+        pub(crate) fn start(session_ttl: Duration) -> Self {
+            let dir = std::env::temp_dir().join(format!("attested-db-kbs-{:016x}", rand_core::OsRng.next_u64()));
+            let attester = SimulatedAttester::persistent(dir.join("sim")).unwrap();
+            let anchors = TrustAnchors::from_pem(attester.local_root_pem().unwrap().as_bytes()).unwrap();
+            let policy = Policy::from_toml_str(&format!(
+                "[[image]]\nname = \"simulated\"\nmeasurements = [\"{}\"]\n",
+                hex::encode(Sha384::digest(SIMULATED_BOOT_IMAGE))
+            ))
+            .unwrap();
+
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let config = KbsConfig { resource_dir: dir.join("resources"), session_ttl, ..KbsConfig::default() };
+            std::thread::spawn(move || {
+                KbsServer::new(config, anchors, policy).serve_listener(listener).unwrap();
+            });
+            TestKbs { url, dir }
+        }
+
+        /// An agent attesting to this KBS with the simulator.
+        pub(crate) fn agent(&self, retry: RetryPolicy) -> Arc<AttestationAgent> {
+            let attester = SimulatedAttester::persistent(self.dir.join("sim")).unwrap();
+            Arc::new(AttestationAgent::new(self.url.clone(), Box::new(attester), retry))
+        }
+
+        fn resource_file(&self, path: &str) -> PathBuf {
+            self.dir.join("resources").join(path)
+        }
+
+        /// Stores (or replaces) the resource at `path`.
+        pub(crate) fn put(&self, path: &str, data: &[u8]) {
+            let file = self.resource_file(path);
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(file, data).unwrap();
+        }
+
+        /// Withholds the resource at `path` from the simulated TEE, or
+        /// releases it again.
+        pub(crate) fn set_denied(&self, path: &str, denied: bool) {
+            let mut file = self.resource_file(path).into_os_string();
+            file.push(".policy.json");
+            if denied {
+                let policy = ResourcePolicy { allowed_tees: vec!["tdx".to_string()], ..ResourcePolicy::default() };
+                std::fs::write(file, serde_json::to_vec(&policy).unwrap()).unwrap();
+            } else {
+                std::fs::remove_file(file).unwrap();
+            }
+        }
     }
 
-    /// Simulates an HTTP GET to the KBS /resource endpoint using the token.
-    async fn retrieve_resource(&self, _token: &str, _path: &str) -> Result<String, PipelineError> {
-        println!("\n[KBS Agent] 3. Requesting Secret...");
-        
-        // The secret retrieved from the KBS is the sensitive connection string.
-        let secret_payload = "host=127.0.0.1 user=app_user password=SECRET_KEY dbname=prod_data".to_string();
-        
-        println!("[KBS Agent] Successfully retrieved resource!");
-        Ok(secret_payload)
+    impl Drop for TestKbs {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
     }
 
-    /// Establishes the database connection using the secret retrieved from the KBS.
-    async fn connect_to_db(&self, connection_string: &str) -> Result<tokio_postgres::Client, Box<dyn Error>> {
-        println!("[DB Connect] Establishing PostgreSQL connection...");
+    /// An agent whose KBS is never reached; tests drive its state directly.
+    pub(crate) fn offline_agent() -> Arc<AttestationAgent> {
+        let attester = SimulatedAttester::new().unwrap();
+        Arc::new(AttestationAgent::new("http://127.0.0.1:9".to_string(), Box::new(attester), RetryPolicy::no_retry()))
+    }
 
-        // tokio_postgres::connect performs the actual connection handshake
-        let (client, connection) = 
-            tokio_postgres::connect(connection_string, NoTls).await?;
+    /// Records an attestation that released `resources` at `paths`, as the
+    /// pipeline does, announcing a rotation if they changed.
+    pub(crate) fn release(agent: &AttestationAgent, paths: &[String], resources: Vec<Vec<u8>>) -> Arc<Released> {
+        agent.record_success(paths, resources, None)
+    }
 
-        // Spawn the connection object into a background task to handle I/O
-        tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                eprintln!("Database connection error: {}", e);
+    /// Records a refusal by the KBS, revoking what it released.
+    pub(crate) fn deny(agent: &AttestationAgent) {
+        let refusal = KbsClientError::Status {
+            status: 403,
+            error_type: "PolicyDenied".to_string(),
+            detail: "not released".to_string(),
+        };
+        agent.record_failure(&refusal.into());
+    }
+
+    const PATH: &str = "default/db/connection";
+
+    fn paths() -> Vec<String> {
+        vec![PATH.to_string()]
+    }
+
+    /// Polls the trust state until `done` holds.
+    async fn wait_for(agent: &AttestationAgent, done: impl Fn(&TrustState) -> bool) -> TrustState {
+        for _ in 0..500 {
+            let state = agent.trust_state();
+            if done(&state) {
+                return state;
             }
-        });
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the agent never reached the expected state: {:?}", agent.trust_state());
+    }
 
-        println!("[DB Connect] Connection established, client ready.");
-        Ok(client)
+    #[test]
+    fn only_refusals_are_denials() {
+        let refused = |status| {
+            AttestationError::from(KbsClientError::Status {
+                status,
+                error_type: "PolicyDenied".to_string(),
+                detail: String::new(),
+            })
+        };
+        assert!(is_denial(&refused(403)));
+        assert!(!is_denial(&refused(404)));
+        assert!(!is_denial(&refused(503)));
+        assert!(!is_denial(&KbsClientError::NoSession.into()));
+        // A broken local policy file is the guest's problem, not a verdict.
+        assert!(!is_denial(&AttestationError::Policy(PolicyError::Invalid("bad hex".to_string()))));
+        assert!(!is_denial(&AttestationError::Timeout("attest step".to_string())));
     }
-}
 
+    #[tokio::test]
+    async fn trust_state_follows_each_attestation() {
+        let kbs = TestKbs::start(Duration::from_secs(60));
+        kbs.put(PATH, b"host=db password=a");
+        let agent = kbs.agent(RetryPolicy::no_retry());
+        assert_eq!(agent.trust_state().level, TrustLevel::Unattested);
+
+        let released = agent.run_attestation_pipeline(&paths()).await.unwrap();
+        let state = agent.trust_state();
+        assert_eq!(state.level, TrustLevel::Trusted);
+        assert_eq!((state.generation, released.generation), (1, 1));
+        assert!(state.attested_at.is_some() && state.last_error.is_none());
+        let expires_at = state.expires_at.unwrap();
+        assert!(expires_at > SystemTime::now() + Duration::from_secs(50));
+
+        // A failure that is not a verdict keeps what was released, but stale.
+        let missing = vec![PATH.to_string(), "default/db/missing".to_string()];
+        assert!(agent.run_attestation_pipeline(&missing).await.is_err());
+        let state = agent.trust_state();
+        assert_eq!(state.level, TrustLevel::Stale);
+        assert!(state.last_error.is_some());
+        assert_eq!(agent.released().unwrap().generation, 1);
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    // Note: In a real app, environment variables would be used for the KBS endpoint,
-    // not loaded via dotenv, as they are part of the trusted container setup.
-    let agent = AttestationAgent {
-        kbs_endpoint: "https://kbs.cloud.provider.com/api/v1".to_string(),
-    };
+        agent.run_attestation_pipeline(&paths()).await.unwrap();
+        assert_eq!(agent.trust_state().level, TrustLevel::Trusted);
+
+        kbs.set_denied(PATH, true);
+        assert!(agent.run_attestation_pipeline(&paths()).await.is_err());
+        let state = agent.trust_state();
+        assert_eq!(state.level, TrustLevel::Denied);
+        assert!(state.attested_at.is_none() && state.expires_at.is_none());
+        assert!(agent.released().is_none());
+
+        // Released again, the resources count as a new generation.
+        kbs.set_denied(PATH, false);
+        agent.run_attestation_pipeline(&paths()).await.unwrap();
+        let state = agent.trust_state();
+        assert_eq!((state.level, state.generation), (TrustLevel::Trusted, 2));
+    }
 
-    let secret_to_fetch = "/keys/database-cred";
+    #[tokio::test]
+    async fn trust_goes_stale_when_the_token_expires() {
+        let kbs = TestKbs::start(Duration::from_secs(1));
+        kbs.put(PATH, b"host=db password=a");
+        let agent = kbs.agent(RetryPolicy::no_retry());
+        agent.run_attestation_pipeline(&paths()).await.unwrap();
 
-    // Run the pipeline
-    if let Err(e) = agent.run_attestation_pipeline(secret_to_fetch).await {
-        eprintln!("\n❌ FATAL ERROR in Attestation/DB Pipeline: {}", e);
-        return Err(e);
+        let expires_at = agent.trust_state().expires_at.unwrap();
+        let remaining = expires_at.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO);
+        tokio::time::sleep(remaining + Duration::from_millis(100)).await;
+        assert_eq!(agent.trust_state().level, TrustLevel::Stale);
+        assert!(agent.released().is_some(), "expiry alone does not revoke");
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn announces_rotations_but_not_unchanged_releases() {
+        let kbs = TestKbs::start(Duration::from_secs(60));
+        kbs.put(PATH, b"host=db password=a");
+        let agent = kbs.agent(RetryPolicy::no_retry());
+        let mut events = agent.subscribe();
+
+        let first = agent.run_attestation_pipeline(&paths()).await.unwrap();
+        match events.try_recv() {
+            Ok(AttestationEvent::Rotated(released)) => assert!(Arc::ptr_eq(&released, &first)),
+            _ => panic!("the first release is a rotation"),
+        }
+
+        let again = agent.run_attestation_pipeline(&paths()).await.unwrap();
+        assert!(Arc::ptr_eq(&again, &first));
+        assert!(matches!(events.try_recv(), Err(broadcast::error::TryRecvError::Empty)));
+
+        kbs.put(PATH, b"host=db password=b");
+        let rotated = agent.run_attestation_pipeline(&paths()).await.unwrap();
+        assert_eq!((rotated.generation, rotated.resources[0].as_slice()), (2, &b"host=db password=b"[..]));
+        match events.try_recv() {
+            Ok(AttestationEvent::Rotated(released)) => assert_eq!(released.generation, 2),
+            _ => panic!("changed resources are a rotation"),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_denial_revokes_and_is_announced() {
+        let kbs = TestKbs::start(Duration::from_secs(60));
+        kbs.put(PATH, b"host=db password=a");
+        let agent = kbs.agent(RetryPolicy::default());
+        agent.run_attestation_pipeline(&paths()).await.unwrap();
+        let mut events = agent.subscribe();
+
+        kbs.set_denied(PATH, true);
+        let refusal = agent.run_attestation_pipeline(&paths()).await.err().unwrap();
+        assert_eq!(refusal.code(), ErrorCode::KbsRejected);
+        match events.try_recv() {
+            Ok(AttestationEvent::Denied { reason }) => assert!(reason.contains("PolicyDenied"), "{}", reason),
+            _ => panic!("a denial is announced"),
+        }
+        assert!(agent.released().is_none());
+        assert_eq!(agent.trust_state().level, TrustLevel::Denied);
+    }
+
+    #[test]
+    fn schedules_the_sooner_of_interval_and_renewal() {
+        let agent = offline_agent();
+        let config = ReattestConfig { interval: Duration::from_secs(600), renew_before: Duration::from_secs(60) };
+        let close = |actual: Duration, expected: Duration| expected.abs_diff(actual) < Duration::from_secs(2);
+
+        // Nothing attested yet: at once.
+        assert_eq!(agent.next_attestation(&config, 0), Duration::ZERO);
+
+        let far = SystemTime::now() + Duration::from_secs(3600);
+        agent.record_success(&paths(), vec![b"a".to_vec()], Some(far));
+        assert!(close(agent.next_attestation(&config, 0), config.interval));
+
+        let soon = SystemTime::now() + Duration::from_secs(300);
+        agent.record_success(&paths(), vec![b"a".to_vec()], Some(soon));
+        assert!(close(agent.next_attestation(&config, 0), Duration::from_secs(240)));
+
+        // Renewal time already passed.
+        agent.record_success(&paths(), vec![b"a".to_vec()], Some(SystemTime::now() + Duration::from_secs(30)));
+        assert_eq!(agent.next_attestation(&config, 0), Duration::ZERO);
+
+        // Failures back off, never beyond the interval.
+        assert!(agent.next_attestation(&config, 3) <= config.interval);
+        let short = ReattestConfig { interval: Duration::from_millis(1), ..config };
+        assert!(agent.next_attestation(&short, 30) <= short.interval);
+
+        // Once denied, retry on schedule.
+        deny(&agent);
+        assert_eq!(agent.next_attestation(&config, 0), config.interval);
+    }
+
+    #[tokio::test]
+    async fn reattests_every_interval() {
+        let kbs = TestKbs::start(Duration::from_secs(60));
+        kbs.put(PATH, b"host=db password=a");
+        let agent = kbs.agent(RetryPolicy::no_retry());
+        let config = ReattestConfig { interval: Duration::from_millis(500), renew_before: Duration::ZERO };
+        let task = agent.spawn_reattestation(paths(), config);
+
+        let first = wait_for(&agent, |state| state.level == TrustLevel::Trusted).await.attested_at.unwrap();
+        let second = wait_for(&agent, |state| state.attested_at.is_some_and(|at| at > first)).await;
+        let gap = second.attested_at.unwrap().duration_since(first).unwrap();
+        assert!(gap >= config.interval && gap < config.interval * 4, "re-attested after {:?}", gap);
+        // Unchanged resources keep their generation.
+        assert_eq!(second.generation, 1);
+
+        kbs.put(PATH, b"host=db password=b");
+        wait_for(&agent, |state| state.generation == 2).await;
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn renews_before_the_token_expires() {
+        let kbs = TestKbs::start(Duration::from_secs(3));
+        kbs.put(PATH, b"host=db password=a");
+        let agent = kbs.agent(RetryPolicy::no_retry());
+        let config = ReattestConfig { interval: Duration::from_secs(600), renew_before: Duration::from_secs(2) };
+        let task = agent.spawn_reattestation(paths(), config);
+
+        let first = wait_for(&agent, |state| state.level == TrustLevel::Trusted).await;
+        let (attested_at, expires_at) = (first.attested_at.unwrap(), first.expires_at.unwrap());
+        let renewed = wait_for(&agent, |state| state.attested_at.is_some_and(|at| at > attested_at)).await;
+        let renewed_at = renewed.attested_at.unwrap();
+        assert!(renewed_at >= expires_at - config.renew_before, "renewed too early");
+        assert!(renewed_at < expires_at, "renewed after the token expired");
+        assert_eq!(renewed.level, TrustLevel::Trusted);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn keeps_trying_on_schedule_after_a_denial() {
+        let kbs = TestKbs::start(Duration::from_secs(60));
+        kbs.put(PATH, b"host=db password=a");
+        kbs.set_denied(PATH, true);
+        let agent = kbs.agent(RetryPolicy::no_retry());
+        let config = ReattestConfig { interval: Duration::from_millis(300), renew_before: Duration::ZERO };
+        let task = agent.spawn_reattestation(paths(), config);
+
+        wait_for(&agent, |state| state.level == TrustLevel::Denied).await;
+        kbs.set_denied(PATH, false);
+        let state = wait_for(&agent, |state| state.level == TrustLevel::Trusted).await;
+        assert_eq!(state.generation, 1);
+        assert!(agent.released().is_some());
+        task.abort();
+    }
 }
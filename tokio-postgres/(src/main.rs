@@ -1,58 +1,62 @@
-use tokio_postgres::{NoTls, Error};
-use dotenv::dotenv;
-use std::env;
+use attester_flow::{attester::attester, error::AttestationError, kbs_client::KBS_URL_ENV, retry::RetryPolicy};
+use std::sync::Arc;
+use std::time::Duration;
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    // 1. Load environment variables (e.g., from a .env file)
-    // This is where the secret retrieved during attestation would be used.
-    dotenv().ok(); 
-
-    // 2. Construct the connection string from secure environment variables
-    let host = env::var("PG_HOST").unwrap_or_else(|_| "localhost".to_string());
-    let user = env::var("PG_USER").expect("PG_USER must be set");
-    let password = env::var("PG_PASSWORD").expect("PG_PASSWORD must be set");
-    let dbname = env::var("PG_DBNAME").expect("PG_DBNAME must be set");
-
-    let connection_string = format!(
-        "host={} user={} password={} dbname={}", 
-        host, user, password, dbname
-    );
-
-    println!("Attempting to connect to PostgreSQL at host: {}", host);
+#[path = "AttestationAgent.rs"]
+mod attestation_agent;
+mod pool;
+mod settings;
+mod tls;
 
-    // 3. Establish the connection
-    let (client, connection) = 
-        tokio_postgres::connect(&connection_string, NoTls).await?;
+use attestation_agent::{AttestationAgent, ReattestConfig};
+use pool::{AttestedPool, PoolConfig};
+use settings::DatabaseConfig;
 
-    // The connection object performs the actual I/O, so it needs to be run in the background.
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("Database connection error: {}", e);
+#[tokio::main]
+async fn main() -> Result<(), AttestationError> {
+    // 1. Load the pool settings. They name the KBS resource holding the
+    // connection string; the password itself is only ever released by the
+    // KBS to an attested guest, never read from the environment.
+    let database = DatabaseConfig::from_env()?;
+
+    // 2. Select the TEE backend (ATTESTER_BACKEND overrides auto-detection).
+    let kbs_endpoint = std::env::var(KBS_URL_ENV).unwrap_or_else(|_| "https://kbs.cloud.provider.com".to_string());
+    let agent = Arc::new(AttestationAgent::new(kbs_endpoint, attester::from_env()?, RetryPolicy::from_env()));
+
+    // 3. Attest and open the pool with the released credentials
+    let pool_config = PoolConfig::from_database(&database);
+    let resources = pool_config.resources();
+    let pool = match AttestedPool::connect(Arc::clone(&agent), pool_config).await {
+        Ok(pool) => pool,
+        Err(e) => {
+            eprintln!("\n❌ FATAL ERROR in Attestation/DB Pipeline: {}", e);
+            return Err(e);
         }
-    });
-
-    println!("✅ Database connection established successfully!");
+    };
+    println!("✅ Database pool established successfully!");
+
+    // Keep attesting in the background; the pool follows rotations and
+    // loses access if the KBS stops trusting the guest.
+    let _reattestation = agent.spawn_reattestation(
+        resources,
+        ReattestConfig {
+            interval: Duration::from_secs(database.refresh_interval_secs),
+            renew_before: Duration::from_secs(database.renew_before_secs),
+        },
+    );
+    let trust = agent.trust_state();
+    println!("Trust state: {:?} (generation {}, token expires {:?})", trust.level, trust.generation, trust.expires_at);
 
     // 4. Test the connection by running a simple query
-    let rows = client
-        .query("SELECT $1::TEXT", &[&"Hello, DB Connection!"])
-        .await?;
-
+    let client = pool.get().await?;
+    let rows = client.query("SELECT $1::TEXT", &[&"Hello, DB Connection!"]).await?;
     let value: &str = rows[0].get(0);
     println!("Database Test Query Result: {}", value);
-    
-    // 5. Example: Querying data
-    let test_query = "SELECT COUNT(*) FROM pg_database WHERE datname = $1";
-    let count_rows = client.query(test_query, &[&dbname]).await?;
-    let db_exists: i64 = count_rows[0].get(0);
-
-    if db_exists > 0 {
-        println!("Database '{}' successfully queried (It exists).", dbname);
-    } else {
-        println!("Database '{}' could not be found.", dbname);
-    }
 
+    // 5. Example: Querying data
+    let rows = client.query("SELECT current_database()", &[]).await?;
+    let db_name: &str = rows[0].get(0);
+    println!("Database Client is connected to: **{}**", db_name);
 
     Ok(())
-      }
+}
@@ -0,0 +1,579 @@
+use attester_flow::error::AttestationError;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_postgres::error::SqlState;
+use tokio_postgres::tls::MakeTlsConnect;
+use tokio_postgres::{Client, Config, NoTls, Socket};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::attestation_agent::{AttestationAgent, AttestationEvent, Released, TrustLevel};
+use crate::settings::DatabaseConfig;
+use crate::tls::{self, SslMode, TlsMaterial};
+
+// --- Attestation-Gated Connection Pool ---
+//
+// The pool never sees a password from the environment or from disk: its
+// connection settings are the secret the KBS releases after a successful
+// attestation, together with the TLS material the connections are secured
+// with. The pool subscribes to the agent: when a re-attestation (scheduled,
+// or because the server rejected the credentials) releases rotated ones it
+// switches over, and when the KBS refuses the guest it drops them along with
+// its idle connections. Connections opened with older credentials are
+// retired as they are returned.
+
+/// How long opening a single connection may take.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pool sizing and credential settings.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Maximum number of open connections.
+    pub size: usize,
+    /// KBS resource holding the connection string.
+    pub credential_resource: String,
+    pub connect_timeout: Duration,
+    pub sslmode: SslMode,
+    /// KBS resources holding the PEM CA bundle and client certificate/key.
+    pub ca_resource: Option<String>,
+    pub client_cert_resource: Option<String>,
+    pub client_key_resource: Option<String>,
+}
+
+impl PoolConfig {
+    pub fn from_database(database: &DatabaseConfig) -> Self {
+        PoolConfig {
+            size: database.pool_size.max(1) as usize,
+            credential_resource: database.credential_resource.clone(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            sslmode: database.sslmode,
+            ca_resource: database.ca_resource.clone(),
+            client_cert_resource: database.client_cert_resource.clone(),
+            client_key_resource: database.client_key_resource.clone(),
+        }
+    }
+
+    /// The resources one attestation fetches: the connection string first,
+    /// then whichever TLS resources are configured.
+    pub fn resources(&self) -> Vec<String> {
+        let tls = [&self.ca_resource, &self.client_cert_resource, &self.client_key_resource];
+        std::iter::once(self.credential_resource.clone())
+            .chain(tls.into_iter().flatten().cloned())
+            .collect()
+    }
+}
+
+/// What one attestation released, ready to connect with.
+#[derive(Clone)]
+struct Credentials {
+    /// `Config`'s `Debug` redacts the password.
+    config: Config,
+    material: TlsMaterial,
+    /// `None` for `sslmode = "disable"`.
+    tls: Option<MakeRustlsConnect>,
+}
+
+impl Credentials {
+    fn from_released(config: &PoolConfig, released: &Released) -> Result<Self, AttestationError> {
+        if released.paths != config.resources() {
+            return Err(AttestationError::Credentials("released resources are not the pool's".to_string()));
+        }
+        let mut released = released.resources.clone();
+        let mut tls_resources = released.split_off(1).into_iter();
+        let mut next_if = |configured: &Option<String>| configured.as_ref().and_then(|_| tls_resources.next());
+        let material = TlsMaterial {
+            ca_pem: next_if(&config.ca_resource),
+            client_cert_pem: next_if(&config.client_cert_resource),
+            client_key_pem: next_if(&config.client_key_resource),
+        };
+
+        let connection_string = released
+            .pop()
+            .and_then(|secret| String::from_utf8(secret).ok())
+            .ok_or_else(|| AttestationError::Credentials("connection string is not UTF-8".to_string()))?;
+        let mut db_config: Config = connection_string
+            .trim()
+            .parse()
+            .map_err(|e: tokio_postgres::Error| AttestationError::Credentials(e.to_string()))?;
+        db_config.ssl_mode(config.sslmode.negotiation());
+
+        let tls = tls::client_config(config.sslmode, &material)?.map(MakeRustlsConnect::new);
+        Ok(Credentials { config: db_config, material, tls })
+    }
+
+    /// Same connection settings and TLS material.
+    fn same_as(&self, other: &Credentials) -> bool {
+        self.config == other.config && self.material == other.material
+    }
+}
+
+struct IdleClient {
+    client: Client,
+    generation: u64,
+}
+
+struct PoolInner {
+    agent: Arc<AttestationAgent>,
+    config: PoolConfig,
+    credentials: RwLock<Option<Credentials>>,
+    /// Bumped every time the KBS releases different credentials or access
+    /// is revoked.
+    generation: AtomicU64,
+    /// Serialises re-attestation so concurrent failures trigger one run.
+    refresh_lock: tokio::sync::Mutex<()>,
+    idle: Mutex<Vec<IdleClient>>,
+    permits: Arc<Semaphore>,
+}
+
+/// A pool of PostgreSQL connections whose credentials come from the KBS.
+#[derive(Clone)]
+pub struct AttestedPool {
+    inner: Arc<PoolInner>,
+}
+
+impl AttestedPool {
+    /// Attests, fetches the initial credentials and opens one connection to
+    /// check them. From then on the pool follows the agent's rotations and
+    /// denials.
+    pub async fn connect(agent: Arc<AttestationAgent>, config: PoolConfig) -> Result<Self, AttestationError> {
+        let pool = AttestedPool::new(agent, config);
+        pool.spawn_listener();
+        pool.refresh().await?;
+        drop(pool.get().await?);
+        println!("[DB Pool] Ready ({} connections max)", pool.inner.config.size);
+        Ok(pool)
+    }
+
+    /// A pool without credentials that does not follow the agent yet.
+    fn new(agent: Arc<AttestationAgent>, config: PoolConfig) -> Self {
+        AttestedPool {
+            inner: Arc::new(PoolInner {
+                agent,
+                permits: Arc::new(Semaphore::new(config.size)),
+                config,
+                credentials: RwLock::new(None),
+                generation: AtomicU64::new(0),
+                refresh_lock: tokio::sync::Mutex::new(()),
+                idle: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// The credential generation; it increases on every rotation.
+    pub fn generation(&self) -> u64 {
+        self.inner.generation.load(Ordering::SeqCst)
+    }
+
+    /// Re-runs the attestation pipeline and installs the released
+    /// credentials. Returns true if they differ from the current ones.
+    pub async fn refresh(&self) -> Result<bool, AttestationError> {
+        let seen = self.generation();
+        let _guard = self.inner.refresh_lock.lock().await;
+        if self.generation() != seen {
+            // Another task rotated the credentials while we waited.
+            return Ok(true);
+        }
+
+        let released = self.inner.agent.run_attestation_pipeline(&self.inner.config.resources()).await?;
+        // The listener may have installed them first.
+        self.install(&released)?;
+        let rotated = self.generation() != seen;
+        if !rotated {
+            println!("[DB Pool] Re-attested; credentials unchanged");
+        }
+        Ok(rotated)
+    }
+
+    /// Switches to released credentials. Returns true if they differ from
+    /// the current ones.
+    fn install(&self, released: &Released) -> Result<bool, AttestationError> {
+        let released = Credentials::from_released(&self.inner.config, released)?;
+        let mut credentials = self.inner.credentials.write()?;
+        if credentials.as_ref().is_some_and(|current| current.same_as(&released)) {
+            return Ok(false);
+        }
+        *credentials = Some(released);
+        let generation = self.inner.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        drop(credentials);
+        if let Ok(mut idle) = self.inner.idle.lock() {
+            idle.clear();
+        }
+        println!("[DB Pool] Installed credentials generation {}", generation);
+        Ok(true)
+    }
+
+    /// Forgets the credentials and closes the idle connections; checked-out
+    /// ones are closed when returned. `get` fails until the agent is trusted
+    /// again.
+    fn revoke(&self, reason: &str) {
+        if let Ok(mut credentials) = self.inner.credentials.write() {
+            *credentials = None;
+        }
+        self.inner.generation.fetch_add(1, Ordering::SeqCst);
+        if let Ok(mut idle) = self.inner.idle.lock() {
+            idle.clear();
+        }
+        println!("[DB Pool] Access revoked: {}", reason);
+    }
+
+    /// Installs rotated credentials if they are the pool's resources.
+    fn apply(&self, released: &Released) {
+        if released.paths != self.inner.config.resources() {
+            return;
+        }
+        if let Err(e) = self.install(released) {
+            eprintln!("[DB Pool] Could not install rotated credentials: {}", e);
+        }
+    }
+
+    /// Applies the agent's rotations and denials until the pool is dropped.
+    fn spawn_listener(&self) -> tokio::task::JoinHandle<()> {
+        let pool: Weak<PoolInner> = Arc::downgrade(&self.inner);
+        let mut events = self.inner.agent.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let event = events.recv().await;
+                let Some(inner) = pool.upgrade() else {
+                    break;
+                };
+                let pool = AttestedPool { inner };
+                match event {
+                    Ok(AttestationEvent::Rotated(released)) => pool.apply(&released),
+                    Ok(AttestationEvent::Denied { reason }) => pool.revoke(&reason),
+                    // Missed events: catch up with the agent's current state.
+                    Err(RecvError::Lagged(_)) => match pool.inner.agent.released() {
+                        Some(released) => pool.apply(&released),
+                        None => {
+                            let state = pool.inner.agent.trust_state();
+                            if state.level == TrustLevel::Denied {
+                                pool.revoke(state.last_error.as_deref().unwrap_or("attestation denied"));
+                            }
+                        }
+                    },
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+
+    /// Checks out a connection, opening one if no current idle connection
+    /// is available. If the server rejects the credentials, the pool
+    /// re-attests once and retries with whatever the KBS releases.
+    pub async fn get(&self) -> Result<PooledClient, AttestationError> {
+        let permit = Arc::clone(&self.inner.permits)
+            .acquire_owned()
+            .await
+            .map_err(|_| AttestationError::PoolClosed)?;
+
+        let generation = self.generation();
+        while let Some(idle) = self.inner.idle.lock().ok().and_then(|mut idle| idle.pop()) {
+            if idle.generation == generation && !idle.client.is_closed() {
+                return Ok(self.checkout(idle.client, idle.generation, permit));
+            }
+        }
+
+        let (client, generation) = match self.open().await {
+            Err(AttestationError::Database(e)) if is_auth_failure(&e) => {
+                println!("[DB Pool] Credentials rejected by the server; re-attesting");
+                self.refresh().await?;
+                self.open().await?
+            }
+            other => other?,
+        };
+        Ok(self.checkout(client, generation, permit))
+    }
+
+    fn checkout(&self, client: Client, generation: u64, permit: OwnedSemaphorePermit) -> PooledClient {
+        PooledClient { client: Some(client), generation, pool: Arc::clone(&self.inner), _permit: permit }
+    }
+
+    async fn open(&self) -> Result<(Client, u64), AttestationError> {
+        let generation = self.generation();
+        let credentials = self
+            .inner
+            .credentials
+            .read()?
+            .clone()
+            .ok_or_else(|| {
+                let level = self.inner.agent.trust_state().level;
+                AttestationError::Credentials(format!("no credentials released (trust state {:?})", level))
+            })?;
+
+        let timeout = self.inner.config.connect_timeout;
+        let client = match credentials.tls {
+            Some(tls) => connect(&credentials.config, tls, timeout).await?,
+            None => connect(&credentials.config, NoTls, timeout).await?,
+        };
+        Ok((client, generation))
+    }
+}
+
+/// Opens one connection and drives it on a background task.
+async fn connect<T>(config: &Config, tls: T, timeout: Duration) -> Result<Client, AttestationError>
+where
+    T: MakeTlsConnect<Socket>,
+    T::Stream: Send + 'static,
+{
+    let (client, connection) = tokio::time::timeout(timeout, config.connect(tls))
+        .await
+        .map_err(|_| AttestationError::Timeout("connecting to the database".to_string()))??;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            eprintln!("[DB Pool] Connection error: {}", e);
+        }
+    });
+    Ok(client)
+}
+
+/// The server refused the user or password, e.g. after a rotation.
+fn is_auth_failure(error: &tokio_postgres::Error) -> bool {
+    matches!(
+        error.code(),
+        Some(code) if *code == SqlState::INVALID_PASSWORD || *code == SqlState::INVALID_AUTHORIZATION_SPECIFICATION
+    )
+}
+
+/// A checked-out connection. It goes back to the pool on drop unless it
+/// was closed or opened with credentials that have since been rotated.
+pub struct PooledClient {
+    client: Option<Client>,
+    generation: u64,
+    pool: Arc<PoolInner>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Deref for PooledClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().expect("client is only taken on drop")
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        let Some(client) = self.client.take() else {
+            return;
+        };
+        if client.is_closed() || self.generation != self.pool.generation.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Ok(mut idle) = self.pool.idle.lock() {
+            idle.push(IdleClient { client, generation: self.generation });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation_agent::tests::{deny, offline_agent, release, TestKbs};
+    use crate::tls::tests::MockDb;
+    use attester_flow::retry::RetryPolicy;
+
+    const CREDENTIALS: &str = "default/db/credentials";
+
+    fn config() -> PoolConfig {
+        PoolConfig {
+            size: 4,
+            credential_resource: CREDENTIALS.to_string(),
+            connect_timeout: Duration::from_secs(5),
+            sslmode: SslMode::Disable,
+            ca_resource: None,
+            client_cert_resource: None,
+            client_key_resource: None,
+        }
+    }
+
+    fn released(config: &PoolConfig, resources: &[&str]) -> Released {
+        let resources = resources.iter().map(|r| r.as_bytes().to_vec()).collect();
+        Released { paths: config.resources(), resources, generation: 1 }
+    }
+
+    fn password(pool: &AttestedPool) -> Option<Vec<u8>> {
+        let credentials = pool.inner.credentials.read().unwrap();
+        credentials.as_ref().map(|c| c.config.get_password().unwrap_or_default().to_vec())
+    }
+
+    fn idle(pool: &AttestedPool) -> Vec<u64> {
+        pool.inner.idle.lock().unwrap().iter().map(|c| c.generation).collect()
+    }
+
+    /// Waits for the event listener to bring the pool to `state`.
+    async fn settle(pool: &AttestedPool, state: impl Fn(&AttestedPool) -> bool) {
+        for _ in 0..200 {
+            if state(pool) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the pool did not follow the agent");
+    }
+
+    #[test]
+    fn installs_and_rotates_credentials() {
+        let config = config();
+        let pool = AttestedPool::new(offline_agent(), config.clone());
+        assert!(pool.install(&released(&config, &["host=db user=app password=a"])).unwrap());
+        assert_eq!(pool.generation(), 1);
+
+        // The same settings, however the secret is formatted, are no rotation.
+        assert!(!pool.install(&released(&config, &["host=db user=app password=a"])).unwrap());
+        assert!(!pool.install(&released(&config, &["  host=db user=app password=a\n"])).unwrap());
+        assert_eq!(pool.generation(), 1);
+
+        assert!(pool.install(&released(&config, &["host=db user=app password=b"])).unwrap());
+        assert_eq!(pool.generation(), 2);
+        assert_eq!(password(&pool).unwrap(), b"b");
+
+        // Resources for other paths, or that do not parse, change nothing.
+        let other = Released { paths: vec!["default/db/other".to_string()], ..released(&config, &["host=x"]) };
+        for bad in [other, released(&config, &["host=db port=not-a-port"])] {
+            assert!(matches!(pool.install(&bad), Err(AttestationError::Credentials(_))));
+        }
+        assert_eq!(pool.generation(), 2);
+        assert_eq!(password(&pool).unwrap(), b"b");
+    }
+
+    #[test]
+    fn tls_material_comes_from_the_configured_resources() {
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let mut ca_params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let client_key = rcgen::KeyPair::generate().unwrap();
+        let client = rcgen::CertificateParams::new(Vec::new())
+            .unwrap()
+            .signed_by(&client_key, &ca, &ca_key)
+            .unwrap();
+
+        let config = PoolConfig {
+            sslmode: SslMode::VerifyFull,
+            ca_resource: Some("default/db/ca".to_string()),
+            client_cert_resource: Some("default/db/cert".to_string()),
+            client_key_resource: Some("default/db/key".to_string()),
+            ..config()
+        };
+        assert_eq!(config.resources(), [CREDENTIALS, "default/db/ca", "default/db/cert", "default/db/key"]);
+
+        let pool = AttestedPool::new(offline_agent(), config.clone());
+        let (ca_pem, cert_pem, key_pem) = (ca.pem(), client.pem(), client_key.serialize_pem());
+        pool.install(&released(&config, &["host=db user=app", &ca_pem, &cert_pem, &key_pem])).unwrap();
+        let credentials = pool.inner.credentials.read().unwrap().clone().unwrap();
+        assert_eq!(credentials.material.ca_pem.as_deref(), Some(ca_pem.as_bytes()));
+        assert_eq!(credentials.material.client_cert_pem.as_deref(), Some(cert_pem.as_bytes()));
+        assert_eq!(credentials.material.client_key_pem.as_deref(), Some(key_pem.as_bytes()));
+        assert!(credentials.tls.is_some());
+
+        // A CA that is not PEM is refused rather than connecting unverified.
+        let err = pool.install(&released(&config, &["host=db user=app", "garbage", &cert_pem, &key_pem]));
+        assert!(matches!(err, Err(AttestationError::Credentials(_))));
+    }
+
+    #[tokio::test]
+    async fn rotation_retires_connections_opened_with_old_credentials() {
+        let db = MockDb::start("a").await;
+        let config = config();
+        let pool = AttestedPool::new(offline_agent(), config.clone());
+        pool.install(&released(&config, &[&db.connection_string("a")])).unwrap();
+
+        let first = pool.get().await.unwrap();
+        let second = pool.get().await.unwrap();
+        drop(first);
+        assert_eq!(idle(&pool), [1]);
+
+        // An idle connection is reused before a new one is opened.
+        let reused = pool.get().await.unwrap();
+        assert!(idle(&pool).is_empty());
+        assert_eq!(db.logins.load(Ordering::SeqCst), 2);
+        drop(reused);
+
+        // A rotation closes the idle connection, and `second`, still checked
+        // out under the old credentials, is not returned to the pool.
+        *db.password.lock().unwrap() = "b".to_string();
+        assert!(pool.install(&released(&config, &[&db.connection_string("b")])).unwrap());
+        assert!(idle(&pool).is_empty());
+        drop(second);
+        assert!(idle(&pool).is_empty());
+
+        drop(pool.get().await.unwrap());
+        assert_eq!(idle(&pool), [2]);
+        assert_eq!(db.logins.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn follows_rotations_and_denials_of_the_agent() {
+        let db = MockDb::start("a").await;
+        let config = config();
+        let agent = offline_agent();
+        let pool = AttestedPool::new(Arc::clone(&agent), config.clone());
+        pool.spawn_listener();
+
+        release(&agent, &config.resources(), vec![db.connection_string("a").into_bytes()]);
+        settle(&pool, |pool| password(pool).as_deref() == Some(b"a")).await;
+        // Rotations of other resources are not the pool's.
+        release(&agent, &["default/db/other".to_string()], vec![b"host=elsewhere".to_vec()]);
+        drop(pool.get().await.unwrap());
+        assert_eq!(idle(&pool), [1]);
+
+        deny(&agent);
+        settle(&pool, |pool| password(pool).is_none()).await;
+        assert!(idle(&pool).is_empty());
+        match pool.get().await {
+            Err(AttestationError::Credentials(e)) => assert!(e.contains("Denied"), "{}", e),
+            Err(e) => panic!("expected no credentials, got {}", e),
+            Ok(_) => panic!("a denied pool handed out a connection"),
+        }
+    }
+
+    #[tokio::test]
+    async fn catches_up_after_missing_events() {
+        let config = config();
+        let agent = offline_agent();
+        let pool = AttestedPool::new(Arc::clone(&agent), config.clone());
+        pool.spawn_listener();
+
+        // More rotations than the channel holds, before the listener runs.
+        for i in 0..40 {
+            release(&agent, &config.resources(), vec![format!("host=db user=app password={}", i).into_bytes()]);
+        }
+        settle(&pool, |pool| password(pool).as_deref() == Some(b"39")).await;
+
+        for i in 40..80 {
+            release(&agent, &config.resources(), vec![format!("host=db user=app password={}", i).into_bytes()]);
+        }
+        deny(&agent);
+        settle(&pool, |pool| password(pool).is_none()).await;
+    }
+
+    #[tokio::test]
+    async fn reattests_once_when_the_server_rejects_the_password() {
+        let db = MockDb::start("new").await;
+        let kbs = TestKbs::start(Duration::from_secs(300));
+        kbs.put(CREDENTIALS, db.connection_string("new").as_bytes());
+        let config = config();
+        let pool = AttestedPool::new(kbs.agent(RetryPolicy::no_retry()), config.clone());
+        pool.install(&released(&config, &[&db.connection_string("old")])).unwrap();
+
+        let client = pool.get().await.unwrap();
+        assert_eq!(pool.generation(), 2);
+        assert_eq!(password(&pool).unwrap(), b"new");
+        assert_eq!(db.logins.load(Ordering::SeqCst), 2);
+        drop(client);
+
+        // The server moved on but the KBS did not: one re-attestation that
+        // releases the same credentials, and the rejection is returned.
+        *db.password.lock().unwrap() = "newer".to_string();
+        pool.inner.idle.lock().unwrap().clear();
+        match pool.get().await {
+            Err(AttestationError::Database(e)) => assert!(is_auth_failure(&e), "{}", e),
+            Err(e) => panic!("expected the password to be rejected, got {}", e),
+            Ok(_) => panic!("connected with a rejected password"),
+        }
+        assert_eq!(db.logins.load(Ordering::SeqCst), 4);
+        assert_eq!(pool.generation(), 2);
+    }
+}
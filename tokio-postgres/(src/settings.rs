@@ -0,0 +1,133 @@
+use attester_flow::error::AttestationError;
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::tls::SslMode;
+
+// --- Database Settings ---
+//
+// The `[database]` table of sec/Settings.toml. It only describes how to
+// obtain credentials and TLS material (the KBS resources holding them) and
+// how large the pool is; the password itself never appears here.
+
+/// Environment variable overriding the settings file path.
+pub const SETTINGS_PATH_ENV: &str = "APP_SETTINGS";
+
+/// Default settings file, relative to the working directory.
+pub const DEFAULT_SETTINGS_PATH: &str = "sec/Settings.toml";
+
+/// Default KBS resource holding the database connection string.
+pub const DEFAULT_CREDENTIAL_RESOURCE: &str = "default/keys/database-cred";
+
+/// Default interval between background re-attestations, in seconds.
+pub const DEFAULT_REFRESH_INTERVAL_SECS: u64 = 300;
+
+/// Default lead time for renewing an attestation token, in seconds.
+pub const DEFAULT_RENEW_BEFORE_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DatabaseConfig {
+    pub pool_size: u32,
+    /// KBS resource (`<repo>/<type>/<tag>`) holding the connection string.
+    #[serde(default = "default_credential_resource")]
+    pub credential_resource: String,
+    /// How often the agent re-attests to pick up rotated credentials.
+    #[serde(default = "default_refresh_interval_secs")]
+    pub refresh_interval_secs: u64,
+    /// How long before its attestation token expires the agent renews it.
+    #[serde(default = "default_renew_before_secs")]
+    pub renew_before_secs: u64,
+    /// `disable`, `prefer`, `require` or `verify-full` (the default).
+    /// Overrides any `sslmode` in the released connection string.
+    #[serde(default)]
+    pub sslmode: SslMode,
+    /// KBS resource holding the PEM CA bundle; required for `verify-full`
+    /// and not allowed with `prefer`.
+    pub ca_resource: Option<String>,
+    /// KBS resources holding the PEM client certificate and key.
+    pub client_cert_resource: Option<String>,
+    pub client_key_resource: Option<String>,
+}
+
+fn default_credential_resource() -> String {
+    DEFAULT_CREDENTIAL_RESOURCE.to_string()
+}
+
+fn default_refresh_interval_secs() -> u64 {
+    DEFAULT_REFRESH_INTERVAL_SECS
+}
+
+fn default_renew_before_secs() -> u64 {
+    DEFAULT_RENEW_BEFORE_SECS
+}
+
+#[derive(Debug, Deserialize)]
+struct SettingsFile {
+    database: DatabaseConfig,
+}
+
+impl DatabaseConfig {
+    /// Reads the `[database]` table from a settings file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, AttestationError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| AttestationError::Configuration(format!("{}: {}", path.display(), e)))?;
+        let settings: SettingsFile = toml::from_str(&text).map_err(|e| AttestationError::Configuration(format!("{}: {}", path.display(), e)))?;
+        settings.database.validate()?;
+        Ok(settings.database)
+    }
+
+    /// Rejects unsafe TLS settings and warns about unauthenticated ones.
+    pub fn validate(&self) -> Result<(), AttestationError> {
+        self.sslmode.check(self.ca_resource.is_some())?;
+        if !self.sslmode.verifies_server() {
+            eprintln!(
+                "[DB TLS] WARNING: sslmode = \"{}\" does not authenticate the database server; \
+                 the released credentials can be phished by anyone able to intercept the connection. \
+                 Use sslmode = \"verify-full\" with a ca_resource.",
+                self.sslmode
+            );
+        }
+        Ok(())
+    }
+
+    /// Reads the file named by `APP_SETTINGS`, or `sec/Settings.toml`.
+    pub fn from_env() -> Result<Self, AttestationError> {
+        let path = std::env::var(SETTINGS_PATH_ENV).unwrap_or_else(|_| DEFAULT_SETTINGS_PATH.to_string());
+        DatabaseConfig::load(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(table: &str) -> DatabaseConfig {
+        toml::from_str::<SettingsFile>(&format!("[database]\npool_size = 1\n{}", table)).unwrap().database
+    }
+
+    #[test]
+    fn defaults_to_verify_full() {
+        let config = parse("ca_resource = \"default/tls/db-ca\"");
+        assert_eq!(config.sslmode, SslMode::VerifyFull);
+        config.validate().unwrap();
+
+        let err = parse("").validate().unwrap_err();
+        assert!(matches!(err, AttestationError::Configuration(_)), "{}", err);
+    }
+
+    #[test]
+    fn rejects_prefer_with_a_ca_resource() {
+        let err = parse("sslmode = \"prefer\"\nca_resource = \"default/tls/db-ca\"").validate().unwrap_err();
+        assert!(matches!(err, AttestationError::Configuration(_)), "{}", err);
+        parse("sslmode = \"prefer\"").validate().unwrap();
+        parse("sslmode = \"require\"\nca_resource = \"default/tls/db-ca\"").validate().unwrap();
+    }
+
+    #[test]
+    fn loads_the_shipped_settings() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../sec/Settings.toml");
+        let config = DatabaseConfig::load(path).unwrap();
+        assert_eq!(config.sslmode, SslMode::VerifyFull);
+        assert!(config.ca_resource.is_some());
+    }
+}
@@ -0,0 +1,314 @@
+// --- TLS for Database Connections ---
+//
+// The pool connects with material the KBS released after attestation: the
+// CA bundle and an optional client certificate and key. The modes and the
+// rustls configuration are shared with the KBS's own Postgres stores.
+
+pub use attester_flow::db_tls::{client_config, SslMode, TlsMaterial};
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use attester_flow::error::AttestationError;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::{RootCertStore, ServerConfig};
+    use serde::Deserialize;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_postgres::NoTls;
+    use tokio_postgres_rustls::MakeRustlsConnect;
+
+    /// `SSLRequest` code of the Postgres wire protocol.
+    const SSL_REQUEST_CODE: u32 = 80877103;
+
+    /// A locally generated CA and a certificate it issued.
+    struct TestPki {
+        ca_pem: String,
+        cert: CertificateDer<'static>,
+        key: PrivateKeyDer<'static>,
+        client_cert_pem: String,
+        client_key_pem: String,
+    }
+
+    impl TestPki {
+        /// A CA issuing a server certificate for `host` and a client certificate.
+        fn new(host: &str) -> Self {
+            let ca_key = rcgen::KeyPair::generate().unwrap();
+            let mut ca_params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+            ca_params.distinguished_name.push(rcgen::DnType::CommonName, "Test DB CA");
+            ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+            ca_params.key_usages = vec![rcgen::KeyUsagePurpose::KeyCertSign];
+            let ca = ca_params.self_signed(&ca_key).unwrap();
+
+            let server_key = rcgen::KeyPair::generate().unwrap();
+            let server = rcgen::CertificateParams::new(vec![host.to_string()])
+                .unwrap()
+                .signed_by(&server_key, &ca, &ca_key)
+                .unwrap();
+
+            let client_key = rcgen::KeyPair::generate().unwrap();
+            let mut client_params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+            client_params.distinguished_name.push(rcgen::DnType::CommonName, "app_user");
+            let client = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+            TestPki {
+                ca_pem: ca.pem(),
+                cert: server.der().clone(),
+                key: PrivateKeyDer::try_from(server_key.serialize_der()).unwrap(),
+                client_cert_pem: client.pem(),
+                client_key_pem: client_key.serialize_pem(),
+            }
+        }
+
+        fn server_config(&self, require_client_cert: bool) -> Arc<ServerConfig> {
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+                .with_safe_default_protocol_versions()
+                .unwrap();
+            let builder = if require_client_cert {
+                let mut roots = RootCertStore::empty();
+                roots.add(rustls_pemfile::certs(&mut self.ca_pem.as_bytes()).next().unwrap().unwrap()).unwrap();
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build().unwrap();
+                builder.with_client_cert_verifier(verifier)
+            } else {
+                builder.with_no_client_auth()
+            };
+            Arc::new(builder.with_single_cert(vec![self.cert.clone()], self.key.clone_key()).unwrap())
+        }
+
+        fn material(&self) -> TlsMaterial {
+            TlsMaterial { ca_pem: Some(self.ca_pem.clone().into_bytes()), ..TlsMaterial::default() }
+        }
+    }
+
+    /// Answers the startup of one connection like a trusting Postgres server:
+    /// `SSLRequest` is accepted if `tls` is set, then any startup message is
+    /// met with `AuthenticationOk` and `ReadyForQuery`. Returns whether the
+    /// session was encrypted.
+    async fn serve_one(listener: TcpListener, tls: Option<Arc<ServerConfig>>) -> std::io::Result<bool> {
+        let (mut stream, _) = listener.accept().await?;
+        let mut head = [0u8; 8];
+        stream.read_exact(&mut head).await?;
+        if u32::from_be_bytes(head[4..8].try_into().unwrap()) != SSL_REQUEST_CODE {
+            startup(&mut stream, head).await?;
+            return Ok(false);
+        }
+        let Some(config) = tls else {
+            stream.write_all(b"N").await?;
+            stream.read_exact(&mut head).await?;
+            startup(&mut stream, head).await?;
+            return Ok(false);
+        };
+        stream.write_all(b"S").await?;
+        let mut stream = tokio_rustls::TlsAcceptor::from(config).accept(stream).await?;
+        stream.read_exact(&mut head).await?;
+        startup(&mut stream, head).await?;
+        Ok(true)
+    }
+
+    /// Consumes the rest of a startup message whose first 8 bytes are `head`
+    /// and lets the client in without a password.
+    async fn startup<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, head: [u8; 8]) -> std::io::Result<()> {
+        let len = u32::from_be_bytes(head[0..4].try_into().unwrap()) as usize;
+        let mut rest = vec![0u8; len.saturating_sub(8)];
+        stream.read_exact(&mut rest).await?;
+        stream.write_all(&[b'R', 0, 0, 0, 8, 0, 0, 0, 0]).await?; // AuthenticationOk
+        stream.write_all(&[b'Z', 0, 0, 0, 5, b'I']).await?; // ReadyForQuery
+        stream.flush().await?;
+        // Hold the connection until the client hangs up.
+        let _ = stream.read(&mut [0u8; 64]).await;
+        Ok(())
+    }
+
+    /// A plaintext Postgres server that asks for a cleartext password and
+    /// lets a client in only if it sends the current `password`; anyone
+    /// else gets `28P01 invalid_password`.
+    pub(crate) struct MockDb {
+        pub(crate) port: u16,
+        pub(crate) password: Arc<Mutex<String>>,
+        /// Password messages received so far.
+        pub(crate) logins: Arc<AtomicUsize>,
+    }
+
+    impl MockDb {
+        pub(crate) async fn start(password: &str) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let db = MockDb {
+                port: listener.local_addr().unwrap().port(),
+                password: Arc::new(Mutex::new(password.to_string())),
+                logins: Arc::new(AtomicUsize::new(0)),
+            };
+            let (password, logins) = (Arc::clone(&db.password), Arc::clone(&db.logins));
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(login(stream, Arc::clone(&password), Arc::clone(&logins)));
+                }
+            });
+            db
+        }
+
+        /// A connection string for this server with `password`.
+        pub(crate) fn connection_string(&self, password: &str) -> String {
+            format!("host=127.0.0.1 port={} user=app_user dbname=prod_data password={}", self.port, password)
+        }
+    }
+
+    async fn login(
+        mut stream: TcpStream,
+        password: Arc<Mutex<String>>,
+        logins: Arc<AtomicUsize>,
+    ) -> std::io::Result<()> {
+        let mut head = [0u8; 8];
+        stream.read_exact(&mut head).await?;
+        let len = u32::from_be_bytes(head[0..4].try_into().unwrap()) as usize;
+        stream.read_exact(&mut vec![0u8; len.saturating_sub(8)]).await?;
+        stream.write_all(&[b'R', 0, 0, 0, 8, 0, 0, 0, 3]).await?; // AuthenticationCleartextPassword
+
+        let mut tag = [0u8; 5];
+        stream.read_exact(&mut tag).await?;
+        let mut sent = vec![0u8; (u32::from_be_bytes(tag[1..5].try_into().unwrap()) as usize).saturating_sub(4)];
+        stream.read_exact(&mut sent).await?;
+        logins.fetch_add(1, Ordering::SeqCst);
+        let expected = password.lock().unwrap().clone();
+        if sent.strip_suffix(&[0]) != Some(expected.as_bytes()) {
+            let fields = b"SFATAL\0C28P01\0Mpassword authentication failed\0\0";
+            let mut error = vec![b'E'];
+            error.extend_from_slice(&(fields.len() as u32 + 4).to_be_bytes());
+            error.extend_from_slice(fields);
+            return stream.write_all(&error).await;
+        }
+        stream.write_all(&[b'R', 0, 0, 0, 8, 0, 0, 0, 0]).await?; // AuthenticationOk
+        stream.write_all(&[b'Z', 0, 0, 0, 5, b'I']).await?; // ReadyForQuery
+        // Hold the connection until the client hangs up.
+        let _ = stream.read(&mut [0u8; 64]).await;
+        Ok(())
+    }
+
+    /// Connects to a test server as `localhost` with `mode`. Returns the
+    /// client's result and whether the server saw an encrypted session.
+    async fn connect(
+        mode: SslMode,
+        material: &TlsMaterial,
+        server_tls: Option<Arc<ServerConfig>>,
+    ) -> (Result<(), String>, std::io::Result<bool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(serve_one(listener, server_tls));
+
+        let mut config = tokio_postgres::Config::new();
+        config
+            .host("localhost")
+            .hostaddr(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .port(port)
+            .user("app_user")
+            .dbname("prod_data")
+            .ssl_mode(mode.negotiation());
+        let client = match client_config(mode, material) {
+            Ok(Some(tls)) => config.connect(MakeRustlsConnect::new(tls)).await.map(|(client, _)| client),
+            Ok(None) => config.connect(NoTls).await.map(|(client, _)| client),
+            Err(e) => {
+                server.abort();
+                return (Err(e.to_string()), Ok(false));
+            }
+        };
+        let result = client.map(drop).map_err(|e| e.to_string());
+        (result, server.await.unwrap())
+    }
+
+    #[test]
+    fn verify_full_is_the_default() {
+        #[derive(Deserialize)]
+        struct Table {
+            #[serde(default)]
+            sslmode: SslMode,
+        }
+        assert_eq!(SslMode::default(), SslMode::VerifyFull);
+        assert_eq!(toml::from_str::<Table>("").unwrap().sslmode, SslMode::VerifyFull);
+        assert_eq!(toml::from_str::<Table>("sslmode = \"require\"").unwrap().sslmode, SslMode::Require);
+    }
+
+    #[test]
+    fn prefer_is_refused_with_a_ca_bundle() {
+        let pki = TestPki::new("localhost");
+        let err = client_config(SslMode::Prefer, &pki.material()).unwrap_err();
+        assert!(matches!(err, AttestationError::Configuration(_)), "{}", err);
+        assert!(client_config(SslMode::Prefer, &TlsMaterial::default()).unwrap().is_some());
+        assert!(client_config(SslMode::Require, &pki.material()).unwrap().is_some());
+    }
+
+    #[test]
+    fn verify_full_requires_a_ca_bundle() {
+        let err = client_config(SslMode::VerifyFull, &TlsMaterial::default()).unwrap_err();
+        assert!(matches!(err, AttestationError::Configuration(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn verify_full_accepts_a_server_issued_by_the_released_ca() {
+        let pki = TestPki::new("localhost");
+        let (client, server) = connect(SslMode::VerifyFull, &pki.material(), Some(pki.server_config(false))).await;
+        client.unwrap();
+        assert!(server.unwrap(), "session was not encrypted");
+    }
+
+    #[tokio::test]
+    async fn verify_full_rejects_another_ca() {
+        let pki = TestPki::new("localhost");
+        let impostor = TestPki::new("localhost");
+        let (client, server) = connect(SslMode::VerifyFull, &pki.material(), Some(impostor.server_config(false))).await;
+        assert!(client.is_err());
+        assert!(server.is_err(), "handshake completed with an untrusted certificate");
+    }
+
+    #[tokio::test]
+    async fn verify_full_rejects_another_host_name() {
+        let pki = TestPki::new("db.internal.example");
+        let (client, server) = connect(SslMode::VerifyFull, &pki.material(), Some(pki.server_config(false))).await;
+        assert!(client.is_err());
+        assert!(server.is_err(), "handshake completed for the wrong host name");
+    }
+
+    #[tokio::test]
+    async fn verify_full_and_require_never_fall_back_to_plaintext() {
+        let pki = TestPki::new("localhost");
+        for mode in [SslMode::VerifyFull, SslMode::Require] {
+            let (client, _) = connect(mode, &pki.material(), None).await;
+            assert!(client.is_err(), "{} connected without TLS", mode);
+        }
+
+        // This is exactly what `prefer` would do, hence it is refused with a CA.
+        let (client, server) = connect(SslMode::Prefer, &TlsMaterial::default(), None).await;
+        client.unwrap();
+        assert!(!server.unwrap());
+    }
+
+    #[tokio::test]
+    async fn require_encrypts_without_authenticating() {
+        let pki = TestPki::new("localhost");
+        let impostor = TestPki::new("elsewhere.example");
+        let (client, server) = connect(SslMode::Require, &pki.material(), Some(impostor.server_config(false))).await;
+        client.unwrap();
+        assert!(server.unwrap());
+    }
+
+    #[tokio::test]
+    async fn presents_the_released_client_certificate() {
+        let pki = TestPki::new("localhost");
+        let server_tls = Some(pki.server_config(true));
+
+        let (client, server) = connect(SslMode::VerifyFull, &pki.material(), server_tls.clone()).await;
+        assert!(client.is_err() && server.is_err(), "server accepted a client without a certificate");
+
+        let material = TlsMaterial {
+            client_cert_pem: Some(pki.client_cert_pem.clone().into_bytes()),
+            client_key_pem: Some(pki.client_key_pem.clone().into_bytes()),
+            ..pki.material()
+        };
+        let (client, server) = connect(SslMode::VerifyFull, &material, server_tls).await;
+        client.unwrap();
+        assert!(server.unwrap());
+    }
+}